@@ -0,0 +1,14 @@
+//! The circuitchat wire protocol, usable without the TUI or Tor.
+//!
+//! [`NoisePeer`] runs the handshake, password authentication and framing over
+//! any `AsyncRead + AsyncWrite` stream, and [`Message`] encodes and decodes the
+//! plaintext frames exchanged afterwards. See `docs/PROTOCOL.md` for the spec.
+
+pub mod fingerprint;
+pub mod message;
+pub mod noise;
+
+pub use message::{
+    ENVELOPE_PROTOCOL, Features, Framing, Hello, MAX_TEXT_LEN, MIN_PROTOCOL_VERSION, Message, PROTOCOL_VERSION,
+};
+pub use noise::{NoisePeer, PATTERN_NN, StaticKeypair};
@@ -0,0 +1,711 @@
+// legacy control frames start with a zero byte followed by a tag, anything
+// else is text
+const CONTROL: u8 = 0x00;
+// envelopes start with their format version, which legacy frames never do
+const ENVELOPE: u8 = 0x01;
+const ENVELOPE_HEADER_LEN: usize = 6;
+// what one Noise message holds once its 16-byte tag is taken off
+const MAX_FRAME_PAYLOAD: usize = 65535 - 16;
+/// The most UTF-8 bytes a `Text` can carry in a single frame, with either
+/// framing and whether or not it ends up compressed.
+pub const MAX_TEXT_LEN: usize = MAX_FRAME_PAYLOAD - ENVELOPE_HEADER_LEN - 16;
+
+const TAG_OFFER: u8 = b'F';
+const TAG_CHUNK: u8 = b'C';
+const TAG_DONE: u8 = b'D';
+const TAG_CANCEL: u8 = b'X';
+const TAG_FILE_ACCEPT: u8 = 0x05;
+const TAG_FILE_REJECT: u8 = 0x06;
+const TAG_TYPING_START: u8 = 0x07;
+const TAG_TYPING_STOP: u8 = 0x08;
+const TAG_DELIVERED: u8 = 0x09;
+const TAG_PING: u8 = 0x0A;
+const TAG_PONG: u8 = 0x0B;
+const TAG_AWAY: u8 = 0x0C;
+const TAG_BACK: u8 = 0x0D;
+const TAG_READ: u8 = 0x0E;
+const TAG_HELLO: u8 = 0xFF;
+
+// envelope message types
+const KIND_TEXT: u8 = 0x01;
+const KIND_HELLO: u8 = 0x02;
+const KIND_COMPRESSED: u8 = 0x03;
+const KIND_FILE_OFFER: u8 = 0x10;
+const KIND_FILE_ACCEPT: u8 = 0x11;
+const KIND_FILE_REJECT: u8 = 0x12;
+const KIND_FILE_CHUNK: u8 = 0x13;
+const KIND_FILE_DONE: u8 = 0x14;
+const KIND_FILE_CANCEL: u8 = 0x15;
+const KIND_FILE_ACK: u8 = 0x16;
+const KIND_FILE_CHUNK_PROOF: u8 = 0x17;
+const KIND_FOLDER_OFFER: u8 = 0x18;
+const KIND_VOICE_OFFER: u8 = 0x19;
+const KIND_TYPING_START: u8 = 0x20;
+const KIND_TYPING_STOP: u8 = 0x21;
+const KIND_DELIVERED: u8 = 0x22;
+const KIND_PING: u8 = 0x23;
+const KIND_PONG: u8 = 0x24;
+const KIND_AWAY: u8 = 0x25;
+const KIND_BACK: u8 = 0x26;
+const KIND_READ: u8 = 0x27;
+const KIND_EDIT: u8 = 0x30;
+const KIND_DELETE: u8 = 0x31;
+const KIND_REACT: u8 = 0x32;
+const KIND_EXPIRE: u8 = 0x40;
+const KIND_EXPIRE_ACCEPT: u8 = 0x41;
+
+const CHECKSUM_LEN: usize = 8;
+// a compressed envelope may unpack to more than fits in a transport frame,
+// but not to more than this
+const MAX_DECOMPRESSED_LEN: usize = 1 << 20;
+
+/// Bumped only when the wire format changes, independent of app releases.
+/// Version 1 is the hello without protocol and feature fields, version 3
+/// added the envelope framing.
+pub const PROTOCOL_VERSION: u16 = 3;
+/// The first protocol that understands envelopes.
+pub const ENVELOPE_PROTOCOL: u16 = 3;
+/// The oldest protocol we can still talk to.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Optional behaviour a client supports. A feature is only used when both
+/// sides advertise it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Features(u32);
+
+impl Features {
+    pub const RECEIPTS: Features = Features(1 << 0);
+    pub const TYPING: Features = Features(1 << 1);
+    pub const AWAY: Features = Features(1 << 2);
+    pub const RESUME: Features = Features(1 << 3);
+    pub const REACTIONS: Features = Features(1 << 4);
+    pub const READ: Features = Features(1 << 5);
+    pub const EDITS: Features = Features(1 << 6);
+    pub const EXPIRY: Features = Features(1 << 7);
+    pub const FLOW: Features = Features(1 << 8);
+    pub const TRANSFERS: Features = Features(1 << 9);
+    pub const HASHES: Features = Features(1 << 10);
+    pub const FOLDERS: Features = Features(1 << 11);
+    pub const COMPRESSION: Features = Features(1 << 12);
+    pub const VOICE: Features = Features(1 << 13);
+
+    /// Assumed for protocol 1 peers, which could not advertise features but
+    /// implemented all of these.
+    pub const LEGACY: Features = Features(0b1111);
+
+    const NAMES: &[(Features, &str)] = &[
+        (Features::RECEIPTS, "receipts"),
+        (Features::TYPING, "typing"),
+        (Features::AWAY, "away"),
+        (Features::RESUME, "resume"),
+        (Features::REACTIONS, "reactions"),
+        (Features::READ, "read"),
+        (Features::EDITS, "edits"),
+        (Features::EXPIRY, "expiry"),
+        (Features::FLOW, "flow"),
+        (Features::TRANSFERS, "transfers"),
+        (Features::HASHES, "hashes"),
+        (Features::FOLDERS, "folders"),
+        (Features::COMPRESSION, "compression"),
+        (Features::VOICE, "voice"),
+    ];
+
+    pub const fn empty() -> Self {
+        Features(0)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Features(bits)
+    }
+
+    pub const fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn names(self) -> Vec<&'static str> {
+        Self::NAMES
+            .iter()
+            .filter(|(f, _)| self.contains(*f))
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+impl std::ops::BitOr for Features {
+    type Output = Features;
+    fn bitor(self, rhs: Features) -> Features {
+        Features(self.0 | rhs.0)
+    }
+}
+
+impl std::ops::BitOrAssign for Features {
+    fn bitor_assign(&mut self, rhs: Features) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl std::ops::BitAnd for Features {
+    type Output = Features;
+    fn bitand(self, rhs: Features) -> Features {
+        Features(self.0 & rhs.0)
+    }
+}
+
+/// Sent by both sides as the first message of a session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hello {
+    /// The sender's app version, informational only.
+    pub app_version: (u8, u8, u8),
+    pub protocol: u16,
+    pub features: Features,
+}
+
+impl Hello {
+    pub fn new(app_version: &str, features: Features) -> Self {
+        let mut parts = app_version
+            .split(['.', '-'])
+            .map(|s| s.parse::<u8>().unwrap_or(0));
+        let mut next = || parts.next().unwrap_or(0);
+        Hello {
+            app_version: (next(), next(), next()),
+            protocol: PROTOCOL_VERSION,
+            features,
+        }
+    }
+
+    pub fn is_compatible(&self) -> bool {
+        self.protocol >= MIN_PROTOCOL_VERSION
+    }
+}
+
+/// How messages are laid out inside a transport frame. Both sides start with
+/// `Legacy` and switch once the peer's hello shows it understands envelopes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// A zero byte and a tag for control messages, bare UTF-8 for text.
+    #[default]
+    Legacy,
+    /// A version byte, a message type, a u32 body length and the body.
+    Envelope,
+}
+
+impl Framing {
+    pub fn for_peer(hello: &Hello) -> Self {
+        if hello.protocol >= ENVELOPE_PROTOCOL {
+            Framing::Envelope
+        } else {
+            Framing::Legacy
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    /// `id` is 0 for text from legacy peers, which cannot carry one.
+    /// `reply_to` is the id of the quoted message, or 0.
+    Text { id: u64, reply_to: u64, text: String },
+    /// Replaces the text of one of the sender's own messages.
+    Edit { id: u64, text: String },
+    /// Withdraws one of the sender's own messages.
+    Delete(u64),
+    /// Sets the sender's reaction to any message, an empty emoji removes it.
+    React { id: u64, emoji: String },
+    /// Proposes deleting messages older than this many seconds on both
+    /// sides, 0 to stop expiring them.
+    Expire(u64),
+    /// Agrees to the peer's proposal, repeating its period.
+    ExpireAccept(u64),
+    /// `transfer` tells apart files in flight at the same time. It is chosen
+    /// by the side offering the file and is 0 in legacy frames and from peers
+    /// without the `transfers` feature.
+    FileOffer {
+        transfer: u64,
+        name: String,
+        size: u64,
+        checksum: Option<Vec<u8>>,
+    },
+    /// Offers `files` files of `size` bytes in all, which follow as ordinary
+    /// offers named `name/...` once this is accepted.
+    FolderOffer {
+        transfer: u64,
+        name: String,
+        files: u32,
+        size: u64,
+    },
+    /// A recorded voice clip of `duration_ms`, an Ogg Opus file sent and
+    /// answered like a `FileOffer`.
+    VoiceOffer {
+        transfer: u64,
+        name: String,
+        size: u64,
+        checksum: Option<Vec<u8>>,
+        duration_ms: u32,
+    },
+    FileAccept { transfer: u64, offset: u64 },
+    FileReject(u64),
+    /// `proof` holds the sibling hashes from the chunk's leaf up to the
+    /// offered tree root, for peers with the `hashes` feature.
+    FileChunk {
+        transfer: u64,
+        proof: Option<Vec<[u8; 32]>>,
+        data: Vec<u8>,
+    },
+    FileDone(u64),
+    /// Sent by either side, naming a transfer of either direction.
+    FileCancel(u64),
+    /// How many bytes of the file the receiver has written, so the sender can
+    /// keep a bounded window in flight.
+    FileAck { transfer: u64, offset: u64 },
+    TypingStart,
+    TypingStop,
+    /// Acknowledges the text with this id, or the oldest unacknowledged one
+    /// if it is 0.
+    Delivered(u64),
+    Read(u64),
+    Ping,
+    Pong,
+    Away,
+    Back,
+    Hello(Hello),
+    /// A message type we do not know, from a newer peer. Never shown to the
+    /// user.
+    Unknown { kind: u8, body: Vec<u8> },
+}
+
+impl Message {
+    /// A text message with a fresh random id.
+    pub fn text(text: impl Into<String>) -> Self {
+        Message::reply(0, text)
+    }
+
+    /// A text message quoting the message `reply_to`.
+    pub fn reply(reply_to: u64, text: impl Into<String>) -> Self {
+        Message::Text {
+            id: rand::random::<u64>().max(1),
+            reply_to,
+            text: text.into(),
+        }
+    }
+
+    /// The transfer a file message belongs to.
+    pub fn transfer(&self) -> Option<u64> {
+        match self {
+            Message::FileOffer { transfer, .. }
+            | Message::FolderOffer { transfer, .. }
+            | Message::VoiceOffer { transfer, .. }
+            | Message::FileAccept { transfer, .. }
+            | Message::FileChunk { transfer, .. }
+            | Message::FileAck { transfer, .. }
+            | Message::FileReject(transfer)
+            | Message::FileDone(transfer)
+            | Message::FileCancel(transfer) => Some(*transfer),
+            _ => None,
+        }
+    }
+
+    /// Hellos are always sent with legacy framing, since they are what the
+    /// framing is negotiated with.
+    pub fn encode(&self, framing: Framing) -> Vec<u8> {
+        match (framing, self) {
+            (_, Message::Hello(_)) | (Framing::Legacy, _) => self.encode_legacy(),
+            (Framing::Envelope, _) => {
+                let (kind, body) = self.envelope_body();
+                let mut msg = Vec::with_capacity(ENVELOPE_HEADER_LEN + body.len() + 8);
+                msg.push(ENVELOPE);
+                msg.push(kind);
+                msg.extend_from_slice(&(body.len() as u32).to_be_bytes());
+                msg.extend_from_slice(&body);
+                // after the body, where peers from before transfer ids skip it
+                if let Some(transfer) = self.transfer().filter(|t| *t != 0) {
+                    msg.extend_from_slice(&transfer.to_be_bytes());
+                }
+                msg
+            }
+        }
+    }
+
+    /// Like `encode`, but packed into a zstd-compressed envelope at `level`
+    /// when that comes out smaller. Only for peers with the `compression`
+    /// feature; legacy frames and hellos are never compressed.
+    pub fn encode_compressed(&self, framing: Framing, level: i32) -> Vec<u8> {
+        let plain = self.encode(framing);
+        if framing == Framing::Legacy || matches!(self, Message::Hello(_)) {
+            return plain;
+        }
+        match zstd::bulk::compress(&plain, level) {
+            Ok(packed) if ENVELOPE_HEADER_LEN + packed.len() < plain.len() => {
+                let mut msg = Vec::with_capacity(ENVELOPE_HEADER_LEN + packed.len());
+                msg.push(ENVELOPE);
+                msg.push(KIND_COMPRESSED);
+                msg.extend_from_slice(&(packed.len() as u32).to_be_bytes());
+                msg.extend_from_slice(&packed);
+                msg
+            }
+            _ => plain,
+        }
+    }
+
+    fn envelope_body(&self) -> (u8, Vec<u8>) {
+        match self {
+            Message::Text { id, reply_to, text } => {
+                let mut body = id.to_be_bytes().to_vec();
+                body.extend_from_slice(&reply_to.to_be_bytes());
+                body.extend_from_slice(text.as_bytes());
+                (KIND_TEXT, body)
+            }
+            Message::Edit { id, text } => {
+                let mut body = id.to_be_bytes().to_vec();
+                body.extend_from_slice(text.as_bytes());
+                (KIND_EDIT, body)
+            }
+            Message::Delete(id) => (KIND_DELETE, id.to_be_bytes().to_vec()),
+            Message::React { id, emoji } => {
+                let mut body = id.to_be_bytes().to_vec();
+                body.extend_from_slice(emoji.as_bytes());
+                (KIND_REACT, body)
+            }
+            Message::Expire(secs) => (KIND_EXPIRE, secs.to_be_bytes().to_vec()),
+            Message::ExpireAccept(secs) => (KIND_EXPIRE_ACCEPT, secs.to_be_bytes().to_vec()),
+            Message::FileOffer {
+                name,
+                size,
+                checksum,
+                ..
+            } => {
+                let checksum = checksum.as_deref().unwrap_or(&[]);
+                let mut body = size.to_be_bytes().to_vec();
+                body.push(checksum.len() as u8);
+                body.extend_from_slice(checksum);
+                body.extend_from_slice(name.as_bytes());
+                (KIND_FILE_OFFER, body)
+            }
+            Message::FolderOffer { name, files, size, .. } => {
+                let mut body = files.to_be_bytes().to_vec();
+                body.extend_from_slice(&size.to_be_bytes());
+                body.extend_from_slice(name.as_bytes());
+                (KIND_FOLDER_OFFER, body)
+            }
+            Message::VoiceOffer {
+                name,
+                size,
+                checksum,
+                duration_ms,
+                ..
+            } => {
+                let checksum = checksum.as_deref().unwrap_or(&[]);
+                let mut body = duration_ms.to_be_bytes().to_vec();
+                body.extend_from_slice(&size.to_be_bytes());
+                body.push(checksum.len() as u8);
+                body.extend_from_slice(checksum);
+                body.extend_from_slice(name.as_bytes());
+                (KIND_VOICE_OFFER, body)
+            }
+            Message::FileAccept { offset, .. } => (KIND_FILE_ACCEPT, offset.to_be_bytes().to_vec()),
+            Message::FileReject(_) => (KIND_FILE_REJECT, Vec::new()),
+            Message::FileChunk { proof: None, data, .. } => (KIND_FILE_CHUNK, data.clone()),
+            Message::FileChunk {
+                proof: Some(proof),
+                data,
+                ..
+            } => {
+                let mut body = Vec::with_capacity(1 + proof.len() * 32 + data.len());
+                body.push(proof.len() as u8);
+                for hash in proof {
+                    body.extend_from_slice(hash);
+                }
+                body.extend_from_slice(data);
+                (KIND_FILE_CHUNK_PROOF, body)
+            }
+            Message::FileDone(_) => (KIND_FILE_DONE, Vec::new()),
+            Message::FileCancel(_) => (KIND_FILE_CANCEL, Vec::new()),
+            Message::FileAck { offset, .. } => (KIND_FILE_ACK, offset.to_be_bytes().to_vec()),
+            Message::TypingStart => (KIND_TYPING_START, Vec::new()),
+            Message::TypingStop => (KIND_TYPING_STOP, Vec::new()),
+            Message::Delivered(id) => (KIND_DELIVERED, id.to_be_bytes().to_vec()),
+            Message::Read(id) => (KIND_READ, id.to_be_bytes().to_vec()),
+            Message::Ping => (KIND_PING, Vec::new()),
+            Message::Pong => (KIND_PONG, Vec::new()),
+            Message::Away => (KIND_AWAY, Vec::new()),
+            Message::Back => (KIND_BACK, Vec::new()),
+            Message::Hello(_) => (KIND_HELLO, self.encode_legacy()[2..].to_vec()),
+            Message::Unknown { kind, body } => (*kind, body.clone()),
+        }
+    }
+
+    fn encode_legacy(&self) -> Vec<u8> {
+        let control = |tag: u8| vec![CONTROL, tag];
+        match self {
+            // legacy text carries no id, so acks for it refer to the oldest one
+            Message::Text { text, .. } => text.as_bytes().to_vec(),
+            Message::FileOffer {
+                name,
+                size,
+                checksum,
+                ..
+            } => {
+                let mut msg = control(TAG_OFFER);
+                msg.extend_from_slice(&size.to_be_bytes());
+                if let Some(c) = checksum {
+                    msg.extend_from_slice(c);
+                }
+                msg.extend_from_slice(name.as_bytes());
+                msg
+            }
+            Message::FileAccept { offset, .. } => {
+                let mut msg = control(TAG_FILE_ACCEPT);
+                msg.extend_from_slice(&offset.to_be_bytes());
+                msg
+            }
+            Message::FileReject(_) => control(TAG_FILE_REJECT),
+            Message::FileChunk { data, .. } => {
+                let mut msg = Vec::with_capacity(2 + data.len());
+                msg.extend_from_slice(&[CONTROL, TAG_CHUNK]);
+                msg.extend_from_slice(data);
+                msg
+            }
+            Message::FileDone(_) => control(TAG_DONE),
+            Message::FileCancel(_) => control(TAG_CANCEL),
+            Message::TypingStart => control(TAG_TYPING_START),
+            Message::TypingStop => control(TAG_TYPING_STOP),
+            Message::Delivered(_) => control(TAG_DELIVERED),
+            Message::Read(id) => {
+                let mut msg = control(TAG_READ);
+                msg.extend_from_slice(&id.to_be_bytes());
+                msg
+            }
+            Message::Ping => control(TAG_PING),
+            Message::Pong => control(TAG_PONG),
+            Message::Away => control(TAG_AWAY),
+            Message::Back => control(TAG_BACK),
+            Message::Hello(hello) => {
+                let (major, minor, patch) = hello.app_version;
+                let mut msg = vec![CONTROL, TAG_HELLO, major, minor, patch];
+                msg.extend_from_slice(&hello.protocol.to_be_bytes());
+                msg.extend_from_slice(&hello.features.bits().to_be_bytes());
+                msg
+            }
+            // no legacy form, these are never sent to legacy peers
+            Message::Edit { .. }
+            | Message::Delete(_)
+            | Message::React { .. }
+            | Message::Expire(_)
+            | Message::ExpireAccept(_)
+            | Message::FolderOffer { .. }
+            | Message::VoiceOffer { .. }
+            | Message::FileAck { .. }
+            | Message::Unknown { .. } => {
+                let (kind, body) = self.envelope_body();
+                let mut msg = control(kind);
+                msg.extend_from_slice(&body);
+                msg
+            }
+        }
+    }
+
+    /// Accepts both framings, since a peer keeps sending legacy frames until
+    /// it has seen our hello. Never fails: malformed envelopes and unknown
+    /// control tags become `Unknown`.
+    pub fn decode(data: &[u8]) -> Self {
+        match data {
+            [ENVELOPE, ..] => Self::decode_envelope(data),
+            _ => Self::decode_legacy(data),
+        }
+    }
+
+    fn decode_envelope(data: &[u8]) -> Self {
+        let kind = data.get(1).copied().unwrap_or(0);
+        let unknown = || Message::Unknown {
+            kind,
+            body: data.get(ENVELOPE_HEADER_LEN..).unwrap_or(&[]).to_vec(),
+        };
+        let Some(len) = data.get(2..ENVELOPE_HEADER_LEN) else {
+            return unknown();
+        };
+        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+        // anything after the body is reserved for later extensions
+        let Some(body) = data.get(ENVELOPE_HEADER_LEN..ENVELOPE_HEADER_LEN + len) else {
+            return unknown();
+        };
+        let transfer = data
+            .get(ENVELOPE_HEADER_LEN + len..ENVELOPE_HEADER_LEN + len + 8)
+            .map(|t| u64::from_be_bytes(t.try_into().unwrap()))
+            .unwrap_or(0);
+        match kind {
+            KIND_TEXT if body.len() >= 16 => Message::Text {
+                id: u64::from_be_bytes(body[..8].try_into().unwrap()),
+                reply_to: u64::from_be_bytes(body[8..16].try_into().unwrap()),
+                text: String::from_utf8_lossy(&body[16..]).to_string(),
+            },
+            KIND_EDIT if body.len() >= 8 => Message::Edit {
+                id: u64::from_be_bytes(body[..8].try_into().unwrap()),
+                text: String::from_utf8_lossy(&body[8..]).to_string(),
+            },
+            KIND_DELETE if body.len() == 8 => Message::Delete(u64::from_be_bytes(body.try_into().unwrap())),
+            KIND_REACT if body.len() >= 8 => Message::React {
+                id: u64::from_be_bytes(body[..8].try_into().unwrap()),
+                emoji: String::from_utf8_lossy(&body[8..]).to_string(),
+            },
+            KIND_EXPIRE if body.len() == 8 => Message::Expire(u64::from_be_bytes(body.try_into().unwrap())),
+            KIND_EXPIRE_ACCEPT if body.len() == 8 => {
+                Message::ExpireAccept(u64::from_be_bytes(body.try_into().unwrap()))
+            }
+            // one level only, so a compressed envelope cannot nest another
+            KIND_COMPRESSED => match zstd::bulk::decompress(body, MAX_DECOMPRESSED_LEN) {
+                Ok(inner) if inner.first() == Some(&ENVELOPE) && inner.get(1) != Some(&KIND_COMPRESSED) => {
+                    Self::decode_envelope(&inner)
+                }
+                _ => unknown(),
+            },
+            KIND_HELLO => match Self::decode_hello(body) {
+                Some(hello) => Message::Hello(hello),
+                None => unknown(),
+            },
+            KIND_FILE_OFFER if body.len() >= 9 && body.len() >= 9 + body[8] as usize => {
+                let checksum_end = 9 + body[8] as usize;
+                Message::FileOffer {
+                    transfer,
+                    name: String::from_utf8_lossy(&body[checksum_end..]).to_string(),
+                    size: u64::from_be_bytes(body[..8].try_into().unwrap()),
+                    checksum: (checksum_end > 9).then(|| body[9..checksum_end].to_vec()),
+                }
+            }
+            KIND_FOLDER_OFFER if body.len() >= 12 => Message::FolderOffer {
+                transfer,
+                name: String::from_utf8_lossy(&body[12..]).to_string(),
+                files: u32::from_be_bytes(body[..4].try_into().unwrap()),
+                size: u64::from_be_bytes(body[4..12].try_into().unwrap()),
+            },
+            KIND_VOICE_OFFER if body.len() >= 13 && body.len() >= 13 + body[12] as usize => {
+                let checksum_end = 13 + body[12] as usize;
+                Message::VoiceOffer {
+                    transfer,
+                    name: String::from_utf8_lossy(&body[checksum_end..]).to_string(),
+                    size: u64::from_be_bytes(body[4..12].try_into().unwrap()),
+                    checksum: (checksum_end > 13).then(|| body[13..checksum_end].to_vec()),
+                    duration_ms: u32::from_be_bytes(body[..4].try_into().unwrap()),
+                }
+            }
+            KIND_FILE_ACCEPT if body.len() == 8 => Message::FileAccept {
+                transfer,
+                offset: u64::from_be_bytes(body.try_into().unwrap()),
+            },
+            KIND_FILE_REJECT => Message::FileReject(transfer),
+            KIND_FILE_CHUNK => Message::FileChunk {
+                transfer,
+                proof: None,
+                data: body.to_vec(),
+            },
+            KIND_FILE_CHUNK_PROOF
+                if let Some(&count) = body.first()
+                    && body.len() > 32 * count as usize =>
+            {
+                let data_start = 1 + 32 * count as usize;
+                Message::FileChunk {
+                    transfer,
+                    proof: Some(
+                        body[1..data_start]
+                            .chunks_exact(32)
+                            .map(|hash| hash.try_into().unwrap())
+                            .collect(),
+                    ),
+                    data: body[data_start..].to_vec(),
+                }
+            }
+            KIND_FILE_DONE => Message::FileDone(transfer),
+            KIND_FILE_CANCEL => Message::FileCancel(transfer),
+            KIND_FILE_ACK if body.len() == 8 => Message::FileAck {
+                transfer,
+                offset: u64::from_be_bytes(body.try_into().unwrap()),
+            },
+            KIND_TYPING_START => Message::TypingStart,
+            KIND_TYPING_STOP => Message::TypingStop,
+            KIND_DELIVERED if body.len() == 8 => {
+                Message::Delivered(u64::from_be_bytes(body.try_into().unwrap()))
+            }
+            KIND_READ if body.len() == 8 => Message::Read(u64::from_be_bytes(body.try_into().unwrap())),
+            KIND_PING => Message::Ping,
+            KIND_PONG => Message::Pong,
+            KIND_AWAY => Message::Away,
+            KIND_BACK => Message::Back,
+            _ => Message::Unknown {
+                kind,
+                body: body.to_vec(),
+            },
+        }
+    }
+
+    fn decode_hello(body: &[u8]) -> Option<Hello> {
+        let app_version = (*body.first()?, *body.get(1)?, *body.get(2)?);
+        // protocol 1 peers only sent the app version
+        Some(match body.get(3..9) {
+            Some(ext) => Hello {
+                app_version,
+                protocol: u16::from_be_bytes([ext[0], ext[1]]),
+                features: Features::from_bits(u32::from_be_bytes(ext[2..6].try_into().unwrap())),
+            },
+            None => Hello {
+                app_version,
+                protocol: 1,
+                features: Features::LEGACY,
+            },
+        })
+    }
+
+    fn decode_legacy(data: &[u8]) -> Self {
+        let [CONTROL, tag, body @ ..] = data else {
+            return Message::Text {
+                id: 0,
+                reply_to: 0,
+                text: String::from_utf8_lossy(data).to_string(),
+            };
+        };
+        match *tag {
+            TAG_HELLO if let Some(hello) = Self::decode_hello(body) => Message::Hello(hello),
+            TAG_OFFER if body.len() >= 8 => {
+                let size = u64::from_be_bytes(body[..8].try_into().unwrap());
+                let (checksum, name) = if body.len() >= 8 + CHECKSUM_LEN {
+                    (
+                        Some(body[8..8 + CHECKSUM_LEN].to_vec()),
+                        &body[8 + CHECKSUM_LEN..],
+                    )
+                } else {
+                    (None, &body[8..])
+                };
+                Message::FileOffer {
+                    transfer: 0,
+                    name: String::from_utf8_lossy(name).to_string(),
+                    size,
+                    checksum,
+                }
+            }
+            TAG_CHUNK => Message::FileChunk {
+                transfer: 0,
+                proof: None,
+                data: body.to_vec(),
+            },
+            TAG_DONE => Message::FileDone(0),
+            TAG_CANCEL => Message::FileCancel(0),
+            TAG_FILE_ACCEPT => Message::FileAccept {
+                transfer: 0,
+                offset: body.get(..8).map_or(0, |o| u64::from_be_bytes(o.try_into().unwrap())),
+            },
+            TAG_FILE_REJECT => Message::FileReject(0),
+            TAG_TYPING_START => Message::TypingStart,
+            TAG_TYPING_STOP => Message::TypingStop,
+            TAG_DELIVERED => Message::Delivered(0),
+            TAG_READ if body.len() >= 8 => Message::Read(u64::from_be_bytes(body[..8].try_into().unwrap())),
+            TAG_PING => Message::Ping,
+            TAG_PONG => Message::Pong,
+            TAG_AWAY => Message::Away,
+            TAG_BACK => Message::Back,
+            _ => Message::Unknown {
+                kind: *tag,
+                body: body.to_vec(),
+            },
+        }
+    }
+}
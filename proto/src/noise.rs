@@ -0,0 +1,408 @@
+use argon2::Argon2;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::error::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use zeroize::Zeroize;
+
+// auth frames are sent through the noise transport once the handshake is done
+const AUTH_VERSION: u8 = 0x02;
+const AUTH_NONE: u8 = 0x00;
+const AUTH_REQUIRED: u8 = 0x01;
+const AUTH_OK: u8 = 0x01;
+const AUTH_FAILED: u8 = 0xFF;
+const AUTH_NONCE_LEN: usize = 32;
+const AUTH_PROOF_LEN: usize = 32;
+
+/// The pattern used when neither side has a static identity key.
+pub const PATTERN_NN: &str = "Noise_NN_25519_ChaChaPoly_BLAKE2s";
+const PATTERN_XX: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const PATTERN_IK: &str = "Noise_IK_25519_ChaChaPoly_BLAKE2s";
+// sent as the cleartext payload of the first XX message so an identity-aware
+// listener can tell it apart from an NN handshake, which has the same length
+const XX_MARKER: &[u8] = b"circuitchat-xx1";
+const DH_LEN: usize = 32;
+
+#[derive(Clone)]
+pub struct StaticKeypair {
+    pub public: Vec<u8>,
+    pub private: Vec<u8>,
+}
+
+impl StaticKeypair {
+    pub fn generate() -> Result<Self, Box<dyn Error>> {
+        let keypair = snow::Builder::new(PATTERN_XX.parse()?).generate_keypair()?;
+        Ok(StaticKeypair {
+            public: keypair.public,
+            private: keypair.private,
+        })
+    }
+}
+
+impl Drop for StaticKeypair {
+    fn drop(&mut self) {
+        self.private.zeroize();
+    }
+}
+
+pub struct NoisePeer<T> {
+    stream: T,
+    transport: snow::TransportState,
+    read_buf: Vec<u8>,
+    handshake_hash: Vec<u8>,
+    pub session_fingerprint: String,
+    pub remote_static: Option<Vec<u8>>,
+    pub auth_pin: Option<Vec<u8>>,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> NoisePeer<T> {
+    pub async fn connect(mut stream: T, pattern: &str) -> Result<Self, Box<dyn Error>> {
+        let params: snow::params::NoiseParams = pattern.parse()?;
+        let mut initiator = snow::Builder::new(params).build_initiator()?;
+
+        let mut out_msg = vec![0u8; 65535];
+        let len = initiator.write_message(&[], &mut out_msg)?;
+        send_frame(&mut stream, &out_msg[..len]).await?;
+
+        let in_msg = recv_frame(&mut stream).await?;
+        let mut tmp = vec![0u8; 65535];
+        initiator.read_message(&in_msg, &mut tmp)?;
+
+        Self::from_handshake(stream, initiator)
+    }
+
+    /// Connects with our static key: IK when the responder's key is already
+    /// known, XX otherwise.
+    pub async fn connect_with_identity(
+        mut stream: T,
+        identity: &StaticKeypair,
+        remote_static: Option<&[u8]>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut out_msg = vec![0u8; 65535];
+        let mut tmp = vec![0u8; 65535];
+
+        let initiator = if let Some(remote) = remote_static {
+            let mut initiator = snow::Builder::new(PATTERN_IK.parse()?)
+                .local_private_key(&identity.private)?
+                .remote_public_key(remote)?
+                .build_initiator()?;
+
+            let len = initiator.write_message(&[], &mut out_msg)?;
+            send_frame(&mut stream, &out_msg[..len]).await?;
+
+            let in_msg = recv_frame(&mut stream).await?;
+            initiator.read_message(&in_msg, &mut tmp)?;
+            initiator
+        } else {
+            let mut initiator = snow::Builder::new(PATTERN_XX.parse()?)
+                .local_private_key(&identity.private)?
+                .build_initiator()?;
+
+            let len = initiator.write_message(XX_MARKER, &mut out_msg)?;
+            send_frame(&mut stream, &out_msg[..len]).await?;
+
+            let in_msg = recv_frame(&mut stream).await?;
+            initiator
+                .read_message(&in_msg, &mut tmp)
+                .map_err(|_| "peer does not support identity mode")?;
+
+            let len = initiator.write_message(&[], &mut out_msg)?;
+            send_frame(&mut stream, &out_msg[..len]).await?;
+            initiator
+        };
+
+        Self::from_handshake(stream, initiator)
+    }
+
+    pub async fn accept(mut stream: T, pattern: &str) -> Result<Self, Box<dyn Error>> {
+        let params: snow::params::NoiseParams = pattern.parse()?;
+        let mut responder = snow::Builder::new(params).build_responder()?;
+
+        let in_msg = recv_frame(&mut stream).await?;
+        let mut tmp = vec![0u8; 65535];
+        responder.read_message(&in_msg, &mut tmp)?;
+
+        let mut out_msg = vec![0u8; 65535];
+        let len = responder.write_message(&[], &mut out_msg)?;
+        send_frame(&mut stream, &out_msg[..len]).await?;
+
+        Self::from_handshake(stream, responder)
+    }
+
+    /// Accepts NN, XX or IK depending on what the initiator opened with, so
+    /// peers without an identity key can still connect.
+    pub async fn accept_with_identity(
+        mut stream: T,
+        pattern: &str,
+        identity: &StaticKeypair,
+    ) -> Result<Self, Box<dyn Error>> {
+        let in_msg = recv_frame(&mut stream).await?;
+        let mut out_msg = vec![0u8; 65535];
+        let mut tmp = vec![0u8; 65535];
+
+        let responder = if in_msg.len() == DH_LEN {
+            let mut responder = snow::Builder::new(pattern.parse()?).build_responder()?;
+            responder.read_message(&in_msg, &mut tmp)?;
+
+            let len = responder.write_message(&[], &mut out_msg)?;
+            send_frame(&mut stream, &out_msg[..len]).await?;
+            responder
+        } else if in_msg.len() == DH_LEN + XX_MARKER.len() && in_msg.ends_with(XX_MARKER) {
+            let mut responder = snow::Builder::new(PATTERN_XX.parse()?)
+                .local_private_key(&identity.private)?
+                .build_responder()?;
+            responder.read_message(&in_msg, &mut tmp)?;
+
+            let len = responder.write_message(&[], &mut out_msg)?;
+            send_frame(&mut stream, &out_msg[..len]).await?;
+
+            let in_msg = recv_frame(&mut stream).await?;
+            responder.read_message(&in_msg, &mut tmp)?;
+            responder
+        } else {
+            let mut responder = snow::Builder::new(PATTERN_IK.parse()?)
+                .local_private_key(&identity.private)?
+                .build_responder()?;
+            responder
+                .read_message(&in_msg, &mut tmp)
+                .map_err(|_| "IK handshake failed, initiator may have a stale key for us")?;
+
+            let len = responder.write_message(&[], &mut out_msg)?;
+            send_frame(&mut stream, &out_msg[..len]).await?;
+            responder
+        };
+
+        Self::from_handshake(stream, responder)
+    }
+
+    fn from_handshake(stream: T, handshake: snow::HandshakeState) -> Result<Self, Box<dyn Error>> {
+        let hash = handshake.get_handshake_hash().to_vec();
+        let fingerprint = crate::fingerprint::derive_fingerprint(&hash);
+        let remote_static = handshake.get_remote_static().map(|k| k.to_vec());
+
+        let transport = handshake.into_transport_mode()?;
+        Ok(NoisePeer {
+            stream,
+            transport,
+            read_buf: Vec::new(),
+            handshake_hash: hash,
+            session_fingerprint: fingerprint,
+            remote_static,
+            auth_pin: None,
+        })
+    }
+
+    pub async fn send(&mut self, plaintext: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut out = vec![0u8; plaintext.len() + 16];
+        let len = self.transport.write_message(plaintext, &mut out)?;
+        send_frame(&mut self.stream, &out[..len]).await?;
+        Ok(())
+    }
+
+    pub async fn recv(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        while self.read_buf.len() < 4 {
+            let mut tmp = [0u8; 4096];
+            let n = self.stream.read(&mut tmp).await?;
+            if n == 0 {
+                return Err("connection closed".into());
+            }
+            self.read_buf.extend_from_slice(&tmp[..n]);
+        }
+
+        let frame_len = u32::from_be_bytes(self.read_buf[..4].try_into().unwrap()) as usize;
+        if frame_len > 65535 {
+            return Err("frame too large".into());
+        }
+
+        let total = 4 + frame_len;
+
+        while self.read_buf.len() < total {
+            let mut tmp = [0u8; 4096];
+            let n = self.stream.read(&mut tmp).await?;
+            if n == 0 {
+                return Err("connection closed mid-frame".into());
+            }
+            self.read_buf.extend_from_slice(&tmp[..n]);
+        }
+
+        let ct = self.read_buf[4..total].to_vec();
+        self.read_buf.drain(..total);
+
+        self.decrypt(&ct)
+    }
+
+    fn decrypt(&mut self, ct: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut pt = vec![0u8; ct.len()];
+        let len = self.transport.read_message(ct, &mut pt)?;
+        pt.truncate(len);
+        Ok(pt)
+    }
+
+    pub async fn auth_responder(&mut self, password: Option<&str>) -> Result<(), Box<dyn Error>> {
+        // raw version byte first, so legacy initiators fail on an unknown flag
+        // instead of reading the first byte of a transport message as one
+        send_frame(&mut self.stream, &[AUTH_VERSION]).await?;
+        let Some(pw) = password else {
+            self.send(&[AUTH_VERSION, AUTH_NONE]).await?;
+            return Ok(());
+        };
+
+        let mut our_nonce = [0u8; AUTH_NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut our_nonce);
+        let mut hello = vec![AUTH_VERSION, AUTH_REQUIRED];
+        hello.extend_from_slice(&our_nonce);
+        self.send(&hello).await?;
+
+        let reply = self.recv().await?;
+        if reply.len() != AUTH_NONCE_LEN + AUTH_PROOF_LEN {
+            return Err("malformed auth proof".into());
+        }
+        let (their_nonce, their_proof) = reply.split_at(AUTH_NONCE_LEN);
+
+        let key = derive_auth_key(pw, &self.handshake_hash)?;
+        let initiator_mac = auth_mac(
+            &key,
+            "circuitchat-auth-v2-initiator",
+            &self.handshake_hash,
+            &our_nonce,
+            their_nonce,
+        );
+        if initiator_mac.verify_slice(their_proof).is_err() {
+            self.send(&[AUTH_FAILED]).await?;
+            return Err("peer failed authentication".into());
+        }
+
+        let responder_proof = auth_mac(
+            &key,
+            "circuitchat-auth-v2-responder",
+            &self.handshake_hash,
+            &our_nonce,
+            their_nonce,
+        )
+        .finalize()
+        .into_bytes();
+        let mut ok = vec![AUTH_OK];
+        ok.extend_from_slice(&responder_proof);
+        self.send(&ok).await?;
+        self.auth_pin = Some(derive_auth_pin(pw)?);
+        Ok(())
+    }
+
+    /// `password` is only called if the responder requires one, so callers can
+    /// prompt for it lazily.
+    pub async fn auth_initiator<F>(&mut self, password: F) -> Result<(), Box<dyn Error>>
+    where
+        F: FnOnce() -> Result<String, Box<dyn Error>>,
+    {
+        // every responder starts with a bare byte outside the transport: the
+        // legacy auth flag, or the auth version with the hello to follow
+        let frame = recv_frame(&mut self.stream).await?;
+        match frame.as_slice() {
+            [0x00] => return Ok(()),
+            [0x01] => return Err("peer uses legacy password authentication, ask them to upgrade".into()),
+            [AUTH_VERSION] => {}
+            _ => return Err("unexpected auth frame".into()),
+        }
+        let hello = self.recv().await?;
+        match hello.as_slice() {
+            [AUTH_VERSION, AUTH_NONE] => Ok(()),
+            [AUTH_VERSION, AUTH_REQUIRED, their_nonce @ ..] if their_nonce.len() == AUTH_NONCE_LEN => {
+                let pw = password()?;
+                let key = derive_auth_key(&pw, &self.handshake_hash)?;
+
+                let mut our_nonce = [0u8; AUTH_NONCE_LEN];
+                rand::thread_rng().fill_bytes(&mut our_nonce);
+                let our_proof = auth_mac(
+                    &key,
+                    "circuitchat-auth-v2-initiator",
+                    &self.handshake_hash,
+                    their_nonce,
+                    &our_nonce,
+                )
+                .finalize()
+                .into_bytes();
+                let mut reply = our_nonce.to_vec();
+                reply.extend_from_slice(&our_proof);
+                self.send(&reply).await?;
+
+                let verdict = self.recv().await?;
+                match verdict.split_first() {
+                    Some((&AUTH_FAILED, _)) => Err("authentication rejected by peer".into()),
+                    Some((&AUTH_OK, their_proof)) => {
+                        let responder_mac = auth_mac(
+                            &key,
+                            "circuitchat-auth-v2-responder",
+                            &self.handshake_hash,
+                            their_nonce,
+                            &our_nonce,
+                        );
+                        responder_mac
+                            .verify_slice(their_proof)
+                            .map_err(|_| "peer failed authentication")?;
+                        self.auth_pin = Some(derive_auth_pin(&pw)?);
+                        Ok(())
+                    }
+                    _ => Err("unexpected auth frame".into()),
+                }
+            }
+            [version, ..] if *version != AUTH_VERSION => {
+                Err(format!("unsupported auth version {}", version).into())
+            }
+            _ => Err("unexpected auth frame".into()),
+        }
+    }
+}
+
+async fn send_frame<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    data: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    let len = (data.len() as u32).to_be_bytes();
+    stream.write_all(&len).await?;
+    stream.write_all(data).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn recv_frame<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut lenb = [0u8; 4];
+    stream.read_exact(&mut lenb).await?;
+    let len = u32::from_be_bytes(lenb) as usize;
+    if len > 65535 {
+        return Err("frame too large".into());
+    }
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+fn derive_auth_key(password: &str, handshake_hash: &[u8]) -> Result<[u8; 32], Box<dyn Error>> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(password.as_bytes(), &handshake_hash[..16], &mut key)
+        .map_err(|e| format!("key derivation failed: {}", e))?;
+    Ok(key)
+}
+
+// stable across sessions so contacts without an identity key can still be pinned
+fn derive_auth_pin(password: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let key = derive_auth_key(password, b"circuitchat-v2-auth-pin")?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key).unwrap();
+    mac.update(b"circuitchat-auth-pin");
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+fn auth_mac(
+    key: &[u8; 32],
+    label: &str,
+    handshake_hash: &[u8],
+    responder_nonce: &[u8],
+    initiator_nonce: &[u8],
+) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(label.as_bytes());
+    mac.update(handshake_hash);
+    mac.update(responder_nonce);
+    mac.update(initiator_nonce);
+    mac
+}
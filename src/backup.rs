@@ -0,0 +1,286 @@
+use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use rpassword::prompt_password;
+use zeroize::Zeroize;
+
+use crate::config;
+use crate::storage::{self, ExportedRow, Storage};
+
+const MAGIC: &[u8; 8] = b"CCBACKUP";
+
+// entry kinds in the sealed payload
+const ENTRY_CONFIG: u8 = 0;
+const ENTRY_KEYSTORE: u8 = 1;
+const ENTRY_ROW: u8 = 2;
+
+/// What a backup holds once it is unsealed.
+struct Archive {
+    config: Option<Vec<u8>>,
+    /// Files of Arti's keystore, relative to `state/keystore`.
+    keystore: Vec<(PathBuf, Vec<u8>)>,
+    rows: Vec<ExportedRow>,
+}
+
+impl Drop for Archive {
+    fn drop(&mut self) {
+        if let Some(ref mut c) = self.config {
+            c.zeroize();
+        }
+        for (_, data) in self.keystore.iter_mut() {
+            data.zeroize();
+        }
+        for (_, _, data) in self.rows.iter_mut() {
+            data.zeroize();
+        }
+    }
+}
+
+fn exe_dir() -> Result<PathBuf, Box<dyn Error>> {
+    Ok(std::env::current_exe()?
+        .parent()
+        .ok_or("could not determine exe directory")?
+        .to_path_buf())
+}
+
+fn keystore_dir() -> Result<PathBuf, Box<dyn Error>> {
+    Ok(exe_dir()?.join("state").join("keystore"))
+}
+
+// the keystore holds the onion service key, so only we may read it, which is
+// also what Arti checks before using it
+fn create_private_dir(path: &Path) -> std::io::Result<()> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(path)
+}
+
+fn write_private_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents)
+}
+
+fn push_entry(out: &mut Vec<u8>, kind: u8, name: &str, data: &[u8]) {
+    out.push(kind);
+    out.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    out.extend_from_slice(name.as_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(data);
+}
+
+fn collect_files(dir: &Path, base: &Path, out: &mut Vec<(PathBuf, Vec<u8>)>) -> Result<(), Box<dyn Error>> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, base, out)?;
+        } else if path.is_file() {
+            out.push((path.strip_prefix(base)?.to_path_buf(), std::fs::read(&path)?));
+        }
+    }
+    Ok(())
+}
+
+impl Archive {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        if let Some(ref c) = self.config {
+            push_entry(&mut out, ENTRY_CONFIG, "circuitchat.toml", c);
+        }
+        for (path, data) in &self.keystore {
+            // always stored with forward slashes, whatever the platform
+            let name = path
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            push_entry(&mut out, ENTRY_KEYSTORE, &name, data);
+        }
+        for (table, id, plain) in &self.rows {
+            let mut data = id.to_be_bytes().to_vec();
+            data.extend_from_slice(plain);
+            push_entry(&mut out, ENTRY_ROW, table, &data);
+            data.zeroize();
+        }
+        out
+    }
+
+    /// Checks every entry, so nothing is written from a damaged archive.
+    fn decode(mut data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut archive = Archive {
+            config: None,
+            keystore: Vec::new(),
+            rows: Vec::new(),
+        };
+        let corrupt = || -> Box<dyn Error> { "backup is corrupt".into() };
+        while !data.is_empty() {
+            let kind = data[0];
+            let name_len = u16::from_be_bytes(data.get(1..3).ok_or_else(corrupt)?.try_into().unwrap()) as usize;
+            let name = data.get(3..3 + name_len).ok_or_else(corrupt)?;
+            let name = std::str::from_utf8(name).map_err(|_| corrupt())?.to_string();
+            let at = 3 + name_len;
+            let len = u32::from_be_bytes(data.get(at..at + 4).ok_or_else(corrupt)?.try_into().unwrap()) as usize;
+            let body = data.get(at + 4..at + 4 + len).ok_or_else(corrupt)?;
+            data = &data[at + 4 + len..];
+
+            match kind {
+                ENTRY_CONFIG => {
+                    toml::from_str::<config::Config>(std::str::from_utf8(body).map_err(|_| corrupt())?)
+                        .map_err(|e| format!("backup holds an invalid config: {}", e))?;
+                    archive.config = Some(body.to_vec());
+                }
+                ENTRY_KEYSTORE => {
+                    let path = PathBuf::from(&name);
+                    // never write outside the keystore
+                    if !path.components().all(|c| matches!(c, std::path::Component::Normal(_))) {
+                        return Err(format!("backup holds an unsafe path '{}'", name).into());
+                    }
+                    archive.keystore.push((path, body.to_vec()));
+                }
+                ENTRY_ROW if body.len() >= 8 => {
+                    let id = i64::from_be_bytes(body[..8].try_into().unwrap());
+                    archive.rows.push((name, id, body[8..].to_vec()));
+                }
+                _ => return Err(corrupt()),
+            }
+        }
+        Ok(archive)
+    }
+}
+
+/// Writes the history, identity, contacts, onion service key and config to
+/// one file sealed under a new passphrase. Rows are decrypted and sealed
+/// again, so the backup does not depend on the database key.
+pub fn backup(path: &Path, storage: Option<&Storage>) -> Result<(), Box<dyn Error>> {
+    let storage = storage.ok_or("backups require identity.persist = true")?;
+    if path.exists() {
+        return Err(format!("{} already exists", path.display()).into());
+    }
+
+    let config_path = config::config_path()?;
+    let mut keystore = Vec::new();
+    let keystore_dir = keystore_dir()?;
+    if keystore_dir.is_dir() {
+        collect_files(&keystore_dir, &keystore_dir, &mut keystore)?;
+    }
+    let archive = Archive {
+        config: config_path.exists().then(|| std::fs::read(&config_path)).transpose()?,
+        keystore,
+        rows: storage.export_rows()?,
+    };
+
+    let mut passphrase = prompt_password("backup passphrase: ")?;
+    if passphrase.is_empty() {
+        return Err("backup passphrase cannot be empty".into());
+    }
+    let mut confirm = prompt_password("confirm backup passphrase: ")?;
+    let matches = passphrase == confirm;
+    confirm.zeroize();
+    if !matches {
+        passphrase.zeroize();
+        return Err("passphrases do not match".into());
+    }
+
+    let mut payload = archive.encode();
+    let sealed = storage::seal_file(MAGIC, &passphrase, &payload);
+    payload.zeroize();
+    passphrase.zeroize();
+    std::fs::write(path, sealed?)?;
+    println!(
+        "backed up {} database row(s), {} keystore file(s) and {} to {}",
+        archive.rows.len(),
+        archive.keystore.len(),
+        if archive.config.is_some() { "the config" } else { "no config" },
+        path.display()
+    );
+    Ok(())
+}
+
+/// Restores a backup made by `backup`. The archive is decrypted and checked
+/// in full, and the new database built next to the old one, before anything
+/// existing is replaced.
+pub fn restore(path: &Path) -> Result<(), Box<dyn Error>> {
+    let data = std::fs::read(path)?;
+    // kept for the database, if the restored config does not set one
+    let mut passphrase = String::new();
+    let mut payload = storage::open_sealed_file(MAGIC, &data, || {
+        passphrase = prompt_password("backup passphrase: ")?;
+        Ok(passphrase.clone())
+    })
+    .map_err(|e| format!("cannot restore {}: {}", path.display(), e))?;
+    let archive = Archive::decode(&payload);
+    payload.zeroize();
+    let archive = archive?;
+
+    let db_path = storage::db_path()?;
+    let config_path = config::config_path()?;
+    let keystore_dir = keystore_dir()?;
+    if db_path.exists() || keystore_dir.exists() || (archive.config.is_some() && config_path.exists()) {
+        print!("this replaces the existing database, onion service key and config. type 'restore' to continue: ");
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        if answer.trim() != "restore" {
+            passphrase.zeroize();
+            return Err("restore cancelled".into());
+        }
+    }
+
+    // the restored config decides which passphrase the history is opened with
+    let history_passphrase = archive
+        .config
+        .as_ref()
+        .and_then(|c| toml::from_str::<config::Config>(&String::from_utf8_lossy(c)).ok())
+        .map(|c| c.history.passphrase)
+        .filter(|p| !p.is_empty());
+    let uses_backup_passphrase = history_passphrase.is_none();
+
+    let staging = db_path.with_extension("db.restoring");
+    if staging.exists() {
+        storage::zero_and_delete_file(&staging)?;
+    }
+    let built = Storage::open_at(&staging, history_passphrase.as_deref().unwrap_or(&passphrase))
+        .and_then(|s| s.import_rows(&archive.rows));
+    passphrase.zeroize();
+    if let Err(e) = built {
+        let _ = storage::zero_and_delete_file(&staging);
+        return Err(format!("could not rebuild the database: {}", e).into());
+    }
+
+    if db_path.exists() {
+        storage::zero_and_delete_file(&db_path)?;
+    }
+    std::fs::rename(&staging, &db_path)?;
+
+    if !archive.keystore.is_empty() {
+        if keystore_dir.exists() {
+            crate::zero_directory_contents(&keystore_dir);
+            std::fs::remove_dir_all(&keystore_dir)?;
+        }
+        for (name, contents) in &archive.keystore {
+            let target = keystore_dir.join(name);
+            if let Some(parent) = target.parent() {
+                create_private_dir(parent)?;
+            }
+            write_private_file(&target, contents)?;
+        }
+    }
+    if let Some(ref contents) = archive.config {
+        std::fs::write(&config_path, contents)?;
+    }
+
+    println!(
+        "restored {} database row(s) and {} keystore file(s)",
+        archive.rows.len(),
+        archive.keystore.len()
+    );
+    if uses_backup_passphrase {
+        println!("the history passphrase is now the backup passphrase, use /rekey to change it");
+    }
+    Ok(())
+}
@@ -1,8 +1,15 @@
 use std::error::Error;
+use std::path::Path;
 
 use crate::ccscript::{self, Action, Block, Event, EventContext, Script};
 use crate::files;
-use crate::noise_peer::NoisePeer;
+use crate::session::{self, SessionEvent, SessionHandle};
+use circuitchat_proto::{Features, Framing, Hello, Message, NoisePeer, PROTOCOL_VERSION};
+use tokio::sync::mpsc;
+
+// bots never send typing, away or receipts
+const BOT_FEATURES: Features =
+    Features::from_bits(Features::RESUME.bits() | Features::FLOW.bits() | Features::HASHES.bits());
 
 pub struct ActionOutcome {
     pub replies: Vec<String>,
@@ -84,24 +91,45 @@ fn apply_action(action: &Action, ctx: &EventContext, outcome: &mut ActionOutcome
     }
 }
 
-async fn send_outcome<T>(
-    np: &mut NoisePeer<T>,
-    outcome: &ActionOutcome,
-) -> Result<(), Box<dyn Error>>
-where
-    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
-{
+async fn send_outcome(handle: &SessionHandle, outcome: &ActionOutcome) -> Result<(), Box<dyn Error>> {
     for ms in &outcome.waits {
         tokio::time::sleep(std::time::Duration::from_millis(*ms)).await;
     }
     for reply in &outcome.replies {
-        np.send(reply.as_bytes()).await?;
+        if !handle.send(&Message::text(reply.as_str())).await {
+            return Err("peer disconnected".into());
+        }
     }
     Ok(())
 }
 
+/// Offers the files a handler asked to send. Bots do not number their
+/// transfers, so only the last offer can be accepted.
+async fn offer_files(handle: &SessionHandle, features: Features, paths: &[String]) -> Option<files::OutgoingFile> {
+    let mut offered = None;
+    for path in paths {
+        match files::OutgoingFile::open(Path::new(path.trim())) {
+            Ok(mut out) => {
+                out.hashed = features.contains(Features::HASHES);
+                if !handle.send(&out.offer(0)).await {
+                    eprintln!("send failed: peer disconnected");
+                    break;
+                }
+                println!(
+                    "[file] offered {} ({}) - waiting for peer to accept",
+                    out.name,
+                    files::format_size(out.size)
+                );
+                offered = Some(out);
+            }
+            Err(e) => eprintln!("cannot open file: {}", e),
+        }
+    }
+    offered
+}
+
 pub async fn run_bot_session<T>(
-    mut np: NoisePeer<T>,
+    np: NoisePeer<T>,
     script: &Script,
     bot_start: std::time::Instant,
     connection_count: u64,
@@ -110,24 +138,12 @@ where
     T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
 {
     let fingerprint = np.session_fingerprint.clone();
+    let (events_tx, mut events) = mpsc::unbounded_channel();
+    let mut handle = session::spawn(connection_count, np, events_tx);
 
-    let _ = np.send(&files::encode_version_negotiate()).await;
-
-    if let Ok(Ok(msg)) =
-        tokio::time::timeout(std::time::Duration::from_millis(250), np.recv()).await
-    {
-        if let files::ParsedMessage::VersionNegotiate {
-            major,
-            minor: _,
-            patch: _,
-        } = files::parse_message(&msg)
-        {
-            let (our_major, _, _) = files::protocol_version();
-            if major != our_major {
-                eprintln!("warning: peer has incompatible major protocol version");
-            }
-        }
-    }
+    handle.send(&Message::Hello(Hello::new(env!("CARGO_PKG_VERSION"), BOT_FEATURES))).await;
+    // the peer's hello arrives before anything else it sends
+    let mut features = Features::empty();
 
     let ctx = EventContext::new_with_bot_state(
         Some(fingerprint.clone()),
@@ -135,258 +151,194 @@ where
         connection_count,
     );
     let outcome = run_handlers(script, &Event::Connect, &ctx);
-    send_outcome(&mut np, &outcome).await?;
-    for path in &outcome.send_files {
-        match files::OutgoingFile::open(path) {
-            Ok(out) => {
-                let _ = np
-                    .send(&files::encode_offer_with_checksum(
-                        &out.name,
-                        out.size,
-                        Some(&out.checksum),
-                    ))
-                    .await;
-                println!(
-                    "[file] offered {} ({}) - waiting for peer to accept",
-                    out.name,
-                    files::format_size(out.size)
-                );
-            }
-            Err(e) => eprintln!("cannot open file: {}", e),
-        }
-    }
+    send_outcome(&handle, &outcome).await?;
+    let mut pending_offer = offer_files(&handle, features, &outcome.send_files).await;
     if outcome.disconnect {
         return Ok(());
     }
 
-    let mut incoming_file: Option<files::IncomingFile> = None;
-    let mut pending_offer: Option<files::OutgoingFile> = None;
+    // with the transfer id the peer chose for it
+    let mut incoming_file: Option<(u64, files::IncomingFile)> = None;
+    let mut outgoing_file: Option<session::FileSender> = None;
 
-    loop {
-        if incoming_file.is_some() {
-            match np.recv().await {
-                Ok(msg) => match files::parse_message(&msg) {
-                    files::ParsedMessage::FileChunk(data) => {
-                        if let Some(ref mut inc) = incoming_file {
-                            if let Err(e) = inc.write_chunk(&data) {
-                                eprintln!("file write error: {}", e);
-                                incoming_file = None;
-                            }
-                        }
-                    }
-                    files::ParsedMessage::FileDone => {
-                        if let Some(inc) = incoming_file.take() {
-                            let name = inc.name.clone();
-                            let size = inc.size;
-                            match inc.finish() {
-                                Ok(path) => {
-                                    println!(
-                                        "[file] saved {} ({}) -> {}",
-                                        name,
-                                        files::format_size(size),
-                                        path.display()
-                                    );
-                                }
-                                Err(e) => eprintln!("file save error: {}", e),
-                            }
-                        }
+    // chunks arrive interleaved with chat, so everything goes through one loop
+    while let Some(event) = events.recv().await {
+        let msg = match event {
+            SessionEvent::Message { data, .. } => data,
+            SessionEvent::FileSent { result, .. } => {
+                if let Some(out) = outgoing_file.take() {
+                    match result {
+                        Ok(()) => println!("[file] sent {} ({})", out.name, files::format_size(out.size)),
+                        Err(e) => eprintln!("file send error: {}", e),
                     }
-                    files::ParsedMessage::FileCancel => {
-                        if let Some(inc) = incoming_file.take() {
-                            inc.cancel();
-                            println!("[file] peer cancelled the transfer");
-                        }
-                    }
-                    _ => {}
-                },
-                Err(_) => {
-                    fire_disconnect(script, &mut np, &fingerprint, bot_start, connection_count)
-                        .await;
-                    break;
                 }
+                continue;
             }
-            continue;
-        }
-
-        match np.recv().await {
-            Ok(msg) => match files::parse_message(&msg) {
-                files::ParsedMessage::Text(content) => {
-                    let mut ctx = EventContext::new_with_bot_state(
-                        Some(fingerprint.clone()),
-                        Some(bot_start),
-                        connection_count,
-                    );
-                    ctx.message = Some(content);
-                    let outcome = run_handlers(script, &Event::Message, &ctx);
-                    send_outcome(&mut np, &outcome).await?;
-                    for path in &outcome.send_files {
-                        match files::OutgoingFile::open(path) {
-                            Ok(out) => {
-                                if let Err(e) = np
-                                    .send(&files::encode_offer_with_checksum(
-                                        &out.name,
-                                        out.size,
-                                        Some(&out.checksum),
-                                    ))
-                                    .await
-                                {
-                                    eprintln!("send failed: {}", e);
-                                } else {
-                                    println!(
-                                        "[file] offered {} ({}) - waiting for peer to accept",
-                                        out.name,
-                                        files::format_size(out.size)
-                                    );
-                                    pending_offer = Some(out);
-                                }
-                            }
-                            Err(e) => eprintln!("cannot open file: {}", e),
-                        }
-                    }
-                    if outcome.disconnect {
-                        fire_disconnect(script, &mut np, &fingerprint, bot_start, connection_count)
-                            .await;
-                        return Ok(());
-                    }
+            SessionEvent::Closed { .. } | SessionEvent::Failed { .. } => {
+                fire_disconnect(script, &handle, &fingerprint, bot_start, connection_count).await;
+                break;
+            }
+            SessionEvent::Joined(_) | SessionEvent::FileProgress { .. } | SessionEvent::FileHashed { .. } => continue,
+        };
+        match Message::decode(&msg) {
+            Message::Text { text: content, .. } => {
+                let mut ctx = EventContext::new_with_bot_state(
+                    Some(fingerprint.clone()),
+                    Some(bot_start),
+                    connection_count,
+                );
+                ctx.message = Some(content);
+                let outcome = run_handlers(script, &Event::Message, &ctx);
+                send_outcome(&handle, &outcome).await?;
+                if let Some(out) = offer_files(&handle, features, &outcome.send_files).await {
+                    pending_offer = Some(out);
                 }
-                files::ParsedMessage::FileOffer {
-                    name,
-                    size,
-                    checksum,
-                } => {
-                    let mut ctx = EventContext::new_with_bot_state(
-                        Some(fingerprint.clone()),
-                        Some(bot_start),
-                        connection_count,
-                    );
-                    ctx.file_name = Some(name.clone());
-                    ctx.file_size = Some(size);
-                    let outcome = run_handlers(script, &Event::File, &ctx);
-                    send_outcome(&mut np, &outcome).await?;
-                    for path in &outcome.send_files {
-                        match files::OutgoingFile::open(path) {
-                            Ok(out) => {
-                                if let Err(e) = np
-                                    .send(&files::encode_offer_with_checksum(
-                                        &out.name,
-                                        out.size,
-                                        Some(&out.checksum),
-                                    ))
-                                    .await
-                                {
-                                    eprintln!("send failed: {}", e);
-                                } else {
-                                    println!(
-                                        "[file] offered {} ({}) - waiting for peer to accept",
-                                        out.name,
-                                        files::format_size(out.size)
-                                    );
-                                    pending_offer = Some(out);
-                                }
-                            }
-                            Err(e) => eprintln!("cannot open file: {}", e),
-                        }
-                    }
-                    if outcome.accept_file {
-                        let existing = files::existing_download_size(&name).unwrap_or(0);
-                        np.send(&files::encode_accept_with_offset(existing))
-                            .await?;
-                        match files::IncomingFile::begin(&name, size, checksum.as_deref()) {
-                            Ok(inc) => {
-                                println!(
-                                    "[file] accepted {} ({})",
-                                    name,
-                                    files::format_size(size)
-                                );
-                                incoming_file = Some(inc);
+                if outcome.disconnect {
+                    fire_disconnect(script, &handle, &fingerprint, bot_start, connection_count).await;
+                    return Ok(());
+                }
+            }
+            Message::FileOffer {
+                transfer,
+                name,
+                size,
+                checksum,
+            } => {
+                let mut ctx = EventContext::new_with_bot_state(
+                    Some(fingerprint.clone()),
+                    Some(bot_start),
+                    connection_count,
+                );
+                ctx.file_name = Some(name.clone());
+                ctx.file_size = Some(size);
+                let outcome = run_handlers(script, &Event::File, &ctx);
+                send_outcome(&handle, &outcome).await?;
+                if let Some(out) = offer_files(&handle, features, &outcome.send_files).await {
+                    pending_offer = Some(out);
+                }
+                if outcome.accept_file && incoming_file.is_some() {
+                    handle.send(&Message::FileReject(transfer)).await;
+                    println!("[file] rejected {}, already receiving a file", name);
+                } else if outcome.accept_file {
+                    let resume = features.contains(Features::RESUME);
+                    // only the app receives folders, so every name is kept flat
+                    let flat = files::sanitize_filename(&name);
+                    match files::IncomingFile::begin(&flat, size, checksum.as_deref(), resume) {
+                        Ok(inc) => {
+                            let accept = Message::FileAccept { transfer, offset: inc.received };
+                            if !handle.send(&accept).await {
+                                return Err("peer disconnected".into());
                             }
-                            Err(e) => eprintln!("file receive error: {}", e),
+                            println!(
+                                "[file] accepted {} ({})",
+                                name,
+                                files::format_size(size)
+                            );
+                            incoming_file = Some((transfer, inc));
                         }
-                    } else if outcome.reject_file {
-                        np.send(&files::encode_reject()).await?;
-                        println!("[file] rejected {}", name);
-                    }
-                    if outcome.disconnect {
-                        fire_disconnect(script, &mut np, &fingerprint, bot_start, connection_count)
-                            .await;
-                        return Ok(());
+                        Err(e) => eprintln!("file receive error: {}", e),
                     }
+                } else if outcome.reject_file {
+                    handle.send(&Message::FileReject(transfer)).await;
+                    println!("[file] rejected {}", name);
                 }
-                files::ParsedMessage::Ping => {
-                    let _ = np.send(&files::encode_pong()).await;
+                if outcome.disconnect {
+                    fire_disconnect(script, &handle, &fingerprint, bot_start, connection_count).await;
+                    return Ok(());
                 }
-                files::ParsedMessage::FileAccept(offset) => {
-                    if let Some(mut out) = pending_offer.take() {
-                        if let Err(e) = out.seek_to(offset) {
-                            eprintln!("file seek error: {}", e);
-                            continue;
+            }
+            Message::FileChunk { transfer, proof, data } => {
+                if let Some((id, ref mut inc)) = incoming_file
+                    && id == transfer
+                {
+                    if let Err(e) = inc.write_chunk(&data, proof.as_deref(), None) {
+                        eprintln!("file write error: {}", e);
+                        if let Some((_, inc)) = incoming_file.take() {
+                            inc.cancel(None);
                         }
-                        loop {
-                            match out.read_next_chunk() {
-                                Ok(Some(chunk)) => {
-                                    if let Err(e) =
-                                        np.send(&files::encode_chunk(&chunk)).await
-                                    {
-                                        eprintln!("file send chunk error: {}", e);
-                                        break;
-                                    }
-                                }
-                                Ok(None) => {
-                                    let _ = np.send(&files::encode_done()).await;
-                                    println!(
-                                        "[file] sent {} ({})",
-                                        out.name,
-                                        files::format_size(out.size)
-                                    );
-                                    break;
-                                }
-                                Err(e) => {
-                                    eprintln!("file read error: {}", e);
-                                    break;
-                                }
-                            }
+                        // otherwise the sender keeps waiting for acks. peers without
+                        // transfer ids send 0 and would take it for one of our files
+                        if transfer != 0 {
+                            handle.send(&Message::FileCancel(transfer)).await;
                         }
+                    } else if features.contains(Features::FLOW) && inc.received - inc.acked >= files::ACK_EVERY {
+                        inc.acked = inc.received;
+                        handle.send(&Message::FileAck { transfer, offset: inc.received }).await;
                     }
                 }
-                files::ParsedMessage::FileReject => {
-                    if let Some(out) = pending_offer.take() {
-                        println!("[file] peer rejected {}", out.name);
+            }
+            Message::FileDone(transfer) => {
+                if let Some((_, inc)) = incoming_file.take_if(|(id, _)| *id == transfer) {
+                    let name = inc.name.clone();
+                    let size = inc.size;
+                    match inc.finish(None) {
+                        Ok(saved) => {
+                            println!(
+                                "[file] saved {} ({}) -> {}",
+                                name,
+                                files::format_size(size),
+                                saved
+                            );
+                        }
+                        Err(e) => eprintln!("file save error: {}", e),
                     }
                 }
-                files::ParsedMessage::VersionNegotiate {
-                    major,
-                    minor,
-                    patch,
-                } => {
-                    let (our_major, our_minor, our_patch) = files::protocol_version();
-                    if major != our_major {
-                        eprintln!(
-                            "warning: incompatible peer version {}.{}.{} (ours {}.{}.{})",
-                            major, minor, patch, our_major, our_minor, our_patch
-                        );
+            }
+            Message::FileCancel(transfer) => {
+                if let Some((_, inc)) = incoming_file.take_if(|(id, _)| *id == transfer) {
+                    inc.cancel(None);
+                    println!("[file] peer cancelled the transfer");
+                }
+            }
+            Message::Ping => {
+                handle.send(&Message::Pong).await;
+            }
+            Message::FileAccept { offset, .. } => {
+                if let Some(mut out) = pending_offer.take() {
+                    if let Err(e) = out.seek_to(offset) {
+                        eprintln!("file seek error: {}", e);
+                        continue;
                     }
+                    outgoing_file = Some(handle.send_file(0, out, features.contains(Features::FLOW)));
                 }
-                _ => {}
-            },
-            Err(_) => {
-                fire_disconnect(script, &mut np, &fingerprint, bot_start, connection_count).await;
-                break;
             }
+            Message::FileAck { offset, .. } => {
+                if let Some(ref out) = outgoing_file {
+                    out.acked(offset);
+                }
+            }
+            Message::FileReject(_) => {
+                if let Some(out) = pending_offer.take() {
+                    println!("[file] peer rejected {}", out.name);
+                }
+            }
+            Message::Unknown { kind, body } => {
+                eprintln!("ignored unknown message type 0x{:02x} ({} bytes)", kind, body.len());
+            }
+            Message::Hello(hello) => {
+                features = BOT_FEATURES & hello.features;
+                handle.framing = Framing::for_peer(&hello);
+                if !hello.is_compatible() {
+                    eprintln!(
+                        "warning: incompatible peer protocol {} (ours {})",
+                        hello.protocol, PROTOCOL_VERSION
+                    );
+                }
+            }
+            _ => {}
         }
     }
 
     Ok(())
 }
 
-async fn fire_disconnect<T>(
+async fn fire_disconnect(
     script: &Script,
-    np: &mut NoisePeer<T>,
+    handle: &SessionHandle,
     fingerprint: &str,
     bot_start: std::time::Instant,
     connection_count: u64,
-) where
-    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
-{
+) {
     let ctx = EventContext::new_with_bot_state(
         Some(fingerprint.to_string()),
         Some(bot_start),
@@ -394,6 +346,6 @@ async fn fire_disconnect<T>(
     );
     let outcome = run_handlers(script, &Event::Disconnect, &ctx);
     for reply in &outcome.replies {
-        let _ = np.send(reply.as_bytes()).await;
+        handle.send(&Message::text(reply.as_str())).await;
     }
 }
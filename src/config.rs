@@ -11,12 +11,17 @@ pub struct Config {
     pub time: TimeConfig,
     pub auth: AuthConfig,
     pub privacy: PrivacyConfig,
+    #[serde(default)]
+    pub transfer: TransferConfig,
     pub bridge: BridgeConfig,
 }
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UiConfig {
     pub message_notification_sound: bool,
     pub mention_notification_sound: bool,
+    /// How to draw image previews: `auto`, `kitty`, `sixel`, `blocks` or `off`.
+    #[serde(default = "default_image_previews")]
+    pub image_previews: String,
 }
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BridgeConfig {
@@ -26,6 +31,8 @@ pub struct BridgeConfig {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PrivacyConfig {
     pub typing_status: bool,
+    #[serde(default)]
+    pub delivery_receipts: bool,
     pub read_receipts: bool,
     pub randomize_filenames: bool,
     #[serde(alias = "session_timeout_minutes")]
@@ -37,6 +44,18 @@ pub struct PrivacyConfig {
 fn default_idle_away_mins() -> u64 {
     5
 }
+
+fn default_image_previews() -> String {
+    "auto".to_string()
+}
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TransferConfig {
+    /// Compress file chunks and long messages with zstd when the peer can.
+    pub compression: bool,
+    /// zstd level, 1 (fastest) to 19.
+    pub compression_level: i32,
+}
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AuthConfig {
     pub enabled: bool,
@@ -45,12 +64,17 @@ pub struct AuthConfig {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct IdentityConfig {
     pub persist: bool,
+    #[serde(default)]
+    pub static_key: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct HistoryConfig {
     pub save: bool,
     pub passphrase: String,
+    /// Keep received files sealed in the database instead of in `downloads/`.
+    #[serde(default)]
+    pub vault: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -69,6 +93,14 @@ impl Default for AuthConfig {
         }
     }
 }
+impl Default for TransferConfig {
+    fn default() -> Self {
+        TransferConfig {
+            compression: true,
+            compression_level: 3,
+        }
+    }
+}
 impl Default for BridgeConfig {
     fn default() -> Self {
         BridgeConfig {
@@ -80,14 +112,19 @@ impl Default for BridgeConfig {
 impl Default for Config {
     fn default() -> Self {
         Config {
-            identity: IdentityConfig { persist: false },
+            identity: IdentityConfig {
+                persist: false,
+                static_key: false,
+            },
             history: HistoryConfig {
                 save: false,
                 passphrase: String::new(),
+                vault: false,
             },
             ui: UiConfig {
                 message_notification_sound: true,
                 mention_notification_sound: true,
+                image_previews: default_image_previews(),
             },
             time: TimeConfig {
                 hour24: true,
@@ -101,11 +138,13 @@ impl Default for Config {
             },
             privacy: PrivacyConfig {
                 typing_status: false,
+                delivery_receipts: false,
                 read_receipts: false,
                 randomize_filenames: true,
                 session_timeout_mins: 0,
                 idle_away_mins: 5,
             },
+            transfer: TransferConfig::default(),
             bridge: BridgeConfig {
                 enabled: false,
                 lines: Vec::new(),
@@ -132,6 +171,11 @@ pub fn load_or_create() -> Result<Config, Box<dyn Error>> {
         if config.history.save && !config.identity.persist {
             eprintln!("warning: history.save = true has no effect without identity.persist = true");
         }
+        if config.identity.static_key && !config.identity.persist {
+            eprintln!(
+                "warning: identity.static_key = true has no effect without identity.persist = true"
+            );
+        }
 
         let mut updated = false;
 
@@ -143,6 +187,10 @@ pub fn load_or_create() -> Result<Config, Box<dyn Error>> {
             updated = true;
         }
 
+        if raw.get("transfer").is_none() {
+            updated = true;
+        }
+
         if updated {
             let contents = toml::to_string_pretty(&config)?;
             std::fs::write(&path, contents)?;
@@ -0,0 +1,355 @@
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+
+use chrono::{Local, SecondsFormat, TimeZone};
+use rpassword::prompt_password;
+use serde::Serialize;
+use zeroize::Zeroize;
+
+use crate::files;
+use crate::storage::{self, FileRecord, MessageDirection};
+
+/// Leads every export sealed with `--encrypt`.
+pub const MAGIC: &[u8; 8] = b"CCEXPORT";
+
+#[derive(Clone, Copy)]
+pub enum Format {
+    Text,
+    Json,
+    Markdown,
+    Html,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "text" | "txt" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            "markdown" | "md" => Some(Format::Markdown),
+            "html" => Some(Format::Html),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Text => "txt",
+            Format::Json => "json",
+            Format::Markdown => "md",
+            Format::Html => "html",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Reaction {
+    pub from: &'static str,
+    pub emoji: String,
+}
+
+#[derive(Serialize)]
+pub struct FileInfo {
+    pub event: &'static str,
+    pub incoming: bool,
+    pub name: String,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub xxh3: Option<String>,
+    /// The root of the file's SHA-256 chunk tree, see `docs/PROTOCOL.md`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256_tree: Option<String>,
+    /// A path under `downloads/`, or "the vault".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub saved_to: Option<String>,
+}
+
+impl From<&FileRecord> for FileInfo {
+    fn from(record: &FileRecord) -> Self {
+        FileInfo {
+            event: record.event.name(),
+            incoming: record.incoming,
+            name: record.name.clone(),
+            size: record.size,
+            xxh3: record
+                .checksum
+                .as_ref()
+                .filter(|sum| files::checksum_kind(sum) == "xxh3")
+                .map(hex::encode),
+            sha256_tree: record
+                .checksum
+                .as_ref()
+                .filter(|sum| files::checksum_kind(sum) != "xxh3")
+                .map(hex::encode),
+            saved_to: record.saved.as_ref().map(|s| s.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Entry {
+    /// RFC 3339 in local time.
+    pub time: String,
+    pub unix_time: i64,
+    /// As shown in the chat window, for the text format.
+    #[serde(skip)]
+    pub shown_time: String,
+    pub direction: &'static str,
+    pub sender: String,
+    /// "message", "file" for transfer events, or "system".
+    pub kind: &'static str,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub id: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub reply_to: u64,
+    pub edited: bool,
+    pub deleted: bool,
+    pub text: String,
+    pub reactions: Vec<Reaction>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<FileInfo>,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+fn direction_name(direction: MessageDirection) -> &'static str {
+    match direction {
+        MessageDirection::Sent => "sent",
+        MessageDirection::Received => "received",
+        MessageDirection::System => "system",
+    }
+}
+
+fn rfc3339(unix_secs: i64) -> String {
+    Local
+        .timestamp_opt(unix_secs, 0)
+        .single()
+        .map(|t| t.to_rfc3339())
+        .unwrap_or_default()
+}
+
+impl Entry {
+    pub fn new(direction: MessageDirection, unix_time: i64, shown_time: String, text: String) -> Self {
+        let kind = match direction {
+            MessageDirection::System => "system",
+            _ => "message",
+        };
+        let sender = match direction {
+            MessageDirection::Sent => "you",
+            MessageDirection::Received => "peer",
+            MessageDirection::System => "system",
+        };
+        Entry {
+            time: rfc3339(unix_time),
+            unix_time,
+            shown_time,
+            direction: direction_name(direction),
+            sender: sender.to_string(),
+            kind,
+            id: 0,
+            reply_to: 0,
+            edited: false,
+            deleted: false,
+            text,
+            reactions: Vec::new(),
+            file: None,
+        }
+    }
+
+    pub fn add_reaction(&mut self, by: MessageDirection, emoji: &str) {
+        let from = if by == MessageDirection::Sent { "you" } else { "peer" };
+        self.reactions.push(Reaction { from, emoji: emoji.to_string() });
+    }
+}
+
+#[derive(Serialize)]
+pub struct Export {
+    pub peer: String,
+    pub conversation: Option<String>,
+    pub session_fingerprint: Option<String>,
+    pub identity_warning: Option<String>,
+    pub exported_at: String,
+    pub messages: Vec<Entry>,
+}
+
+impl Export {
+    pub fn new(peer: &str, messages: Vec<Entry>) -> Self {
+        Export {
+            peer: peer.to_string(),
+            conversation: None,
+            session_fingerprint: None,
+            identity_warning: None,
+            exported_at: Local::now().to_rfc3339_opts(SecondsFormat::Secs, false),
+            messages,
+        }
+    }
+
+    pub fn render(&self, format: Format) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(match format {
+            Format::Text => self.text().into_bytes(),
+            Format::Json => serde_json::to_vec_pretty(self)?,
+            Format::Markdown => self.markdown().into_bytes(),
+            Format::Html => self.html().into_bytes(),
+        })
+    }
+
+    fn text(&self) -> String {
+        let mut out = String::new();
+        for m in &self.messages {
+            out.push_str(&format!("[{}] {}: {}\n", m.shown_time, m.sender, m.text));
+        }
+        out
+    }
+
+    fn markdown(&self) -> String {
+        let mut out = format!("# Chat with {}\n\n", escape_markdown(&self.peer));
+        if let Some(ref c) = self.conversation {
+            out.push_str(&format!("- Conversation: `{}`\n", c));
+        }
+        if let Some(ref f) = self.session_fingerprint {
+            out.push_str(&format!("- Session fingerprint: `{}`\n", f));
+        }
+        if let Some(ref w) = self.identity_warning {
+            out.push_str(&format!("- **Warning:** {}\n", escape_markdown(w)));
+        }
+        out.push_str(&format!("- Exported: {}\n\n", self.exported_at));
+
+        for m in &self.messages {
+            let mut line = format!("- `{}` ", m.time);
+            match m.kind {
+                "message" => line.push_str(&format!("**{}:** ", escape_markdown(&m.sender))),
+                _ => line.push('_'),
+            }
+            if m.deleted {
+                line.push_str("~~message deleted~~");
+            } else {
+                line.push_str(&escape_markdown(&m.text).replace('\n', "  \n  "));
+            }
+            if m.kind != "message" {
+                line.push('_');
+            }
+            if m.reply_to != 0 {
+                line.push_str(&format!(" (reply to #{})", m.reply_to));
+            }
+            if m.edited {
+                line.push_str(" (edited)");
+            }
+            for r in &m.reactions {
+                line.push_str(&format!(" [{} {}]", r.from, escape_markdown(&r.emoji)));
+            }
+            out.push_str(&line);
+            out.push('\n');
+        }
+        out
+    }
+
+    fn html(&self) -> String {
+        let mut out = String::from(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n",
+        );
+        out.push_str(&format!("<title>Chat with {}</title>\n", escape_html(&self.peer)));
+        out.push_str(
+            "<style>\n\
+             body { font-family: sans-serif; max-width: 50em; margin: 2em auto; }\n\
+             .meta { color: #666; }\n\
+             .warning { color: #b00; }\n\
+             .msg { margin: 0.3em 0; white-space: pre-wrap; }\n\
+             .sent .who { color: #070; }\n\
+             .received .who { color: #06c; }\n\
+             .system { color: #888; font-style: italic; }\n\
+             time, .note { color: #888; font-size: 0.85em; }\n\
+             </style>\n</head>\n<body>\n",
+        );
+        out.push_str(&format!("<h1>Chat with {}</h1>\n<ul class=\"meta\">\n", escape_html(&self.peer)));
+        if let Some(ref c) = self.conversation {
+            out.push_str(&format!("<li>Conversation: <code>{}</code></li>\n", escape_html(c)));
+        }
+        if let Some(ref f) = self.session_fingerprint {
+            out.push_str(&format!("<li>Session fingerprint: <code>{}</code></li>\n", escape_html(f)));
+        }
+        if let Some(ref w) = self.identity_warning {
+            out.push_str(&format!("<li class=\"warning\">Warning: {}</li>\n", escape_html(w)));
+        }
+        out.push_str(&format!("<li>Exported: {}</li>\n</ul>\n", escape_html(&self.exported_at)));
+
+        for m in &self.messages {
+            out.push_str(&format!(
+                "<div class=\"msg {}\"><time datetime=\"{}\">{}</time> ",
+                m.direction,
+                m.time,
+                escape_html(&m.shown_time)
+            ));
+            if m.kind == "message" {
+                out.push_str(&format!("<span class=\"who\">{}:</span> ", escape_html(&m.sender)));
+            }
+            if m.deleted {
+                out.push_str("<del>message deleted</del>");
+            } else {
+                out.push_str(&escape_html(&m.text));
+            }
+            if m.reply_to != 0 {
+                out.push_str(&format!(" <span class=\"note\">(reply to #{})</span>", m.reply_to));
+            }
+            if m.edited {
+                out.push_str(" <span class=\"note\">(edited)</span>");
+            }
+            for r in &m.reactions {
+                out.push_str(&format!(" <span class=\"note\">[{} {}]</span>", r.from, escape_html(&r.emoji)));
+            }
+            out.push_str("</div>\n");
+        }
+        out.push_str("</body>\n</html>\n");
+        out
+    }
+}
+
+fn escape_markdown(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\`*_{}[]<>()#+-.!|~".contains(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Reads back an export sealed with `/exportchat --encrypt`, into `output`
+/// or else to stdout.
+pub fn decrypt_file(path: &Path, output: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    if let Some(out) = output
+        && out.exists()
+    {
+        return Err(format!("{} already exists", out.display()).into());
+    }
+    let data = std::fs::read(path)?;
+    let mut plain = storage::open_sealed_file(MAGIC, &data, || Ok(prompt_password("export passphrase: ")?))
+        .map_err(|e| format!("cannot decrypt {}: {}", path.display(), e))?;
+    let written = match output {
+        Some(out) => std::fs::write(out, &plain),
+        None => std::io::stdout().write_all(&plain),
+    };
+    plain.zeroize();
+    written?;
+    if let Some(out) = output {
+        eprintln!("decrypted {} to {}", path.display(), out.display());
+    }
+    Ok(())
+}
@@ -1,198 +1,142 @@
-pub const MSG_VERSION_NEGOTIATE: u8 = 0xFF;
-
-pub fn protocol_version() -> (u8, u8, u8) {
-    let v = env!("CARGO_PKG_VERSION");
-    let mut parts = v.split(|c| c == '.' || c == '-');
-    let major = parts.next().and_then(|s| s.parse::<u8>().ok()).unwrap_or(0);
-    let minor = parts.next().and_then(|s| s.parse::<u8>().ok()).unwrap_or(0);
-    let patch = parts.next().and_then(|s| s.parse::<u8>().ok()).unwrap_or(0);
-    (major, minor, patch)
-}
-
-pub fn encode_version_negotiate() -> Vec<u8> {
-    let (major, minor, patch) = protocol_version();
-    vec![0x00, MSG_VERSION_NEGOTIATE, major, minor, patch]
-}
-use rand::Rng;
+use circuitchat_proto::Message;
+use rand::{Rng, RngCore};
 use rand::distributions::Alphanumeric;
 use std::error::Error;
 use std::fs;
-use std::io::{Read, Seek, Write};
+use sha2::{Digest, Sha256};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use xxhash_rust::xxh3::Xxh3;
 
-const CHUNK_SIZE: usize = 60_000;
-const OFFER_TAG: u8 = b'F';
-const CHUNK_TAG: u8 = b'C';
-const DONE_TAG: u8 = b'D';
-const CANCEL_TAG: u8 = b'X';
-pub const MSG_FILE_ACCEPT: u8 = 0x05;
-pub const MSG_FILE_REJECT: u8 = 0x06;
-pub const MSG_TYPING_START: u8 = 0x07;
-pub const MSG_TYPING_STOP: u8 = 0x08;
-pub const MSG_DELIVERED: u8 = 0x09;
-pub const MSG_PING: u8 = 0x0A;
-pub const MSG_PONG: u8 = 0x0B;
-pub const MSG_AWAY: u8 = 0x0C;
-pub const MSG_BACK: u8 = 0x0D;
+use crate::storage::{SavedFile, Storage};
 
-pub fn encode_typing_start() -> Vec<u8> {
-    vec![0x00, MSG_TYPING_START]
-}
-pub fn encode_typing_stop() -> Vec<u8> {
-    vec![0x00, MSG_TYPING_STOP]
-}
-pub fn encode_delivered() -> Vec<u8> {
-    vec![0x00, MSG_DELIVERED]
-}
-pub fn encode_ping() -> Vec<u8> {
-    vec![0x00, MSG_PING]
-}
-pub fn encode_pong() -> Vec<u8> {
-    vec![0x00, MSG_PONG]
-}
-pub fn encode_away() -> Vec<u8> {
-    vec![0x00, MSG_AWAY]
-}
-pub fn encode_back() -> Vec<u8> {
-    vec![0x00, MSG_BACK]
+const CHUNK_SIZE: usize = 60_000;
+/// Bytes a sender may have in flight beyond the receiver's last `FileAck`.
+pub const WINDOW: u64 = 16 * CHUNK_SIZE as u64;
+/// How often a receiver acknowledges, a quarter of the window so the sender
+/// rarely has to stop.
+pub const ACK_EVERY: u64 = WINDOW / 4;
+
+/// A node of the SHA-256 tree over a file's chunks.
+pub type Hash = [u8; 32];
+
+// leaves and inner nodes are prefixed differently so one cannot pass for the other
+fn leaf_hash(chunk: &[u8]) -> Hash {
+    Sha256::new().chain_update([0]).chain_update(chunk).finalize().into()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    Sha256::new()
+        .chain_update([1])
+        .chain_update(left)
+        .chain_update(right)
+        .finalize()
+        .into()
+}
+
+/// Leaves in the tree of a file this size. An empty file has one empty leaf.
+fn leaf_count(size: u64) -> usize {
+    size.div_ceil(CHUNK_SIZE as u64).max(1) as usize
+}
+
+/// Every level of the tree, leaves first. A node without a sibling moves up
+/// unchanged.
+fn tree_levels(leaves: Vec<Hash>) -> Vec<Vec<Hash>> {
+    let mut levels = vec![leaves];
+    while let Some(level) = levels.last()
+        && level.len() > 1
+    {
+        let up = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => node_hash(left, right),
+                _ => pair[0],
+            })
+            .collect();
+        levels.push(up);
+    }
+    levels
 }
-//pub fn encode_accept() -> Vec<u8> {
-//    vec![0x00, MSG_FILE_ACCEPT]
-//}
 
-pub fn encode_accept_with_offset(offset: u64) -> Vec<u8> {
-    let mut msg = vec![0x00, MSG_FILE_ACCEPT];
-    msg.extend_from_slice(&offset.to_be_bytes());
-    msg
-}
-pub fn encode_reject() -> Vec<u8> {
-    vec![0x00, MSG_FILE_REJECT]
-}
-/*
-pub fn encode_offer(name: &str, size: u64) -> Vec<u8> {
-    let mut msg = vec![0x00, OFFER_TAG];
-    msg.extend_from_slice(&size.to_be_bytes());
-    msg.extend_from_slice(name.as_bytes());
-    msg
-}
-*/
-pub fn encode_offer_with_checksum(name: &str, size: u64, checksum: Option<&[u8]>) -> Vec<u8> {
-    let mut msg = vec![0x00, OFFER_TAG];
-    msg.extend_from_slice(&size.to_be_bytes());
-    if let Some(c) = checksum {
-        msg.extend_from_slice(c);
+/// Checks that `leaf` is chunk `index` of the tree with this root, `proof`
+/// being the sibling hashes on the way up.
+fn verify_leaf(root: &Hash, count: usize, mut index: usize, leaf: Hash, proof: &[Hash]) -> bool {
+    if index >= count {
+        return false;
     }
-    msg.extend_from_slice(name.as_bytes());
-    msg
+    let (mut hash, mut width, mut proof) = (leaf, count, proof.iter());
+    while width > 1 {
+        if index ^ 1 < width {
+            let Some(sibling) = proof.next() else {
+                return false;
+            };
+            hash = if index.is_multiple_of(2) {
+                node_hash(&hash, sibling)
+            } else {
+                node_hash(sibling, &hash)
+            };
+        }
+        index /= 2;
+        width = width.div_ceil(2);
+    }
+    proof.next().is_none() && hash == *root
 }
 
-pub fn encode_chunk(data: &[u8]) -> Vec<u8> {
-    let mut msg = Vec::with_capacity(2 + data.len());
-    msg.push(0x00);
-    msg.push(CHUNK_TAG);
-    msg.extend_from_slice(data);
-    msg
+/// Whether an offer's checksum is a tree root, so every chunk can be checked
+/// as it arrives.
+pub fn is_tree_root(sum: Option<&[u8]>) -> bool {
+    sum.is_some_and(|sum| sum.len() == size_of::<Hash>())
 }
 
-pub fn encode_done() -> Vec<u8> {
-    vec![0x00, DONE_TAG]
+/// What a checksum from an offer is: a tree root, or xxh3 from older peers.
+pub fn checksum_kind(sum: &[u8]) -> &'static str {
+    if sum.len() == size_of::<Hash>() { "sha256-tree" } else { "xxh3" }
 }
 
-pub fn encode_cancel() -> Vec<u8> {
-    vec![0x00, CANCEL_TAG]
+/// Reads up to a whole chunk, so chunk boundaries match the tree's leaves.
+fn read_chunk(reader: &mut impl Read) -> std::io::Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+    reader.by_ref().take(CHUNK_SIZE as u64).read_to_end(&mut chunk)?;
+    Ok(chunk)
 }
 
-pub enum ParsedMessage {
-    Text(String),
-    FileOffer {
-        name: String,
-        size: u64,
-        checksum: Option<Vec<u8>>,
+/// `<name>.<ext>` next to a download, for what is known about it.
+fn side_path(path: &Path, ext: &str) -> PathBuf {
+    path.with_file_name(format!("{}.{}", path.file_name().unwrap().to_string_lossy(), ext))
+}
+
+enum Target {
+    Disk {
+        writer: std::io::BufWriter<fs::File>,
+        path: PathBuf,
     },
-    FileAccept(u64),
-    FileReject,
-    FileChunk(Vec<u8>),
-    FileDone,
-    FileCancel,
-    TypingStart,
-    TypingStop,
-    Delivered,
-    Ping,
-    Pong,
-    Away,
-    Back,
-    VersionNegotiate {
-        major: u8,
-        minor: u8,
-        patch: u8,
+    /// Chunks are sealed into the database as they arrive, so the file is
+    /// never on disk in the clear. Offers with an xxh3 checksum are hashed
+    /// on the way in instead, with the checksum they have to reach.
+    Vault {
+        attachment: u64,
+        xxh3: Option<(Box<Xxh3>, Vec<u8>)>,
     },
 }
 
-pub fn parse_message(data: &[u8]) -> ParsedMessage {
-    if data.len() >= 2 && data[0] == 0x00 {
-        match data[1] {
-            MSG_VERSION_NEGOTIATE if data.len() >= 5 => {
-                let major = data[2];
-                let minor = data[3];
-                let patch = data[4];
-                ParsedMessage::VersionNegotiate {
-                    major,
-                    minor,
-                    patch,
-                }
-            }
-            OFFER_TAG if data.len() >= 10 => {
-                let size = u64::from_be_bytes(data[2..10].try_into().unwrap());
-                if data.len() >= 18 {
-                    let checksum = data[10..18].to_vec();
-                    let name = String::from_utf8_lossy(&data[18..]).to_string();
-                    ParsedMessage::FileOffer {
-                        name,
-                        size,
-                        checksum: Some(checksum),
-                    }
-                } else {
-                    let name = String::from_utf8_lossy(&data[10..]).to_string();
-                    ParsedMessage::FileOffer {
-                        name,
-                        size,
-                        checksum: None,
-                    }
-                }
-            }
-            CHUNK_TAG => ParsedMessage::FileChunk(data[2..].to_vec()),
-            DONE_TAG => ParsedMessage::FileDone,
-            CANCEL_TAG => ParsedMessage::FileCancel,
-            MSG_FILE_ACCEPT => {
-                if data.len() >= 10 {
-                    let offset = u64::from_be_bytes(data[2..10].try_into().unwrap());
-                    ParsedMessage::FileAccept(offset)
-                } else {
-                    ParsedMessage::FileAccept(0)
-                }
-            }
-            MSG_FILE_REJECT => ParsedMessage::FileReject,
-            MSG_TYPING_START => ParsedMessage::TypingStart,
-            MSG_TYPING_STOP => ParsedMessage::TypingStop,
-            MSG_DELIVERED => ParsedMessage::Delivered,
-            MSG_PING => ParsedMessage::Ping,
-            MSG_PONG => ParsedMessage::Pong,
-            MSG_AWAY => ParsedMessage::Away,
-            MSG_BACK => ParsedMessage::Back,
-            _ => ParsedMessage::Text(String::from_utf8_lossy(data).to_string()),
-        }
-    } else {
-        ParsedMessage::Text(String::from_utf8_lossy(data).to_string())
-    }
+/// Checks every chunk against the tree root from the offer as it arrives.
+struct TreeCheck {
+    root: Hash,
+    count: usize,
+    /// `<name>.hashes`: the root, then the leaves checked so far, so a resume
+    /// can tell which part of the partial file is still good.
+    record: Option<fs::File>,
 }
 
 pub struct IncomingFile {
     pub name: String,
     pub size: u64,
     pub received: u64,
-    writer: std::io::BufWriter<fs::File>,
-    path: PathBuf,
+    /// Offset last acknowledged to the sender with `FileAck`.
+    pub acked: u64,
+    pub checksum: Option<Vec<u8>>,
+    target: Target,
+    check: Option<TreeCheck>,
 }
 
 impl IncomingFile {
@@ -200,17 +144,39 @@ impl IncomingFile {
         name: &str,
         size: u64,
         expected_checksum: Option<&[u8]>,
+        resume: bool,
     ) -> Result<Self, Box<dyn Error>> {
-        let dir = downloads_dir()?;
-        fs::create_dir_all(&dir)?;
-
-        let sanitized = sanitize_filename(name);
-        let path = dir.join(&sanitized);
+        let sanitized = sanitize_path(name);
+        let path = downloads_dir()?.join(&sanitized);
+        fs::create_dir_all(path.parent().unwrap())?;
+
+        if let Some(root) = expected_checksum.and_then(|sum| Hash::try_from(sum).ok()) {
+            let (file, received, record) = open_checked(&path, &root, resume)?;
+            return Ok(IncomingFile {
+                name: sanitized,
+                size,
+                received,
+                acked: received,
+                checksum: Some(root.to_vec()),
+                target: Target::Disk {
+                    writer: std::io::BufWriter::new(file),
+                    path,
+                },
+                check: Some(TreeCheck {
+                    root,
+                    count: leaf_count(size),
+                    record: Some(record),
+                }),
+            });
+        }
 
-        let file_exists = path.exists();
+        // without resume the sender starts from offset 0, so drop any partial file
+        let file_exists = resume && path.exists();
         let file = std::fs::OpenOptions::new()
             .create(true)
-            .append(true)
+            .append(resume)
+            .write(true)
+            .truncate(!resume)
             .open(&path)?;
         let received = if file_exists {
             file.metadata()?.len()
@@ -220,11 +186,7 @@ impl IncomingFile {
         let writer = std::io::BufWriter::new(file);
 
         if let Some(sum) = expected_checksum {
-            let meta_path = path.with_file_name(format!(
-                "{}.xxh3",
-                path.file_name().unwrap().to_string_lossy()
-            ));
-            let mut mf = fs::File::create(&meta_path)?;
+            let mut mf = fs::File::create(side_path(&path, "xxh3"))?;
             let hexstr = hex::encode(sum);
             mf.write_all(hexstr.as_bytes())?;
         }
@@ -233,102 +195,284 @@ impl IncomingFile {
             name: sanitized,
             size,
             received,
-            writer,
-            path,
+            acked: received,
+            checksum: expected_checksum.map(<[u8]>::to_vec),
+            target: Target::Disk { writer, path },
+            check: None,
         })
     }
 
-    pub fn write_chunk(&mut self, data: &[u8]) -> Result<(), Box<dyn Error>> {
-        self.writer.write_all(data)?;
+    /// Receives into the attachment vault instead of `downloads/`. There is
+    /// no partial file to resume from, so the transfer starts at 0.
+    pub fn begin_vault(name: &str, size: u64, expected_checksum: Option<&[u8]>) -> Self {
+        let root = expected_checksum.and_then(|sum| Hash::try_from(sum).ok());
+        IncomingFile {
+            name: sanitize_path(name),
+            size,
+            received: 0,
+            acked: 0,
+            checksum: expected_checksum.map(<[u8]>::to_vec),
+            target: Target::Vault {
+                attachment: rand::thread_rng().next_u64() >> 1,
+                xxh3: expected_checksum
+                    .filter(|_| root.is_none())
+                    .map(|sum| (Box::new(Xxh3::new()), sum.to_vec())),
+            },
+            check: root.map(|root| TreeCheck {
+                root,
+                count: leaf_count(size),
+                record: None,
+            }),
+        }
+    }
+
+    /// `storage` is only needed when receiving into the vault. With a tree
+    /// root in the offer, a chunk that does not match its `proof` is refused
+    /// before it is written. Data past the offered size is always refused.
+    pub fn write_chunk(
+        &mut self,
+        data: &[u8],
+        proof: Option<&[Hash]>,
+        storage: Option<&Storage>,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.received + data.len() as u64 > self.size {
+            return Err("more data than the offered size".into());
+        }
+        if let Some(check) = &mut self.check {
+            let index = (self.received / CHUNK_SIZE as u64) as usize;
+            let whole = data.len() == CHUNK_SIZE || self.received + data.len() as u64 == self.size;
+            let leaf = leaf_hash(data);
+            if !whole || !proof.is_some_and(|proof| verify_leaf(&check.root, check.count, index, leaf, proof)) {
+                return Err(format!("chunk {} does not match the offered hash", index + 1).into());
+            }
+            if let Some(record) = &mut check.record {
+                record.write_all(&leaf)?;
+            }
+        }
+        match self.target {
+            Target::Disk { ref mut writer, .. } => writer.write_all(data)?,
+            Target::Vault { attachment, ref mut xxh3 } => {
+                storage
+                    .ok_or("the vault needs history to be saved")?
+                    .append_attachment(attachment, data)?;
+                if let Some((hasher, _)) = xxh3 {
+                    hasher.update(data);
+                }
+            }
+        }
         self.received += data.len() as u64;
         Ok(())
     }
 
-    pub fn finish(mut self) -> Result<PathBuf, Box<dyn Error>> {
-        self.writer.flush()?;
-
-        let meta_path = self.path.with_file_name(format!(
-            "{}.xxh3",
-            self.path.file_name().unwrap().to_string_lossy()
-        ));
-        if meta_path.exists() {
-            if let Ok(expected_hex) = fs::read_to_string(&meta_path) {
-                if !expected_hex.trim().is_empty() {
-                    let expected = hex::decode(expected_hex.trim())?;
-                    let actual = file_xxh3(&self.path)?;
-                    if expected != actual {
-                        return Err(From::from("checksum mismatch after download"));
+    /// Checks the checksum and returns where the file now is. A vault file
+    /// that does not match is dropped again.
+    pub fn finish(self, storage: Option<&mut Storage>) -> Result<SavedFile, Box<dyn Error>> {
+        // every chunk was checked on arrival, so only a short file is left to catch
+        if let Some(check) = &self.check
+            && (self.received != self.size || (self.size == 0 && check.root != leaf_hash(&[])))
+        {
+            if let Target::Vault { attachment, .. } = self.target
+                && let Some(s) = storage
+            {
+                let _ = s.delete_attachment(attachment);
+            }
+            return Err(From::from("checksum mismatch after download"));
+        }
+        match self.target {
+            Target::Disk { mut writer, path } => {
+                writer.flush()?;
+
+                let _ = fs::remove_file(side_path(&path, "hashes"));
+                let meta_path = side_path(&path, "xxh3");
+                if meta_path.exists() {
+                    if let Ok(expected_hex) = fs::read_to_string(&meta_path)
+                        && !expected_hex.trim().is_empty()
+                    {
+                        let expected = hex::decode(expected_hex.trim())?;
+                        let actual = file_xxh3(&path)?;
+                        if expected != actual {
+                            return Err(From::from("checksum mismatch after download"));
+                        }
                     }
+                    let _ = fs::remove_file(&meta_path);
+                }
+                Ok(SavedFile::Downloads(path))
+            }
+            Target::Vault { attachment, xxh3 } => {
+                if xxh3.is_some_and(|(hasher, expected)| expected != hasher.digest().to_be_bytes()) {
+                    if let Some(s) = storage {
+                        let _ = s.delete_attachment(attachment);
+                    }
+                    return Err(From::from("checksum mismatch after download"));
+                }
+                Ok(SavedFile::Vault(attachment))
+            }
+        }
+    }
+
+    /// Keeps what arrived of a download cut off by a disconnect, so accepting
+    /// the same file again can resume it. Vault downloads are dropped.
+    pub fn interrupt(self, storage: Option<&mut Storage>) {
+        match self.target {
+            Target::Disk { mut writer, .. } => {
+                let _ = writer.flush();
+            }
+            Target::Vault { .. } => self.cancel(storage),
+        }
+    }
+
+    pub fn cancel(self, storage: Option<&mut Storage>) {
+        match self.target {
+            Target::Disk { writer, path } => {
+                drop(writer);
+                let _ = fs::remove_file(side_path(&path, "hashes"));
+                let _ = fs::remove_file(side_path(&path, "xxh3"));
+                let _ = fs::remove_file(&path);
+            }
+            Target::Vault { attachment, .. } => {
+                if let Some(s) = storage {
+                    let _ = s.delete_attachment(attachment);
                 }
             }
-            let _ = fs::remove_file(&meta_path);
         }
-        Ok(self.path)
     }
+}
 
-    pub fn cancel(self) {
-        drop(self.writer);
-        let _ = fs::remove_file(&self.path);
+/// Opens a download checked against `root`. When resuming, the chunks on disk
+/// are hashed again and compared with the leaves in `<name>.hashes`, and the
+/// file is cut back to the longest prefix that still matches.
+fn open_checked(path: &Path, root: &Hash, resume: bool) -> Result<(fs::File, u64, fs::File), Box<dyn Error>> {
+    let record_path = side_path(path, "hashes");
+    let mut leaves = Vec::new();
+    let mut received = 0;
+    if resume
+        && let Ok(saved) = fs::read(&record_path)
+        && saved.starts_with(root)
+        && let Ok(file) = fs::File::open(path)
+    {
+        let mut reader = std::io::BufReader::new(file);
+        for saved_leaf in saved[root.len()..].chunks_exact(root.len()) {
+            let chunk = read_chunk(&mut reader)?;
+            if chunk.is_empty() || leaf_hash(&chunk)[..] != *saved_leaf {
+                break;
+            }
+            leaves.extend_from_slice(saved_leaf);
+            received += chunk.len() as u64;
+        }
     }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(path)?;
+    file.set_len(received)?;
+    file.seek(SeekFrom::End(0))?;
+    let mut record = fs::File::create(&record_path)?;
+    record.write_all(root)?;
+    record.write_all(&leaves)?;
+    Ok((file, received, record))
 }
 
 pub struct OutgoingFile {
     pub name: String,
     pub size: u64,
     pub sent: u64,
-    pub checksum: Vec<u8>,
+    /// Offer the tree root and send proofs with each chunk instead of only
+    /// an xxh3 checksum, for peers with the `hashes` feature.
+    pub hashed: bool,
+    xxh3: Vec<u8>,
+    tree: Vec<Vec<Hash>>,
     reader: std::io::BufReader<fs::File>,
 }
 
 impl OutgoingFile {
-    pub fn open(path: &str) -> Result<Self, Box<dyn Error>> {
-        let path = path.trim();
-        let metadata = fs::metadata(path)?;
-        let size = metadata.len();
-        let name = Path::new(path)
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let name = path
             .file_name()
             .ok_or("invalid file path")?
             .to_string_lossy()
             .to_string();
+        Self::from_file(name, fs::File::open(path)?)
+    }
+
+    /// Like `open`, for a file that is open already, such as an unlinked
+    /// temporary one.
+    pub fn from_file(name: String, file: fs::File) -> Result<Self, Box<dyn Error>> {
+        let size = file.metadata()?.len();
 
+        // one pass for both, the peer's features are not known yet
         let mut hasher = Xxh3::new();
-        let mut hfile = fs::File::open(path)?;
-        let mut hreader = std::io::BufReader::new(&mut hfile);
-        let mut buf = [0u8; 8192];
+        let mut leaves = Vec::new();
+        let mut reader = std::io::BufReader::new(file);
         loop {
-            let n = hreader.read(&mut buf)?;
-            if n == 0 {
+            let chunk = read_chunk(&mut reader)?;
+            if chunk.is_empty() && !leaves.is_empty() {
+                break;
+            }
+            hasher.update(&chunk);
+            leaves.push(leaf_hash(&chunk));
+            if chunk.len() < CHUNK_SIZE {
                 break;
             }
-            hasher.update(&buf[..n]);
         }
-        let checksum_u = hasher.digest();
-        let checksum = checksum_u.to_be_bytes().to_vec();
 
-        let file = fs::File::open(path)?;
-        let reader = std::io::BufReader::new(file);
+        reader.seek(SeekFrom::Start(0))?;
         Ok(OutgoingFile {
             name,
             size,
             sent: 0,
-            checksum,
+            hashed: false,
+            xxh3: hasher.digest().to_be_bytes().to_vec(),
+            tree: tree_levels(leaves),
             reader,
         })
     }
 
-    pub fn read_next_chunk(&mut self) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
-        let mut buf = vec![0u8; CHUNK_SIZE];
-        let n = self.reader.read(&mut buf)?;
-        if n == 0 {
+    /// The tree root when `hashed`, otherwise the xxh3 checksum.
+    pub fn checksum(&self) -> Vec<u8> {
+        if self.hashed {
+            self.tree.last().unwrap()[0].to_vec()
+        } else {
+            self.xxh3.clone()
+        }
+    }
+
+    pub fn offer(&self, transfer: u64) -> Message {
+        Message::FileOffer {
+            transfer,
+            name: self.name.clone(),
+            size: self.size,
+            checksum: Some(self.checksum()),
+        }
+    }
+
+    /// Reads the next chunk into a `FileChunk`, with its proof when `hashed`.
+    pub fn next_chunk(&mut self, transfer: u64) -> Result<Option<Message>, Box<dyn Error>> {
+        let index = (self.sent / CHUNK_SIZE as u64) as usize;
+        let data = read_chunk(&mut self.reader)?;
+        if data.is_empty() {
             return Ok(None);
         }
-        buf.truncate(n);
-        self.sent += n as u64;
-        Ok(Some(buf))
+        self.sent += data.len() as u64;
+        let proof = self.hashed.then(|| {
+            let mut proof = Vec::new();
+            let mut index = index;
+            for level in &self.tree[..self.tree.len() - 1] {
+                if let Some(sibling) = level.get(index ^ 1) {
+                    proof.push(*sibling);
+                }
+                index /= 2;
+            }
+            proof
+        });
+        Ok(Some(Message::FileChunk { transfer, proof, data }))
     }
 
     pub fn seek_to(&mut self, offset: u64) -> Result<(), Box<dyn Error>> {
-        use std::io::SeekFrom;
+        // proofs cover whole chunks only
+        if self.hashed && !offset.is_multiple_of(CHUNK_SIZE as u64) && offset != self.size {
+            return Err(From::from("resume offset is not on a chunk boundary"));
+        }
         self.reader.get_mut().seek(SeekFrom::Start(offset))?;
         self.sent = offset;
         Ok(())
@@ -350,10 +494,14 @@ pub fn exports_dir() -> Result<PathBuf, Box<dyn Error>> {
     Ok(exe_dir.join("exports"))
 }
 
-pub fn download_path(name: &str) -> Result<PathBuf, Box<dyn Error>> {
-    let dir = downloads_dir()?;
-    Ok(dir.join(sanitize_filename(name)))
+/// Creates a new file in `downloads/`, failing if one with the name exists.
+pub fn create_download(name: &str) -> Result<(PathBuf, fs::File), Box<dyn Error>> {
+    let path = downloads_dir()?.join(sanitize_path(name));
+    fs::create_dir_all(path.parent().unwrap())?;
+    let file = fs::OpenOptions::new().write(true).create_new(true).open(&path)?;
+    Ok((path, file))
 }
+
 pub fn remove_downloads_dir() -> Result<(), Box<dyn Error>> {
     let dir = downloads_dir()?;
     if dir.exists() {
@@ -386,16 +534,156 @@ pub fn sanitize_filename(name: &str) -> String {
     name.replace(['/', '\\', ':', '*', '?', '"', '<', '>', '|'], "_")
 }
 
-pub fn existing_download_size(name: &str) -> Result<u64, Box<dyn Error>> {
-    let dir = downloads_dir()?;
-    let sanitized = sanitize_filename(name);
-    let path = dir.join(&sanitized);
-    if path.exists() {
-        Ok(fs::metadata(path)?.len())
+/// `sanitize_filename` for every part of a relative path such as
+/// `logs/app/today.log`. Empty, `.` and `..` parts are dropped, so the result
+/// always stays inside `downloads/`.
+pub fn sanitize_path(name: &str) -> String {
+    let parts: Vec<String> = name
+        .split(['/', '\\'])
+        .filter(|part| !part.is_empty() && *part != "." && *part != "..")
+        .map(sanitize_filename)
+        .collect();
+    if parts.is_empty() {
+        "unnamed".to_string()
     } else {
-        Ok(0)
+        parts.join("/")
+    }
+}
+
+/// A file waiting in the send queue. It is only opened and hashed when it is
+/// offered, so a queued folder does not hold all of its files open.
+pub struct QueuedFile {
+    /// What the peer is told, `folder/sub/file` for files of a folder.
+    pub name: String,
+    pub size: u64,
+    pub path: PathBuf,
+    /// Open already instead of at `path`, for pasted data that only exists
+    /// as an unlinked temporary file.
+    pub file: Option<fs::File>,
+    /// Part of a folder the peer has accepted as a whole.
+    pub in_folder: bool,
+    /// The length of a recorded voice clip, which is offered with `VoiceOffer`.
+    pub voice_ms: Option<u32>,
+}
+
+impl QueuedFile {
+    /// Queues `data` from an unlinked temporary file, so nothing is left
+    /// behind once it has been sent.
+    pub fn temporary(name: String, data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut file = tempfile::tempfile()?;
+        file.write_all(data)?;
+        file.rewind()?;
+        Ok(QueuedFile {
+            name,
+            size: data.len() as u64,
+            path: PathBuf::new(),
+            file: Some(file),
+            in_folder: false,
+            voice_ms: None,
+        })
+    }
+}
+
+/// Expands a `/send` argument: a file, a directory with everything under it,
+/// or a `*` / `?` pattern in the last part of the path. Returns the folder the
+/// files are sent under, which is None for a single file. Symlinks inside a
+/// folder are skipped.
+pub fn expand(arg: &str) -> Result<(Option<String>, Vec<QueuedFile>), Box<dyn Error>> {
+    let path = Path::new(arg.trim());
+    let pattern = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .filter(|n| n.contains(['*', '?']));
+    let mut queued = Vec::new();
+    if let Some(pattern) = pattern {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let folder = folder_name(dir)?;
+        let pattern: Vec<char> = pattern.chars().collect();
+        for (name, entry) in sorted_entries(dir)? {
+            // like a shell, wildcards do not match hidden files
+            let hidden = name.starts_with('.') && pattern.first() != Some(&'.');
+            if !hidden && wildcard_match(&pattern, &name.chars().collect::<Vec<_>>()) {
+                collect(&entry, format!("{}/{}", folder, name), &mut queued)?;
+            }
+        }
+        if queued.is_empty() {
+            return Err(format!("no files match {}", arg.trim()).into());
+        }
+        return Ok((Some(folder), queued));
+    }
+    if fs::metadata(path)?.is_dir() {
+        let folder = folder_name(path)?;
+        for (name, entry) in sorted_entries(path)? {
+            collect(&entry, format!("{}/{}", folder, name), &mut queued)?;
+        }
+        if queued.is_empty() {
+            return Err("the folder has no files".into());
+        }
+        return Ok((Some(folder), queued));
+    }
+    let name = path.file_name().ok_or("invalid file path")?.to_string_lossy().to_string();
+    queued.push(QueuedFile {
+        name,
+        size: fs::metadata(path)?.len(),
+        path: path.to_path_buf(),
+        file: None,
+        in_folder: false,
+        voice_ms: None,
+    });
+    Ok((None, queued))
+}
+
+fn folder_name(dir: &Path) -> Result<String, Box<dyn Error>> {
+    Ok(fs::canonicalize(dir)?
+        .file_name()
+        .ok_or("cannot send a whole filesystem")?
+        .to_string_lossy()
+        .to_string())
+}
+
+fn sorted_entries(dir: &Path) -> Result<Vec<(String, PathBuf)>, Box<dyn Error>> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| (e.file_name().to_string_lossy().to_string(), e.path())))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    Ok(entries)
+}
+
+/// Queues `path` as `name`, and everything under it if it is a directory.
+fn collect(path: &Path, name: String, queued: &mut Vec<QueuedFile>) -> Result<(), Box<dyn Error>> {
+    let meta = fs::symlink_metadata(path)?;
+    if meta.is_dir() {
+        for (child, entry) in sorted_entries(path)? {
+            collect(&entry, format!("{}/{}", name, child), queued)?;
+        }
+    } else if meta.is_file() {
+        queued.push(QueuedFile {
+            name,
+            size: meta.len(),
+            path: path.to_path_buf(),
+            file: None,
+            in_folder: true,
+            voice_ms: None,
+        });
+    }
+    Ok(())
+}
+
+fn wildcard_match(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => {
+            wildcard_match(&pattern[1..], name) || (!name.is_empty() && wildcard_match(pattern, &name[1..]))
+        }
+        (Some('?'), Some(_)) => wildcard_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => wildcard_match(&pattern[1..], &name[1..]),
+        _ => false,
     }
 }
+
 /*
 fn unique_path(dir: &Path, name: &str) -> PathBuf {
     let base = dir.join(name);
@@ -439,6 +727,21 @@ pub fn randomize_filename_preserve_ext(name: &str) -> String {
     }
 }
 
+// formats that are compressed already, where zstd only costs time
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "apk", "avi", "br", "bz2", "deb", "docx", "flac", "gif", "gz", "heic", "jar", "jpeg", "jpg", "lz", "lz4",
+    "lzma", "m4a", "mkv", "mov", "mp3", "mp4", "odt", "ogg", "opus", "pdf", "png", "rar", "rpm", "tgz", "txz",
+    "webm", "webp", "xlsx", "xz", "zip", "zst",
+];
+
+/// Whether sending `name` compressed is worth trying, judging by its extension.
+pub fn compressible(name: &str) -> bool {
+    match Path::new(name).extension() {
+        Some(ext) => !COMPRESSED_EXTENSIONS.contains(&ext.to_string_lossy().to_ascii_lowercase().as_str()),
+        None => true,
+    }
+}
+
 pub fn format_size(bytes: u64) -> String {
     if bytes < 1024 {
         format!("{} B", bytes)
@@ -450,3 +753,105 @@ pub fn format_size(bytes: u64) -> String {
         format!("{:.2} GB", bytes as f64 / (1024.0 * 1024.0 * 1024.0))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a hashed sender over `size` bytes that differ from chunk to chunk
+    fn outgoing(size: usize) -> OutgoingFile {
+        let mut file = tempfile::tempfile().unwrap();
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        file.write_all(&data).unwrap();
+        file.rewind().unwrap();
+        let mut out = OutgoingFile::from_file("test".to_string(), file).unwrap();
+        out.hashed = true;
+        out
+    }
+
+    fn root(out: &OutgoingFile) -> Hash {
+        out.checksum().try_into().unwrap()
+    }
+
+    fn chunks(out: &mut OutgoingFile) -> Vec<(Vec<u8>, Vec<Hash>)> {
+        let mut chunks = Vec::new();
+        while let Some(msg) = out.next_chunk(1).unwrap() {
+            let Message::FileChunk { proof, data, .. } = msg else {
+                panic!("expected a chunk");
+            };
+            chunks.push((data, proof.unwrap()));
+        }
+        chunks
+    }
+
+    fn round_trip(size: usize) {
+        let mut out = outgoing(size);
+        let root = root(&out);
+        let chunks = chunks(&mut out);
+        assert_eq!(chunks.len(), size.div_ceil(CHUNK_SIZE));
+        let count = leaf_count(size as u64);
+        for (index, (data, proof)) in chunks.iter().enumerate() {
+            assert!(
+                verify_leaf(&root, count, index, leaf_hash(data), proof),
+                "chunk {} of a {} byte file",
+                index,
+                size
+            );
+        }
+    }
+
+    #[test]
+    fn empty_file_is_one_empty_leaf() {
+        let mut out = outgoing(0);
+        assert_eq!(root(&out), leaf_hash(&[]));
+        assert!(chunks(&mut out).is_empty());
+        assert!(verify_leaf(&leaf_hash(&[]), leaf_count(0), 0, leaf_hash(&[]), &[]));
+    }
+
+    #[test]
+    fn single_chunk() {
+        round_trip(CHUNK_SIZE);
+    }
+
+    #[test]
+    fn three_chunks() {
+        round_trip(2 * CHUNK_SIZE + 1);
+        round_trip(3 * CHUNK_SIZE);
+    }
+
+    #[test]
+    fn one_past_a_power_of_two() {
+        round_trip(8 * CHUNK_SIZE + 1);
+        round_trip(16 * CHUNK_SIZE + 7);
+    }
+
+    #[test]
+    fn tampering_is_caught() {
+        let size = 8 * CHUNK_SIZE + 1;
+        let mut out = outgoing(size);
+        let root = root(&out);
+        let chunks = chunks(&mut out);
+        let count = leaf_count(size as u64);
+
+        let (data, proof) = &chunks[3];
+        let mut tampered = data.clone();
+        tampered[0] ^= 1;
+        assert!(!verify_leaf(&root, count, 3, leaf_hash(&tampered), proof));
+
+        let mut bad_proof = proof.clone();
+        bad_proof[1][0] ^= 1;
+        assert!(!verify_leaf(&root, count, 3, leaf_hash(data), &bad_proof));
+
+        // right chunk and proof, claimed for the wrong place or with extra hashes
+        assert!(!verify_leaf(&root, count, 2, leaf_hash(data), proof));
+        let mut long_proof = proof.clone();
+        long_proof.push(root);
+        assert!(!verify_leaf(&root, count, 3, leaf_hash(data), &long_proof));
+        assert!(!verify_leaf(&root, count, count, leaf_hash(data), proof));
+
+        // the odd last chunk moves up alone, so its proof is the shortest
+        let (last, last_proof) = chunks.last().unwrap();
+        assert_eq!(last_proof.len(), 1);
+        assert!(!verify_leaf(&root, count, count - 1, leaf_hash(&last[1..]), last_proof));
+    }
+}
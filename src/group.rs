@@ -0,0 +1,295 @@
+use std::error::Error;
+
+use arti_client::TorClient;
+use crossterm::event::{Event, EventStream};
+use futures::StreamExt;
+use tokio::sync::mpsc;
+use tor_cell::relaycell::msg::Connected;
+use tor_rtcompat::PreferredRuntime;
+
+use crate::config::Config;
+use circuitchat_proto::{Features, Framing, Hello, MAX_TEXT_LEN, Message, StaticKeypair};
+use crate::session::{self, SessionEvent, SessionHandle};
+use crate::storage::{MessageDirection, Storage};
+use crate::tui;
+
+struct Member {
+    handle: SessionHandle,
+    label: String,
+}
+
+struct Group {
+    members: Vec<Member>,
+}
+
+impl Group {
+    fn label_of(&self, id: u64) -> Option<String> {
+        self.members
+            .iter()
+            .find(|m| m.handle.id == id)
+            .map(|m| m.label.clone())
+    }
+
+    async fn send_to(&self, id: u64, msg: Message) {
+        if let Some(m) = self.members.iter().find(|m| m.handle.id == id) {
+            m.handle.send(&msg).await;
+        }
+    }
+
+    // encoded per member, since each may use a different framing
+    async fn broadcast(&self, text: String, except: Option<u64>) {
+        let msg = Message::text(text);
+        for m in &self.members {
+            if Some(m.handle.id) != except {
+                m.handle.send(&msg).await;
+            }
+        }
+    }
+
+    fn remove(&mut self, id: u64) -> Option<Member> {
+        let pos = self.members.iter().position(|m| m.handle.id == id)?;
+        Some(self.members.remove(pos))
+    }
+
+    fn labels(&self) -> String {
+        if self.members.is_empty() {
+            return "(none)".to_string();
+        }
+        self.members
+            .iter()
+            .map(|m| m.label.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+fn valid_label(label: &str) -> bool {
+    !label.is_empty()
+        && label.len() <= 24
+        && label
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Hosts a group chat: every member connects to our onion service like a
+/// normal initiator, and text from one member is relayed to all the others
+/// prefixed with the sender's label.
+pub async fn run_group_host(
+    tor: &TorClient<PreferredRuntime>,
+    storage: &mut Option<Storage>,
+    identity: Option<&StaticKeypair>,
+    password: Option<String>,
+    cfg: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let (_service, addr_str, stream_requests) = crate::launch_listener(tor).await?;
+    let mut stream_requests = Box::pin(stream_requests);
+
+    let t = &cfg.time;
+    let now = || tui::now_timestamp(t.local, t.hour24, t.show_tz, t.show_seconds);
+
+    let mut terminal = ratatui::init();
+    let mut app = tui::App::new(
+        "hosting group",
+        cfg.ui.message_notification_sound,
+        cfg.ui.mention_notification_sound,
+    );
+    app.add_plain_message(
+        MessageDirection::System,
+        format!("[group] hosting at {}. share this address with members", addr_str),
+        now(),
+    );
+
+    let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+    let mut key_events = EventStream::new();
+    let mut group = Group {
+        members: Vec::new(),
+    };
+    let mut next_id: u64 = 0;
+
+    loop {
+        app.view_mut().status = format!("hosting group | {} member(s)", group.members.len());
+        terminal.draw(|f| app.draw(f))?;
+
+        tokio::select! {
+            request = stream_requests.next() => {
+                let Some(request) = request else { break };
+                match request.accept(Connected::new_empty()).await {
+                    Ok(stream) => {
+                        next_id += 1;
+                        session::spawn_accept(
+                            next_id,
+                            stream,
+                            crate::PATTERN,
+                            identity.cloned(),
+                            password.clone(),
+                            events_tx.clone(),
+                        );
+                    }
+                    Err(e) => app.view_mut().status = format!("failed to accept connection: {}", e),
+                }
+            }
+            event = events_rx.recv() => {
+                let Some(event) = event else { break };
+                match event {
+                    SessionEvent::Joined(handle) => {
+                        let contact = match (storage.as_ref(), handle.remote_static.as_deref()) {
+                            (Some(s), Some(key)) => s.find_contact_by_key(key).unwrap_or(None),
+                            _ => None,
+                        };
+                        let label = match contact {
+                            Some(c) if group.members.iter().all(|m| m.label != c.nickname) => c.nickname,
+                            _ => format!("member-{}", handle.id),
+                        };
+                        let notice = format!("* {} joined", label);
+                        group.broadcast(notice, None).await;
+                        app.add_plain_message(
+                            MessageDirection::System,
+                            format!("[group] {} joined (fp: {})", label, handle.fingerprint),
+                            now(),
+                        );
+
+                        // the host only relays text, so it advertises no optional features
+                        handle.send(&Message::Hello(Hello::new(env!("CARGO_PKG_VERSION"), Features::empty()))).await;
+                        handle.send(&Message::text(format!(
+                            "* welcome, you are {}. members: {}. type /nick <name> to change your name",
+                            label,
+                            group.labels()
+                        ))).await;
+                        group.members.push(Member { handle, label });
+                    }
+                    SessionEvent::Failed { id, error } => {
+                        app.view_mut().status = format!("connection {} rejected: {}", id, error);
+                    }
+                    // file transfer is not available in groups
+                    SessionEvent::FileProgress { .. } | SessionEvent::FileSent { .. } | SessionEvent::FileHashed { .. } => {}
+                    SessionEvent::Closed { id } => {
+                        if let Some(member) = group.remove(id) {
+                            let notice = format!("* {} left", member.label);
+                            group.broadcast(notice, None).await;
+                            app.add_plain_message(
+                                MessageDirection::System,
+                                format!("[group] {} left", member.label),
+                                now(),
+                            );
+                        }
+                    }
+                    SessionEvent::Message { id, data } => {
+                        let Some(label) = group.label_of(id) else { continue };
+                        match Message::decode(&data) {
+                            Message::Text { text: content, .. } => {
+                                if let Some(new_label) = content.strip_prefix("/nick ") {
+                                    let new_label = new_label.trim();
+                                    if !valid_label(new_label) {
+                                        group.send_to(id, Message::text("* names may only use letters, digits, - and _")).await;
+                                    } else if group.members.iter().any(|m| m.label == new_label) {
+                                        group.send_to(id, Message::text(format!("* {} is already taken", new_label))).await;
+                                    } else {
+                                        if let Some(m) = group.members.iter_mut().find(|m| m.handle.id == id) {
+                                            m.label = new_label.to_string();
+                                        }
+                                        let notice = format!("* {} is now known as {}", label, new_label);
+                                        group.broadcast(notice, None).await;
+                                        app.add_plain_message(
+                                            MessageDirection::System,
+                                            format!("[group] {} is now known as {}", label, new_label),
+                                            now(),
+                                        );
+                                    }
+                                    continue;
+                                }
+                                // relayed with the label in front, which has to fit in one frame too
+                                if content.len() > MAX_TEXT_LEN - (label.len() + 3) {
+                                    group.send_to(id, Message::text("* message too long for the group, not relayed")).await;
+                                    continue;
+                                }
+                                let relayed = format!("[{}] {}", label, content);
+                                group.broadcast(relayed, Some(id)).await;
+                                let spans = if content.contains("@peer") {
+                                    tui::highlighted(&content, "@peer")
+                                } else {
+                                    tui::plain(&content)
+                                };
+                                app.add_message_from(&label, spans, now());
+                            }
+                            Message::Hello(hello) => {
+                                if let Some(m) = group.members.iter_mut().find(|m| m.handle.id == id) {
+                                    m.handle.framing = Framing::for_peer(&hello);
+                                }
+                            }
+                            Message::Ping => {
+                                group.send_to(id, Message::Pong).await;
+                            }
+                            Message::FileOffer { .. } => {
+                                group.send_to(id, Message::FileReject(0)).await;
+                                group.send_to(id, Message::text("* file transfer is not supported in group chats")).await;
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            event = key_events.next() => {
+                match event {
+                    Some(Ok(Event::Key(key))) => {
+                        if let Some(text) = app.handle_key(key) {
+                            if let Some(label) = text.strip_prefix("/kick ") {
+                                let label = label.trim();
+                                let id = group
+                                    .members
+                                    .iter()
+                                    .find(|m| m.label == label)
+                                    .map(|m| m.handle.id);
+                                match id.and_then(|id| group.remove(id)) {
+                                    Some(member) => {
+                                        member.handle.send(&Message::text("* you were removed from the group")).await;
+                                        drop(member);
+                                        let notice = format!("* {} was removed by the host", label);
+                                        group.broadcast(notice, None).await;
+                                        app.add_plain_message(
+                                            MessageDirection::System,
+                                            format!("[group] removed {}", label),
+                                            now(),
+                                        );
+                                    }
+                                    None => app.view_mut().status = format!("no member named {}", label),
+                                }
+                            } else if text == "/members" {
+                                app.add_plain_message(
+                                    MessageDirection::System,
+                                    format!("[group] members: {}", group.labels()),
+                                    now(),
+                                );
+                            } else if text == "/help" {
+                                app.add_plain_message(
+                                    MessageDirection::System,
+                                    "[help] group commands: /members, /kick <name>, /clear, /help, /panic, /wipe".to_string(),
+                                    now(),
+                                );
+                            } else if text == "/clear" {
+                                app.view_mut().messages.clear();
+                            } else if text == "/panic" || text == "/wipe" {
+                                if let Err(e) = crate::perform_panic_and_exit(storage.take()) {
+                                    eprintln!("panic cleanup failed: {}", e);
+                                }
+                                std::process::exit(1);
+                            } else if text.len() > MAX_TEXT_LEN {
+                                app.view_mut().status = "message too long to send".to_string();
+                            } else {
+                                group.broadcast(text.clone(), None).await;
+                                app.add_plain_message(MessageDirection::Sent, text, now());
+                            }
+                        }
+                        if app.should_quit {
+                            break;
+                        }
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(_)) | None => break,
+                }
+            }
+        }
+    }
+
+    ratatui::restore();
+    Ok(())
+}
@@ -0,0 +1,346 @@
+use base64::Engine;
+use circuitchat_proto::MAX_TEXT_LEN;
+use image::{DynamicImage, ImageReader, Limits, RgbImage, imageops::FilterType};
+use ratatui::{
+    DefaultTerminal,
+    style::{Color, Style},
+    text::{Line, Span},
+};
+use std::error::Error;
+use std::fs;
+use std::io::{Cursor, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::files::QueuedFile;
+
+// the largest preview, in terminal cells
+const MAX_COLS: u32 = 40;
+const MAX_ROWS: u32 = 10;
+// what a cell is assumed to measure in pixels, about twice as tall as wide
+const CELL_WIDTH: u32 = 10;
+const CELL_HEIGHT: u32 = 20;
+/// Files larger than this are not decoded for a preview.
+pub const MAX_PREVIEW_FILE: u64 = 20 * 1024 * 1024;
+const MAX_IMAGE_SIDE: u32 = 8192;
+const IMAGE_EXTENSIONS: &[&str] = &["bmp", "gif", "jpeg", "jpg", "png", "webp"];
+
+/// How previews are drawn. `Kitty` and `Sixel` draw real pixels over blank
+/// cells after each frame, `Blocks` draws two pixels per cell with half-block
+/// characters as part of the frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Graphics {
+    Kitty,
+    Sixel,
+    Blocks,
+    Off,
+}
+
+impl Graphics {
+    /// From `ui.image_previews`, where "auto" picks by the terminal.
+    pub fn from_config(setting: &str) -> Self {
+        match setting {
+            "kitty" => Graphics::Kitty,
+            "sixel" => Graphics::Sixel,
+            "blocks" => Graphics::Blocks,
+            "off" => Graphics::Off,
+            _ => Self::detect(),
+        }
+    }
+
+    // terminals do not reliably answer queries once the TUI owns stdin, so
+    // this goes by what they put in the environment
+    fn detect() -> Self {
+        let var = |name: &str| std::env::var(name).unwrap_or_default();
+        let (term, program) = (var("TERM"), var("TERM_PROGRAM"));
+        if std::env::var_os("KITTY_WINDOW_ID").is_some()
+            || term == "xterm-kitty"
+            || term == "xterm-ghostty"
+            || program == "WezTerm"
+        {
+            Graphics::Kitty
+        } else if term.starts_with("foot") || term.starts_with("mlterm") || term.contains("sixel") {
+            Graphics::Sixel
+        } else {
+            Graphics::Blocks
+        }
+    }
+}
+
+pub fn is_image(name: &str) -> bool {
+    Path::new(name)
+        .extension()
+        .is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.to_string_lossy().to_ascii_lowercase().as_str()))
+}
+
+/// A scaled-down image shown under a chat message.
+pub struct Preview {
+    pub cols: u16,
+    pub rows: u16,
+    /// Tells previews apart when checking whether any of them moved.
+    id: u64,
+    /// One pixel per column and two per row, for half blocks.
+    blocks: RgbImage,
+    /// Roughly the cells' size in pixels, for the graphics protocols.
+    pixels: RgbImage,
+}
+
+impl Preview {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if fs::metadata(path)?.len() > MAX_PREVIEW_FILE {
+            return Err("too large to preview".into());
+        }
+        Self::decode(&fs::read(path)?)
+    }
+
+    /// Decodes an image from a peer, refusing sizes that would take more
+    /// memory than a preview is worth.
+    pub fn decode(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut reader = ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(MAX_IMAGE_SIDE);
+        limits.max_image_height = Some(MAX_IMAGE_SIDE);
+        reader.limits(limits);
+        Ok(Self::new(&reader.decode()?))
+    }
+
+    pub fn new(image: &DynamicImage) -> Self {
+        let (width, height) = (image.width().max(1), image.height().max(1));
+        // fit the box, but never blow a small image up past its own size
+        let scale = (MAX_COLS * CELL_WIDTH) as f64 / width as f64;
+        let scale = scale.min((MAX_ROWS * CELL_HEIGHT) as f64 / height as f64).min(1.0);
+        let (px_width, px_height) = (
+            ((width as f64 * scale) as u32).max(1),
+            ((height as f64 * scale) as u32).max(1),
+        );
+        let cols = px_width.div_ceil(CELL_WIDTH);
+        let rows = px_height.div_ceil(CELL_HEIGHT);
+        let rgb = image.to_rgb8();
+        Preview {
+            cols: cols as u16,
+            rows: rows as u16,
+            id: rand::random(),
+            blocks: image::imageops::resize(&rgb, cols, rows * 2, FilterType::Triangle),
+            pixels: image::imageops::resize(&rgb, px_width, px_height, FilterType::Triangle),
+        }
+    }
+
+    /// The rows the preview takes in the chat: half blocks, or blank cells
+    /// for `Kitty` and `Sixel` to draw over.
+    pub fn lines(&self, graphics: Graphics) -> Vec<Line<'static>> {
+        (0..self.rows as u32)
+            .map(|row| {
+                if graphics != Graphics::Blocks {
+                    return Line::from(" ".repeat(self.cols as usize));
+                }
+                let spans: Vec<Span> = (0..self.cols as u32)
+                    .map(|col| {
+                        let [tr, tg, tb] = self.blocks.get_pixel(col, row * 2).0;
+                        let [br, bg, bb] = self.blocks.get_pixel(col, row * 2 + 1).0;
+                        Span::styled(
+                            "\u{2580}",
+                            Style::default().fg(Color::Rgb(tr, tg, tb)).bg(Color::Rgb(br, bg, bb)),
+                        )
+                    })
+                    .collect();
+                Line::from(spans)
+            })
+            .collect()
+    }
+
+    fn kitty(&self) -> Vec<u8> {
+        let mut png = Vec::new();
+        if self.pixels.write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png).is_err() {
+            return Vec::new();
+        }
+        let encoded = base64::engine::general_purpose::STANDARD.encode(png);
+        let chunks: Vec<&[u8]> = encoded.as_bytes().chunks(4096).collect();
+        let mut out = Vec::new();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let more = if i + 1 < chunks.len() { 1 } else { 0 };
+            if i == 0 {
+                // C=1 leaves the cursor where it was
+                write!(out, "\x1b_Ga=T,f=100,q=2,C=1,m={};", more).unwrap();
+            } else {
+                write!(out, "\x1b_Gm={};", more).unwrap();
+            }
+            out.extend_from_slice(chunk);
+            out.extend_from_slice(b"\x1b\\");
+        }
+        out
+    }
+
+    // colours are rounded to a 6x6x6 cube, plenty for a thumbnail
+    fn sixel(&self) -> Vec<u8> {
+        let (width, height) = self.pixels.dimensions();
+        let level = |c: u8| (c as u32 * 5 + 127) / 255;
+        let index: Vec<u32> = self
+            .pixels
+            .pixels()
+            .map(|p| level(p[0]) * 36 + level(p[1]) * 6 + level(p[2]))
+            .collect();
+        let mut out = Vec::new();
+        write!(out, "\x1bP0;1;0q\"1;1;{};{}", width, height).unwrap();
+        for color in 0..216 {
+            let percent = |l: u32| l * 100 / 5;
+            write!(out, "#{};2;{};{};{}", color, percent(color / 36), percent(color / 6 % 6), percent(color % 6))
+                .unwrap();
+        }
+        for band in (0..height).step_by(6) {
+            let rows = (height - band).min(6);
+            let mut colors: Vec<u32> = (band..band + rows)
+                .flat_map(|y| index[(y * width) as usize..((y + 1) * width) as usize].iter().copied())
+                .collect();
+            colors.sort_unstable();
+            colors.dedup();
+            for color in colors {
+                write!(out, "#{}", color).unwrap();
+                let sixels: Vec<u8> = (0..width)
+                    .map(|x| {
+                        let bits = (0..rows)
+                            .filter(|r| index[((band + r) * width + x) as usize] == color)
+                            .fold(0u8, |bits, r| bits | 1 << r);
+                        63 + bits
+                    })
+                    .collect();
+                let mut x = 0;
+                while x < sixels.len() {
+                    let run = sixels[x..].iter().take_while(|s| **s == sixels[x]).count();
+                    if run > 3 {
+                        write!(out, "!{}{}", run, sixels[x] as char).unwrap();
+                    } else {
+                        out.extend(std::iter::repeat_n(sixels[x], run));
+                    }
+                    x += run;
+                }
+                out.push(b'$');
+            }
+            out.push(b'-');
+        }
+        out.extend_from_slice(b"\x1b\\");
+        out
+    }
+}
+
+/// Where the last frame left room for a preview, in screen cells.
+#[derive(Clone)]
+pub struct Placement {
+    pub x: u16,
+    pub y: u16,
+    pub preview: Arc<Preview>,
+}
+
+/// Draws previews with the terminal's graphics protocol after a frame.
+pub struct Overlay {
+    pub graphics: Graphics,
+    shown: Vec<(u16, u16, u64)>,
+}
+
+impl Overlay {
+    pub fn new(graphics: Graphics) -> Self {
+        Overlay {
+            graphics,
+            shown: Vec::new(),
+        }
+    }
+
+    /// Redraws the previews if any of them moved since the last call. Sixel
+    /// pixels stay until their cells are written again, so the screen is
+    /// cleared and `redraw` is called first to get rid of old ones.
+    pub fn update(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        placements: &[Placement],
+        redraw: impl FnOnce(&mut DefaultTerminal) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        if !matches!(self.graphics, Graphics::Kitty | Graphics::Sixel) {
+            return Ok(());
+        }
+        let now: Vec<(u16, u16, u64)> = placements.iter().map(|p| (p.x, p.y, p.preview.id)).collect();
+        if now == self.shown {
+            return Ok(());
+        }
+        let mut out = Vec::new();
+        match self.graphics {
+            Graphics::Kitty => out.extend_from_slice(b"\x1b_Ga=d,d=A,q=2\x1b\\"),
+            _ if !self.shown.is_empty() => {
+                terminal.clear()?;
+                redraw(terminal)?;
+            }
+            _ => {}
+        }
+        for p in placements {
+            // save the cursor, move to the cell and put it back afterwards
+            write!(out, "\x1b7\x1b[{};{}H", p.y + 1, p.x + 1)?;
+            out.extend(match self.graphics {
+                Graphics::Kitty => p.preview.kitty(),
+                _ => p.preview.sixel(),
+            });
+            out.extend_from_slice(b"\x1b8");
+        }
+        let mut stdout = std::io::stdout();
+        stdout.write_all(&out)?;
+        stdout.flush()?;
+        self.shown = now;
+        Ok(())
+    }
+}
+
+/// What `/paste` sends: short text as a message, anything else as a file,
+/// with a preview if it is an image.
+pub enum Pasted {
+    Text(String),
+    File(QueuedFile, Option<Preview>),
+}
+
+/// Reads an image or text from the system clipboard. Data sent as a file is
+/// written to an unlinked temporary file, so it is gone once it has been sent.
+pub fn paste_clipboard() -> Result<Pasted, Box<dyn Error>> {
+    let mut clipboard = arboard::Clipboard::new()?;
+    let stamp = chrono::Local::now().format("%Y%m%d-%H%M%S");
+    if let Ok(data) = clipboard.get_image() {
+        let rgba = image::RgbaImage::from_raw(data.width as u32, data.height as u32, data.bytes.into_owned())
+            .ok_or("malformed clipboard image")?;
+        let image = DynamicImage::ImageRgba8(rgba);
+        let mut png = Vec::new();
+        image.write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)?;
+        let queued = QueuedFile::temporary(format!("clipboard-{}.png", stamp), &png)?;
+        return Ok(Pasted::File(queued, Some(Preview::new(&image))));
+    }
+    let text = clipboard.get_text()?;
+    if text.trim().is_empty() {
+        return Err("the clipboard is empty".into());
+    }
+    // longer text would not fit in one frame
+    if text.len() > MAX_TEXT_LEN {
+        return Ok(Pasted::File(QueuedFile::temporary(format!("clipboard-{}.txt", stamp), text.as_bytes())?, None));
+    }
+    Ok(Pasted::Text(text))
+}
+
+/// `/paste <path>`, for when there is no clipboard: an image or a text file.
+pub fn paste_file(path: &str) -> Result<Pasted, Box<dyn Error>> {
+    let path = Path::new(path);
+    let size = fs::metadata(path)?.len();
+    let name = path.file_name().ok_or("invalid file path")?.to_string_lossy().to_string();
+    let queued = QueuedFile {
+        name: name.clone(),
+        size,
+        path: path.to_path_buf(),
+        file: None,
+        in_folder: false,
+        voice_ms: None,
+    };
+    if is_image(&name) {
+        return Ok(Pasted::File(queued, Some(Preview::load(path)?)));
+    }
+    // too long for one message anyway, so sent as a file without reading it
+    if size > MAX_TEXT_LEN as u64 {
+        return Ok(Pasted::File(queued, None));
+    }
+    match fs::read_to_string(path) {
+        Ok(text) if !text.trim().is_empty() => Ok(Pasted::Text(text)),
+        Ok(_) => Ok(Pasted::File(queued, None)),
+        Err(_) => Err("not an image or text, use /send for other files".into()),
+    }
+}
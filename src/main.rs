@@ -1,42 +1,58 @@
 use std::env;
 use std::error::Error;
-use std::io::Write;
 
 use arti_client::config::CfgPath;
 use arti_client::{StreamPrefs, TorClient, TorClientConfig};
-use crossterm::event::{Event, EventStream, KeyCode, KeyEventKind};
+use crossterm::event::{Event, EventStream};
 use futures::StreamExt;
 use safelog::DisplayRedacted;
 use tor_cell::relaycell::msg::Connected;
 use tor_hsservice::config::OnionServiceConfigBuilder;
-use tor_hsservice::handle_rend_requests;
+use tor_hsservice::{RunningOnionService, StreamRequest, handle_rend_requests};
+use tokio::sync::mpsc;
 use tor_rtcompat::PreferredRuntime;
 
 use tor_hsservice::status::State;
 
+mod backup;
 mod bot;
 mod ccscript;
 mod config;
+mod export;
 mod files;
-mod fingerprint;
-mod noise_peer;
+mod group;
+mod images;
+mod session;
 mod storage;
 mod tui;
+mod voice;
 
 use crossterm::{
     cursor::MoveTo,
     execute,
     terminal::{Clear, ClearType},
 };
-use noise_peer::NoisePeer;
+use circuitchat_proto::{
+    Features, Framing, Hello, MIN_PROTOCOL_VERSION, Message, NoisePeer, PATTERN_NN,
+    PROTOCOL_VERSION, StaticKeypair,
+};
+use session::{SessionEvent, SessionHandle};
+use std::collections::{HashMap, VecDeque};
 use std::process;
-use storage::{MessageDirection, Storage, clear_history};
+use std::sync::Arc;
+use storage::{Contact, FileEvent, FileRecord, MessageDirection, PinCheck, SavedFile, Storage};
 use zeroize::Zeroize;
 use chrono::Local;
+use ratatui::style::{Color, Modifier, Style};
 
-const PATTERN: &str = "Noise_NN_25519_ChaChaPoly_BLAKE2s";
+const PATTERN: &str = PATTERN_NN;
+// reactions are meant to be a single emoji, which may be a few code points
+const MAX_REACTION_CHARS: usize = 8;
 const CONNECT_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(10);
+// files offered or being sent at once per session, the rest wait in a queue
+const MAX_OUTGOING_TRANSFERS: usize = 3;
 
+#[derive(Clone)]
 struct StatusContext {
     bootstrap_secs: Option<f64>,
     bridges_configured: usize,
@@ -44,7 +60,17 @@ struct StatusContext {
     identity_persist: bool,
     onion_addr: Option<String>,
     history_saving: bool,
-    peer_version: Option<(u8, u8, u8)>,
+    peer_hello: Option<Hello>,
+    features: Features,
+    ignored_messages: u64,
+    // type and size of the last one, for /status
+    last_ignored: Option<(u8, usize)>,
+    local_identity: Option<Vec<u8>>,
+    peer_identity: Option<Vec<u8>>,
+    peer_onion: Option<String>,
+    peer_auth_pin: Option<Vec<u8>>,
+    contact: Option<String>,
+    identity_warning: Option<String>,
 }
 fn build_tor_config(
     persist: bool,
@@ -88,6 +114,153 @@ fn build_tor_config(
     Ok((builder.build()?, _tmp_dir))
 }
 
+fn load_identity(
+    cfg: &config::Config,
+    storage: Option<&Storage>,
+) -> Result<Option<StaticKeypair>, Box<dyn Error>> {
+    if !cfg.identity.static_key {
+        return Ok(None);
+    }
+    let storage = storage.ok_or("identity.static_key requires identity.persist = true")?;
+    let identity = storage.load_or_create_identity()?;
+    println!("identity key: {}", hex::encode(&identity.public));
+    Ok(Some(identity))
+}
+
+fn check_contact(
+    storage: Option<&Storage>,
+    contact: &mut Contact,
+    handle: &SessionHandle,
+) -> Option<String> {
+    match contact.check_pins(handle.remote_static.as_deref(), handle.auth_pin.as_deref()) {
+        PinCheck::Changed(what) => Some(format!(
+            "the {} of contact '{}' has CHANGED since first contact",
+            what, contact.nickname
+        )),
+        PinCheck::FirstUse => {
+            if let Some(Err(e)) = storage.map(|s| s.save_contact(contact)) {
+                eprintln!("failed to pin contact: {}", e);
+            }
+            None
+        }
+        PinCheck::Match => None,
+    }
+}
+
+fn run_contacts_command(args: &[String], storage: Option<&Storage>) -> Result<(), Box<dyn Error>> {
+    let storage = storage.ok_or("contacts require identity.persist = true")?;
+    match args.get(2).map(|a| a.as_str()) {
+        None | Some("list") => {
+            let contacts = storage.list_contacts()?;
+            if contacts.is_empty() {
+                println!("no contacts");
+            }
+            for c in contacts {
+                println!(
+                    "{}  {}  key: {}  password: {}",
+                    c.nickname,
+                    c.onion.as_deref().unwrap_or("(no address)"),
+                    c.pinned_key.as_deref().unwrap_or("not pinned"),
+                    if c.pinned_auth.is_some() { "pinned (outgoing sessions only)" } else { "not pinned" }
+                );
+            }
+        }
+        Some("add") if args.len() >= 5 => {
+            if storage.find_contact(&args[3])?.is_some() {
+                return Err(format!("contact '{}' already exists", args[3]).into());
+            }
+            let mut contact = Contact::new(&args[3], Some(args[4].clone()));
+            storage.save_contact(&mut contact)?;
+            println!("added contact {}", args[3]);
+        }
+        Some("remove") if args.len() >= 4 => {
+            if storage.remove_contact(&args[3])? {
+                println!("removed contact {}", args[3]);
+            } else {
+                return Err(format!("unknown contact '{}'", args[3]).into());
+            }
+        }
+        Some("forget") if args.len() >= 4 => {
+            let mut contact = storage
+                .find_contact(&args[3])?
+                .ok_or_else(|| format!("unknown contact '{}'", args[3]))?;
+            contact.pinned_key = None;
+            contact.pinned_auth = None;
+            storage.save_contact(&mut contact)?;
+            println!("cleared pins for {}, they will be re-pinned on next contact", args[3]);
+        }
+        _ => {
+            eprintln!(
+                "usage: {} contacts [list | add <nickname> <onion_addr> | remove <nickname> | forget <nickname>]",
+                args[0]
+            );
+            std::process::exit(2);
+        }
+    }
+    Ok(())
+}
+
+/// Finds the saved conversation a name given on the command line refers to:
+/// its key as printed by `conversations list`, a contact's nickname or a label.
+fn resolve_conversation(storage: &Storage, name: &str) -> Result<String, Box<dyn Error>> {
+    let keys: Vec<String> = storage.list_conversations()?.into_iter().map(|c| c.key).collect();
+    let mut candidates = vec![name.to_string(), format!("label:{}", name)];
+    if name == "unsorted" {
+        candidates.push(String::new());
+    }
+    if let Some(contact) = storage.find_contact(name)? {
+        candidates.extend(contact.pinned_key.map(|k| format!("key:{}", k)));
+        candidates.extend(contact.onion.map(|o| format!("onion:{}", o)));
+    }
+    candidates
+        .into_iter()
+        .find(|c| keys.contains(c))
+        .ok_or_else(|| format!("no saved conversation '{}'", name).into())
+}
+
+fn run_conversations_command(args: &[String], storage: Option<Storage>) -> Result<(), Box<dyn Error>> {
+    let mut storage = storage.ok_or("history requires identity.persist = true")?;
+    match args.get(2).map(|a| a.as_str()) {
+        None | Some("list") => {
+            let conversations = storage.list_conversations()?;
+            if conversations.is_empty() {
+                println!("no saved conversations");
+            }
+            let contacts = storage.list_contacts()?;
+            for c in conversations {
+                // name the contact a key or address belongs to
+                let contact = contacts.iter().find(|contact| {
+                    contact.pinned_key.as_ref().is_some_and(|k| c.key == format!("key:{}", k))
+                        || contact.onion.as_ref().is_some_and(|o| c.key == format!("onion:{}", o))
+                });
+                let last = chrono::DateTime::from_timestamp(c.last_timestamp, 0)
+                    .map(|t| t.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string())
+                    .unwrap_or_default();
+                println!(
+                    "{}{}  {} message(s)  last: {}",
+                    if c.key.is_empty() { "unsorted" } else { &c.key },
+                    contact.map(|contact| format!(" ({})", contact.nickname)).unwrap_or_default(),
+                    c.messages,
+                    last
+                );
+            }
+        }
+        Some("delete") if args.len() >= 4 => {
+            let key = resolve_conversation(&storage, &args[3])?;
+            let deleted = storage.delete_conversation(&key)?;
+            println!("deleted {} message(s) of {}", deleted, args[3]);
+        }
+        _ => {
+            eprintln!(
+                "usage: {} conversations [list | delete <conversation | nickname | label>]",
+                args[0]
+            );
+            std::process::exit(2);
+        }
+    }
+    Ok(())
+}
+
 fn perform_panic_and_exit(storage: Option<Storage>) -> Result<(), Box<dyn Error>> {
     use std::io::Write;
 
@@ -147,555 +320,2161 @@ fn zero_directory_contents(dir: &std::path::Path) {
     }
 }
 
-async fn chat_loop<T>(
-    mut np: NoisePeer<T>,
-    storage: &mut Option<Storage>,
-    initial_status: &str,
-    status_ctx: &mut StatusContext,
+struct ChatSettings {
     time_local: bool,
     hour24: bool,
     show_seconds: bool,
     show_tz: bool,
-    typing_indicators: bool,
-    delivery_receipts: bool,
+    features: Features,
     randomize_filenames: bool,
+    attachment_vault: bool,
+    compression_level: i32,
     message_notification_sound: bool,
     mention_notification_sound: bool,
+    graphics: images::Graphics,
     session_timeout_mins: u64,
     idle_away_mins: u64,
-) -> Result<(), Box<dyn Error>>
-where
-    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sized + 'static,
-{
-    let mut terminal = ratatui::init();
-    let mut app = tui::App::new(
-        initial_status,
-        message_notification_sound,
-        mention_notification_sound,
-    );
-    app.session_fingerprint = Some(np.session_fingerprint.clone());
+}
 
-    let session_deadline_tokio = if session_timeout_mins > 0 {
-        Some(
-            tokio::time::Instant::now() + std::time::Duration::from_secs(session_timeout_mins * 60),
-        )
+// receipts and typing are privacy settings and compression costs time, so they
+// are only advertised when enabled, and voice clips only by builds that can
+// play them; everything else is always on. read receipts imply delivery
+// receipts
+fn local_features(cfg: &config::Config) -> Features {
+    let mut features = Features::AWAY
+        | Features::RESUME
+        | Features::REACTIONS
+        | Features::EDITS
+        | Features::EXPIRY
+        | Features::FLOW
+        | Features::TRANSFERS
+        | Features::HASHES
+        | Features::FOLDERS;
+    if cfg!(feature = "voice") {
+        features |= Features::VOICE;
+    }
+    if cfg.privacy.delivery_receipts || cfg.privacy.read_receipts {
+        features |= Features::RECEIPTS;
+    }
+    if cfg.privacy.read_receipts {
+        features |= Features::READ;
+    }
+    if cfg.privacy.typing_status {
+        features |= Features::TYPING;
+    }
+    if cfg.transfer.compression {
+        features |= Features::COMPRESSION;
+    }
+    features
+}
+
+impl ChatSettings {
+    fn from_config(cfg: &config::Config) -> Self {
+        Self {
+            time_local: cfg.time.local,
+            hour24: cfg.time.hour24,
+            show_seconds: cfg.time.show_seconds,
+            show_tz: cfg.time.show_tz,
+            features: local_features(cfg),
+            randomize_filenames: cfg.privacy.randomize_filenames,
+            attachment_vault: cfg.history.vault,
+            compression_level: cfg.transfer.compression_level.clamp(1, 19),
+            message_notification_sound: cfg.ui.message_notification_sound,
+            mention_notification_sound: cfg.ui.mention_notification_sound,
+            graphics: images::Graphics::from_config(&cfg.ui.image_previews),
+            session_timeout_mins: cfg.privacy.session_timeout_mins,
+            idle_away_mins: cfg.privacy.idle_away_mins,
+        }
+    }
+
+    fn hello(&self) -> Message {
+        Message::Hello(Hello::new(env!("CARGO_PKG_VERSION"), self.features))
+    }
+
+    fn now(&self) -> String {
+        tui::now_timestamp(self.time_local, self.hour24, self.show_tz, self.show_seconds)
+    }
+
+    fn format(&self, unix_secs: i64) -> String {
+        tui::format_timestamp(unix_secs, self.time_local, self.hour24, self.show_tz, self.show_seconds)
+    }
+}
+
+/// Incoming connections for `listen`, accepted while the chat is running.
+struct Listener {
+    requests: std::pin::Pin<Box<dyn futures::Stream<Item = StreamRequest>>>,
+    identity: Option<StaticKeypair>,
+    password: Option<String>,
+}
+
+/// Per-conversation state owned by the chat loop; the matching
+/// `tui::SessionView` shares the session id.
+struct PeerSession {
+    handle: SessionHandle,
+    ctx: StatusContext,
+    /// Transfers are keyed by the id of the side that offered the file.
+    incoming_offers: HashMap<u64, (String, u64, Option<Vec<u8>>)>,
+    incoming_files: HashMap<u64, files::IncomingFile>,
+    /// Folders offered by the peer, with their number of files and size.
+    incoming_folders: HashMap<u64, (String, u32, u64)>,
+    /// Accepted folders and how many files and bytes of them are still to
+    /// be offered. Offers within them are accepted without asking.
+    accepted_folders: HashMap<u64, (String, u32, u64)>,
+    /// Incoming voice clips being received, with their length in ms.
+    voice_transfers: HashMap<u64, u32>,
+    /// Voice clips offered past `voice::MAX_AUTO_CLIPS`, waiting in
+    /// `incoming_offers` for `/accept`, with their length in ms.
+    voice_offers: HashMap<u64, u32>,
+    /// Voice clips accepted without asking in this session.
+    voice_auto_accepted: u32,
+    /// Our offers waiting for the peer to accept or reject them.
+    pending_offers: HashMap<u64, files::OutgoingFile>,
+    pending_folders: HashMap<u64, (String, Vec<files::QueuedFile>)>,
+    outgoing_files: HashMap<u64, session::FileSender>,
+    /// Files being opened and hashed, to be offered once `FileHashed` comes.
+    hashing: HashMap<u64, files::QueuedFile>,
+    /// Files waiting for a free slot before they are offered.
+    send_queue: VecDeque<(u64, files::QueuedFile)>,
+    last_pong: tokio::time::Instant,
+    peer_responding: bool,
+    awaiting_ping_response: bool,
+    /// Expiry we proposed and the peer has not accepted yet.
+    expire_proposed: Option<u64>,
+    /// Expiry the peer proposed, waiting for `/expire accept`.
+    expire_offered: Option<u64>,
+}
+
+/// The conversation a session's history is saved under: the peer's identity
+/// key if it has one, otherwise the onion address we dialled. Anonymous peers
+/// get a conversation of their own until they are given a `/label`.
+fn conversation_key(ctx: &StatusContext, id: u64) -> String {
+    if let Some(ref key) = ctx.peer_identity {
+        format!("key:{}", hex::encode(key))
+    } else if let Some(ref onion) = ctx.peer_onion {
+        format!("onion:{}", onion)
     } else {
-        None
-    };
-    let session_deadline_std = if session_timeout_mins > 0 {
-        Some(std::time::Instant::now() + std::time::Duration::from_secs(session_timeout_mins * 60))
+        format!("session:{}-{}", chrono::Utc::now().timestamp(), id)
+    }
+}
+
+fn valid_conversation_label(label: &str) -> bool {
+    !label.is_empty()
+        && label.len() <= 64
+        && label
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Shows saved history above everything already in `view`.
+fn show_history(view: &mut tui::SessionView, messages: Vec<storage::Message>, settings: &ChatSettings) {
+    for (i, msg) in messages.into_iter().enumerate() {
+        let shown = view.insert_history(
+            i,
+            msg.direction,
+            msg.msg_id,
+            tui::plain(&String::from_utf8_lossy(&msg.content)),
+            settings.format(msg.timestamp),
+        );
+        shown.time = msg.timestamp;
+        shown.reply_to = msg.reply_to;
+        shown.edited = msg.edited;
+        shown.reactions = msg.reactions;
+    }
+}
+
+/// Shows a file transfer event and saves it with the tab's conversation, so
+/// history keeps the file's name, size, checksum and location.
+fn file_event(
+    view: &mut tui::SessionView,
+    storage: Option<&Storage>,
+    direction: MessageDirection,
+    record: FileRecord,
+    text: String,
+    settings: &ChatSettings,
+) {
+    if let Some(Err(e)) = storage
+        .zip(view.conversation.as_deref())
+        .map(|(s, c)| s.save_file_event(c, direction, record, text.as_bytes()))
+    {
+        view.status = format!("save error: {}", e);
+    }
+    view.add_plain_message(direction, text, settings.now());
+}
+
+/// Decodes a received image for its preview, `None` if it cannot be read.
+fn saved_preview(saved: &SavedFile, storage: Option<&Storage>, size: u64) -> Option<images::Preview> {
+    match saved {
+        SavedFile::Downloads(path) => images::Preview::load(path).ok(),
+        SavedFile::Vault(attachment) => {
+            if size > images::MAX_PREVIEW_FILE {
+                return None;
+            }
+            let mut data = Vec::new();
+            storage?.read_attachment(*attachment, &mut data).ok()?;
+            images::Preview::decode(&data).ok()
+        }
+    }
+}
+
+/// Maps a transfer id from the peer to one of our outgoing transfers. Peers
+/// without the `transfers` feature send 0 and have only one of ours open.
+fn outgoing_transfer(peer: &PeerSession, transfer: u64) -> u64 {
+    if transfer != 0 {
+        return transfer;
+    }
+    peer.pending_offers
+        .keys()
+        .chain(peer.outgoing_files.keys())
+        .next()
+        .copied()
+        .unwrap_or(0)
+}
+
+/// Starts hashing queued files while the peer has room for more; they are
+/// offered by `offer_hashed`. Peers without the `transfers` feature take one
+/// file at a time.
+fn offer_queued(view: &mut tui::SessionView, peer: &mut PeerSession) {
+    let limit = if peer.ctx.features.contains(Features::TRANSFERS) {
+        MAX_OUTGOING_TRANSFERS
     } else {
-        None
+        1
     };
-    app.session_deadline = session_deadline_std;
+    while peer.pending_offers.len() + peer.outgoing_files.len() + peer.hashing.len() < limit {
+        let Some((transfer, mut queued)) = peer.send_queue.pop_front() else {
+            break;
+        };
+        // opened only now, so a file changed while queued is hashed as it is sent
+        peer.handle
+            .hash_file(transfer, queued.name.clone(), queued.path.clone(), queued.file.take());
+        view.start_transfer(transfer, false, "hashing", 0);
+        peer.hashing.insert(transfer, queued);
+    }
+}
 
-    if let Some(ref s) = *storage {
-        if let Ok(messages) = s.load_history() {
-            for msg in messages {
-                app.add_plain_message(
-                    msg.direction,
-                    String::from_utf8_lossy(&msg.content).to_string(),
-                    tui::format_timestamp(msg.timestamp, time_local, hour24, show_tz, show_seconds),
-                );
-            }
+/// Offers a file once `hash_file` is done with it, unless it was cancelled
+/// meanwhile, and fills its slot with the next one if it cannot be offered.
+async fn offer_hashed(
+    view: &mut tui::SessionView,
+    peer: &mut PeerSession,
+    storage: Option<&Storage>,
+    settings: &ChatSettings,
+    transfer: u64,
+    result: Result<files::OutgoingFile, String>,
+) {
+    let Some(queued) = peer.hashing.remove(&transfer) else {
+        return;
+    };
+    let mut out = match result {
+        Ok(out) => out,
+        Err(e) => {
+            view.remove_transfer(transfer, false);
+            let record = FileRecord::new(FileEvent::Failed, false, &queued.name, queued.size);
+            let line = format!("[file] cannot open {}: {}", queued.name, e);
+            file_event(view, storage, MessageDirection::Sent, record, line, settings);
+            offer_queued(view, peer);
+            return;
         }
+    };
+    out.name = queued.name;
+    out.hashed = peer.ctx.features.contains(Features::HASHES);
+    let offer = match queued.voice_ms {
+        Some(duration_ms) => Message::VoiceOffer {
+            transfer,
+            name: out.name.clone(),
+            size: out.size,
+            checksum: Some(out.checksum()),
+            duration_ms,
+        },
+        None => out.offer(transfer),
+    };
+    if !peer.handle.send(&offer).await {
+        view.status = "send failed: peer disconnected".to_string();
+        view.remove_transfer(transfer, false);
+        return;
     }
-    terminal.draw(|f| app.draw(f))?;
-    app.scroll_to_bottom();
-    let _ = np.send(&files::encode_version_negotiate()).await;
-    if let Ok(Ok(msg)) =
-        tokio::time::timeout(std::time::Duration::from_millis(250), np.recv()).await
-    {
-        if let files::ParsedMessage::VersionNegotiate {
-            major,
-            minor,
-            patch,
-        } = files::parse_message(&msg)
-        {
-            status_ctx.peer_version = Some((major, minor, patch));
-            let (our_major, our_minor, our_patch) = files::protocol_version();
-            if major != our_major || minor != our_minor || patch != our_patch {
-                let mut warn = format!(
-                    "warning: peer protocol {}.{}.{} differs from local {}.{}.{}",
-                    major, minor, patch, our_major, our_minor, our_patch
-                );
-                if major != our_major {
-                    warn = format!("INCOMPATIBLE MAJOR VERSION - {}", warn);
-                }
-                app.add_plain_message(
-                    MessageDirection::System,
-                    warn,
-                    tui::now_timestamp(time_local, hour24, show_tz, show_seconds),
-                );
-            }
+    // the folder's own offer was recorded, its files are accepted without asking
+    if !queued.in_folder {
+        let mut record = FileRecord::new(FileEvent::Offered, false, &out.name, out.size);
+        record.checksum = Some(out.checksum());
+        let line = match queued.voice_ms {
+            Some(ms) => format!("[voice] sending a voice message ({})", voice::format_duration(ms)),
+            None => format!(
+                "[file] offered {} ({}) - waiting for peer to accept",
+                out.name,
+                files::format_size(out.size)
+            ),
+        };
+        file_event(view, storage, MessageDirection::Sent, record, line, settings);
+    }
+    view.start_transfer(transfer, false, "offered", 0);
+    peer.pending_offers.insert(transfer, out);
+}
+
+/// Queues a single file and starts offering it right away if there is
+/// room. Returns the transfer's number.
+fn queue_file(view: &mut tui::SessionView, peer: &mut PeerSession, queued: files::QueuedFile) -> u64 {
+    let transfer = rand::random::<u64>().max(1);
+    view.add_transfer(transfer, false, &queued.name, queued.size, "queued");
+    peer.send_queue.push_back((transfer, queued));
+    offer_queued(view, peer);
+    if let Some(t) = view.transfer(transfer, false).filter(|t| t.state == "queued") {
+        view.status = format!("queued {} until a transfer finishes", t.name);
+    }
+    transfer
+}
+
+/// Stops a `/voice` recording and offers it in the session it was started in.
+fn finish_recording(app: &mut tui::App, sessions: &mut HashMap<u64, PeerSession>, (recorder, id): (voice::Recorder, u64)) {
+    app.recording = None;
+    let clip = recorder.finish();
+    let (Some(peer), Some(view)) = (sessions.get_mut(&id), app.view_by_id(id)) else {
+        return;
+    };
+    match clip {
+        Ok(queued) => {
+            queue_file(view, peer, queued);
         }
+        Err(e) => view.status = format!("cannot send voice message: {}", e),
     }
-    let mut events = EventStream::new();
-    let mut incoming_file: Option<files::IncomingFile> = None;
-    let mut outgoing_file: Option<files::OutgoingFile> = None;
-    let mut pending_offer: Option<files::OutgoingFile> = None;
-    let mut last_input_empty = true;
+}
 
-    let mut last_activity = tokio::time::Instant::now();
-    let mut is_away = false;
-    let idle_away_duration = if idle_away_mins > 0 {
-        Some(std::time::Duration::from_secs(idle_away_mins * 60))
+/// Opens an accepted file and tells the peer to start sending, from what is
+/// already on disk when resuming. Returns false if that failed.
+async fn accept_file(
+    view: &mut tui::SessionView,
+    peer: &mut PeerSession,
+    storage: Option<&Storage>,
+    transfer: u64,
+    (name, size, checksum): (String, u64, Option<Vec<u8>>),
+    settings: &ChatSettings,
+) -> bool {
+    // vault files are written as they arrive, so there is nothing to resume
+    let vault = settings.attachment_vault && storage.is_some() && view.conversation.is_some();
+    let resume = !vault && peer.ctx.features.contains(Features::RESUME);
+    // opening checks what is already on disk, so accept from what survived;
+    // a complete file is accepted at its end and the sender only sends FileDone
+    let begun = if vault {
+        Ok(files::IncomingFile::begin_vault(&name, size, checksum.as_deref()))
     } else {
-        None
+        files::IncomingFile::begin(&name, size, checksum.as_deref(), resume)
     };
+    match begun {
+        Ok(inc) => {
+            let accept = Message::FileAccept { transfer, offset: inc.received };
+            if !peer.handle.send(&accept).await {
+                view.remove_transfer(transfer, true);
+                view.status = "send failed: peer disconnected".to_string();
+                return false;
+            }
+            view.start_transfer(transfer, true, "receiving", inc.received);
+            peer.incoming_files.insert(transfer, inc);
+            true
+        }
+        Err(e) => {
+            view.remove_transfer(transfer, true);
+            view.status = format!("file receive error: {}", e);
+            false
+        }
+    }
+}
 
-    let mut ping_interval = tokio::time::interval(std::time::Duration::from_secs(15));
-    ping_interval.reset();
-    let mut session_tick = tokio::time::interval(std::time::Duration::from_secs(1));
-    session_tick.reset();
-    let mut last_pong = tokio::time::Instant::now();
-    let ping_timeout = std::time::Duration::from_secs(45);
-    let mut peer_responding = true;
-    let mut awaiting_ping_response = false;
-    app.add_plain_message(
+/// The transfer `/accept`, `/reject` or `/cancel` means: the one numbered
+/// `args` in the panel, or the only one `matches` allows.
+fn pick_transfer(
+    view: &tui::SessionView,
+    args: &str,
+    matches: impl Fn(&tui::TransferProgress) -> bool,
+    none: &str,
+) -> Result<(u64, bool), String> {
+    if !args.is_empty() {
+        return match args.parse::<usize>().ok().and_then(|n| view.transfers.get(n.wrapping_sub(1))) {
+            Some(t) if matches(t) => Ok((t.id, t.incoming)),
+            _ => Err(format!("no such transfer: {}", args)),
+        };
+    }
+    let mut candidates = view.transfers.iter().filter(|t| matches(t));
+    match (candidates.next(), candidates.next()) {
+        (Some(t), None) => Ok((t.id, t.incoming)),
+        (Some(_), Some(_)) => Err("several transfers, give the number shown in the panel".to_string()),
+        (None, _) => Err(none.to_string()),
+    }
+}
+
+/// Completed transfers of a conversation, oldest first, as numbered by
+/// `/files` and `/extract`.
+fn completed_files(storage: &Storage, conversation: &str) -> Result<Vec<(i64, FileRecord)>, Box<dyn Error>> {
+    Ok(storage
+        .load_history(conversation)?
+        .into_iter()
+        .filter_map(|m| m.file.filter(|f| f.event == FileEvent::Completed).map(|f| (m.timestamp, f)))
+        .collect())
+}
+
+fn open_session(
+    app: &mut tui::App,
+    handle: SessionHandle,
+    ctx: StatusContext,
+    storage: Option<&Storage>,
+    settings: &ChatSettings,
+) -> PeerSession {
+    let id = handle.id;
+    let first = app.views.len() == 1 && app.views[0].id == 0;
+    if first {
+        app.views[0].id = id;
+        app.views[0].status = "connected".to_string();
+    } else {
+        let label = format!("peer {}", id);
+        let index = app.views.len() + 1;
+        app.add_plain_message(
             MessageDirection::System,
-            "compare the fingerprint at the bottom with your peer's. if it is the same, the connection is secure.".to_string(),
-            tui::now_timestamp(time_local, hour24, show_tz, show_seconds),
+            format!(
+                "[session] {} connected, switch with alt+{}",
+                ctx.contact.as_deref().unwrap_or(&label),
+                index
+            ),
+            settings.now(),
         );
-    loop {
-        terminal.draw(|f| app.draw(f))?;
+        // keep tabs apart while several anonymous peers are connected
+        app.add_view(id, "connected").peer_label = label;
+    }
 
-        // file mode
-        if outgoing_file.is_some() {
-            let cancelled = tokio::select! {
-                biased;
-                event = events.next() => {
-                    matches!(
-                        event,
-                        Some(Ok(Event::Key(crossterm::event::KeyEvent {
-                            code: KeyCode::Esc,
-                            kind: KeyEventKind::Press,
-                            ..
-                        })))
-                    )
-                }
-                _ = tokio::time::sleep(std::time::Duration::from_millis(1)) => false,
-            };
+    let view = app.view_by_id(id).unwrap();
+    view.session_fingerprint = Some(handle.fingerprint.clone());
+    if let Some(s) = storage {
+        let conversation = conversation_key(&ctx, id);
+        match s.load_history(&conversation) {
+            Ok(messages) => show_history(view, messages, settings),
+            Err(e) => view.status = format!("history error: {}", e),
+        }
+        view.expire_after = s.conversation_expiry(&conversation).unwrap_or(0);
+        view.conversation = Some(conversation);
+    }
+    view.add_plain_message(
+        MessageDirection::System,
+        "compare the fingerprint at the bottom with your peer's. if it is the same, the connection is secure.".to_string(),
+        settings.now(),
+    );
+    if let Some(ref nickname) = ctx.contact {
+        view.peer_label = nickname.clone();
+        view.add_plain_message(
+            MessageDirection::System,
+            format!("[contact] connected to {}", nickname),
+            settings.now(),
+        );
+    }
+    if let Some(ref warning) = ctx.identity_warning {
+        view.identity_warning = Some(warning.clone());
+        view.add_message(
+            MessageDirection::System,
+            vec![(
+                format!("WARNING: {}. this may be an impersonation attempt, verify the fingerprint out of band before trusting this session", warning),
+                Some(Style::default().fg(Color::Red).add_modifier(Modifier::BOLD)),
+            )],
+            settings.now(),
+        );
+    }
 
-            if cancelled {
-                let _ = np.send(&files::encode_cancel()).await;
-                let out = outgoing_file.take().unwrap();
-                app.add_plain_message(
-                    MessageDirection::Sent,
-                    format!("[file] cancelled sending {}", out.name),
-                    tui::now_timestamp(time_local, hour24, show_tz, show_seconds),
+    PeerSession {
+        handle,
+        ctx,
+        incoming_offers: HashMap::new(),
+        incoming_files: HashMap::new(),
+        incoming_folders: HashMap::new(),
+        accepted_folders: HashMap::new(),
+        voice_transfers: HashMap::new(),
+        voice_offers: HashMap::new(),
+        voice_auto_accepted: 0,
+        pending_offers: HashMap::new(),
+        pending_folders: HashMap::new(),
+        outgoing_files: HashMap::new(),
+        hashing: HashMap::new(),
+        send_queue: VecDeque::new(),
+        last_pong: tokio::time::Instant::now(),
+        peer_responding: true,
+        awaiting_ping_response: false,
+        expire_proposed: None,
+        expire_offered: None,
+    }
+}
+
+async fn handle_peer_message(
+    view: &mut tui::SessionView,
+    peer: &mut PeerSession,
+    storage: &mut Option<Storage>,
+    settings: &ChatSettings,
+    msg: Vec<u8>,
+) {
+    peer.last_pong = tokio::time::Instant::now();
+    if !peer.peer_responding {
+        peer.peer_responding = true;
+        view.status = view.status.replace(" | peer not responding", "");
+    }
+    // what the message took on the wire, before it was decompressed
+    let wire = msg.len() as u64;
+    match Message::decode(&msg) {
+        Message::Hello(hello) => {
+            peer.ctx.peer_hello = Some(hello);
+            peer.handle.framing = Framing::for_peer(&hello);
+            peer.ctx.features = settings.features & hello.features;
+            peer.handle.compression = peer
+                .ctx
+                .features
+                .contains(Features::COMPRESSION)
+                .then_some(settings.compression_level);
+            if !hello.is_compatible() {
+                view.add_plain_message(
+                    MessageDirection::System,
+                    format!(
+                        "INCOMPATIBLE PROTOCOL - peer speaks protocol {}, we need at least {}",
+                        hello.protocol, MIN_PROTOCOL_VERSION
+                    ),
+                    settings.now(),
+                );
+            } else if hello.protocol != PROTOCOL_VERSION {
+                view.add_plain_message(
+                    MessageDirection::System,
+                    format!(
+                        "peer speaks protocol {} (we speak {}), only shared features are used",
+                        hello.protocol, PROTOCOL_VERSION
+                    ),
+                    settings.now(),
                 );
-                app.clear_send_progress();
-                continue;
             }
-
-            let result = outgoing_file.as_mut().unwrap().read_next_chunk();
-            match result {
-                Ok(Some(data)) => {
-                    if let Err(e) = np.send(&files::encode_chunk(&data)).await {
-                        app.add_plain_message(
-                            MessageDirection::Sent,
-                            format!("[file] send error: {}", e),
-                            tui::now_timestamp(time_local, hour24, show_tz, show_seconds),
-                        );
-                        app.clear_send_progress();
-                        outgoing_file = None;
-                    } else {
-                        let sent = outgoing_file.as_ref().unwrap().sent;
-                        app.update_send_progress(sent);
-                    }
-                }
-                Ok(None) => {
-                    let _ = np.send(&files::encode_done()).await;
-                    let out = outgoing_file.take().unwrap();
-                    app.add_plain_message(
-                        MessageDirection::Sent,
-                        format!(
-                            "[file] sent {} ({})",
-                            out.name,
-                            files::format_size(out.size)
-                        ),
-                        tui::now_timestamp(time_local, hour24, show_tz, show_seconds),
-                    );
-                    app.clear_send_progress();
-                }
-                Err(e) => {
-                    app.add_plain_message(
-                        MessageDirection::Sent,
-                        format!("[file] read error: {}", e),
-                        tui::now_timestamp(time_local, hour24, show_tz, show_seconds),
-                    );
-                    app.clear_send_progress();
-                    outgoing_file = None;
+        }
+        Message::Text { id, reply_to, text: content } => {
+            let spans = if content.contains("@peer") {
+                tui::highlighted(&content, "@peer")
+            } else {
+                tui::plain(&content)
+            };
+            let shown = view.add_text(MessageDirection::Received, id, spans, settings.now());
+            shown.reply_to = reply_to;
+            // without read receipts there is nothing to track once it arrived
+            if !peer.ctx.features.contains(Features::READ) {
+                shown.state = tui::MessageState::None;
+            }
+            if let Some(Err(e)) = storage
+                .as_ref()
+                .zip(view.conversation.as_deref())
+                .map(|(s, c)| s.save_message(c, MessageDirection::Received, id, reply_to, content.as_bytes()))
+            {
+                view.status = format!("save error: {}", e);
+            }
+            if peer.ctx.features.contains(Features::RECEIPTS) {
+                peer.handle.send(&Message::Delivered(id)).await;
+            }
+            if peer.ctx.features.contains(Features::TYPING) {
+                view.peer_typing = false;
+                view.status = view.status.replace(" | peer is typing...", "");
+            }
+            if view.peer_away {
+                view.peer_away = false;
+                view.status = view.status.replace(" | peer is away", "");
+            }
+        }
+        Message::FileOffer {
+            transfer,
+            name,
+            size,
+            checksum,
+        } => {
+            // a file of a folder we accepted, as long as it stays within what was offered
+            let saved = files::sanitize_path(&name);
+            let folder = peer.accepted_folders.iter_mut().find(|(_, (folder, files, bytes))| {
+                *files > 0 && size <= *bytes && saved.strip_prefix(folder.as_str()).is_some_and(|rest| rest.starts_with('/'))
+            });
+            if let Some((&id, entry)) = folder {
+                entry.1 -= 1;
+                entry.2 -= size;
+                if entry.1 == 0 {
+                    peer.accepted_folders.remove(&id);
                 }
+                view.add_transfer(transfer, true, &name, size, "offered");
+                accept_file(view, peer, storage.as_ref(), transfer, (name, size, checksum), settings).await;
+                return;
             }
-            continue;
+            let size_str = files::format_size(size);
+            let mut record = FileRecord::new(FileEvent::Offered, true, &name, size);
+            record.checksum = checksum.clone();
+            file_event(
+                view,
+                storage.as_ref(),
+                MessageDirection::Received,
+                record,
+                format!(
+                    "[file] peer wants to send {} ({}) - type /accept or /reject",
+                    name, size_str
+                ),
+                settings,
+            );
+            // peers without transfer ids replace their one offer
+            view.remove_transfer(transfer, true);
+            view.add_transfer(transfer, true, &name, size, "offered");
+            peer.incoming_offers.insert(transfer, (name, size, checksum));
         }
-        // normal mode
-        tokio::select! {
-            _ = session_tick.tick() => {
-                if let Some(idle_dur) = idle_away_duration {
-                    if !is_away && last_activity.elapsed() >= idle_dur {
-                        is_away = true;
-                        let _ = np.send(&files::encode_away()).await;
+        Message::VoiceOffer {
+            transfer,
+            name,
+            size,
+            checksum,
+            duration_ms,
+        } => {
+            if !peer.ctx.features.contains(Features::VOICE) {
+                return;
+            }
+            let duration = voice::format_duration(duration_ms);
+            // clips are accepted without asking, so only small ones
+            if size > voice::MAX_CLIP_SIZE {
+                peer.handle.send(&Message::FileReject(transfer)).await;
+                file_event(
+                    view,
+                    storage.as_ref(),
+                    MessageDirection::Received,
+                    FileRecord::new(FileEvent::Rejected, true, &name, size),
+                    format!("[voice] rejected a {} voice message of {}", duration, files::format_size(size)),
+                    settings,
+                );
+                return;
+            }
+            let mut record = FileRecord::new(FileEvent::Offered, true, &name, size);
+            record.checksum = checksum.clone();
+            // past the cap, or without a tree root to check each chunk against,
+            // they are offered like files, so a peer cannot fill the disk
+            if peer.voice_auto_accepted >= voice::MAX_AUTO_CLIPS || !files::is_tree_root(checksum.as_deref()) {
+                let line = format!(
+                    "[voice] peer wants to send a voice message ({}) - type /accept or /reject",
+                    duration
+                );
+                file_event(view, storage.as_ref(), MessageDirection::Received, record, line, settings);
+                view.remove_transfer(transfer, true);
+                view.add_transfer(transfer, true, &name, size, "offered");
+                peer.incoming_offers.insert(transfer, (name, size, checksum));
+                peer.voice_offers.insert(transfer, duration_ms);
+                return;
+            }
+            let line = format!("[voice] peer is sending a voice message ({})", duration);
+            file_event(view, storage.as_ref(), MessageDirection::Received, record, line, settings);
+            view.add_transfer(transfer, true, &name, size, "offered");
+            if accept_file(view, peer, storage.as_ref(), transfer, (name, size, checksum), settings).await {
+                peer.voice_auto_accepted += 1;
+                peer.voice_transfers.insert(transfer, duration_ms);
+            }
+        }
+        Message::FolderOffer {
+            transfer,
+            name,
+            files: count,
+            size,
+        } => {
+            if !peer.ctx.features.contains(Features::FOLDERS) {
+                return;
+            }
+            // one part, so that the files are offered under a single folder in downloads/
+            let name = files::sanitize_filename(&name);
+            file_event(
+                view,
+                storage.as_ref(),
+                MessageDirection::Received,
+                FileRecord::new(FileEvent::Offered, true, &format!("{}/", name), size),
+                format!(
+                    "[file] peer wants to send folder {} ({} files, {}) - type /accept or /reject",
+                    name,
+                    count,
+                    files::format_size(size)
+                ),
+                settings,
+            );
+            view.add_transfer(transfer, true, &format!("{}/ ({} files)", name, count), size, "offered");
+            peer.incoming_folders.insert(transfer, (name, count, size));
+        }
+        Message::FileChunk { transfer, proof, data } => {
+            if let Some(inc) = peer.incoming_files.get_mut(&transfer) {
+                match inc.write_chunk(&data, proof.as_deref(), storage.as_ref()) {
+                    Ok(()) => {
+                        view.update_transfer(transfer, true, inc.received, wire);
+                        if peer.ctx.features.contains(Features::FLOW) && inc.received - inc.acked >= files::ACK_EVERY {
+                            inc.acked = inc.received;
+                            peer.handle.send(&Message::FileAck { transfer, offset: inc.received }).await;
+                        }
+                    }
+                    Err(e) => {
+                        view.remove_transfer(transfer, true);
+                        let inc = peer.incoming_files.remove(&transfer).unwrap();
+                        let record = FileRecord::new(FileEvent::Failed, true, &inc.name, inc.size);
+                        let line = format!("[file] could not write {}: {}", inc.name, e);
+                        inc.cancel(storage.as_mut());
+                        file_event(view, storage.as_ref(), MessageDirection::Received, record, line, settings);
+                        view.status = format!("file write error: {}", e);
+                        if peer.ctx.features.contains(Features::TRANSFERS) {
+                            peer.handle.send(&Message::FileCancel(transfer)).await;
+                        }
                     }
                 }
-                terminal.draw(|f| app.draw(f))?;
             }
-            _ = ping_interval.tick() => {
-                if last_pong.elapsed() > ping_timeout {
-                    if peer_responding {
-                        peer_responding = false;
-                        let base = app.status.replace(" | peer not responding", "");
-                        app.status = format!("{} | peer not responding", base);
+        }
+        Message::FileDone(transfer) => {
+            if let Some(inc) = peer.incoming_files.remove(&transfer) {
+                view.remove_transfer(transfer, true);
+                let clip = peer.voice_transfers.remove(&transfer);
+                let name = inc.name.clone();
+                let size = inc.size;
+                let mut record = FileRecord::new(FileEvent::Completed, true, &name, size);
+                record.checksum = inc.checksum.clone();
+                match inc.finish(storage.as_mut()) {
+                    Ok(saved) => {
+                        let line = match clip {
+                            Some(ms) => {
+                                view.voice_clips.push(saved.clone());
+                                format!("[voice] voice message ({}) - type /play to listen", voice::format_duration(ms))
+                            }
+                            None => format!("[file] saved {} ({}) -> {}", name, files::format_size(size), saved),
+                        };
+                        let preview = (settings.graphics != images::Graphics::Off && images::is_image(&name))
+                            .then(|| saved_preview(&saved, storage.as_ref(), size))
+                            .flatten();
+                        record.saved = Some(saved);
+                        file_event(view, storage.as_ref(), MessageDirection::Received, record, line, settings);
+                        if let Some(preview) = preview {
+                            view.set_preview(preview);
+                        }
+                        view.status = "file received".to_string();
+                    }
+                    Err(e) => {
+                        record.event = FileEvent::Failed;
+                        let line = format!("[file] could not save {}: {}", name, e);
+                        file_event(view, storage.as_ref(), MessageDirection::Received, record, line, settings);
+                        view.status = format!("file save error: {}", e);
                     }
                 }
-                awaiting_ping_response = false;
-                let _ = np.send(&files::encode_ping()).await;
             }
-            _ = async {
-                if let Some(deadline) = session_deadline_tokio {
-                    tokio::time::sleep_until(deadline).await
-                } else {
-                    std::future::pending().await
+        }
+        Message::FileCancel(transfer) => {
+            // the id alone does not say which way the file was going
+            let outgoing = outgoing_transfer(peer, transfer);
+            let (record, line) = if let Some(inc) = peer.incoming_files.remove(&transfer) {
+                view.remove_transfer(transfer, true);
+                peer.voice_transfers.remove(&transfer);
+                let record = FileRecord::new(FileEvent::Cancelled, true, &inc.name, inc.size);
+                inc.cancel(storage.as_mut());
+                (record, "[file] peer cancelled the transfer".to_string())
+            } else if let Some((name, size, _)) = peer.incoming_offers.remove(&transfer) {
+                view.remove_transfer(transfer, true);
+                peer.voice_offers.remove(&transfer);
+                let line = format!("[file] peer withdrew its offer of {}", name);
+                (FileRecord::new(FileEvent::Cancelled, true, &name, size), line)
+            } else if let Some((name, _, size)) = peer.incoming_folders.remove(&transfer) {
+                view.remove_transfer(transfer, true);
+                let line = format!("[file] peer withdrew its offer of folder {}", name);
+                (FileRecord::new(FileEvent::Cancelled, true, &format!("{}/", name), size), line)
+            } else if let Some((name, size)) = peer
+                .outgoing_files
+                .remove(&outgoing)
+                .map(|out| (out.name.clone(), out.size))
+                .or_else(|| peer.pending_offers.remove(&outgoing).map(|out| (out.name, out.size)))
+            {
+                view.remove_transfer(outgoing, false);
+                let line = format!("[file] peer cancelled receiving {}", name);
+                (FileRecord::new(FileEvent::Cancelled, false, &name, size), line)
+            } else {
+                return;
+            };
+            file_event(view, storage.as_ref(), MessageDirection::Received, record, line, settings);
+            view.status = "transfer cancelled by peer".to_string();
+            offer_queued(view, peer);
+        }
+        Message::FileAccept { transfer, offset } => {
+            let transfer = outgoing_transfer(peer, transfer);
+            if let Some((folder, queued)) = peer.pending_folders.remove(&transfer) {
+                view.remove_transfer(transfer, false);
+                view.add_plain_message(
+                    MessageDirection::Received,
+                    format!("[file] peer accepted folder {}, sending {} files", folder, queued.len()),
+                    settings.now(),
+                );
+                for q in queued {
+                    let id = rand::random::<u64>().max(1);
+                    view.add_transfer(id, false, &q.name, q.size, "queued");
+                    peer.send_queue.push_back((id, q));
                 }
-            } => {
-                ratatui::restore();
-                let owned_storage = storage.take();
-                if let Err(e) = perform_panic_and_exit(owned_storage) {
-                    eprintln!("session timeout cleanup failed: {}", e);
+                offer_queued(view, peer);
+                return;
+            }
+            if let Some(mut out) = peer.pending_offers.remove(&transfer) {
+                if let Err(e) = out.seek_to(offset) {
+                    view.status = format!("file seek error: {}", e);
+                    view.remove_transfer(transfer, false);
+                    offer_queued(view, peer);
+                } else {
+                    view.add_plain_message(
+                        MessageDirection::Received,
+                        format!("[file] peer accepted {}", out.name),
+                        settings.now(),
+                    );
+                    view.start_transfer(transfer, false, "sending", out.sent);
+                    let windowed = peer.ctx.features.contains(Features::FLOW);
+                    peer.outgoing_files.insert(transfer, peer.handle.send_file(transfer, out, windowed));
                 }
             }
-            result = np.recv() => {
-                match result {
-                    Ok(msg) => {
-                        last_pong = tokio::time::Instant::now();
-                        if !peer_responding {
-                            peer_responding = true;
-                            app.status = app.status.replace(" | peer not responding", "");
-                        }
-                        match files::parse_message(&msg) {
-                            files::ParsedMessage::VersionNegotiate { major, minor, patch } => {
-                                status_ctx.peer_version = Some((major, minor, patch));
-                                let (our_major, our_minor, our_patch) = files::protocol_version();
-
-                                if major != our_major || minor != our_minor || patch != our_patch {
-                                    let mut warn = format!("warning: peer protocol {}.{}.{} differs from local {}.{}.{}", major, minor, patch, our_major, our_minor, our_patch);
-                                    if major != our_major {
-                                        warn = format!("INCOMPATIBLE MAJOR VERSION - {}", warn);
-                                    }
-                                    app.add_plain_message(
-                                        MessageDirection::System,
-                                        warn,
-                                        tui::now_timestamp(time_local, hour24, show_tz, show_seconds),
-                                    );
-                                }
-                            }
-
-                            files::ParsedMessage::Text(content) => {
-                                let spans = if content.contains("@peer") {
-                                    tui::highlighted(&content, "@peer")
-                                } else {
-                                    tui::plain(&content)
-                                };
-                                app.add_message(
-                                    MessageDirection::Received,
-                                    spans,
-                                    tui::now_timestamp(time_local, hour24, show_tz, show_seconds),
-                                );
-                                if let Some(ref s) = *storage {
-                                    if let Err(e) = s.save_message(MessageDirection::Received, &msg) {
-                                        app.status = format!("save error: {}", e);
-                                    }
-                                }
-                                if delivery_receipts {
-                                    let _ = np.send(&files::encode_delivered()).await;
-                                }
-                                if typing_indicators {
-                                    app.peer_typing = false;
-                                    app.status = app.status.replace(" | peer is typing...", "");
-                                }
-                                if app.peer_away {
-                                    app.peer_away = false;
-                                    app.status = app.status.replace(" | peer is away", "");
-                                }
-                            }
-                            files::ParsedMessage::FileOffer { name, size, checksum } => {
-                                let size_str = files::format_size(size);
-                                app.add_plain_message(
-                                    MessageDirection::Received,
-                                    format!(
-                                        "[file] peer wants to send {} ({}) - type /accept or /reject",
-                                        name, size_str
-                                    ),
-                                    tui::now_timestamp(time_local, hour24, show_tz, show_seconds),
-                                );
-                                app.pending_incoming_offer = Some((name, size, checksum));
+        }
+        Message::FileAck { transfer, offset } => {
+            if let Some(out) = peer.outgoing_files.get(&outgoing_transfer(peer, transfer)) {
+                out.acked(offset);
+            }
+        }
+        Message::FileReject(transfer) => {
+            let transfer = outgoing_transfer(peer, transfer);
+            if let Some((folder, queued)) = peer.pending_folders.remove(&transfer) {
+                view.remove_transfer(transfer, false);
+                let size = queued.iter().map(|q| q.size).sum();
+                file_event(
+                    view,
+                    storage.as_ref(),
+                    MessageDirection::Received,
+                    FileRecord::new(FileEvent::Rejected, false, &format!("{}/", folder), size),
+                    format!("[file] peer rejected folder {}", folder),
+                    settings,
+                );
+                return;
+            }
+            if let Some(out) = peer.pending_offers.remove(&transfer) {
+                view.remove_transfer(transfer, false);
+                file_event(
+                    view,
+                    storage.as_ref(),
+                    MessageDirection::Received,
+                    FileRecord::new(FileEvent::Rejected, false, &out.name, out.size),
+                    format!("[file] peer rejected {}", out.name),
+                    settings,
+                );
+                offer_queued(view, peer);
+            }
+        }
+        Message::TypingStart => {
+            if peer.ctx.features.contains(Features::TYPING) {
+                view.peer_typing = true;
+                let current = view.status.trim_end_matches(" | peer is typing...").to_string();
+                view.status = format!("{} | peer is typing...", current);
+            }
+        }
+        Message::TypingStop => {
+            if peer.ctx.features.contains(Features::TYPING) {
+                view.peer_typing = false;
+                view.status = view.status.replace(" | peer is typing...", "");
+            }
+        }
+        Message::Delivered(id) => {
+            if peer.ctx.features.contains(Features::RECEIPTS) {
+                view.mark_sent(id, tui::MessageState::Delivered);
+            }
+        }
+        Message::Read(id) => {
+            if peer.ctx.features.contains(Features::READ) {
+                view.mark_sent(id, tui::MessageState::Read);
+            }
+        }
+        // the peer may only edit and delete its own messages
+        Message::Edit { id, text } => {
+            if peer.ctx.features.contains(Features::EDITS)
+                && view.edit_text(MessageDirection::Received, id, &text)
+                && let Some(Err(e)) = storage
+                    .as_ref()
+                    .zip(view.conversation.as_deref())
+                    .map(|(s, c)| s.edit_message(c, MessageDirection::Received, id, text.as_bytes()))
+            {
+                view.status = format!("save error: {}", e);
+            }
+        }
+        Message::Delete(id) => {
+            if peer.ctx.features.contains(Features::EDITS)
+                && view.delete_text(MessageDirection::Received, id)
+                && let Some(Err(e)) = storage
+                    .as_mut()
+                    .zip(view.conversation.as_deref())
+                    .map(|(s, c)| s.delete_message(c, MessageDirection::Received, id))
+            {
+                view.status = format!("save error: {}", e);
+            }
+        }
+        Message::React { id, emoji } => {
+            if !peer.ctx.features.contains(Features::REACTIONS) || emoji.chars().count() > MAX_REACTION_CHARS {
+                return;
+            }
+            // reactions to our own messages come back with our id
+            let direction = if view.find_text(MessageDirection::Sent, id).is_some() {
+                MessageDirection::Sent
+            } else {
+                MessageDirection::Received
+            };
+            let stored = (!emoji.is_empty()).then_some(emoji.as_str());
+            if view.set_reaction(direction, id, MessageDirection::Received, &emoji)
+                && let Some(Err(e)) = storage
+                    .as_ref()
+                    .zip(view.conversation.as_deref())
+                    .map(|(s, c)| s.set_reaction(c, id, MessageDirection::Received, stored))
+            {
+                view.status = format!("save error: {}", e);
+            }
+        }
+        Message::Expire(secs) => {
+            if !peer.ctx.features.contains(Features::EXPIRY) {
+                return;
+            }
+            peer.expire_offered = Some(secs);
+            let proposal = if secs == 0 {
+                "to stop deleting old messages".to_string()
+            } else {
+                format!("to delete messages after {}", format_duration(secs))
+            };
+            view.add_plain_message(
+                MessageDirection::System,
+                format!("[expire] peer proposes {}. type /expire accept to agree", proposal),
+                settings.now(),
+            );
+        }
+        Message::ExpireAccept(secs) => {
+            if peer.expire_proposed.take_if(|p| *p == secs).is_some() {
+                apply_expiry(view, storage, secs, settings);
+            }
+        }
+        Message::Ping => {
+            peer.handle.send(&Message::Pong).await;
+        }
+        Message::Pong => {
+            if peer.awaiting_ping_response {
+                view.add_plain_message(
+                    MessageDirection::Received,
+                    "Pong!".to_string(),
+                    settings.now(),
+                );
+            }
+            peer.awaiting_ping_response = false;
+        }
+        Message::Away => {
+            view.peer_away = true;
+            if !view.status.contains("| peer is away") {
+                let base = view.status.replace(" | peer is away", "");
+                view.status = format!("{} | peer is away", base);
+            }
+        }
+        Message::Back => {
+            view.peer_away = false;
+            view.status = view.status.replace(" | peer is away", "");
+        }
+        // from a newer peer, counted and described in /status instead of being shown
+        Message::Unknown { kind, body } => {
+            peer.ctx.ignored_messages += 1;
+            peer.ctx.last_ignored = Some((kind, body.len()));
+        }
+    }
+}
+
+const FIND_PAGE: usize = 10;
+
+/// Results of the last `/find`, newest first, shown a page at a time.
+struct FindResults {
+    query: String,
+    terms: Vec<String>,
+    messages: Vec<storage::Message>,
+    page: usize,
+}
+
+fn show_find_page(view: &mut tui::SessionView, results: &FindResults, settings: &ChatSettings) {
+    let start = results.page * FIND_PAGE;
+    let end = (start + FIND_PAGE).min(results.messages.len());
+    view.add_plain_message(
+        MessageDirection::System,
+        format!(
+            "[find] results {}-{} of {} for '{}':",
+            start + 1,
+            end,
+            results.messages.len(),
+            results.query
+        ),
+        settings.now(),
+    );
+    let terms: Vec<&str> = results.terms.iter().map(String::as_str).collect();
+    for msg in &results.messages[start..end] {
+        let prefix = match msg.direction {
+            MessageDirection::Sent => "you",
+            MessageDirection::Received => "peer",
+            MessageDirection::System => "system",
+        };
+        let text = format!("[{}] {}", prefix, String::from_utf8_lossy(&msg.content));
+        view.add_message(MessageDirection::System, tui::highlighted_any(&text, &terms), settings.format(msg.timestamp));
+    }
+    if end < results.messages.len() {
+        view.add_plain_message(
+            MessageDirection::System,
+            "[find] /find next for more, /find prev to go back".to_string(),
+            settings.now(),
+        );
+    }
+}
+
+/// What the two masked prompts after `/rekey` or `/exportchat --encrypt`
+/// are for.
+enum SecretPurpose {
+    Rekey,
+    /// Where the sealed export goes, and what it holds.
+    Export(std::path::PathBuf, Vec<u8>),
+}
+
+/// Progress through the two masked prompts, `first` holds the first line.
+struct SecretPrompt {
+    purpose: SecretPurpose,
+    first: Option<String>,
+}
+
+/// Collects what `/exportchat` writes for a tab: its saved conversation with
+/// this session's events in between, or what is on screen if nothing is saved.
+fn build_export(
+    view: &tui::SessionView,
+    storage: Option<&Storage>,
+    settings: &ChatSettings,
+) -> Result<export::Export, Box<dyn Error>> {
+    let saved = match (storage, view.conversation.as_deref()) {
+        (Some(s), Some(conversation)) => Some(s.load_history(conversation)?),
+        _ => None,
+    };
+    let mut entries = Vec::new();
+    for msg in saved.iter().flatten() {
+        let text = String::from_utf8_lossy(&msg.content).into_owned();
+        let mut entry = export::Entry::new(msg.direction, msg.timestamp, settings.format(msg.timestamp), text);
+        entry.id = msg.msg_id;
+        entry.reply_to = msg.reply_to;
+        entry.edited = msg.edited;
+        if let Some(ref file) = msg.file {
+            entry.kind = "file";
+            entry.file = Some(file.into());
+        }
+        for (by, emoji) in &msg.reactions {
+            entry.add_reaction(*by, emoji);
+        }
+        entries.push(entry);
+    }
+    for m in &view.messages {
+        // saved messages are already in, unlike transfers and other events
+        if saved.is_some() && m.direction != MessageDirection::System {
+            continue;
+        }
+        let mut entry = export::Entry::new(m.direction, m.time, m.timestamp.clone(), m.text());
+        // file events are plain lines without an id
+        if m.id == 0 && entry.text.starts_with("[file]") {
+            entry.kind = "file";
+        }
+        if let Some(ref sender) = m.sender {
+            entry.sender = sender.clone();
+        }
+        entry.id = m.id;
+        entry.reply_to = m.reply_to;
+        entry.edited = m.edited;
+        entry.deleted = m.deleted;
+        for (by, emoji) in &m.reactions {
+            entry.add_reaction(*by, emoji);
+        }
+        entries.push(entry);
+    }
+    entries.sort_by_key(|e| e.unix_time);
+
+    let mut out = export::Export::new(&view.peer_label, entries);
+    out.conversation = view.conversation.clone();
+    out.session_fingerprint = view.session_fingerprint.clone();
+    out.identity_warning = view.identity_warning.clone();
+    Ok(out)
+}
+
+/// Sends a text, quoting the peer's message `reply_to` unless it is 0, and
+/// shows and saves it. Returns false if the session is gone.
+async fn send_text(
+    view: &mut tui::SessionView,
+    peer: &mut PeerSession,
+    storage: &Option<Storage>,
+    settings: &ChatSettings,
+    text: String,
+    reply_to: u64,
+) -> bool {
+    let quoted = view
+        .find_text(MessageDirection::Received, reply_to)
+        .map(|m| m.text().chars().take(40).collect::<String>());
+    // legacy text cannot carry the reference, so quote inline instead
+    let msg = match quoted {
+        Some(quoted) if peer.handle.framing == Framing::Legacy => Message::text(format!("> {}\n{}", quoted, text)),
+        _ => Message::reply(reply_to, text.clone()),
+    };
+    let Message::Text { id, .. } = msg else {
+        unreachable!()
+    };
+    if !peer.handle.send(&msg).await {
+        view.status = "send failed: peer disconnected".to_string();
+        return false;
+    }
+    let shown = view.add_text(MessageDirection::Sent, id, tui::plain(&text), settings.now());
+    shown.reply_to = reply_to;
+    // only tracked when the peer will acknowledge it
+    if !peer.ctx.features.contains(Features::RECEIPTS) {
+        shown.state = tui::MessageState::None;
+    }
+    if let Some(Err(e)) = storage
+        .as_ref()
+        .zip(view.conversation.as_deref())
+        .map(|(s, c)| s.save_message(c, MessageDirection::Sent, id, reply_to, text.as_bytes()))
+    {
+        view.status = format!("save error: {}", e);
+    }
+    true
+}
+
+/// Splits an optional leading message number off command arguments, so
+/// `/react 2 👍` refers to the second most recent message. Defaults to 1.
+fn split_message_number(args: &str) -> (usize, &str) {
+    let args = args.trim();
+    let (first, rest) = args.split_once(' ').unwrap_or((args, ""));
+    match first.parse::<usize>() {
+        Ok(n) if n > 0 => (n, rest.trim()),
+        _ => (1, args),
+    }
+}
+
+/// Parses an expiry period such as `90s`, `30m`, `12h`, `7d` or `2w` into
+/// seconds.
+fn parse_duration(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let unit = match text.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    let n: u64 = text[..text.len() - 1].parse().ok()?;
+    n.checked_mul(unit).filter(|&secs| secs > 0)
+}
+
+fn format_duration(secs: u64) -> String {
+    for (unit, name) in [(7 * 24 * 60 * 60, "w"), (24 * 60 * 60, "d"), (60 * 60, "h"), (60, "m")] {
+        if secs.is_multiple_of(unit) {
+            return format!("{}{}", secs / unit, name);
+        }
+    }
+    format!("{}s", secs)
+}
+
+/// Applies an expiry both sides agreed on to the tab and its saved
+/// conversation. 0 keeps messages again.
+fn apply_expiry(view: &mut tui::SessionView, storage: &mut Option<Storage>, secs: u64, settings: &ChatSettings) {
+    view.expire_after = secs;
+    if let (Some(s), Some(conversation)) = (storage.as_mut(), view.conversation.as_deref()) {
+        let now = chrono::Utc::now().timestamp();
+        if let Err(e) = s.set_conversation_expiry(conversation, secs).and_then(|_| s.purge_expired(now)) {
+            view.status = format!("save error: {}", e);
+        }
+    }
+    view.expire(chrono::Utc::now().timestamp());
+    let notice = if secs == 0 {
+        "[expire] messages are no longer deleted".to_string()
+    } else {
+        format!("[expire] messages older than {} are now deleted on both sides", format_duration(secs))
+    };
+    view.add_plain_message(MessageDirection::System, notice, settings.now());
+}
+
+/// Runs the chat UI. The initiator passes its single connected session in
+/// `first`; the listener passes a `Listener` and keeps accepting new peers,
+/// each in its own tab.
+async fn chat_loop(
+    first: Option<(SessionHandle, StatusContext)>,
+    mut listener: Option<Listener>,
+    events_tx: mpsc::UnboundedSender<SessionEvent>,
+    mut events_rx: mpsc::UnboundedReceiver<SessionEvent>,
+    storage: &mut Option<Storage>,
+    base_ctx: StatusContext,
+    settings: &ChatSettings,
+) -> Result<(), Box<dyn Error>> {
+    let mut terminal = ratatui::init();
+    let initial_status = if listener.is_some() {
+        "waiting for connections"
+    } else {
+        "connected"
+    };
+    let mut app = tui::App::new(
+        initial_status,
+        settings.message_notification_sound,
+        settings.mention_notification_sound,
+    );
+    app.graphics = settings.graphics;
+    let mut overlay = images::Overlay::new(settings.graphics);
+
+    let session_deadline_tokio = if settings.session_timeout_mins > 0 {
+        Some(
+            tokio::time::Instant::now() + std::time::Duration::from_secs(settings.session_timeout_mins * 60),
+        )
+    } else {
+        None
+    };
+    let session_deadline_std = if settings.session_timeout_mins > 0 {
+        Some(std::time::Instant::now() + std::time::Duration::from_secs(settings.session_timeout_mins * 60))
+    } else {
+        None
+    };
+    app.session_deadline = session_deadline_std;
+
+    if let Some(ref addr) = base_ctx.onion_addr {
+        app.add_plain_message(
+            MessageDirection::System,
+            format!("[session] listening at {}. share your address with your peers", addr),
+            settings.now(),
+        );
+    }
+    app.scroll_to_bottom();
+
+    let mut sessions: HashMap<u64, PeerSession> = HashMap::new();
+    let mut next_id: u64 = 0;
+    if let Some((handle, ctx)) = first {
+        next_id = handle.id;
+        handle.send(&settings.hello()).await;
+        let peer = open_session(&mut app, handle, ctx, storage.as_ref(), settings);
+        sessions.insert(peer.handle.id, peer);
+    }
+
+    let mut events = EventStream::new();
+    let mut last_input_empty = true;
+    let mut secret: Option<SecretPrompt> = None;
+    let mut find: Option<FindResults> = None;
+    // the session a running /voice recording goes to
+    let mut recording: Option<(voice::Recorder, u64)> = None;
+    let mut player: Option<voice::Player> = None;
+
+    let mut last_activity = tokio::time::Instant::now();
+    let mut is_away = false;
+    let idle_away_duration = if settings.idle_away_mins > 0 {
+        Some(std::time::Duration::from_secs(settings.idle_away_mins * 60))
+    } else {
+        None
+    };
+
+    let mut ping_interval = tokio::time::interval(std::time::Duration::from_secs(15));
+    ping_interval.reset();
+    let mut session_tick = tokio::time::interval(std::time::Duration::from_secs(1));
+    session_tick.reset();
+    let mut expire_tick = tokio::time::interval(std::time::Duration::from_secs(10));
+    let ping_timeout = std::time::Duration::from_secs(45);
+    loop {
+        terminal.draw(|f| app.draw(f))?;
+        let placements = app.placements.clone();
+        overlay.update(&mut terminal, &placements, |t| t.draw(|f| app.draw(f)).map(|_| ()))?;
+        let read = app.take_read();
+        if let Some(peer) = sessions.get(&app.view().id).filter(|p| p.ctx.features.contains(Features::READ)) {
+            for id in read {
+                peer.handle.send(&Message::Read(id)).await;
+            }
+        }
+
+        tokio::select! {
+            _ = session_tick.tick() => {
+                if app.recording.is_some_and(|started| started.elapsed() >= voice::MAX_DURATION)
+                    && let Some(rec) = recording.take()
+                {
+                    finish_recording(&mut app, &mut sessions, rec);
+                }
+                if let Some(idle_dur) = idle_away_duration {
+                    if !is_away && last_activity.elapsed() >= idle_dur {
+                        is_away = true;
+                        for peer in sessions.values().filter(|p| p.ctx.features.contains(Features::AWAY)) {
+                            peer.handle.send(&Message::Away).await;
+                        }
+                    }
+                }
+            }
+            _ = expire_tick.tick() => {
+                let now = chrono::Utc::now().timestamp();
+                for view in app.views.iter_mut() {
+                    view.expire(now);
+                }
+                if let Some(Err(e)) = storage.as_mut().map(|s| s.purge_expired(now)) {
+                    app.view_mut().status = format!("expiry error: {}", e);
+                }
+            }
+            _ = ping_interval.tick() => {
+                for peer in sessions.values_mut() {
+                    if peer.last_pong.elapsed() > ping_timeout && peer.peer_responding {
+                        peer.peer_responding = false;
+                        if let Some(view) = app.view_by_id(peer.handle.id) {
+                            let base = view.status.replace(" | peer not responding", "");
+                            view.status = format!("{} | peer not responding", base);
+                        }
+                    }
+                    peer.awaiting_ping_response = false;
+                    peer.handle.send(&Message::Ping).await;
+                }
+            }
+            _ = async {
+                if let Some(deadline) = session_deadline_tokio {
+                    tokio::time::sleep_until(deadline).await
+                } else {
+                    std::future::pending().await
+                }
+            } => {
+                ratatui::restore();
+                let owned_storage = storage.take();
+                if let Err(e) = perform_panic_and_exit(owned_storage) {
+                    eprintln!("session timeout cleanup failed: {}", e);
+                }
+            }
+            request = async {
+                match listener.as_mut() {
+                    Some(l) => l.requests.next().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                let Some(l) = listener.as_ref() else { continue };
+                let Some(request) = request else {
+                    app.view_mut().status = "onion service stopped".to_string();
+                    listener = None;
+                    continue;
+                };
+                match request.accept(Connected::new_empty()).await {
+                    Ok(stream) => {
+                        next_id += 1;
+                        session::spawn_accept(
+                            next_id,
+                            stream,
+                            PATTERN,
+                            l.identity.clone(),
+                            l.password.clone(),
+                            events_tx.clone(),
+                        );
+                    }
+                    Err(e) => app.view_mut().status = format!("failed to accept connection: {}", e),
+                }
+            }
+            event = events_rx.recv() => {
+                let Some(event) = event else { break };
+                match event {
+                    SessionEvent::Joined(handle) => {
+                        let mut contact = match (storage.as_ref(), handle.remote_static.as_deref()) {
+                            (Some(s), Some(key)) => s.find_contact_by_key(key).unwrap_or(None),
+                            _ => None,
+                        };
+                        let identity_warning = contact
+                            .as_mut()
+                            .and_then(|c| check_contact(storage.as_ref(), c, &handle));
+                        let ctx = StatusContext {
+                            peer_identity: handle.remote_static.clone(),
+                            peer_auth_pin: handle.auth_pin.clone(),
+                            contact: contact.map(|c| c.nickname),
+                            identity_warning,
+                            ..base_ctx.clone()
+                        };
+                        handle.send(&settings.hello()).await;
+                        let peer = open_session(&mut app, handle, ctx, storage.as_ref(), settings);
+                        sessions.insert(peer.handle.id, peer);
+                    }
+                    SessionEvent::Failed { id, error } => {
+                        app.add_plain_message(
+                            MessageDirection::System,
+                            format!("[session] connection {} rejected: {}", id, error),
+                            settings.now(),
+                        );
+                    }
+                    SessionEvent::Closed { id } => {
+                        let mut interrupted = Vec::new();
+                        if let Some(peer) = sessions.remove(&id) {
+                            for inc in peer.incoming_files.into_values() {
+                                interrupted.push(FileRecord::new(FileEvent::Failed, true, &inc.name, inc.size));
+                                inc.interrupt(storage.as_mut());
+                            }
+                            for out in peer.outgoing_files.values() {
+                                interrupted.push(FileRecord::new(FileEvent::Failed, false, &out.name, out.size));
+                            }
+                        }
+                        if let Some(view) = app.view_by_id(id) {
+                            for record in interrupted {
+                                let verb = if record.incoming { "receiving" } else { "sending" };
+                                let line = format!("[file] {} {} failed: peer disconnected", verb, record.name);
+                                file_event(view, storage.as_ref(), MessageDirection::Received, record, line, settings);
+                            }
+                            view.status = "peer disconnected".to_string();
+                            view.transfers.clear();
+                            view.add_plain_message(
+                                MessageDirection::System,
+                                "[session] peer disconnected".to_string(),
+                                settings.now(),
+                            );
+                        }
+                        if listener.is_none() && sessions.is_empty() {
+                            terminal.draw(|f| app.draw(f))?;
+                            break;
+                        }
+                    }
+                    SessionEvent::Message { id, data } => {
+                        let (Some(peer), Some(view)) = (sessions.get_mut(&id), app.view_by_id(id)) else {
+                            continue;
+                        };
+                        handle_peer_message(view, peer, storage, settings, data).await;
+                    }
+                    SessionEvent::FileProgress { id, transfer, sent, wire } => {
+                        if let Some(view) = app.view_by_id(id) {
+                            view.update_transfer(transfer, false, sent, wire);
+                        }
+                    }
+                    SessionEvent::FileSent { id, transfer, result } => {
+                        let (Some(peer), Some(view)) = (sessions.get_mut(&id), app.view_by_id(id)) else {
+                            continue;
+                        };
+                        let Some(out) = peer.outgoing_files.remove(&transfer) else { continue };
+                        let mut record = FileRecord::new(FileEvent::Completed, false, &out.name, out.size);
+                        let line = match result {
+                            Ok(()) => {
+                                record.checksum = Some(out.checksum.clone());
+                                format!("[file] sent {} ({})", out.name, files::format_size(out.size))
+                            }
+                            Err(e) => {
+                                record.event = FileEvent::Failed;
+                                format!("[file] send error: {}", e)
+                            }
+                        };
+                        file_event(view, storage.as_ref(), MessageDirection::Sent, record, line, settings);
+                        view.remove_transfer(transfer, false);
+                        offer_queued(view, peer);
+                    }
+                    SessionEvent::FileHashed { id, transfer, result } => {
+                        let (Some(peer), Some(view)) = (sessions.get_mut(&id), app.view_by_id(id)) else {
+                            continue;
+                        };
+                        offer_hashed(view, peer, storage.as_ref(), settings, transfer, result).await;
+                    }
+                }
+            }
+            event = events.next() => {
+                match event {
+                    Some(Ok(Event::Key(key))) => {
+                        last_activity = tokio::time::Instant::now();
+                        if is_away {
+                            is_away = false;
+                            for peer in sessions.values().filter(|p| p.ctx.features.contains(Features::AWAY)) {
+                                peer.handle.send(&Message::Back).await;
+                            }
+                        }
+                        let submitted = app.handle_key(key);
+                        let active_id = app.view().id;
+                        let peer = sessions.get_mut(&active_id);
+                        {
+                            let now_empty = app.input.is_empty();
+                            let typing = app.secret_prompt.is_none();
+                            if let Some(peer) = peer.as_ref().filter(|p| typing && p.ctx.features.contains(Features::TYPING)) {
+                                if last_input_empty && !now_empty {
+                                    peer.handle.send(&Message::TypingStart).await;
+                                } else if !last_input_empty && now_empty {
+                                    peer.handle.send(&Message::TypingStop).await;
+                                }
+                            }
+                            last_input_empty = now_empty;
+                        }
+                        if let Some(mut text) = submitted {
+                            // the passphrase prompts of /rekey and /exportchat take the next two lines
+                            if let Some(mut prompt) = secret.take() {
+                                // most likely a command typed into the wrong box
+                                if text.starts_with('/') {
+                                    text.zeroize();
+                                    secret = Some(prompt);
+                                    app.view_mut().status = "a passphrase cannot start with /".to_string();
+                                    continue;
+                                }
+                                let Some(mut first) = prompt.first.take() else {
+                                    prompt.first = Some(text);
+                                    app.secret_prompt = Some(match prompt.purpose {
+                                        SecretPurpose::Rekey => "confirm new passphrase".to_string(),
+                                        SecretPurpose::Export(..) => "confirm export passphrase".to_string(),
+                                    });
+                                    secret = Some(prompt);
+                                    continue;
+                                };
+                                app.secret_prompt = None;
+                                let matches = first == text;
+                                first.zeroize();
+                                let line = match prompt.purpose {
+                                    SecretPurpose::Rekey => {
+                                        let result = match storage.as_mut() {
+                                            _ if !matches => Err("passphrases do not match".into()),
+                                            Some(s) => s.rekey(&text),
+                                            None => Err("no storage available".into()),
+                                        };
+                                        match result {
+                                            Ok(()) => "[rekey] passphrase changed and database re-encrypted".to_string(),
+                                            Err(e) => format!("[rekey] failed: {}", e),
+                                        }
+                                    }
+                                    SecretPurpose::Export(path, mut content) => {
+                                        let result = if !matches {
+                                            Err("passphrases do not match".into())
+                                        } else if text.is_empty() {
+                                            Err("passphrase cannot be empty".into())
+                                        } else {
+                                            storage::seal_file(export::MAGIC, &text, &content)
+                                                .and_then(|sealed| Ok(std::fs::write(&path, sealed)?))
+                                        };
+                                        content.zeroize();
+                                        match result {
+                                            Ok(()) => format!("[export] saved encrypted chat to {}", path.display()),
+                                            Err(e) => format!("[export] failed: {}", e),
+                                        }
+                                    }
+                                };
+                                text.zeroize();
+                                app.add_plain_message(MessageDirection::System, line, settings.now());
+                                continue;
                             }
-                            files::ParsedMessage::FileChunk(data) => {
-                                if let Some(ref mut inc) = incoming_file {
-                                    if let Err(e) = inc.write_chunk(&data) {
-                                        app.status = format!("file write error: {}", e);
-                                        app.clear_recv_progress();
-                                        incoming_file = None;
+                            if text == "/rekey" {
+                                if storage.is_some() {
+                                    secret = Some(SecretPrompt {
+                                        purpose: SecretPurpose::Rekey,
+                                        first: None,
+                                    });
+                                    app.secret_prompt = Some("new passphrase".to_string());
+                                } else {
+                                    app.view_mut().status = "no storage available".to_string();
+                                }
+                            } else if let Some(path) = text.strip_prefix("/send ") {
+                                let Some(peer) = peer else {
+                                    app.view_mut().status = "no active session".to_string();
+                                    continue;
+                                };
+                                let view = app.view_mut();
+                                let (folder, mut queued) = match files::expand(path) {
+                                    Ok(expanded) => expanded,
+                                    Err(e) => {
+                                        view.status = format!("cannot send {}: {}", path.trim(), e);
+                                        continue;
+                                    }
+                                };
+                                if settings.randomize_filenames {
+                                    // the same random name for each folder keeps the structure intact
+                                    let mut renamed: HashMap<String, String> = HashMap::new();
+                                    for q in &mut queued {
+                                        q.name = q
+                                            .name
+                                            .split('/')
+                                            .map(|part| {
+                                                renamed
+                                                    .entry(part.to_string())
+                                                    .or_insert_with(|| files::randomize_filename_preserve_ext(part))
+                                                    .clone()
+                                            })
+                                            .collect::<Vec<_>>()
+                                            .join("/");
+                                    }
+                                }
+                                let Some(folder) = folder else {
+                                    queue_file(view, peer, queued.pop().unwrap());
+                                    continue;
+                                };
+                                if !peer.ctx.features.contains(Features::FOLDERS) {
+                                    view.status = "the peer's version cannot receive folders".to_string();
+                                    continue;
+                                }
+                                let transfer = rand::random::<u64>().max(1);
+                                // every file starts with the folder's name, so take the renamed one
+                                let folder = queued[0].name.split('/').next().unwrap_or(&folder).to_string();
+                                let size: u64 = queued.iter().map(|q| q.size).sum();
+                                let offer = Message::FolderOffer {
+                                    transfer,
+                                    name: folder.clone(),
+                                    files: queued.len() as u32,
+                                    size,
+                                };
+                                if !peer.handle.send(&offer).await {
+                                    view.status = "send failed: peer disconnected".to_string();
+                                    continue;
+                                }
+                                let label = format!("{}/ ({} files)", folder, queued.len());
+                                view.add_transfer(transfer, false, &label, size, "offered");
+                                file_event(
+                                    view,
+                                    storage.as_ref(),
+                                    MessageDirection::Sent,
+                                    FileRecord::new(FileEvent::Offered, false, &format!("{}/", folder), size),
+                                    format!(
+                                        "[file] offered folder {} ({} files, {}) - waiting for peer to accept",
+                                        folder,
+                                        queued.len(),
+                                        files::format_size(size)
+                                    ),
+                                    settings,
+                                );
+                                peer.pending_folders.insert(transfer, (folder, queued));
+                            } else if text == "/paste" || text.starts_with("/paste ") {
+                                let Some(peer) = peer else {
+                                    app.view_mut().status = "no active session".to_string();
+                                    continue;
+                                };
+                                let arg = text[6..].trim();
+                                let pasted = if arg.is_empty() {
+                                    images::paste_clipboard()
+                                } else {
+                                    images::paste_file(arg)
+                                };
+                                let view = app.view_mut();
+                                match pasted {
+                                    Ok(images::Pasted::Text(text)) => {
+                                        send_text(view, peer, storage, settings, text, 0).await;
+                                    }
+                                    Ok(images::Pasted::File(mut queued, preview)) => {
+                                        if settings.randomize_filenames {
+                                            queued.name = files::randomize_filename_preserve_ext(&queued.name);
+                                        }
+                                        let name = queued.name.clone();
+                                        let transfer = queue_file(view, peer, queued);
+                                        let Some(preview) = preview.filter(|_| settings.graphics != images::Graphics::Off) else {
+                                            continue;
+                                        };
+                                        // the preview goes under the offer, or on a line of its own
+                                        // if the file is queued to be offered later
+                                        match view.transfer(transfer, false).map(|t| t.state) {
+                                            Some("queued") => view.add_plain_message(
+                                                MessageDirection::System,
+                                                format!("[file] pasted {}", name),
+                                                settings.now(),
+                                            ),
+                                            Some(_) => {}
+                                            None => continue,
+                                        }
+                                        view.set_preview(preview);
+                                    }
+                                    Err(e) => {
+                                        view.status = format!("cannot paste: {}", e);
+                                    }
+                                }
+                            } else if text == "/voice" || text.starts_with("/voice ") {
+                                let arg = text[6..].trim();
+                                // Enter or Esc while recording
+                                if let Some(rec) = recording.take() {
+                                    if arg == "cancel" {
+                                        app.recording = None;
+                                        app.view_mut().status = "recording discarded".to_string();
                                     } else {
-                                        app.update_recv_progress(inc.received);
+                                        finish_recording(&mut app, &mut sessions, rec);
                                     }
+                                    continue;
                                 }
-                            }
-                            files::ParsedMessage::FileDone => {
-                                if let Some(inc) = incoming_file.take() {
-                                    let name = inc.name.clone();
-                                    let size = inc.size;
-                                    match inc.finish() {
-                                        Ok(path) => {
-                                            app.add_plain_message(
-                                                MessageDirection::Received,
-                                                format!(
-                                                    "[file] saved {} ({}) -> {}",
-                                                    name,
-                                                    files::format_size(size),
-                                                    path.display()
-                                                ),
-                                                tui::now_timestamp(time_local, hour24, show_tz, show_seconds),
-                                            );
-                                            app.status = "file received".to_string();
-                                            app.clear_recv_progress();
+                                let Some(peer) = peer else {
+                                    app.view_mut().status = "no active session".to_string();
+                                    continue;
+                                };
+                                let view = app.view_mut();
+                                if !peer.ctx.features.contains(Features::VOICE) {
+                                    view.status = "the peer's version cannot receive voice messages".to_string();
+                                    continue;
+                                }
+                                if arg == "cancel" {
+                                    view.status = "not recording".to_string();
+                                } else if arg.is_empty() {
+                                    match voice::Recorder::start() {
+                                        Ok(recorder) => {
+                                            recording = Some((recorder, active_id));
+                                            app.recording = Some(std::time::Instant::now());
                                         }
-                                        Err(e) => {
-                                            app.status = format!("file save error: {}", e);
-                                            app.clear_recv_progress();
+                                        Err(e) => view.status = format!("cannot record: {}", e),
+                                    }
+                                } else {
+                                    match voice::encode_wav(std::path::Path::new(arg)) {
+                                        Ok(queued) => {
+                                            queue_file(view, peer, queued);
                                         }
+                                        Err(e) => view.status = format!("cannot send {}: {}", arg, e),
                                     }
                                 }
-                            }
-                            files::ParsedMessage::FileCancel => {
-                                if let Some(inc) = incoming_file.take() {
-                                    inc.cancel();
-                                        app.add_plain_message(
-                                        MessageDirection::Received,
-                                        "[file] peer cancelled the transfer".to_string(),
-                                        tui::now_timestamp(time_local, hour24, show_tz, show_seconds),
-                                    );
-                                    app.status = "transfer cancelled by peer".to_string();
-                                    app.clear_recv_progress();
+                            } else if text == "/play" || text.starts_with("/play ") {
+                                let arg = text[5..].trim();
+                                let view = app.view_mut();
+                                if arg == "stop" {
+                                    view.status = match player.take() {
+                                        Some(p) if !p.finished() => "playback stopped".to_string(),
+                                        _ => "nothing is playing".to_string(),
+                                    };
+                                    continue;
                                 }
-                            }
-                            files::ParsedMessage::FileAccept(offset) => {
-                                if let Some(mut out) = pending_offer.take() {
-                                    if let Err(e) = out.seek_to(offset) {
-                                        app.status = format!("file seek error: {}", e);
+                                let (n, _) = split_message_number(arg);
+                                let Some(clip) = view.voice_clips.len().checked_sub(n).map(|i| view.voice_clips[i].clone()) else {
+                                    view.status = "no such voice message".to_string();
+                                    continue;
+                                };
+                                let data: Result<Vec<u8>, Box<dyn Error>> = match clip {
+                                    SavedFile::Downloads(path) => std::fs::read(path).map_err(Into::into),
+                                    SavedFile::Vault(attachment) => match storage.as_ref() {
+                                        Some(s) => {
+                                            let mut data = Vec::new();
+                                            s.read_attachment(attachment, &mut data).map(|_| data)
+                                        }
+                                        None => Err("no storage available".into()),
+                                    },
+                                };
+                                // only one at a time
+                                player = None;
+                                match data.and_then(voice::Player::start) {
+                                    Ok(p) => {
+                                        player = Some(p);
+                                        view.status = "playing voice message, /play stop to stop".to_string();
+                                    }
+                                    Err(e) => view.status = format!("cannot play voice message: {}", e),
+                                }
+                            } else if text == "/cancel" || text.starts_with("/cancel ") {
+                                let view = app.view_mut();
+                                let Some(peer) = peer else {
+                                    view.status = "no active session".to_string();
+                                    continue;
+                                };
+                                let picked = pick_transfer(
+                                    view,
+                                    text[7..].trim(),
+                                    |t| !(t.incoming && t.state == "offered"),
+                                    "no active transfer",
+                                );
+                                let (transfer, incoming) = match picked {
+                                    Ok(t) => t,
+                                    Err(e) => {
+                                        view.status = e;
+                                        continue;
+                                    }
+                                };
+                                view.remove_transfer(transfer, incoming);
+                                if incoming {
+                                    let Some(inc) = peer.incoming_files.remove(&transfer) else { continue };
+                                    let record = FileRecord::new(FileEvent::Cancelled, true, &inc.name, inc.size);
+                                    let line = format!("[file] cancelled receiving {}", inc.name);
+                                    inc.cancel(storage.as_mut());
+                                    // older peers would take it for cancelling a file of theirs
+                                    if peer.ctx.features.contains(Features::TRANSFERS) {
+                                        peer.handle.send(&Message::FileCancel(transfer)).await;
+                                    }
+                                    file_event(view, storage.as_ref(), MessageDirection::Sent, record, line, settings);
+                                    view.status = "cancelled incoming transfer".to_string();
+                                } else if let Some(i) = peer.send_queue.iter().position(|(t, _)| *t == transfer) {
+                                    let (_, queued) = peer.send_queue.remove(i).unwrap();
+                                    view.status = format!("removed {} from the queue", queued.name);
+                                } else if let Some(queued) = peer.hashing.remove(&transfer) {
+                                    // not offered yet, so the peer has nothing to be told
+                                    view.status = format!("removed {} from the queue", queued.name);
+                                    offer_queued(view, peer);
+                                } else {
+                                    let (name, size, line) = if let Some(out) = peer.outgoing_files.remove(&transfer) {
+                                        // behind the chunks already queued, so the peer stops at the right file
+                                        peer.handle.send_bulk(&Message::FileCancel(transfer)).await;
+                                        (out.name.clone(), out.size, format!("[file] cancelled sending {}", out.name))
+                                    } else if let Some(out) = peer.pending_offers.remove(&transfer) {
+                                        peer.handle.send(&Message::FileCancel(transfer)).await;
+                                        let line = format!("[file] withdrew the offer of {}", out.name);
+                                        (out.name, out.size, line)
+                                    } else if let Some((folder, queued)) = peer.pending_folders.remove(&transfer) {
+                                        peer.handle.send(&Message::FileCancel(transfer)).await;
+                                        let line = format!("[file] withdrew the offer of folder {}", folder);
+                                        (format!("{}/", folder), queued.iter().map(|q| q.size).sum(), line)
                                     } else {
-                                        app.add_plain_message(
-                                            MessageDirection::Received,
-                                            format!("[file] peer accepted {}", out.name),
-                                            tui::now_timestamp(time_local, hour24, show_tz, show_seconds),
-                                        );
-                                        app.set_send_progress(out.name.clone(), out.size);
-                                        app.update_send_progress(out.sent);
-                                        outgoing_file = Some(out);
+                                        continue;
+                                    };
+                                    let record = FileRecord::new(FileEvent::Cancelled, false, &name, size);
+                                    file_event(view, storage.as_ref(), MessageDirection::Sent, record, line, settings);
+                                    offer_queued(view, peer);
+                                }
+                            } else if text == "/accept" || text.starts_with("/accept ") {
+                                let view = app.view_mut();
+                                let Some(peer) = peer else {
+                                    view.status = "no active session".to_string();
+                                    continue;
+                                };
+                                let picked = pick_transfer(
+                                    view,
+                                    text[7..].trim(),
+                                    |t| t.incoming && t.state == "offered",
+                                    "no pending file offer",
+                                );
+                                let transfer = match picked {
+                                    Ok((t, _)) => t,
+                                    Err(e) => {
+                                        view.status = e;
+                                        continue;
                                     }
+                                };
+                                if let Some(folder) = peer.incoming_folders.remove(&transfer) {
+                                    view.remove_transfer(transfer, true);
+                                    if !peer.handle.send(&Message::FileAccept { transfer, offset: 0 }).await {
+                                        view.status = "send failed: peer disconnected".to_string();
+                                        continue;
+                                    }
+                                    let line = format!("[file] accepted folder {}, saving into downloads/{}", folder.0, folder.0);
+                                    view.add_plain_message(MessageDirection::Sent, line, settings.now());
+                                    peer.accepted_folders.insert(transfer, folder);
+                                    continue;
                                 }
-                            }
-                            files::ParsedMessage::FileReject => {
-                                if let Some(out) = pending_offer.take() {
-                                    app.add_plain_message(
-                                        MessageDirection::Received,
-                                        format!("[file] peer rejected {}", out.name),
-                                        tui::now_timestamp(time_local, hour24, show_tz, show_seconds),
+                                let Some(offer) = peer.incoming_offers.remove(&transfer) else {
+                                    continue;
+                                };
+                                let name = offer.0.clone();
+                                let clip = peer.voice_offers.remove(&transfer);
+                                if accept_file(view, peer, storage.as_ref(), transfer, offer, settings).await {
+                                    if let Some(ms) = clip {
+                                        peer.voice_transfers.insert(transfer, ms);
+                                    }
+                                    view.add_plain_message(
+                                        MessageDirection::Sent,
+                                        format!("[file] accepted {}", name),
+                                        settings.now(),
                                     );
                                 }
-                            }
-                            files::ParsedMessage::TypingStart => {
-                                if typing_indicators {
-                                    app.peer_typing = true;
-                                    let current = app.status.trim_end_matches(" | peer is typing...").to_string();
-                                    app.status = format!("{} | peer is typing...", current);
+                            } else if text == "/reject" || text.starts_with("/reject ") {
+                                let view = app.view_mut();
+                                let picked = pick_transfer(
+                                    view,
+                                    text[7..].trim(),
+                                    |t| t.incoming && t.state == "offered",
+                                    "no pending file offer",
+                                );
+                                let transfer = match picked {
+                                    Ok((t, _)) => t,
+                                    Err(e) => {
+                                        view.status = e;
+                                        continue;
+                                    }
+                                };
+                                view.remove_transfer(transfer, true);
+                                let Some(peer) = peer else { continue };
+                                peer.voice_offers.remove(&transfer);
+                                let Some((name, size)) = peer
+                                    .incoming_offers
+                                    .remove(&transfer)
+                                    .map(|(name, size, _)| (name, size))
+                                    .or_else(|| peer.incoming_folders.remove(&transfer).map(|(name, _, size)| (format!("{}/", name), size)))
+                                else {
+                                    continue;
+                                };
+                                peer.handle.send(&Message::FileReject(transfer)).await;
+                                file_event(
+                                    view,
+                                    storage.as_ref(),
+                                    MessageDirection::Sent,
+                                    FileRecord::new(FileEvent::Rejected, true, &name, size),
+                                    format!("[file] rejected {}", name),
+                                    settings,
+                                );
+                            } else if text == "/files" {
+                                let view = app.view_mut();
+                                let (Some(s), Some(conversation)) = (storage.as_ref(), view.conversation.as_deref()) else {
+                                    view.status = "no saved history in this tab".to_string();
+                                    continue;
+                                };
+                                let transfers = match completed_files(s, conversation) {
+                                    Ok(t) => t,
+                                    Err(e) => {
+                                        view.status = format!("history error: {}", e);
+                                        continue;
+                                    }
+                                };
+                                if transfers.is_empty() {
+                                    view.add_plain_message(
+                                        MessageDirection::System,
+                                        "[files] no files sent or received in this conversation".to_string(),
+                                        settings.now(),
+                                    );
                                 }
-                            }
-                            files::ParsedMessage::TypingStop => {
-                                if typing_indicators {
-                                    app.peer_typing = false;
-                                    app.status = app.status.replace(" | peer is typing...", "");
+                                for (n, (timestamp, file)) in transfers.iter().enumerate() {
+                                    let checksum = file.checksum.as_ref().map_or("xxh3 none".to_string(), |sum| {
+                                        format!("{} {}", files::checksum_kind(sum), hex::encode(sum))
+                                    });
+                                    let line = match file.saved {
+                                        Some(ref saved) => format!(
+                                            "[files] {}. {} ({}) received {}, {}, in {}",
+                                            n + 1,
+                                            file.name,
+                                            files::format_size(file.size),
+                                            settings.format(*timestamp),
+                                            checksum,
+                                            saved
+                                        ),
+                                        None => format!(
+                                            "[files] {}. {} ({}) sent {}, {}",
+                                            n + 1,
+                                            file.name,
+                                            files::format_size(file.size),
+                                            settings.format(*timestamp),
+                                            checksum
+                                        ),
+                                    };
+                                    view.add_plain_message(MessageDirection::System, line, settings.now());
                                 }
-                            }
-                            files::ParsedMessage::Delivered => {
-                                if delivery_receipts && app.pending_delivery > 0 {
-                                    app.pending_delivery -= 1;
-                                    app.mark_last_sent_delivered();
+                            } else if let Some(arg) = text.strip_prefix("/extract ") {
+                                let view = app.view_mut();
+                                let (Some(s), Some(conversation)) = (storage.as_ref(), view.conversation.as_deref()) else {
+                                    view.status = "no saved history in this tab".to_string();
+                                    continue;
+                                };
+                                let transfers = match completed_files(s, conversation) {
+                                    Ok(t) => t,
+                                    Err(e) => {
+                                        view.status = format!("history error: {}", e);
+                                        continue;
+                                    }
+                                };
+                                let Some((_, file)) = arg.trim().parse::<usize>().ok().and_then(|n| transfers.get(n.wrapping_sub(1))) else {
+                                    view.status = "usage: /extract <n>, numbered as in /files".to_string();
+                                    continue;
+                                };
+                                let Some(SavedFile::Vault(attachment)) = file.saved else {
+                                    view.status = format!("{} is not in the vault", file.name);
+                                    continue;
+                                };
+                                let result = files::create_download(&file.name).and_then(|(path, out)| {
+                                    let mut out = std::io::BufWriter::new(out);
+                                    let written = s
+                                        .read_attachment(attachment, &mut out)
+                                        .and_then(|_| Ok(std::io::Write::flush(&mut out)?));
+                                    if written.is_err() {
+                                        drop(out);
+                                        let _ = std::fs::remove_file(&path);
+                                    }
+                                    written.map(|_| path)
+                                });
+                                match result {
+                                    Ok(path) => view.add_plain_message(
+                                        MessageDirection::System,
+                                        format!("[files] extracted {} to {}, it is no longer encrypted there", file.name, path.display()),
+                                        settings.now(),
+                                    ),
+                                    Err(e) => view.status = format!("extract failed: {}", e),
                                 }
-                            }
-                            files::ParsedMessage::Ping => {
-                                let _ = np.send(&files::encode_pong()).await;
-                            }
-                            files::ParsedMessage::Pong => {
-                                if awaiting_ping_response {
-                                    app.add_plain_message(
-                                        MessageDirection::Received,
-                                        "Pong!".to_string(),
-                                        tui::now_timestamp(time_local, hour24, show_tz, show_seconds),
-                                    );
+                            } else if text == "/cleardb" {
+                                let view = app.view_mut();
+                                let (Some(s), Some(conversation)) = (storage.as_mut(), view.conversation.as_deref()) else {
+                                    view.status = "no saved history in this tab".to_string();
+                                    continue;
+                                };
+                                match s.delete_conversation(conversation) {
+                                    Ok(n) => {
+                                        view.messages.clear();
+                                        view.add_plain_message(
+                                            MessageDirection::System,
+                                            format!("[history] deleted {} saved message(s) of this conversation", n),
+                                            settings.now(),
+                                        );
+                                    }
+                                    Err(e) => view.status = format!("failed to clear history: {}", e),
+                                }
+                            } else if let Some(label) = text.strip_prefix("/label ") {
+                                let label = label.trim();
+                                let view = app.view_mut();
+                                let (Some(s), Some(conversation)) = (storage.as_ref(), view.conversation.clone()) else {
+                                    view.status = "no saved history in this tab".to_string();
+                                    continue;
+                                };
+                                if !valid_conversation_label(label) {
+                                    view.status = "labels may only use letters, digits, - and _".to_string();
+                                    continue;
+                                }
+                                // identified peers are found again by their key or address
+                                if !conversation.starts_with("session:") && !conversation.starts_with("label:") {
+                                    view.status = "this conversation is already kept by the peer's key or address".to_string();
+                                    continue;
                                 }
-                                awaiting_ping_response = false;
-                            }
-                            files::ParsedMessage::Away => {
-                                app.peer_away = true;
-                                if !app.status.contains("| peer is away") {
-                                    let base = app.status.replace(" | peer is away", "");
-                                    app.status = format!("{} | peer is away", base);
+                                let target = format!("label:{}", label);
+                                if target == conversation {
+                                    continue;
                                 }
-                            }
-                            files::ParsedMessage::Back => {
-                                app.peer_away = false;
-                                app.status = app.status.replace(" | peer is away", "");
-                            }
-                        }
-                    }
-                    Err(_) => {
-                        app.status = "peer disconnected".to_string();
-                        terminal.draw(|f| app.draw(f))?;
-                        break;
-                    }
-                }
-            }
-            event = events.next() => {
-                match event {
-                    Some(Ok(Event::Key(key))) => {
-                        last_activity = tokio::time::Instant::now();
-                        if is_away {
-                            is_away = false;
-                            let _ = np.send(&files::encode_back()).await;
-                        }
-                        let submitted = app.handle_key(key);
-                        if typing_indicators {
-                                let now_empty = app.input.is_empty();
-                                if last_input_empty && !now_empty {
-                                    let _ = np.send(&files::encode_typing_start()).await;
-                                } else if !last_input_empty && now_empty {
-                                    let _ = np.send(&files::encode_typing_stop()).await;
+                                let result = s
+                                    .load_history(&target)
+                                    .and_then(|earlier| s.rename_conversation(&conversation, &target).map(|_| earlier));
+                                match result {
+                                    Ok(earlier) => {
+                                        show_history(view, earlier, settings);
+                                        view.conversation = Some(target);
+                                        view.add_plain_message(
+                                            MessageDirection::System,
+                                            format!("[history] this conversation is now saved as {}", label),
+                                            settings.now(),
+                                        );
+                                    }
+                                    Err(e) => view.status = format!("failed to label conversation: {}", e),
                                 }
-                                last_input_empty = now_empty;
-                            }
-                            if let Some(text) = submitted {
-                            if text.starts_with("/send ") {
-                                let path = text[6..].trim();
-                                match files::OutgoingFile::open(path) {
-                                    Ok(mut out) => {
-                                        if randomize_filenames {
-                                            out.name = files::randomize_filename_preserve_ext(&out.name);
-                                        }
-                                        if let Err(e) = np.send(
-                                            &files::encode_offer_with_checksum(&out.name, out.size, Some(&out.checksum)),
-                                        ).await {
-                                            app.status = format!("send failed: {}", e);
-                                        } else {
-                                            app.add_plain_message(
-                                                MessageDirection::Sent,
-                                                format!(
-                                                    "[file] offered {} ({}) - waiting for peer to accept",
-                                                    out.name,
-                                                    files::format_size(out.size)
-                                                ),
-                                                tui::now_timestamp(time_local, hour24, show_tz, show_seconds),
+                            } else if let Some(nickname) = text.strip_prefix("/contact save ") {
+                                let nickname = nickname.trim();
+                                let Some(peer) = peer else {
+                                    app.view_mut().status = "no active session".to_string();
+                                    continue;
+                                };
+                                let result = match *storage {
+                                    Some(ref s) => match s.find_contact(nickname) {
+                                        Ok(Some(_)) => Err(format!("contact '{}' already exists", nickname).into()),
+                                        Ok(None) => {
+                                            let mut contact = Contact::new(nickname, peer.ctx.peer_onion.clone());
+                                            contact.check_pins(
+                                                peer.ctx.peer_identity.as_deref(),
+                                                peer.ctx.peer_auth_pin.as_deref(),
                                             );
-                                            pending_offer = Some(out);
+                                            s.save_contact(&mut contact)
                                         }
+                                        Err(e) => Err(e),
+                                    },
+                                    None => Err("contacts require identity.persist = true".into()),
+                                };
+                                match result {
+                                    Ok(()) => {
+                                        peer.ctx.contact = Some(nickname.to_string());
+                                        app.view_mut().peer_label = nickname.to_string();
+                                        // incoming sessions are only matched to contacts by identity key
+                                        let note = if peer.ctx.peer_identity.is_none() {
+                                            " (no identity key, not verified on incoming sessions)"
+                                        } else {
+                                            ""
+                                        };
+                                        app.add_plain_message(
+                                            MessageDirection::System,
+                                            format!("[contact] saved peer as {}{}", nickname, note),
+                                            settings.now(),
+                                        );
                                     }
                                     Err(e) => {
-                                        app.status = format!("cannot open file: {}", e);
+                                        app.view_mut().status = format!("contact save failed: {}", e);
                                     }
                                 }
-                            } else if text == "/cancel" {
-                                if let Some(inc) = incoming_file.take() {
-                                    inc.cancel();
-                                        app.add_plain_message(
-                                            MessageDirection::Sent,
-                                            "[file] cancelled receiving".to_string(),
-                                            tui::now_timestamp(time_local, hour24, show_tz, show_seconds),
-                                        );
-                                    app.status = "cancelled incoming transfer".to_string();
-                                    app.clear_recv_progress();
-                                } else {
-                                    app.status = "no active incoming transfer".to_string();
+                            } else if let Some(args) = text.strip_prefix("/reply ") {
+                                let Some(peer) = peer else {
+                                    app.view_mut().status = "no active session".to_string();
+                                    continue;
+                                };
+                                let (n, reply) = split_message_number(args);
+                                let Some(target) = app.view().nth_last_text(MessageDirection::Received, n).map(|m| m.id) else {
+                                    app.view_mut().status = "no such message to reply to".to_string();
+                                    continue;
+                                };
+                                if reply.is_empty() {
+                                    app.view_mut().status = "usage: /reply [n] <text>".to_string();
+                                    continue;
+                                }
+                                send_text(app.view_mut(), peer, storage, settings, reply.to_string(), target).await;
+                            } else if let Some(new_text) = text.strip_prefix("/edit ") {
+                                let Some(peer) = peer.filter(|p| p.ctx.features.contains(Features::EDITS)) else {
+                                    app.view_mut().status = "no active session that supports edits".to_string();
+                                    continue;
+                                };
+                                let new_text = new_text.trim();
+                                let view = app.view_mut();
+                                let Some(id) = view.nth_last_text(MessageDirection::Sent, 1).map(|m| m.id) else {
+                                    view.status = "no sent message to edit".to_string();
+                                    continue;
+                                };
+                                if new_text.is_empty() || !peer.handle.send(&Message::Edit { id, text: new_text.to_string() }).await {
+                                    continue;
+                                }
+                                view.edit_text(MessageDirection::Sent, id, new_text);
+                                if let Some(Err(e)) = storage.as_ref().zip(view.conversation.as_deref()).map(|(s, c)| s.edit_message(c, MessageDirection::Sent, id, new_text.as_bytes())) {
+                                    view.status = format!("save error: {}", e);
+                                }
+                            } else if text == "/unsend" || text.starts_with("/unsend ") {
+                                let Some(peer) = peer.filter(|p| p.ctx.features.contains(Features::EDITS)) else {
+                                    app.view_mut().status = "no active session that supports deleting messages".to_string();
+                                    continue;
+                                };
+                                let (n, _) = split_message_number(&text[7..]);
+                                let view = app.view_mut();
+                                let Some(id) = view.nth_last_text(MessageDirection::Sent, n).map(|m| m.id) else {
+                                    view.status = "no such sent message".to_string();
+                                    continue;
+                                };
+                                if !peer.handle.send(&Message::Delete(id)).await {
+                                    continue;
+                                }
+                                view.delete_text(MessageDirection::Sent, id);
+                                if let Some(Err(e)) = storage.as_mut().zip(view.conversation.as_deref()).map(|(s, c)| s.delete_message(c, MessageDirection::Sent, id)) {
+                                    view.status = format!("save error: {}", e);
+                                }
+                            } else if text == "/react" || text.starts_with("/react ") {
+                                let Some(peer) = peer.filter(|p| p.ctx.features.contains(Features::REACTIONS)) else {
+                                    app.view_mut().status = "no active session that supports reactions".to_string();
+                                    continue;
+                                };
+                                let (n, emoji) = split_message_number(&text[6..]);
+                                if emoji.chars().count() > MAX_REACTION_CHARS {
+                                    app.view_mut().status = "a reaction is a single emoji".to_string();
+                                    continue;
+                                }
+                                let view = app.view_mut();
+                                let Some(id) = view.nth_last_text(MessageDirection::Received, n).map(|m| m.id) else {
+                                    view.status = "no such message to react to".to_string();
+                                    continue;
+                                };
+                                if !peer.handle.send(&Message::React { id, emoji: emoji.to_string() }).await {
+                                    continue;
+                                }
+                                view.set_reaction(MessageDirection::Received, id, MessageDirection::Sent, emoji);
+                                let stored = (!emoji.is_empty()).then_some(emoji);
+                                if let Some(Err(e)) = storage.as_ref().zip(view.conversation.as_deref()).map(|(s, c)| s.set_reaction(c, id, MessageDirection::Sent, stored)) {
+                                    view.status = format!("save error: {}", e);
                                 }
-                            } else if text == "/accept" {
-                                if incoming_file.is_some() {
-                                    app.status = "transfer already in progress".to_string();
-                                } else if let Some(ref offer) = app.pending_incoming_offer {
-                                    let name = offer.0.clone();
-                                    let size = offer.1;
-                                    let checksum = offer.2.as_ref();
-                                    let existing = match files::existing_download_size(&name) {
-                                        Ok(n) => n,
-                                        Err(_) => 0,
+                            } else if text == "/expire" || text.starts_with("/expire ") {
+                                let Some(peer) = peer.filter(|p| p.ctx.features.contains(Features::EXPIRY)) else {
+                                    app.view_mut().status = "no active session that supports expiry".to_string();
+                                    continue;
+                                };
+                                let arg = text[7..].trim();
+                                let view = app.view_mut();
+                                if arg.is_empty() {
+                                    let current = match view.expire_after {
+                                        0 => "off".to_string(),
+                                        secs => format_duration(secs),
                                     };
-
-                                    if existing == size && checksum.is_some() {
-                                        if let Ok(path) = files::download_path(&name) {
-                                                if let Ok(sum) = files::file_xxh3(&path) {
-                                                if &sum == checksum.unwrap() {
-                                                    app.add_plain_message(
-                                                        MessageDirection::Received,
-                                                        format!("[file] already downloaded {}", name),
-                                                        tui::now_timestamp(time_local, hour24, show_tz, show_seconds),
-                                                    );
-                                                    app.pending_incoming_offer = None;
-                                                    continue;
-                                                }
-                                            }
-                                        }
-                                    }
-                                    if let Err(e) = np
-                                        .send(&files::encode_accept_with_offset(existing))
-                                        .await
-                                    {
-                                        app.status = format!("send failed: {}", e);
-                                    } else {
-                                        match files::IncomingFile::begin(&name, size, offer.2.as_deref()) {
-                                            Ok(inc) => {
-                                                app.set_recv_progress(name.clone(), size);
-                                                incoming_file = Some(inc);
-                                                app.pending_incoming_offer = None;
-                                                app.add_plain_message(
-                                                    MessageDirection::Sent,
-                                                    format!("[file] accepted {}", name),
-                                                    tui::now_timestamp(time_local, hour24, show_tz, show_seconds),
-                                                );
-                                            }
-                                            Err(e) => {
-                                                app.status = format!("file receive error: {}", e);
-                                            }
-                                        }
+                                    view.add_plain_message(
+                                        MessageDirection::System,
+                                        format!("[expire] current: {}. usage: /expire <30m | 12h | 7d | off> or /expire accept", current),
+                                        settings.now(),
+                                    );
+                                } else if arg == "accept" {
+                                    let Some(secs) = peer.expire_offered.take() else {
+                                        view.status = "the peer has not proposed an expiry".to_string();
+                                        continue;
+                                    };
+                                    if peer.handle.send(&Message::ExpireAccept(secs)).await {
+                                        apply_expiry(view, storage, secs, settings);
                                     }
                                 } else {
-                                    app.status = "no pending file offer".to_string();
-                                }
-                            } else if text == "/reject" {
-                                if let Some(ref offer) = app.pending_incoming_offer {
-                                    let name = offer.0.clone();
-                                    let _ = np.send(&files::encode_reject()).await;
-                                    app.pending_incoming_offer = None;
-                                    app.add_plain_message(
-                                        MessageDirection::Sent,
-                                        format!("[file] rejected {}", name),
-                                        tui::now_timestamp(time_local, hour24, show_tz, show_seconds),
+                                    let secs = if arg == "off" { Some(0) } else { parse_duration(arg) };
+                                    let Some(secs) = secs else {
+                                        view.status = "expiry must look like 30m, 12h, 7d or off".to_string();
+                                        continue;
+                                    };
+                                    if !peer.handle.send(&Message::Expire(secs)).await {
+                                        continue;
+                                    }
+                                    peer.expire_proposed = Some(secs);
+                                    view.add_plain_message(
+                                        MessageDirection::System,
+                                        format!("[expire] proposed {}, waiting for the peer to accept", if secs == 0 { "off".to_string() } else { format_duration(secs) }),
+                                        settings.now(),
                                     );
-                                } else {
-                                    app.status = "no pending file offer".to_string();
                                 }
-                            } else if text == "/cleardb" {
-                                let owned_storage = storage.take();
-                                if let Err(e) = clear_history(owned_storage) {
-                                    eprintln!("failed to clear database: {}", e);
+                            } else if text == "/sessions" {
+                                let ts = settings.now();
+                                let lines: Vec<String> = app
+                                    .views
+                                    .iter()
+                                    .enumerate()
+                                    .map(|(i, v)| {
+                                        let state = if sessions.contains_key(&v.id) { "connected" } else { "closed" };
+                                        format!("[session] {}: {} - {} ({} unread)", i + 1, v.peer_label, state, v.unread)
+                                    })
+                                    .collect();
+                                for line in lines {
+                                    app.add_plain_message(MessageDirection::System, line, ts.clone());
+                                }
+                            } else if text == "/close" {
+                                sessions.remove(&active_id);
+                                if listener.is_none() && sessions.is_empty() {
+                                    break;
+                                }
+                                if app.views.len() > 1 {
+                                    app.remove_view(app.active);
+                                } else {
+                                    app.view_mut().status = "session closed".to_string();
                                 }
-                                app.messages.clear();
-                                app.add_plain_message(
-                                    MessageDirection::System,
-                                    "database cleared".to_string(),
-                                    tui::now_timestamp(time_local, hour24, show_tz, show_seconds)
-                                );
                             } else if text == "/clear" {
-                                app.messages.clear();
+                                app.view_mut().messages.clear();
                             } else if text == "/panic" || text == "/wipe" {
                                 let owned_storage = storage.take();
                                 if let Err(e) = perform_panic_and_exit(owned_storage) {
@@ -705,11 +2484,12 @@ where
                             } else if text == "/help" {
                                 app.add_plain_message(
                                     MessageDirection::System,
-                                    "[help] available commands: /clear, /help, /status, /send, /ping, /panic, /wipe, /find".to_string(),
-                                    tui::now_timestamp(time_local, hour24, show_tz, show_seconds),
+                                    "[help] available commands: /clear, /help, /status, /send <path>, /paste [path], /voice [file.wav], /play [n], /accept [n], /reject [n], /cancel [n], /ping, /panic, /wipe, /find, /files, /extract <n>, /cleardb, /label <name>, /exportchat [format] [--encrypt], /contact save <nickname>, /reply [n] <text>, /edit <text>, /unsend [n], /react [n] <emoji>, /expire [period], /rekey, /sessions, /close".to_string(),
+                                    settings.now(),
                                 );
                             } else if text == "/status" {
-                                let ts = tui::now_timestamp(time_local, hour24, show_tz, show_seconds);
+                                let ts = settings.now();
+                                let status_ctx = peer.as_deref().map(|p| &p.ctx).unwrap_or(&base_ctx);
                                 let bs_line = if let Some(s) = status_ctx.bootstrap_secs {
                                     format!("[status] tor: connected ({:.1}s bootstrap)", s)
                                 } else {
@@ -731,21 +2511,54 @@ where
                                         ts.clone(),
                                     );
                                 }
-                                let peer_version_str = if let Some((maj, min, pat)) = status_ctx.peer_version {
-                                    format!("{}.{}.{}", maj, min, pat)
+                                let peer_protocol_str = if let Some(ref hello) = status_ctx.peer_hello {
+                                    let (maj, min, pat) = hello.app_version;
+                                    format!("{} (circuitchat {}.{}.{})", hello.protocol, maj, min, pat)
                                 } else {
                                     "unknown".to_string()
                                 };
                                 app.add_plain_message(
                                     MessageDirection::System,
-                                    format!("[status] peer protocol version: {}", peer_version_str),
-                                    tui::now_timestamp(time_local, hour24, show_tz, show_seconds),
+                                    format!("[status] peer protocol version: {}", peer_protocol_str),
+                                    ts.clone(),
+                                );
+                                app.add_plain_message(
+                                    MessageDirection::System,
+                                    format!("[status] protocol version: {}", PROTOCOL_VERSION),
+                                    ts.clone(),
                                 );
-                                let (our_major, our_minor, our_patch) = files::protocol_version();
+                                if let Some(peer) = peer.as_deref() {
+                                    let framing = match peer.handle.framing {
+                                        Framing::Legacy => "legacy",
+                                        Framing::Envelope => "envelope",
+                                    };
+                                    let last = match peer.ctx.last_ignored {
+                                        Some((kind, len)) => format!(", last type 0x{:02x} ({} bytes)", kind, len),
+                                        None => String::new(),
+                                    };
+                                    app.add_plain_message(
+                                        MessageDirection::System,
+                                        format!(
+                                            "[status] framing: {} ({} unknown messages ignored{})",
+                                            framing, peer.ctx.ignored_messages, last
+                                        ),
+                                        ts.clone(),
+                                    );
+                                }
+                                let features = if status_ctx.peer_hello.is_some() {
+                                    status_ctx.features
+                                } else {
+                                    settings.features
+                                };
+                                let names = features.names();
                                 app.add_plain_message(
                                     MessageDirection::System,
-                                    format!("[status] protocol version: {}.{}.{}", our_major, our_minor, our_patch),
-                                    tui::now_timestamp(time_local, hour24, show_tz, show_seconds),
+                                    format!(
+                                        "[status] {}: {}",
+                                        if status_ctx.peer_hello.is_some() { "shared features" } else { "features" },
+                                        if names.is_empty() { "none".to_string() } else { names.join(", ") }
+                                    ),
+                                    ts.clone(),
                                 );
                                 let identity_line = if status_ctx.identity_persist {
                                     "[status] identity: persistent".to_string()
@@ -753,178 +2566,178 @@ where
                                     "[status] identity: ephemeral".to_string()
                                 };
 
-                                let history_line = if status_ctx.history_saving {
+                                let local_identity_line = match status_ctx.local_identity {
+                                    Some(ref key) => format!("[status] identity key: {}", hex::encode(key)),
+                                    None => "[status] identity key: none (ephemeral)".to_string(),
+                                };
+                                let peer_identity_line = match status_ctx.peer_identity {
+                                    Some(ref key) => format!("[status] peer identity key: {}", hex::encode(key)),
+                                    None => "[status] peer identity key: none (unauthenticated)".to_string(),
+                                };
+
+                                let history_line = if let Some(ref conversation) = app.view().conversation {
+                                    format!("[status] history: saving as {} (encrypted)", conversation)
+                                } else if status_ctx.history_saving {
                                     "[status] history: saving (encrypted)".to_string()
                                 } else {
                                     "[status] history: disabled".to_string()
                                 };
+                                let expiry_line = match app.view().expire_after {
+                                    0 => "[status] expiry: off".to_string(),
+                                    secs => format!("[status] expiry: messages are deleted after {}", format_duration(secs)),
+                                };
+                                let sessions_line = format!("[status] sessions: {} connected", sessions.len());
 
-                                app.add_plain_message(
-                                    MessageDirection::System,
+                                for line in [
                                     bs_line,
-                                    ts.clone(),
-                                );
-                                app.add_plain_message(
-                                    MessageDirection::System,
                                     bridges_line,
-                                    ts.clone(),
-                                );
-                                app.add_plain_message(
-                                    MessageDirection::System,
                                     identity_line,
-                                    ts.clone(),
-                                );
-                                app.add_plain_message(
-                                    MessageDirection::System,
+                                    local_identity_line,
+                                    peer_identity_line,
                                     history_line,
-                                    ts,
-                                );
+                                    expiry_line,
+                                    sessions_line,
+                                ] {
+                                    app.add_plain_message(MessageDirection::System, line, ts.clone());
+                                }
                             } else if text == "/ping" {
-                                awaiting_ping_response = true;
+                                let Some(peer) = peer else {
+                                    app.view_mut().status = "no active session".to_string();
+                                    continue;
+                                };
+                                peer.awaiting_ping_response = true;
                                 app.add_plain_message(
                                     MessageDirection::Sent,
                                     "Ping?".to_string(),
-                                    tui::now_timestamp(time_local, hour24, show_tz, show_seconds),
+                                    settings.now(),
                                 );
-                                let _ = np.send(&files::encode_ping()).await;
-                            } else if text == "/exportchat" {
+                                peer.handle.send(&Message::Ping).await;
+                            } else if text == "/exportchat" || text.starts_with("/exportchat ") {
+                                let mut args: Vec<&str> = text.split_whitespace().skip(1).collect();
+                                let encrypt = args.contains(&"--encrypt");
+                                args.retain(|a| *a != "--encrypt");
+                                let format = match args[..] {
+                                    [] => Some(export::Format::Text),
+                                    [name] => export::Format::parse(name),
+                                    _ => None,
+                                };
+                                let Some(format) = format else {
+                                    app.view_mut().status =
+                                        "usage: /exportchat [text|json|markdown|html] [--encrypt]".to_string();
+                                    continue;
+                                };
                                 let dir = match files::exports_dir() {
                                     Ok(d) => d,
                                     Err(e) => {
-                                        app.status = format!("export failed: {}", e);
+                                        app.view_mut().status = format!("export failed: {}", e);
                                         continue;
                                     }
                                 };
                                 if let Err(e) = std::fs::create_dir_all(&dir) {
-                                    app.status = format!("export failed: {}", e);
+                                    app.view_mut().status = format!("export failed: {}", e);
                                     continue;
                                 }
 
                                 let now = Local::now();
                                 let now_formatted = now.format("%Y-%m-%d_%H-%M-%S");
-                                let filename = format!("chat_export_{}.txt", now_formatted);
+                                let mut filename = format!("chat_export_{}.{}", now_formatted, format.extension());
+                                if encrypt {
+                                    filename.push_str(".enc");
+                                }
                                 let path = dir.join(&filename);
 
-                                match std::fs::File::create(&path) {
-                                    Ok(mut f) => {
-                                        // from storage if available
-                                        let res = if let Some(ref s) = *storage {
-                                            match s.load_history() {
-                                                Ok(messages) => {
-                                                    for msg in messages {
-                                                        let prefix = match msg.direction {
-                                                            MessageDirection::Sent => "you",
-                                                            MessageDirection::Received => "peer",
-                                                            MessageDirection::System => "system",
-                                                        };
-                                                        let time = tui::format_timestamp(
-                                                            msg.timestamp,
-                                                            time_local,
-                                                            hour24,
-                                                            show_tz,
-                                                            show_seconds,
-                                                        );
-                                                        let text = String::from_utf8_lossy(&msg.content);
-                                                        if let Err(e) = writeln!(f, "[{}] {}: {}", time, prefix, text)
-                                                        {
-                                                            return Err(Box::new(e));
-                                                        }
-                                                    }
-                                                    Ok(())
-                                                }
-                                                Err(e) => Err(e),
-                                            }
-                                        } else {
-                                            // export from in memory messages
-                                            for m in &app.messages {
-                                                let prefix = match m.direction {
-                                                    MessageDirection::Sent => "you",
-                                                    MessageDirection::Received => "peer",
-                                                    MessageDirection::System => "system",
-                                                };
-                                                let text: String = m.content.iter().map(|(s, _)| s.as_str()).collect();
-                                                if let Err(e) = writeln!(f, "[{}] {}: {}", m.timestamp, prefix, text) {
-                                                    return Err(Box::new(e));
-                                                }
-                                            }
-                                            Ok(())
-                                        };
-
-                                        match res {
-                                            Ok(()) => {
-                                                app.add_plain_message(
-                                                    MessageDirection::System,
-                                                    format!("[export] saved chat to {}", path.display()),
-                                                    tui::now_timestamp(time_local, hour24, show_tz, show_seconds),
-                                                );
-                                            }
-                                            Err(e) => {
-                                                app.status = format!("export failed: {}", e);
-                                            }
-                                        }
+                                let content = match build_export(app.view(), storage.as_ref(), settings)
+                                    .and_then(|e| e.render(format))
+                                {
+                                    Ok(c) => c,
+                                    Err(e) => {
+                                        app.view_mut().status = format!("export failed: {}", e);
+                                        continue;
+                                    }
+                                };
+                                if encrypt {
+                                    secret = Some(SecretPrompt {
+                                        purpose: SecretPurpose::Export(path, content),
+                                        first: None,
+                                    });
+                                    app.secret_prompt = Some("export passphrase".to_string());
+                                    continue;
+                                }
+                                match std::fs::write(&path, &content) {
+                                    Ok(()) => {
+                                        app.add_plain_message(
+                                            MessageDirection::System,
+                                            format!("[export] saved chat to {}", path.display()),
+                                            settings.now(),
+                                        );
                                     }
                                     Err(e) => {
-                                        app.status = format!("export failed: {}", e);
+                                        app.view_mut().status = format!("export failed: {}", e);
                                     }
                                 }
-                            } else if text.starts_with("/find") {
-                                let ts = tui::now_timestamp(time_local, hour24, show_tz, show_seconds);
-                                let term = text[6..].trim();
-                                if term.is_empty() {
-                                    app.add_plain_message(MessageDirection::System, "[find] usage: /find <term>".to_string(), ts);
-                                } else if let Some(s) = storage {
-                                    match s.search_history(term) {
+                            } else if text == "/find next" || text == "/find prev" {
+                                let Some(ref mut results) = find else {
+                                    app.view_mut().status = "no search results to page through".to_string();
+                                    continue;
+                                };
+                                if text == "/find next" && (results.page + 1) * FIND_PAGE < results.messages.len() {
+                                    results.page += 1;
+                                } else if text == "/find prev" && results.page > 0 {
+                                    results.page -= 1;
+                                } else {
+                                    app.view_mut().status = "no more results".to_string();
+                                    continue;
+                                }
+                                show_find_page(app.view_mut(), results, settings);
+                            } else if let Some(query) = text.strip_prefix("/find") {
+                                let ts = settings.now();
+                                let query = query.trim();
+                                let parsed = match storage::SearchQuery::parse(query) {
+                                    Ok(parsed) => parsed,
+                                    Err(e) => {
+                                        app.add_plain_message(
+                                            MessageDirection::System,
+                                            format!("[find] {}. usage: /find <words | \"phrase\"> [from:you | from:peer] [before:YYYY-MM-DD] [after:YYYY-MM-DD]", e),
+                                            ts,
+                                        );
+                                        continue;
+                                    }
+                                };
+                                if let (Some(s), Some(conversation)) = (storage.as_ref(), app.view().conversation.clone()) {
+                                    match s.search_history(&conversation, &parsed) {
                                         Ok(results) if results.is_empty() => {
-                                            app.add_plain_message(MessageDirection::System, format!("[find] no results for '{}'", term), ts);
+                                            app.add_plain_message(MessageDirection::System, format!("[find] no results for '{}'", query), ts);
                                         }
-                                        Ok(results) => {
-                                            app.add_plain_message(MessageDirection::System, format!("[find] {} result(s) for '{}':", results.len(), term), ts.clone());
-                                            for msg in results {
-                                                let text = String::from_utf8_lossy(&msg.content).to_string();
-                                                let prefix = match msg.direction {
-                                                    MessageDirection::Sent => "you",
-                                                    MessageDirection::Received => "peer",
-                                                    MessageDirection::System => "system",
-                                                };
-                                                let spans = tui::highlighted(&format!("[{}] {}", prefix, text), term);
-                                                app.add_message(
-                                                    MessageDirection::System,
-                                                    spans,
-                                                    tui::format_timestamp(msg.timestamp, time_local, hour24, show_tz, show_seconds),
-                                                );
-                                            }
+                                        Ok(mut messages) => {
+                                            // newest first
+                                            messages.reverse();
+                                            let results = find.insert(FindResults {
+                                                query: query.to_string(),
+                                                terms: parsed.terms,
+                                                messages,
+                                                page: 0,
+                                            });
+                                            show_find_page(app.view_mut(), results, settings);
                                         }
                                         Err(e) => {
                                             app.add_plain_message(MessageDirection::System, format!("[find] error: {}", e), ts);
                                         }
                                     }
                                 } else {
-                                    app.add_plain_message(MessageDirection::System, "[find] no storage available".to_string(), ts);
+                                    app.add_plain_message(MessageDirection::System, "[find] no saved history in this tab".to_string(), ts);
                                 }
                             } else {
-                                let bytes = text.as_bytes().to_vec();
-                                if let Err(e) = np.send(&bytes).await {
-                                    app.status = format!("send failed: {}", e);
-                                    terminal.draw(|f| app.draw(f))?;
-                                    break;
-                                }
-                                app.add_plain_message(
-                                    MessageDirection::Sent,
-                                    text,
-                                    tui::now_timestamp(time_local, hour24, show_tz, show_seconds),
-                                );
-                                if typing_indicators {
-                                    let _ = np.send(&files::encode_typing_stop()).await;
-                                    last_input_empty = true;
-                                }
-                                if delivery_receipts {
-                                    app.pending_delivery += 1;
+                                let Some(peer) = peer else {
+                                    app.view_mut().status = "no active session".to_string();
+                                    continue;
+                                };
+                                if !send_text(app.view_mut(), peer, storage, settings, text, 0).await {
+                                    continue;
                                 }
-                                if let Some(ref s) = *storage {
-                                    if let Err(e) = s.save_message(MessageDirection::Sent, &bytes) {
-                                        app.status = format!("save error: {}", e);
-                                    }
+                                if peer.ctx.features.contains(Features::TYPING) {
+                                    peer.handle.send(&Message::TypingStop).await;
                                 }
+                                last_input_empty = true;
                             }
                         }
                         if app.should_quit {
@@ -940,6 +2753,10 @@ where
     }
 
     ratatui::restore();
+    // deletions since the last expiry tick
+    if let Some(Err(e)) = storage.as_mut().map(|s| s.ratchet_pending()) {
+        eprintln!("could not replace the database key after deleting messages: {}", e);
+    }
     Ok(())
 }
 
@@ -947,21 +2764,25 @@ async fn run_initiator(
     tor: &TorClient<PreferredRuntime>,
     peer_onion: &str,
     mut storage: Option<Storage>,
-    time_local: bool,
-    hour24: bool,
-    show_seconds: bool,
-    show_tz: bool,
+    identity: Option<&StaticKeypair>,
+    peer_key: Option<Vec<u8>>,
+    mut contact: Option<Contact>,
     auth_enabled: bool,
     password: String,
-    typing_indicators: bool,
-    delivery_receipts: bool,
-    randomize_filenames: bool,
-    message_notification_sound: bool,
-    mention_notification_sound: bool,
+    settings: &ChatSettings,
 ) -> Result<(), Box<dyn Error>> {
     let mut prefs = StreamPrefs::new();
     prefs.connect_to_onion_services(arti_client::config::BoolOrAuto::Explicit(true));
 
+    if peer_key.is_some() && identity.is_none() {
+        return Err("--peer-key requires identity.static_key = true".into());
+    }
+    let pinned_key = contact
+        .as_ref()
+        .and_then(|c| c.pinned_key.as_deref())
+        .and_then(|k| hex::decode(k).ok());
+    let mut ik_key = peer_key.clone().or(pinned_key);
+
     let start = std::time::Instant::now();
     let mut attempt = 0u32;
     loop {
@@ -979,47 +2800,70 @@ async fn run_initiator(
         match tor.connect_with_prefs((peer_onion, 9999u16), &prefs).await {
             Ok(stream) => {
                 println!("connected in {:.1}s", start.elapsed().as_secs_f64());
-                let mut np = NoisePeer::connect(stream, PATTERN).await.map_err(|e| {
-                    eprintln!("initiator handshake failed: {}", e);
-                    e
-                })?;
+                let handshake = match identity {
+                    Some(id) => NoisePeer::connect_with_identity(stream, id, ik_key.as_deref()).await,
+                    None => NoisePeer::connect(stream, PATTERN).await,
+                };
+                let mut np = match handshake {
+                    Ok(np) => np,
+                    Err(e) if ik_key.is_some() => {
+                        eprintln!("IK handshake failed ({}), retrying with XX", e);
+                        ik_key = None;
+                        continue;
+                    }
+                    Err(e) => {
+                        eprintln!("initiator handshake failed: {}", e);
+                        return Err(e);
+                    }
+                };
+                if peer_key.is_some() && np.remote_static != peer_key {
+                    return Err("peer identity key does not match --peer-key".into());
+                }
                 let auth_pw = if auth_enabled {
                     Some(password.clone())
                 } else {
                     None
                 };
-                np.auth_initiator(auth_pw.as_deref()).await?;
-                let mut status_ctx = StatusContext {
+                np.auth_initiator(|| match auth_pw {
+                    Some(pw) => Ok(pw),
+                    None => Ok(rpassword::prompt_password("peer requires a password: ")?),
+                })
+                .await?;
+                let mut password_owned = password;
+                password_owned.zeroize();
+
+                let (events_tx, events_rx) = mpsc::unbounded_channel();
+                let handle = session::spawn(1, np, events_tx.clone());
+                let identity_warning = contact
+                    .as_mut()
+                    .and_then(|c| check_contact(storage.as_ref(), c, &handle));
+                let status_ctx = StatusContext {
                     bootstrap_secs: None,
                     bridges_configured: 0,
                     bridges_active: false,
                     identity_persist: storage.is_some(),
                     onion_addr: None,
                     history_saving: storage.is_some(),
-                    peer_version: None,
+                    peer_hello: None,
+                    ignored_messages: 0,
+                    last_ignored: None,
+                    features: Features::empty(),
+                    local_identity: identity.map(|id| id.public.clone()),
+                    peer_identity: handle.remote_static.clone(),
+                    peer_onion: Some(peer_onion.to_string()),
+                    peer_auth_pin: handle.auth_pin.clone(),
+                    contact: contact.as_ref().map(|c| c.nickname.clone()),
+                    identity_warning,
                 };
-                let mut password_owned = password;
-                password_owned.zeroize();
-                let initial_status = "connected";
 
                 return chat_loop(
-                    np,
+                    Some((handle, status_ctx.clone())),
+                    None,
+                    events_tx,
+                    events_rx,
                     &mut storage,
-                    &initial_status,
-                    &mut status_ctx,
-                    time_local,
-                    hour24,
-                    show_seconds,
-                    show_tz,
-                    typing_indicators,
-                    delivery_receipts,
-                    randomize_filenames,
-                    message_notification_sound,
-                    mention_notification_sound,
-                    crate::config::load_or_create()?
-                        .privacy
-                        .session_timeout_mins,
-                    crate::config::load_or_create()?.privacy.idle_away_mins,
+                    status_ctx,
+                    settings,
                 )
                 .await;
             }
@@ -1036,21 +2880,16 @@ async fn run_initiator(
     }
 }
 
-async fn run_responder(
+async fn launch_listener(
     tor: &TorClient<PreferredRuntime>,
-    mut storage: Option<Storage>,
-    time_local: bool,
-    hour24: bool,
-    show_seconds: bool,
-    show_tz: bool,
-    auth_enabled: bool,
-    password: String,
-    typing_indicators: bool,
-    delivery_receipts: bool,
-    randomize_filenames: bool,
-    message_notification_sound: bool,
-    mention_notification_sound: bool,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<
+    (
+        Arc<RunningOnionService>,
+        String,
+        impl futures::Stream<Item = StreamRequest> + use<>,
+    ),
+    Box<dyn Error>,
+> {
     let config = OnionServiceConfigBuilder::default()
         .nickname("circuitchat".to_owned().try_into()?)
         .build()?;
@@ -1119,79 +2958,60 @@ async fn run_responder(
     );
     println!("share your address with your peer. waiting for connection...");
 
-    let mut stream_requests = handle_rend_requests(rend_requests);
+    Ok((service, addr_str, handle_rend_requests(rend_requests)))
+}
 
-    while let Some(stream_request) = stream_requests.next().await {
-        let data_stream = match stream_request.accept(Connected::new_empty()).await {
-            Ok(s) => s,
-            Err(e) => {
-                eprintln!("failed to accept incoming connection: {}", e);
-                continue;
-            }
-        };
+async fn run_responder(
+    tor: &TorClient<PreferredRuntime>,
+    mut storage: Option<Storage>,
+    identity: Option<&StaticKeypair>,
+    auth_enabled: bool,
+    mut password: String,
+    settings: &ChatSettings,
+) -> Result<(), Box<dyn Error>> {
+    let (_service, addr_str, stream_requests) = launch_listener(tor).await?;
 
-        let mut np = match NoisePeer::accept(data_stream, PATTERN).await {
-            Ok(n) => n,
-            Err(e) => {
-                eprintln!("responder handshake failed: {}", e);
-                continue;
-            }
-        };
-        let mut auth_pw = if auth_enabled {
+    let listener = Listener {
+        requests: Box::pin(stream_requests),
+        identity: identity.cloned(),
+        password: if auth_enabled {
             Some(password.clone())
         } else {
             None
-        };
-        if let Err(e) = np.auth_responder(auth_pw.as_deref()).await {
-            if let Some(ref mut p) = auth_pw {
-                p.zeroize();
-            }
-            eprintln!("authentication failed: {}", e);
-            continue;
-        }
-        if let Some(ref mut p) = auth_pw {
-            p.zeroize();
-        }
-        let status = "connected".to_string();
-
-        let mut status_ctx = StatusContext {
-            bootstrap_secs: None,
-            bridges_configured: 0,
-            bridges_active: false,
-            identity_persist: storage.is_some(),
-            onion_addr: Some(addr_str.clone()),
-            history_saving: storage.is_some(),
-            peer_version: None,
-        };
-
-        if let Err(e) = chat_loop(
-            np,
-            &mut storage,
-            &status,
-            &mut status_ctx,
-            time_local,
-            hour24,
-            show_seconds,
-            show_tz,
-            typing_indicators,
-            delivery_receipts,
-            randomize_filenames,
-            message_notification_sound,
-            mention_notification_sound,
-            crate::config::load_or_create()?
-                .privacy
-                .session_timeout_mins,
-            crate::config::load_or_create()?.privacy.idle_away_mins,
-        )
-        .await
-        {
-            eprintln!("chat loop ended with error: {}", e);
-        } else {
-            println!("peer disconnected, waiting for next connection...");
-        }
-    }
+        },
+    };
+    password.zeroize();
+
+    let status_ctx = StatusContext {
+        bootstrap_secs: None,
+        bridges_configured: 0,
+        bridges_active: false,
+        identity_persist: storage.is_some(),
+        onion_addr: Some(addr_str),
+        history_saving: storage.is_some(),
+        peer_hello: None,
+        ignored_messages: 0,
+        last_ignored: None,
+        features: Features::empty(),
+        local_identity: identity.map(|id| id.public.clone()),
+        peer_identity: None,
+        peer_onion: None,
+        peer_auth_pin: None,
+        contact: None,
+        identity_warning: None,
+    };
 
-    Ok(())
+    let (events_tx, events_rx) = mpsc::unbounded_channel();
+    chat_loop(
+        None,
+        Some(listener),
+        events_tx,
+        events_rx,
+        &mut storage,
+        status_ctx,
+        settings,
+    )
+    .await
 }
 
 #[tokio::main]
@@ -1209,6 +3029,10 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 std::fs::remove_file(&db)?;
                 println!("deleted {}", db.display());
             }
+            for backup in storage::backup_paths() {
+                std::fs::remove_file(&backup)?;
+                println!("deleted {}", backup.display());
+            }
 
             let cache = exe_dir.join("cache");
             if cache.exists() {
@@ -1240,9 +3064,18 @@ async fn main() -> Result<(), Box<dyn Error>> {
         return run_bot_mode(&args[2]).await;
     }
 
+    // before anything else touches the database or config it replaces
+    if args.len() >= 3 && args[1] == "restore" {
+        return backup::restore(std::path::Path::new(&args[2]));
+    }
+
+    if args.len() >= 3 && args[1] == "decrypt-export" {
+        return export::decrypt_file(std::path::Path::new(&args[2]), args.get(3).map(std::path::Path::new));
+    }
+
     if args.len() < 2 {
         eprintln!(
-            "usage: {} (initiate <onion_addr> | listen | bot <script.ccscript>) [--reset, --version]",
+            "usage: {} (initiate <onion_addr | nickname> [--peer-key <hex>] | listen [--group] | contacts | conversations | backup <file> | restore <file> | decrypt-export <file> [output] | bot <script.ccscript>) [--reset, --version]",
             args[0]
         );
         std::process::exit(2);
@@ -1261,6 +3094,22 @@ async fn main() -> Result<(), Box<dyn Error>> {
         p.zeroize();
     }
 
+    let identity = load_identity(&cfg, storage.as_ref())?;
+
+    if args[1] == "contacts" {
+        return run_contacts_command(&args, storage.as_ref());
+    }
+    if args[1] == "conversations" {
+        return run_conversations_command(&args, storage);
+    }
+    if args[1] == "backup" {
+        let Some(path) = args.get(2) else {
+            eprintln!("usage: {} backup <file>", args[0]);
+            std::process::exit(2);
+        };
+        return backup::backup(std::path::Path::new(path), storage.as_ref());
+    }
+
     let (tor_config, _ephemeral_dir) = build_tor_config(cfg.identity.persist, &cfg.bridge)?;
 
     println!("bootstrapping tor...");
@@ -1281,42 +3130,65 @@ async fn main() -> Result<(), Box<dyn Error>> {
     match args[1].as_str() {
         "initiate" => {
             if args.len() < 3 {
-                eprintln!("usage: {} initiate <onion_addr>", args[0]);
+                eprintln!("usage: {} initiate <onion_addr | nickname> [--peer-key <hex>]", args[0]);
                 std::process::exit(2);
             }
+            let (peer_onion, contact) = if args[2].ends_with(".onion") {
+                (args[2].clone(), None)
+            } else {
+                let s = storage
+                    .as_ref()
+                    .ok_or("contacts require identity.persist = true")?;
+                let contact = s
+                    .find_contact(&args[2])?
+                    .ok_or_else(|| format!("unknown contact '{}'", args[2]))?;
+                let onion = contact
+                    .onion
+                    .clone()
+                    .ok_or_else(|| format!("contact '{}' has no onion address", contact.nickname))?;
+                (onion, Some(contact))
+            };
+            let peer_key = match args.iter().position(|a| a == "--peer-key") {
+                Some(i) => {
+                    let hex_key = args.get(i + 1).ok_or("--peer-key needs a value")?;
+                    let key = hex::decode(hex_key).map_err(|_| "--peer-key must be hex")?;
+                    if key.len() != 32 {
+                        return Err("--peer-key must be a 32-byte key".into());
+                    }
+                    Some(key)
+                }
+                None => None,
+            };
             run_initiator(
                 &tor,
-                &args[2],
+                &peer_onion,
                 storage,
-                cfg.time.local,
-                cfg.time.hour24,
-                cfg.time.show_seconds,
-                cfg.time.show_tz,
+                identity.as_ref(),
+                peer_key,
+                contact,
                 cfg.auth.enabled,
                 auth_password.unwrap_or_default(),
-                cfg.privacy.typing_status,
-                cfg.privacy.read_receipts,
-                cfg.privacy.randomize_filenames,
-                cfg.ui.message_notification_sound,
-                cfg.ui.mention_notification_sound,
+                &ChatSettings::from_config(&cfg),
             )
             .await?;
         }
+        "listen" if args.iter().any(|a| a == "--group") => {
+            let mut storage = storage;
+            let password = if cfg.auth.enabled {
+                auth_password
+            } else {
+                None
+            };
+            group::run_group_host(&tor, &mut storage, identity.as_ref(), password, &cfg).await?;
+        }
         "listen" => {
             run_responder(
                 &tor,
                 storage,
-                cfg.time.local,
-                cfg.time.hour24,
-                cfg.time.show_seconds,
-                cfg.time.show_tz,
+                identity.as_ref(),
                 cfg.auth.enabled,
                 auth_password.unwrap_or_default(),
-                cfg.privacy.typing_status,
-                cfg.privacy.read_receipts,
-                cfg.privacy.randomize_filenames,
-                cfg.ui.message_notification_sound,
-                cfg.ui.mention_notification_sound,
+                &ChatSettings::from_config(&cfg),
             )
             .await?;
         }
@@ -1358,6 +3230,7 @@ async fn run_bot_mode(script_path: &str) -> Result<(), Box<dyn Error>> {
         Some(ref p) if cfg.identity.persist => Some(storage::Storage::open(p)?),
         _ => None,
     };
+    let identity = load_identity(&cfg, storage.as_ref())?;
     drop(storage);
 
     if let Some(ref mut p) = passphrase {
@@ -1447,7 +3320,11 @@ async fn run_bot_mode(script_path: &str) -> Result<(), Box<dyn Error>> {
             }
         };
 
-        let mut np = match NoisePeer::accept(data_stream, PATTERN).await {
+        let handshake = match identity {
+            Some(ref id) => NoisePeer::accept_with_identity(data_stream, PATTERN, id).await,
+            None => NoisePeer::accept(data_stream, PATTERN).await,
+        };
+        let mut np = match handshake {
             Ok(n) => n,
             Err(e) => {
                 eprintln!("handshake failed: {}", e);
@@ -0,0 +1,259 @@
+use circuitchat_proto::{Framing, Message, NoisePeer, StaticKeypair};
+use std::fs;
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, watch};
+use zeroize::Zeroize;
+
+use crate::files::{self, OutgoingFile};
+
+// frames queued per session before senders have to wait for the transport
+const QUEUE_DEPTH: usize = 64;
+// file chunks queued behind it. kept short, since everything else overtakes
+// them and a chunk already handed to the transport cannot be recalled
+const BULK_DEPTH: usize = 4;
+// shorter text is sent as it is, zstd rarely shrinks it
+const MIN_COMPRESSED_TEXT: usize = 256;
+
+pub enum SessionEvent {
+    Joined(SessionHandle),
+    Message { id: u64, data: Vec<u8> },
+    Closed { id: u64 },
+    Failed { id: u64, error: String },
+    /// A background sender has queued its file up to this offset, taking
+    /// `wire` more bytes on the wire for it.
+    FileProgress { id: u64, transfer: u64, sent: u64, wire: u64 },
+    /// A background sender finished, `Err` with why it stopped early.
+    FileSent { id: u64, transfer: u64, result: Result<(), String> },
+    /// A file from `hash_file` is open and hashed, ready to be offered.
+    FileHashed { id: u64, transfer: u64, result: Result<OutgoingFile, String> },
+}
+
+/// A connected peer whose transport is driven by its own task. Dropping the
+/// handle flushes anything already queued and then closes the connection.
+pub struct SessionHandle {
+    pub id: u64,
+    pub fingerprint: String,
+    pub remote_static: Option<Vec<u8>>,
+    pub auth_pin: Option<Vec<u8>>,
+    /// Switched to `Envelope` once the peer's hello allows it.
+    pub framing: Framing,
+    /// The zstd level to compress with, once the peer's hello shows it has
+    /// the `compression` feature.
+    pub compression: Option<i32>,
+    outgoing: mpsc::Sender<Vec<u8>>,
+    bulk: mpsc::Sender<Vec<u8>>,
+    events: mpsc::UnboundedSender<SessionEvent>,
+}
+
+impl SessionHandle {
+    pub async fn send(&self, msg: &Message) -> bool {
+        self.outgoing.send(self.encode(msg)).await.is_ok()
+    }
+
+    // only text is long enough to be worth compressing, file chunks are
+    // compressed by their sender task
+    fn encode(&self, msg: &Message) -> Vec<u8> {
+        match (self.compression, msg) {
+            (Some(level), Message::Text { text, .. } | Message::Edit { text, .. })
+                if text.len() >= MIN_COMPRESSED_TEXT =>
+            {
+                msg.encode_compressed(self.framing, level)
+            }
+            _ => msg.encode(self.framing),
+        }
+    }
+
+    /// Queues behind file chunks, for messages that must not overtake them.
+    pub async fn send_bulk(&self, msg: &Message) -> bool {
+        self.bulk.send(msg.encode(self.framing)).await.is_ok()
+    }
+
+    /// Opens and hashes a file on a blocking thread, since a large one takes
+    /// a while, and reports it with `FileHashed`. `file` is used instead of
+    /// `path` when it is open already.
+    pub fn hash_file(&self, transfer: u64, name: String, path: PathBuf, file: Option<fs::File>) {
+        let (id, events) = (self.id, self.events.clone());
+        tokio::task::spawn_blocking(move || {
+            let result = match file {
+                Some(file) => OutgoingFile::from_file(name, file),
+                None => OutgoingFile::open(&path),
+            }
+            .map_err(|e| e.to_string());
+            let _ = events.send(SessionEvent::FileHashed { id, transfer, result });
+        });
+    }
+
+    /// Sends an accepted file from a task of its own, reporting back with
+    /// `FileProgress` and `FileSent`. Chunks go through the bulk queue, so
+    /// chat keeps flowing. With `windowed`, at most `files::WINDOW` bytes are
+    /// sent beyond what the peer has acknowledged with `FileAck`. Chunks are
+    /// compressed unless the file's type is compressed already.
+    pub fn send_file(&self, transfer: u64, mut out: OutgoingFile, windowed: bool) -> FileSender {
+        let (acks, mut acked) = watch::channel(out.sent);
+        let (name, size, checksum) = (out.name.clone(), out.size, out.checksum());
+        let compression = self.compression.filter(|_| files::compressible(&name));
+        let (id, framing, bulk, events) = (self.id, self.framing, self.bulk.clone(), self.events.clone());
+        let task = tokio::spawn(async move {
+            let result = loop {
+                while windowed && out.sent.saturating_sub(*acked.borrow()) >= files::WINDOW {
+                    if acked.changed().await.is_err() {
+                        return;
+                    }
+                }
+                // boxed errors are not Send, so they cannot be held across an await
+                let chunk = out.next_chunk(transfer).map_err(|e| e.to_string());
+                match chunk {
+                    Ok(Some(msg)) => {
+                        let frame = match compression {
+                            Some(level) => msg.encode_compressed(framing, level),
+                            None => msg.encode(framing),
+                        };
+                        let wire = frame.len() as u64;
+                        if bulk.send(frame).await.is_err() {
+                            break Err("peer disconnected".to_string());
+                        }
+                        let _ = events.send(SessionEvent::FileProgress { id, transfer, sent: out.sent, wire });
+                    }
+                    Ok(None) => {
+                        let _ = bulk.send(Message::FileDone(transfer).encode(framing)).await;
+                        break Ok(());
+                    }
+                    Err(e) => break Err(format!("read error: {}", e)),
+                }
+            };
+            let _ = events.send(SessionEvent::FileSent { id, transfer, result });
+        });
+        FileSender {
+            name,
+            size,
+            checksum,
+            acks,
+            task,
+        }
+    }
+}
+
+/// A file being sent in the background. Dropping it stops the transfer.
+pub struct FileSender {
+    pub name: String,
+    pub size: u64,
+    pub checksum: Vec<u8>,
+    acks: watch::Sender<u64>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl FileSender {
+    /// Records a `FileAck` from the peer, opening the window again.
+    pub fn acked(&self, offset: u64) {
+        self.acks.send_if_modified(|acked| {
+            let newer = offset > *acked;
+            if newer {
+                *acked = offset;
+            }
+            newer
+        });
+    }
+}
+
+impl Drop for FileSender {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+pub fn spawn<T>(
+    id: u64,
+    mut np: NoisePeer<T>,
+    events: mpsc::UnboundedSender<SessionEvent>,
+) -> SessionHandle
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (outgoing, mut queued) = mpsc::channel::<Vec<u8>>(QUEUE_DEPTH);
+    let (bulk, mut bulk_queued) = mpsc::channel::<Vec<u8>>(BULK_DEPTH);
+    let handle = SessionHandle {
+        id,
+        fingerprint: np.session_fingerprint.clone(),
+        remote_static: np.remote_static.clone(),
+        auth_pin: np.auth_pin.clone(),
+        framing: Framing::Legacy,
+        compression: None,
+        outgoing,
+        bulk,
+        events: events.clone(),
+    };
+
+    tokio::spawn(async move {
+        loop {
+            // chat and control frames first, then whatever the peer sent,
+            // and file chunks only when neither is waiting
+            tokio::select! {
+                biased;
+                data = queued.recv() => match data {
+                    Some(data) => {
+                        if np.send(&data).await.is_err() {
+                            break;
+                        }
+                    }
+                    // closed from our side, nobody is waiting for a Closed event
+                    None => return,
+                },
+                // errors are dropped here since boxed errors are not Send
+                data = async { np.recv().await.ok() } => match data {
+                    Some(data) => {
+                        if events.send(SessionEvent::Message { id, data }).is_err() {
+                            return;
+                        }
+                    }
+                    None => break,
+                },
+                Some(data) = bulk_queued.recv() => {
+                    if np.send(&data).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        let _ = events.send(SessionEvent::Closed { id });
+    });
+
+    handle
+}
+
+/// Runs the responder handshake and authentication for a new connection in
+/// the background, then reports it as `Joined` or `Failed`.
+pub fn spawn_accept<T>(
+    id: u64,
+    stream: T,
+    pattern: &'static str,
+    identity: Option<StaticKeypair>,
+    mut password: Option<String>,
+    events: mpsc::UnboundedSender<SessionEvent>,
+) where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let handshake = match identity {
+            Some(ref id) => NoisePeer::accept_with_identity(stream, pattern, id).await,
+            None => NoisePeer::accept(stream, pattern).await,
+        }
+        .map_err(|e| format!("handshake failed: {}", e));
+        let result = match handshake {
+            Ok(mut np) => np
+                .auth_responder(password.as_deref())
+                .await
+                .map(|()| np)
+                .map_err(|e| format!("authentication failed: {}", e)),
+            Err(error) => Err(error),
+        };
+        if let Some(ref mut p) = password {
+            p.zeroize();
+        }
+        let event = match result {
+            Ok(np) => SessionEvent::Joined(spawn(id, np, events.clone())),
+            Err(error) => SessionEvent::Failed { id, error },
+        };
+        let _ = events.send(event);
+    });
+}
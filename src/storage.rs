@@ -1,14 +1,21 @@
 use argon2::Argon2;
 use chacha20poly1305::aead::{Aead, KeyInit, OsRng};
 use chacha20poly1305::{AeadCore, XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
 use rand::RngCore;
-use rusqlite::Connection;
+use circuitchat_proto::StaticKeypair;
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::error::Error;
 use std::io::Write;
 use std::path::PathBuf;
 use zeroize::Zeroize;
 
-fn derive_key(passphrase: &str, salt: &[u8; 16]) -> Result<[u8; 32], Box<dyn Error>> {
+pub fn derive_key(passphrase: &str, salt: &[u8; 16]) -> Result<[u8; 32], Box<dyn Error>> {
     let mut key = [0u8; 32];
     Argon2::default()
         .hash_password_into(passphrase.as_bytes(), salt, &mut key)
@@ -16,7 +23,7 @@ fn derive_key(passphrase: &str, salt: &[u8; 16]) -> Result<[u8; 32], Box<dyn Err
     Ok(key)
 }
 
-fn encrypt(key: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+pub fn encrypt(key: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
     let cipher = XChaCha20Poly1305::new(key.into());
     let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
     let ciphertext = cipher
@@ -29,7 +36,7 @@ fn encrypt(key: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>, Box<dyn Error>>
     Ok(out)
 }
 
-fn decrypt(key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+pub fn decrypt(key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
     if data.len() < 24 {
         return Err("ciphertext too short".into());
     }
@@ -42,109 +49,1118 @@ fn decrypt(key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
     Ok(plaintext)
 }
 
+const SEALED_FILE_VERSION: u8 = 1;
+
+/// Seals `plain` into a file of its own under a passphrase: `magic`, a
+/// format version, a fresh Argon2 salt and the XChaCha20-Poly1305 ciphertext.
+pub fn seal_file(magic: &[u8; 8], passphrase: &str, plain: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let mut key = derive_key(passphrase, &salt)?;
+    let sealed = encrypt(&key, plain);
+    key.zeroize();
+
+    let mut out = magic.to_vec();
+    out.push(SEALED_FILE_VERSION);
+    out.extend_from_slice(&salt);
+    out.extend_from_slice(&sealed?);
+    Ok(out)
+}
+
+/// Opens a file made by `seal_file`. The passphrase is only asked for once
+/// `data` is known to be the right kind of file.
+pub fn open_sealed_file(
+    magic: &[u8; 8],
+    data: &[u8],
+    passphrase: impl FnOnce() -> Result<String, Box<dyn Error>>,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let header = magic.len() + 1 + 16;
+    if data.len() < header || &data[..magic.len()] != magic {
+        return Err("not a file of this kind".into());
+    }
+    if data[magic.len()] != SEALED_FILE_VERSION {
+        return Err("the file was made by a newer version of circuitchat".into());
+    }
+    let salt: [u8; 16] = data[magic.len() + 1..header].try_into().unwrap();
+    let mut passphrase = passphrase()?;
+    let mut key = derive_key(&passphrase, &salt)?;
+    passphrase.zeroize();
+    let plain = decrypt(&key, &data[header..]).map_err(|_| "wrong passphrase, or the file has been damaged");
+    key.zeroize();
+    Ok(plain?)
+}
+
+// sealed rows are padded to a multiple of this, so their size only hints at
+// the length of a message
+const PAD_TO: usize = 64;
+
+/// Everything, including message metadata, is sealed into per-row blobs under
+/// a random data key. The passphrase only wraps that key, and the key is
+/// replaced soon after something is deleted, so a deleted row cannot be
+/// recovered from an old copy of the file with the current key.
 pub struct Storage {
     conn: Connection,
+    // derived from the passphrase, only used to wrap `key`
+    master_key: [u8; 32],
     key: [u8; 32],
+    // rows were deleted since the last ratchet, see `ratchet_pending`
+    deleted: bool,
+    // no message expires before this, as of the last full look in
+    // `purge_expired`. `None` when it has to be looked for again
+    next_expiry: Cell<Option<i64>>,
+}
+
+/// A decrypted database row as `(table, id, plaintext)`.
+pub type ExportedRow = (String, i64, Vec<u8>);
+
+// tables whose rows are sealed under the data key, with their blob column.
+// vault chunks are sealed under a key of their file's, kept in
+// `attachment_keys`, so ratcheting only re-wraps those keys
+const SEALED_TABLES: [(&str, &str); 6] = [
+    ("messages", "record"),
+    ("attachment_keys", "record"),
+    ("reactions", "record"),
+    ("conversations", "record"),
+    ("contacts", "record"),
+    ("identity", "keypair"),
+];
+
+fn table_exists(conn: &Connection, name: &str) -> Result<bool, Box<dyn Error>> {
+    Ok(conn
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            [name],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some())
+}
+
+fn seal(key: &[u8; 32], mut plain: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+    let len = plain.len() as u32;
+    plain.splice(0..0, len.to_be_bytes());
+    plain.resize(plain.len().div_ceil(PAD_TO) * PAD_TO, 0);
+    let sealed = encrypt(key, &plain);
+    plain.zeroize();
+    sealed
+}
+
+fn unseal(key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut plain = decrypt(key, data)?;
+    let len = plain
+        .get(..4)
+        .map(|l| u32::from_be_bytes(l.try_into().unwrap()) as usize)
+        .filter(|l| l + 4 <= plain.len())
+        .ok_or("corrupt record in database")?;
+    let out = plain[4..4 + len].to_vec();
+    plain.zeroize();
+    Ok(out)
+}
+
+// search index entries are keyed hashes of the conversation and a word, cut
+// to this many bytes
+const TOKEN_LEN: usize = 16;
+
+/// Lowercase words of a message, each once. This is what the search index
+/// matches on.
+fn words(text: &str) -> Vec<String> {
+    let mut words: Vec<String> = text
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_string)
+        .collect();
+    words.sort();
+    words.dedup();
+    words
+}
+
+fn token(index_key: &[u8; 32], conversation: &str, word: &str) -> Vec<u8> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(index_key).unwrap();
+    mac.update(conversation.as_bytes());
+    mac.update(&[0]);
+    mac.update(word.as_bytes());
+    mac.finalize().into_bytes()[..TOKEN_LEN].to_vec()
+}
+
+// first byte of a message record that carries its conversation. older records
+// start with the direction byte and belong to no conversation
+const RECORD_CONVERSATION: u8 = 0x80;
+// a file transfer event: laid out like RECORD_CONVERSATION, with the encoded
+// `FileRecord` and its u16 length in front of the content
+const RECORD_FILE: u8 = 0x81;
+// first byte of a reaction record that carries its conversation, followed by
+// who reacted, the message id and the conversation with its length. older
+// records start with who reacted and belong to no conversation
+const RECORD_REACTION: u8 = 0x82;
+
+// per-conversation settings, sealed as `[expire_after u64][key]`
+struct ConversationRow {
+    id: i64,
+    key: String,
+    expire_after: u64,
+}
+
+struct ReactionRow {
+    id: i64,
+    conversation: String,
+    msg_id: u64,
+    by: MessageDirection,
+    emoji: String,
+}
+
+impl FileRecord {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = vec![self.event.to_byte(), self.incoming as u8];
+        out.extend_from_slice(&self.size.to_be_bytes());
+        let checksum = self.checksum.as_deref().unwrap_or_default();
+        out.push(checksum.len() as u8);
+        out.extend_from_slice(checksum);
+        out.extend_from_slice(&(self.name.len() as u16).to_be_bytes());
+        out.extend_from_slice(self.name.as_bytes());
+        match self.saved {
+            None => out.push(0),
+            Some(SavedFile::Downloads(ref path)) => {
+                out.push(1);
+                out.extend_from_slice(path.to_string_lossy().as_bytes());
+            }
+            Some(SavedFile::Vault(attachment)) => {
+                out.push(2);
+                out.extend_from_slice(&attachment.to_be_bytes());
+            }
+        }
+        out
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let size = u64::from_be_bytes(data.get(2..10)?.try_into().ok()?);
+        let sum_len = *data.get(10)? as usize;
+        let checksum = data.get(11..11 + sum_len)?;
+        let at = 11 + sum_len;
+        let name_len = u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?) as usize;
+        let name = data.get(at + 2..at + 2 + name_len)?;
+        let at = at + 2 + name_len;
+        let saved = match *data.get(at)? {
+            0 => None,
+            1 => Some(SavedFile::Downloads(PathBuf::from(
+                String::from_utf8_lossy(&data[at + 1..]).to_string(),
+            ))),
+            2 => Some(SavedFile::Vault(u64::from_be_bytes(data.get(at + 1..at + 9)?.try_into().ok()?))),
+            _ => return None,
+        };
+        Some(FileRecord {
+            event: FileEvent::from_byte(data[0])?,
+            incoming: data[1] != 0,
+            name: String::from_utf8_lossy(name).to_string(),
+            size,
+            checksum: (sum_len > 0).then(|| checksum.to_vec()),
+            saved,
+        })
+    }
+}
+
+impl Message {
+    fn to_record(&self) -> Vec<u8> {
+        let kind = if self.file.is_some() { RECORD_FILE } else { RECORD_CONVERSATION };
+        let mut out = vec![kind, self.direction.to_byte(), self.edited as u8];
+        out.extend_from_slice(&self.timestamp.to_be_bytes());
+        out.extend_from_slice(&self.msg_id.to_be_bytes());
+        out.extend_from_slice(&self.reply_to.to_be_bytes());
+        out.push(self.conversation.len() as u8);
+        out.extend_from_slice(self.conversation.as_bytes());
+        if let Some(ref file) = self.file {
+            let file = file.encode();
+            out.extend_from_slice(&(file.len() as u16).to_be_bytes());
+            out.extend_from_slice(&file);
+        }
+        out.extend_from_slice(&self.content);
+        out
+    }
+
+    fn from_record(record: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let corrupt = || -> Box<dyn Error> { "corrupt message in database".into() };
+        let (record, has_conversation, has_file) = match record.split_first() {
+            Some((&RECORD_CONVERSATION, rest)) => (rest, true, false),
+            Some((&RECORD_FILE, rest)) => (rest, true, true),
+            _ => (record, false, false),
+        };
+        if record.len() < 26 {
+            return Err(corrupt());
+        }
+        let int = |at: usize| u64::from_be_bytes(record[at..at + 8].try_into().unwrap());
+        let (conversation, mut content) = if has_conversation {
+            let len = *record.get(26).ok_or_else(corrupt)? as usize;
+            let conversation = record.get(27..27 + len).ok_or_else(corrupt)?;
+            (String::from_utf8_lossy(conversation).to_string(), &record[27 + len..])
+        } else {
+            (String::new(), &record[26..])
+        };
+        let file = if has_file {
+            let len = u16::from_be_bytes(content.get(..2).ok_or_else(corrupt)?.try_into().unwrap()) as usize;
+            let file = FileRecord::decode(content.get(2..2 + len).ok_or_else(corrupt)?).ok_or_else(corrupt)?;
+            content = &content[2 + len..];
+            Some(file)
+        } else {
+            None
+        };
+        Ok(Message {
+            conversation,
+            direction: MessageDirection::from_byte(record[0]),
+            edited: record[1] != 0,
+            timestamp: int(2) as i64,
+            msg_id: int(10),
+            reply_to: int(18),
+            content: content.to_vec(),
+            reactions: Vec::new(),
+            file,
+        })
+    }
 }
 
 impl Storage {
     pub fn open(passphrase: &str) -> Result<Self, Box<dyn Error>> {
-        let db_path = db_path()?;
-        let is_new = !db_path.exists();
-        let conn = Connection::open(&db_path)?;
-
-        conn.execute_batch(
-            "CREATE TABLE IF NOT EXISTS meta (
-                 id    INTEGER PRIMARY KEY CHECK (id = 1),
-                 salt  BLOB NOT NULL,
-                 check_blob BLOB NOT NULL
-             );
-
-             CREATE TABLE IF NOT EXISTS messages (
-                 id        INTEGER PRIMARY KEY AUTOINCREMENT,
-                 direction TEXT NOT NULL CHECK (direction IN ('sent', 'received')),
-                 content   BLOB NOT NULL,
-                 timestamp INTEGER NOT NULL
-             );",
+        Self::open_at(&db_path()?, passphrase)
+    }
+
+    pub fn open_at(path: &std::path::Path, passphrase: &str) -> Result<Self, Box<dyn Error>> {
+        let conn = Connection::open(path)?;
+        // overwrite deleted rows instead of leaving them in free pages
+        conn.execute_batch("PRAGMA secure_delete = ON;")?;
+        let from_version = migrate(&conn, path, passphrase)?;
+
+        let keyring: Option<(Vec<u8>, Vec<u8>)> = conn
+            .query_row("SELECT salt, wrapped_key FROM keyring WHERE id = 1", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .optional()?;
+
+        let (master_key, key) = match keyring {
+            Some((salt_vec, wrapped)) => {
+                let salt: [u8; 16] = salt_vec
+                    .try_into()
+                    .map_err(|_| "corrupt salt in database")?;
+                let master_key = derive_key(passphrase, &salt)?;
+                let mut plain = decrypt(&master_key, &wrapped)?;
+                let key: [u8; 32] = plain
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| "corrupt data key in database")?;
+                plain.zeroize();
+                (master_key, key)
+            }
+            None => {
+                let mut salt = [0u8; 16];
+                rand::thread_rng().fill_bytes(&mut salt);
+                let master_key = derive_key(passphrase, &salt)?;
+                let mut key = [0u8; 32];
+                rand::thread_rng().fill_bytes(&mut key);
+                conn.execute(
+                    "INSERT INTO keyring (id, salt, wrapped_key) VALUES (1, ?1, ?2)",
+                    rusqlite::params![salt.as_slice(), encrypt(&master_key, &key)?],
+                )?;
+                (master_key, key)
+            }
+        };
+
+        let storage = Storage {
+            conn,
+            master_key,
+            key,
+            deleted: false,
+            next_expiry: Cell::new(None),
+        };
+        // the index needs the data key, so it is filled once the store is open
+        if from_version < 3 {
+            storage.rebuild_index()?;
+        }
+        if from_version < 5 {
+            storage.split_attachment_keys()?;
+        }
+        Ok(storage)
+    }
+
+    // the key a vault file's chunks are sealed under
+    fn attachment_key(&self, conn: &Connection, attachment: u64) -> Result<Option<[u8; 32]>, Box<dyn Error>> {
+        let Some(blob) = conn
+            .query_row(
+                "SELECT record FROM attachment_keys WHERE attachment = ?1",
+                [attachment as i64],
+                |row| row.get::<_, Vec<u8>>(0),
+            )
+            .optional()?
+        else {
+            return Ok(None);
+        };
+        let mut record = unseal(&self.key, &blob)?;
+        let key = record.get(8..40).and_then(|k| k.try_into().ok());
+        record.zeroize();
+        key.map(Some).ok_or_else(|| "corrupt attachment key in database".into())
+    }
+
+    fn new_attachment_key(&self, conn: &Connection, attachment: u64) -> Result<[u8; 32], Box<dyn Error>> {
+        let mut key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        let mut record = attachment.to_be_bytes().to_vec();
+        record.extend_from_slice(&key);
+        conn.execute(
+            "INSERT INTO attachment_keys (attachment, record) VALUES (?1, ?2)",
+            rusqlite::params![attachment as i64, seal(&self.key, record)?],
         )?;
+        Ok(key)
+    }
+
+    // vault chunks used to be sealed under the data key. they are moved under
+    // keys of their own one at a time, so the vault is never all in memory
+    fn split_attachment_keys(&self) -> Result<(), Box<dyn Error>> {
+        let tx = self.conn.unchecked_transaction()?;
+        let chunks: Vec<(i64, i64)> = tx
+            .prepare("SELECT id, attachment FROM attachments ORDER BY id ASC")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (id, attachment) in chunks {
+            let mut key = match self.attachment_key(&tx, attachment as u64)? {
+                Some(key) => key,
+                None => self.new_attachment_key(&tx, attachment as u64)?,
+            };
+            let blob: Vec<u8> = tx.query_row("SELECT record FROM attachments WHERE id = ?1", [id], |row| row.get(0))?;
+            let mut plain = decrypt(&self.key, &blob)?;
+            let blob = encrypt(&key, &plain);
+            plain.zeroize();
+            key.zeroize();
+            tx.execute(
+                "UPDATE attachments SET record = ?1 WHERE id = ?2",
+                rusqlite::params![blob?, id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
 
-        let key = if is_new {
-            let mut salt = [0u8; 16];
-            rand::thread_rng().fill_bytes(&mut salt);
-            let key = derive_key(passphrase, &salt)?;
+    // the index key follows the data key, so ratcheting also replaces every
+    // token and old ones cannot be matched against the current file
+    fn index_key(&self) -> [u8; 32] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.key).unwrap();
+        mac.update(b"circuitchat-search-index");
+        mac.finalize().into_bytes().into()
+    }
 
-            let check = encrypt(&key, b"circuitchat")?;
+    // replaces the index entries of one message row
+    fn index_row(&self, conn: &Connection, id: i64, msg: &Message) -> Result<(), Box<dyn Error>> {
+        conn.execute("DELETE FROM search_index WHERE message = ?1", [id])?;
+        let mut index_key = self.index_key();
+        for word in words(&String::from_utf8_lossy(&msg.content)) {
             conn.execute(
-                "INSERT INTO meta (id, salt, check_blob) VALUES (1, ?1, ?2)",
-                rusqlite::params![salt.as_slice(), check],
-            )?;
-            key
-        } else {
-            let (salt_vec, check_blob): (Vec<u8>, Vec<u8>) = conn.query_row(
-                "SELECT salt, check_blob FROM meta WHERE id = 1",
-                [],
-                |row| Ok((row.get(0)?, row.get(1)?)),
+                "INSERT INTO search_index (token, message) VALUES (?1, ?2)",
+                rusqlite::params![token(&index_key, &msg.conversation, &word), id],
             )?;
+        }
+        index_key.zeroize();
+        Ok(())
+    }
+
+    fn rebuild_index(&self) -> Result<(), Box<dyn Error>> {
+        let tx = self.conn.unchecked_transaction()?;
+        self.reindex(&tx)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    // refills the whole index inside the caller's transaction
+    fn reindex(&self, conn: &Connection) -> Result<(), Box<dyn Error>> {
+        conn.execute("DELETE FROM search_index", [])?;
+        for (id, msg) in self.load_rows()? {
+            self.index_row(conn, id, &msg)?;
+        }
+        Ok(())
+    }
 
-            let salt: [u8; 16] = salt_vec
-                .try_into()
-                .map_err(|_| "corrupt salt in database")?;
-            let key = derive_key(passphrase, &salt)?;
+    /// Re-encrypts every row under a fresh data key and forgets the old one.
+    /// Vault chunks are left as they are, only their files' keys are
+    /// re-encrypted.
+    pub fn ratchet(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut new_key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut new_key);
 
-            decrypt(&key, &check_blob)?;
-            key
-        };
+        let tx = self.conn.unchecked_transaction()?;
+        for (table, column) in SEALED_TABLES {
+            let rows: Vec<(i64, Vec<u8>)> = tx
+                .prepare(&format!("SELECT id, {} FROM {}", column, table))?
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<Vec<_>, _>>()?;
+            for (id, blob) in rows {
+                let mut plain = decrypt(&self.key, &blob)?;
+                let blob = encrypt(&new_key, &plain)?;
+                plain.zeroize();
+                tx.execute(
+                    &format!("UPDATE {} SET {} = ?1 WHERE id = ?2", table, column),
+                    rusqlite::params![blob, id],
+                )?;
+            }
+        }
+        tx.execute(
+            "UPDATE keyring SET wrapped_key = ?1 WHERE id = 1",
+            [encrypt(&self.master_key, &new_key)?],
+        )?;
+        // the rows already hold the new key, so the index is rebuilt with it
+        // before anything commits. the old key comes back if that fails
+        let mut old_key = std::mem::replace(&mut self.key, new_key);
+        new_key.zeroize();
+        let result = self.reindex(&tx).and_then(|()| Ok(tx.commit()?));
+        if result.is_err() {
+            std::mem::swap(&mut self.key, &mut old_key);
+        }
+        old_key.zeroize();
+        result?;
+        self.deleted = false;
+        Ok(())
+    }
 
-        Ok(Storage { conn, key })
+    /// Ratchets if anything was deleted since the last ratchet. Deleting a
+    /// single message or vault file leaves the ratchet to this, so a burst of
+    /// deletions costs one; the chat loop calls it through `purge_expired`
+    /// every few seconds and once more on the way out.
+    pub fn ratchet_pending(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.deleted {
+            self.ratchet()?;
+        }
+        Ok(())
+    }
+
+    /// Changes the passphrase: derives a new master key under a new salt and
+    /// ratchets, so every row is re-encrypted as well.
+    pub fn rekey(&mut self, new_passphrase: &str) -> Result<(), Box<dyn Error>> {
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let mut master_key = derive_key(new_passphrase, &salt)?;
+        self.conn.execute(
+            "UPDATE keyring SET salt = ?1, wrapped_key = ?2 WHERE id = 1",
+            rusqlite::params![salt.as_slice(), encrypt(&master_key, &self.key)?],
+        )?;
+        self.master_key.zeroize();
+        self.master_key = master_key;
+        master_key.zeroize();
+        self.ratchet()
     }
 
+    /// `msg_id` and `reply_to` are the protocol message ids, 0 if unknown.
     pub fn save_message(
         &self,
+        conversation: &str,
         direction: MessageDirection,
+        msg_id: u64,
+        reply_to: u64,
         content: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        self.insert_message(conversation, direction, msg_id, reply_to, content, None)
+    }
+
+    /// Saves a file transfer event. `content` is the line shown for it, which
+    /// is also what `/find` matches.
+    pub fn save_file_event(
+        &self,
+        conversation: &str,
+        direction: MessageDirection,
+        file: FileRecord,
+        content: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        self.insert_message(conversation, direction, 0, 0, content, Some(file))
+    }
+
+    fn insert_message(
+        &self,
+        conversation: &str,
+        direction: MessageDirection,
+        msg_id: u64,
+        reply_to: u64,
+        content: &[u8],
+        file: Option<FileRecord>,
     ) -> Result<(), Box<dyn Error>> {
         let timestamp = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)?
             .as_secs() as i64;
 
-        let encrypted = encrypt(&self.key, content)?;
+        let msg = Message {
+            conversation: conversation.to_string(),
+            direction,
+            content: content.to_vec(),
+            timestamp,
+            msg_id,
+            reply_to,
+            edited: false,
+            reactions: Vec::new(),
+            file,
+        };
+        self.conn.execute(
+            "INSERT INTO messages (record) VALUES (?1)",
+            [seal(&self.key, msg.to_record())?],
+        )?;
+        if let Some(next) = self.next_expiry.get() {
+            let expire_after = self.conversation_expiry(conversation)?;
+            if expire_after > 0 {
+                self.next_expiry
+                    .set(Some(next.min(timestamp.saturating_add(expire_after as i64))));
+            }
+        }
+        self.index_row(&self.conn, self.conn.last_insert_rowid(), &msg)
+    }
+
+    // every row has to be unsealed to find one, since nothing is indexed in
+    // the clear
+    fn find_message(
+        &self,
+        conversation: &str,
+        direction: MessageDirection,
+        msg_id: u64,
+    ) -> Result<Option<(i64, Message)>, Box<dyn Error>> {
+        if msg_id == 0 {
+            return Ok(None);
+        }
+        for (id, msg) in self.load_rows()? {
+            if msg.conversation == conversation && msg.direction == direction && msg.msg_id == msg_id {
+                return Ok(Some((id, msg)));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn edit_message(
+        &self,
+        conversation: &str,
+        direction: MessageDirection,
+        msg_id: u64,
+        content: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some((id, mut msg)) = self.find_message(conversation, direction, msg_id)? {
+            msg.content = content.to_vec();
+            msg.edited = true;
+            self.conn.execute(
+                "UPDATE messages SET record = ?1 WHERE id = ?2",
+                rusqlite::params![seal(&self.key, msg.to_record())?, id],
+            )?;
+            self.index_row(&self.conn, id, &msg)?;
+        }
+        Ok(())
+    }
+
+    /// The deleted row can be recovered until the next `ratchet_pending`.
+    pub fn delete_message(
+        &mut self,
+        conversation: &str,
+        direction: MessageDirection,
+        msg_id: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        let Some((id, _)) = self.find_message(conversation, direction, msg_id)? else {
+            return Ok(());
+        };
+        self.conn.execute("DELETE FROM messages WHERE id = ?1", [id])?;
+        self.conn.execute("DELETE FROM search_index WHERE message = ?1", [id])?;
+        for reaction in self.load_reactions()? {
+            if reaction.conversation == conversation && reaction.msg_id == msg_id {
+                self.conn.execute("DELETE FROM reactions WHERE id = ?1", [reaction.id])?;
+            }
+        }
+        self.deleted = true;
+        Ok(())
+    }
+
+    fn load_reactions(&self) -> Result<Vec<ReactionRow>, Box<dyn Error>> {
+        let rows: Vec<(i64, Vec<u8>)> = self
+            .conn
+            .prepare("SELECT id, record FROM reactions ORDER BY id ASC")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut reactions = Vec::with_capacity(rows.len());
+        for (id, blob) in rows {
+            let corrupt = || -> Box<dyn Error> { "corrupt reaction in database".into() };
+            let record = unseal(&self.key, &blob)?;
+            let (record, has_conversation) = match record.split_first() {
+                Some((&RECORD_REACTION, rest)) => (rest, true),
+                _ => (&record[..], false),
+            };
+            if record.len() < 9 {
+                return Err(corrupt());
+            }
+            let msg_id = u64::from_be_bytes(record[1..9].try_into().unwrap());
+            let (conversation, emoji) = if has_conversation {
+                let len = *record.get(9).ok_or_else(corrupt)? as usize;
+                let conversation = record.get(10..10 + len).ok_or_else(corrupt)?;
+                (String::from_utf8_lossy(conversation).to_string(), &record[10 + len..])
+            } else {
+                (String::new(), &record[9..])
+            };
+            reactions.push(ReactionRow {
+                id,
+                conversation,
+                msg_id,
+                by: MessageDirection::from_byte(record[0]),
+                emoji: String::from_utf8_lossy(emoji).to_string(),
+            });
+        }
+        Ok(reactions)
+    }
+
+    /// `by` is who reacted: `Sent` for us, `Received` for the peer.
+    pub fn set_reaction(
+        &self,
+        conversation: &str,
+        msg_id: u64,
+        by: MessageDirection,
+        emoji: Option<&str>,
+    ) -> Result<(), Box<dyn Error>> {
+        for reaction in self.load_reactions()? {
+            if reaction.conversation == conversation && reaction.msg_id == msg_id && reaction.by == by {
+                self.conn.execute("DELETE FROM reactions WHERE id = ?1", [reaction.id])?;
+            }
+        }
+        if let Some(emoji) = emoji {
+            let mut record = vec![RECORD_REACTION, by.to_byte()];
+            record.extend_from_slice(&msg_id.to_be_bytes());
+            record.push(conversation.len() as u8);
+            record.extend_from_slice(conversation.as_bytes());
+            record.extend_from_slice(emoji.as_bytes());
+            self.conn.execute(
+                "INSERT INTO reactions (record) VALUES (?1)",
+                [seal(&self.key, record)?],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn load_rows(&self) -> Result<Vec<(i64, Message)>, Box<dyn Error>> {
+        let rows: Vec<(i64, Vec<u8>)> = self
+            .conn
+            .prepare("SELECT id, record FROM messages ORDER BY id ASC")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut messages = Vec::with_capacity(rows.len());
+        for (id, blob) in rows {
+            let mut record = unseal(&self.key, &blob)?;
+            messages.push((id, Message::from_record(&record)?));
+            record.zeroize();
+        }
+        Ok(messages)
+    }
+
+    pub fn load_history(&self, conversation: &str) -> Result<Vec<Message>, Box<dyn Error>> {
+        let reactions = self.load_reactions()?;
+        let mut messages: Vec<Message> = self
+            .load_rows()?
+            .into_iter()
+            .map(|(_, m)| m)
+            .filter(|m| m.conversation == conversation)
+            .collect();
+        for msg in messages.iter_mut().filter(|m| m.msg_id != 0) {
+            msg.reactions = reactions
+                .iter()
+                .filter(|r| r.conversation == conversation && r.msg_id == msg.msg_id)
+                .map(|r| (r.by, r.emoji.clone()))
+                .collect();
+        }
+        Ok(messages)
+    }
+
+    /// Every conversation with saved messages, most recently active first.
+    pub fn list_conversations(&self) -> Result<Vec<Conversation>, Box<dyn Error>> {
+        let mut conversations: Vec<Conversation> = Vec::new();
+        for (_, msg) in self.load_rows()? {
+            match conversations.iter_mut().find(|c| c.key == msg.conversation) {
+                Some(c) => {
+                    c.messages += 1;
+                    c.last_timestamp = c.last_timestamp.max(msg.timestamp);
+                }
+                None => conversations.push(Conversation {
+                    key: msg.conversation,
+                    messages: 1,
+                    last_timestamp: msg.timestamp,
+                }),
+            }
+        }
+        conversations.sort_by_key(|c| std::cmp::Reverse(c.last_timestamp));
+        Ok(conversations)
+    }
+
+    fn load_conversation_rows(&self) -> Result<Vec<ConversationRow>, Box<dyn Error>> {
+        let rows: Vec<(i64, Vec<u8>)> = self
+            .conn
+            .prepare("SELECT id, record FROM conversations ORDER BY id ASC")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut conversations = Vec::with_capacity(rows.len());
+        for (id, blob) in rows {
+            let record = unseal(&self.key, &blob)?;
+            if record.len() < 8 {
+                return Err("corrupt conversation in database".into());
+            }
+            conversations.push(ConversationRow {
+                id,
+                key: String::from_utf8_lossy(&record[8..]).to_string(),
+                expire_after: u64::from_be_bytes(record[..8].try_into().unwrap()),
+            });
+        }
+        Ok(conversations)
+    }
+
+    /// The agreed message lifetime of a conversation in seconds, 0 if its
+    /// messages are kept.
+    pub fn conversation_expiry(&self, conversation: &str) -> Result<u64, Box<dyn Error>> {
+        Ok(self
+            .load_conversation_rows()?
+            .into_iter()
+            .find(|c| c.key == conversation)
+            .map_or(0, |c| c.expire_after))
+    }
+
+    pub fn set_conversation_expiry(&self, conversation: &str, expire_after: u64) -> Result<(), Box<dyn Error>> {
+        self.next_expiry.set(None);
+        for row in self.load_conversation_rows()? {
+            if row.key == conversation {
+                self.conn.execute("DELETE FROM conversations WHERE id = ?1", [row.id])?;
+            }
+        }
+        if expire_after > 0 {
+            let mut record = expire_after.to_be_bytes().to_vec();
+            record.extend_from_slice(conversation.as_bytes());
+            self.conn.execute(
+                "INSERT INTO conversations (record) VALUES (?1)",
+                [seal(&self.key, record)?],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Deletes every message that has outlived its conversation's expiry,
+    /// then ratchets if this or anything before it deleted rows. Returns how
+    /// many were deleted.
+    pub fn purge_expired(&mut self, now: i64) -> Result<usize, Box<dyn Error>> {
+        let deleted = if self.next_expiry.get().is_some_and(|next| now < next) {
+            0
+        } else {
+            self.delete_expired(now)?
+        };
+        self.ratchet_pending()?;
+        Ok(deleted)
+    }
+
+    // the full look behind `purge_expired`, which also finds when it is
+    // needed next
+    fn delete_expired(&mut self, now: i64) -> Result<usize, Box<dyn Error>> {
+        let expiring = self.load_conversation_rows()?;
+        if expiring.is_empty() {
+            self.next_expiry.set(Some(i64::MAX));
+            return Ok(0);
+        }
+        let (expired, kept): (Vec<_>, Vec<_>) = self
+            .load_rows()?
+            .into_iter()
+            .filter_map(|(id, m)| {
+                let at = expiring
+                    .iter()
+                    .find(|c| c.key == m.conversation)
+                    .map(|c| m.timestamp.saturating_add(c.expire_after as i64))?;
+                Some((at, id, m))
+            })
+            .partition(|(at, _, _)| *at <= now);
+        self.next_expiry
+            .set(Some(kept.iter().map(|(at, _, _)| *at).min().unwrap_or(i64::MAX)));
+        let rows: Vec<(i64, Message)> = expired.into_iter().map(|(_, id, m)| (id, m)).collect();
+        self.delete_rows(&rows)
+    }
+
+    // deletes messages with their reactions and vault files, leaving the
+    // ratchet to the caller
+    fn delete_rows(&mut self, rows: &[(i64, Message)]) -> Result<usize, Box<dyn Error>> {
+        if rows.is_empty() {
+            return Ok(0);
+        }
+        let reactions = self.load_reactions()?;
+        let tx = self.conn.unchecked_transaction()?;
+        for (id, msg) in rows {
+            tx.execute("DELETE FROM messages WHERE id = ?1", [id])?;
+            tx.execute("DELETE FROM search_index WHERE message = ?1", [id])?;
+            if let Some(SavedFile::Vault(attachment)) = msg.file.as_ref().and_then(|f| f.saved.as_ref()) {
+                tx.execute("DELETE FROM attachments WHERE attachment = ?1", [*attachment as i64])?;
+                tx.execute("DELETE FROM attachment_keys WHERE attachment = ?1", [*attachment as i64])?;
+            }
+            for reaction in reactions
+                .iter()
+                .filter(|r| msg.msg_id != 0 && r.conversation == msg.conversation && r.msg_id == msg.msg_id)
+            {
+                tx.execute("DELETE FROM reactions WHERE id = ?1", [reaction.id])?;
+            }
+        }
+        tx.commit()?;
+        self.deleted = true;
+        Ok(rows.len())
+    }
+
+    /// Every sealed row, for backups. Padding is kept, so rows can be sealed
+    /// again byte for byte by `import_rows`. Vault chunks are exported
+    /// unsealed like the rest and get new file keys when imported.
+    pub fn export_rows(&self) -> Result<Vec<ExportedRow>, Box<dyn Error>> {
+        let mut out = Vec::new();
+        for (table, column) in SEALED_TABLES.iter().filter(|(t, _)| *t != "attachment_keys") {
+            let rows: Vec<(i64, Vec<u8>)> = self
+                .conn
+                .prepare(&format!("SELECT id, {} FROM {} ORDER BY id ASC", column, table))?
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<Vec<_>, _>>()?;
+            for (id, blob) in rows {
+                out.push((table.to_string(), id, decrypt(&self.key, &blob)?));
+            }
+        }
+        let chunks: Vec<(i64, i64, Vec<u8>)> = self
+            .conn
+            .prepare("SELECT id, attachment, record FROM attachments ORDER BY id ASC")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut keys: HashMap<i64, [u8; 32]> = HashMap::new();
+        for (id, attachment, blob) in chunks {
+            if let Entry::Vacant(e) = keys.entry(attachment) {
+                let key = self
+                    .attachment_key(&self.conn, attachment as u64)?
+                    .ok_or("corrupt attachment in database")?;
+                e.insert(key);
+            }
+            out.push(("attachments".to_string(), id, decrypt(&keys[&attachment], &blob)?));
+        }
+        keys.values_mut().for_each(|k| k.zeroize());
+        Ok(out)
+    }
+
+    /// Seals rows from `export_rows` under this database's key, keeping
+    /// their ids, and rebuilds the search index.
+    pub fn import_rows(&self, rows: &[ExportedRow]) -> Result<(), Box<dyn Error>> {
+        self.next_expiry.set(None);
+        let tx = self.conn.unchecked_transaction()?;
+        let mut keys: HashMap<u64, [u8; 32]> = HashMap::new();
+        for (table, id, plain) in rows {
+            // the lookup column of a vault chunk is a copy of its first 8
+            // bytes, past the length of the padded record
+            if table == "attachments" {
+                let attachment = plain
+                    .get(4..12)
+                    .map(|a| u64::from_be_bytes(a.try_into().unwrap()))
+                    .ok_or("corrupt attachment in backup")?;
+                if let Entry::Vacant(e) = keys.entry(attachment) {
+                    e.insert(self.new_attachment_key(&tx, attachment)?);
+                }
+                tx.execute(
+                    "INSERT INTO attachments (id, attachment, record) VALUES (?1, ?2, ?3)",
+                    rusqlite::params![id, attachment as i64, encrypt(&keys[&attachment], plain)?],
+                )?;
+                continue;
+            }
+            let (table, column) = SEALED_TABLES
+                .iter()
+                .filter(|(t, _)| *t != "attachment_keys")
+                .find(|(t, _)| t == table)
+                .ok_or_else(|| format!("unknown table '{}'", table))?;
+            tx.execute(
+                &format!("INSERT INTO {} (id, {}) VALUES (?1, ?2)", table, column),
+                rusqlite::params![id, encrypt(&self.key, plain)?],
+            )?;
+        }
+        keys.values_mut().for_each(|k| k.zeroize());
+        tx.commit()?;
+        self.rebuild_index()
+    }
+
+    /// Moves every message of `from` into `to`, merging them if `to` exists.
+    /// The expiry of `from` moves along unless `to` already has one.
+    pub fn rename_conversation(&self, from: &str, to: &str) -> Result<(), Box<dyn Error>> {
+        let expire_after = self.conversation_expiry(from)?;
+        if expire_after > 0 && self.conversation_expiry(to)? == 0 {
+            self.set_conversation_expiry(to, expire_after)?;
+        }
+        self.set_conversation_expiry(from, 0)?;
+        let tx = self.conn.unchecked_transaction()?;
+        for (id, mut msg) in self.load_rows()? {
+            if msg.conversation == from {
+                msg.conversation = to.to_string();
+                tx.execute(
+                    "UPDATE messages SET record = ?1 WHERE id = ?2",
+                    rusqlite::params![seal(&self.key, msg.to_record())?, id],
+                )?;
+                // tokens include the conversation
+                self.index_row(&tx, id, &msg)?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Deletes a conversation's messages and their reactions, then ratchets.
+    /// Its expiry is kept. Returns how many messages were deleted.
+    pub fn delete_conversation(&mut self, conversation: &str) -> Result<usize, Box<dyn Error>> {
+        let rows: Vec<(i64, Message)> = self
+            .load_rows()?
+            .into_iter()
+            .filter(|(_, m)| m.conversation == conversation)
+            .collect();
+        let deleted = self.delete_rows(&rows)?;
+        self.ratchet_pending()?;
+        Ok(deleted)
+    }
 
+    /// Adds the next chunk of a file kept in the vault. Chunks are read back
+    /// in the order they were added.
+    pub fn append_attachment(&self, attachment: u64, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut key = match self.attachment_key(&self.conn, attachment)? {
+            Some(key) => key,
+            None => self.new_attachment_key(&self.conn, attachment)?,
+        };
+        let mut record = attachment.to_be_bytes().to_vec();
+        record.extend_from_slice(data);
+        let sealed = seal(&key, record);
+        key.zeroize();
         self.conn.execute(
-            "INSERT INTO messages (direction, content, timestamp) VALUES (?1, ?2, ?3)",
-            rusqlite::params![direction.as_str(), encrypted, timestamp],
+            "INSERT INTO attachments (attachment, record) VALUES (?1, ?2)",
+            rusqlite::params![attachment as i64, sealed?],
         )?;
+        Ok(())
+    }
 
+    /// Writes a file from the vault to `out`, returning its size.
+    pub fn read_attachment(&self, attachment: u64, out: &mut dyn std::io::Write) -> Result<u64, Box<dyn Error>> {
+        let mut key = self
+            .attachment_key(&self.conn, attachment)?
+            .ok_or("the file is no longer in the vault")?;
+        let mut stmt = self
+            .conn
+            .prepare("SELECT record FROM attachments WHERE attachment = ?1 ORDER BY id ASC")?;
+        let mut rows = stmt.query([attachment as i64])?;
+        let mut size = 0;
+        while let Some(row) = rows.next()? {
+            let mut record = unseal(&key, &row.get::<_, Vec<u8>>(0)?)?;
+            let data = record.get(8..).ok_or("corrupt attachment in database")?;
+            out.write_all(data)?;
+            size += data.len() as u64;
+            record.zeroize();
+        }
+        key.zeroize();
+        if size == 0 {
+            return Err("the file is no longer in the vault".into());
+        }
+        Ok(size)
+    }
+
+    /// Drops a file from the vault, such as a cancelled download. Its key can
+    /// be recovered until the next `ratchet_pending`.
+    pub fn delete_attachment(&mut self, attachment: u64) -> Result<(), Box<dyn Error>> {
+        self.conn
+            .execute("DELETE FROM attachments WHERE attachment = ?1", [attachment as i64])?;
+        self.conn
+            .execute("DELETE FROM attachment_keys WHERE attachment = ?1", [attachment as i64])?;
+        self.deleted = true;
         Ok(())
     }
 
-    pub fn load_history(&self) -> Result<Vec<Message>, Box<dyn Error>> {
+    pub fn load_or_create_identity(&self) -> Result<StaticKeypair, Box<dyn Error>> {
+        let existing: Option<Vec<u8>> = self
+            .conn
+            .query_row("SELECT keypair FROM identity WHERE id = 1", [], |row| {
+                row.get(0)
+            })
+            .optional()?;
+
+        if let Some(encrypted) = existing {
+            let mut plain = decrypt(&self.key, &encrypted)?;
+            if plain.len() != 64 {
+                plain.zeroize();
+                return Err("corrupt identity key in database".into());
+            }
+            let keypair = StaticKeypair {
+                private: plain[..32].to_vec(),
+                public: plain[32..].to_vec(),
+            };
+            plain.zeroize();
+            return Ok(keypair);
+        }
+
+        let keypair = StaticKeypair::generate()?;
+        let mut plain = Vec::with_capacity(64);
+        plain.extend_from_slice(&keypair.private);
+        plain.extend_from_slice(&keypair.public);
+        let encrypted = encrypt(&self.key, &plain)?;
+        plain.zeroize();
+
+        self.conn.execute(
+            "INSERT INTO identity (id, keypair) VALUES (1, ?1)",
+            rusqlite::params![encrypted],
+        )?;
+        Ok(keypair)
+    }
+
+    pub fn list_contacts(&self) -> Result<Vec<Contact>, Box<dyn Error>> {
         let mut stmt = self
             .conn
-            .prepare("SELECT direction, content, timestamp FROM messages ORDER BY timestamp ASC")?;
+            .prepare("SELECT id, record FROM contacts ORDER BY id ASC")?;
 
-        let rows: Vec<(String, Vec<u8>, i64)> = stmt
-            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        let rows: Vec<(i64, Vec<u8>)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
             .collect::<Result<Vec<_>, _>>()?;
 
-        let mut messages = Vec::with_capacity(rows.len());
-        for (direction, encrypted, timestamp) in rows {
-            let content = decrypt(&self.key, &encrypted)?;
-            messages.push(Message {
-                direction: MessageDirection::from_str(&direction),
-                content,
-                timestamp,
-            });
+        let mut contacts = Vec::with_capacity(rows.len());
+        for (id, encrypted) in rows {
+            let plain = decrypt(&self.key, &encrypted)?;
+            let mut contact: Contact = toml::from_str(&String::from_utf8_lossy(&plain))?;
+            contact.id = id;
+            contacts.push(contact);
         }
+        Ok(contacts)
+    }
 
-        Ok(messages)
+    pub fn find_contact(&self, nickname: &str) -> Result<Option<Contact>, Box<dyn Error>> {
+        Ok(self
+            .list_contacts()?
+            .into_iter()
+            .find(|c| c.nickname == nickname))
+    }
+
+    pub fn find_contact_by_key(&self, key: &[u8]) -> Result<Option<Contact>, Box<dyn Error>> {
+        let key_hex = hex::encode(key);
+        Ok(self
+            .list_contacts()?
+            .into_iter()
+            .find(|c| c.pinned_key.as_deref() == Some(key_hex.as_str())))
+    }
+
+    /// Inserts the contact when `id` is 0, otherwise updates the existing row.
+    pub fn save_contact(&self, contact: &mut Contact) -> Result<(), Box<dyn Error>> {
+        let encrypted = encrypt(&self.key, toml::to_string(contact)?.as_bytes())?;
+        if contact.id == 0 {
+            self.conn.execute(
+                "INSERT INTO contacts (record) VALUES (?1)",
+                rusqlite::params![encrypted],
+            )?;
+            contact.id = self.conn.last_insert_rowid();
+        } else {
+            self.conn.execute(
+                "UPDATE contacts SET record = ?1 WHERE id = ?2",
+                rusqlite::params![encrypted, contact.id],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn remove_contact(&self, nickname: &str) -> Result<bool, Box<dyn Error>> {
+        match self.find_contact(nickname)? {
+            Some(contact) => {
+                self.conn
+                    .execute("DELETE FROM contacts WHERE id = ?1", [contact.id])?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
     }
 
     pub fn wipe(mut self) {
-        let _ = self
-            .conn
-            .execute_batch("DELETE FROM messages; DELETE FROM meta; VACUUM;");
+        let _ = self.conn.execute_batch(
+            "DELETE FROM messages; DELETE FROM reactions; DELETE FROM attachments; DELETE FROM attachment_keys; DELETE FROM search_index; DELETE FROM conversations; DELETE FROM identity; DELETE FROM contacts; DELETE FROM keyring; VACUUM;",
+        );
 
         self.key.zeroize();
+        self.master_key.zeroize();
 
         drop(self.conn);
         if let Ok(path) = db_path() {
@@ -152,30 +1168,324 @@ impl Storage {
                 let _ = zero_and_delete_file(&path);
             }
         }
+        for backup in backup_paths() {
+            let _ = zero_and_delete_file(&backup);
+        }
+    }
+    /// Looks up the words of the query in the index and only unseals the
+    /// rows that contain all of them. Queries with nothing but filters
+    /// have to check every row.
+    pub fn search_history(&self, conversation: &str, query: &SearchQuery) -> Result<Vec<Message>, Box<dyn Error>> {
+        let mut query_words: Vec<String> = query.terms.iter().flat_map(|t| words(t)).collect();
+        query_words.sort();
+        query_words.dedup();
+
+        let rows = if query_words.is_empty() {
+            self.load_rows()?
+        } else {
+            let mut index_key = self.index_key();
+            let mut candidates: Option<Vec<i64>> = None;
+            for word in &query_words {
+                let ids: Vec<i64> = self
+                    .conn
+                    .prepare("SELECT DISTINCT message FROM search_index WHERE token = ?1 ORDER BY message ASC")?
+                    .query_map([token(&index_key, conversation, word)], |row| row.get(0))?
+                    .collect::<Result<Vec<_>, _>>()?;
+                candidates = Some(match candidates {
+                    Some(c) => c.into_iter().filter(|id| ids.binary_search(id).is_ok()).collect(),
+                    None => ids,
+                });
+            }
+            index_key.zeroize();
+            let mut rows = Vec::new();
+            for id in candidates.unwrap_or_default() {
+                let blob: Vec<u8> = self
+                    .conn
+                    .query_row("SELECT record FROM messages WHERE id = ?1", [id], |row| row.get(0))?;
+                let mut record = unseal(&self.key, &blob)?;
+                rows.push((id, Message::from_record(&record)?));
+                record.zeroize();
+            }
+            rows
+        };
+
+        Ok(rows
+            .into_iter()
+            .map(|(_, m)| m)
+            .filter(|m| m.conversation == conversation && query.matches(m))
+            .collect())
     }
-    pub fn search_history(&self, term: &str) -> Result<Vec<Message>, Box<dyn Error>> {
-    let mut statement = self.conn.prepare(
-        "SELECT direction, content, timestamp FROM messages ORDER BY timestamp ASC"
+}
+
+/// Schema version kept in `PRAGMA user_version`. Bump it and add a step to
+/// `MIGRATIONS` whenever the layout changes.
+const SCHEMA_VERSION: i32 = 5;
+
+type Migration = fn(&Connection, &str) -> Result<(), Box<dyn Error>>;
+
+// step `i` upgrades a database from version `i` to `i + 1`. databases from
+// before versioning report 0 whatever layout they reached, so the steps
+// covering them must be safe to run on any of those
+const MIGRATIONS: [Migration; SCHEMA_VERSION as usize] = [
+    migrate_sealed_rows,
+    migrate_conversations,
+    migrate_search_index,
+    migrate_attachments,
+    migrate_attachment_keys,
+];
+
+/// Brings the database up to `SCHEMA_VERSION`, backing it up first if it
+/// already held anything. Returns the version it was at.
+fn migrate(conn: &Connection, path: &std::path::Path, passphrase: &str) -> Result<i32, Box<dyn Error>> {
+    let version: i32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "circuitchat.db was written by a newer version of circuitchat (schema {}, this version knows up to {}). update circuitchat to open it",
+            version, SCHEMA_VERSION
+        )
+        .into());
+    }
+    if version == SCHEMA_VERSION {
+        return Ok(version);
+    }
+
+    let empty: bool = conn.query_row("SELECT COUNT(*) = 0 FROM sqlite_master", [], |row| row.get(0))?;
+    if !empty {
+        let backup = path.with_extension(format!("db.v{}.bak", version));
+        if backup.exists() {
+            zero_and_delete_file(&backup)?;
+        }
+        conn.execute("VACUUM INTO ?1", [backup.to_string_lossy()])?;
+        println!("upgrading circuitchat.db, the old version was saved to {}", backup.display());
+    }
+    for (step, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migration(conn, passphrase)?;
+        conn.pragma_update(None, "user_version", step as i32 + 1)?;
+    }
+    Ok(version)
+}
+
+/// Copies of the database kept from before migrations.
+pub fn backup_paths() -> Vec<PathBuf> {
+    let Some(dir) = db_path().ok().and_then(|p| p.parent().map(|d| d.to_path_buf())) else {
+        return Vec::new();
+    };
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with("circuitchat.db.v") && n.ends_with(".bak"))
+        })
+        .collect()
+}
+
+// the first layouts only encrypted the content column, under the passphrase
+// key directly. they are recognised by their `meta` table
+fn migrate_sealed_rows(conn: &Connection, passphrase: &str) -> Result<(), Box<dyn Error>> {
+    if table_exists(conn, "meta")? {
+        migrate_column_encryption(conn, passphrase)?;
+    }
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS keyring (
+             id          INTEGER PRIMARY KEY CHECK (id = 1),
+             salt        BLOB NOT NULL,
+             wrapped_key BLOB NOT NULL
+         );
+
+         CREATE TABLE IF NOT EXISTS messages (
+             id     INTEGER PRIMARY KEY AUTOINCREMENT,
+             record BLOB NOT NULL
+         );
+
+         CREATE TABLE IF NOT EXISTS reactions (
+             id     INTEGER PRIMARY KEY AUTOINCREMENT,
+             record BLOB NOT NULL
+         );
+
+         CREATE TABLE IF NOT EXISTS identity (
+             id      INTEGER PRIMARY KEY CHECK (id = 1),
+             keypair BLOB NOT NULL
+         );
+
+         CREATE TABLE IF NOT EXISTS contacts (
+             id     INTEGER PRIMARY KEY AUTOINCREMENT,
+             record BLOB NOT NULL
+         );",
+    )?;
+    Ok(())
+}
+
+// message records without a conversation stay readable, so only the
+// settings table is new
+fn migrate_conversations(conn: &Connection, _: &str) -> Result<(), Box<dyn Error>> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS conversations (
+             id     INTEGER PRIMARY KEY AUTOINCREMENT,
+             record BLOB NOT NULL
+         );",
+    )?;
+    Ok(())
+}
+
+fn migrate_search_index(conn: &Connection, _: &str) -> Result<(), Box<dyn Error>> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS search_index (
+             token   BLOB NOT NULL,
+             message INTEGER NOT NULL
+         );
+         CREATE INDEX IF NOT EXISTS search_index_token ON search_index (token);
+         CREATE INDEX IF NOT EXISTS search_index_message ON search_index (message);",
     )?;
+    Ok(())
+}
 
-    let rows: Vec<(String, Vec<u8>, i64)> = statement
-        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+// chunks of vault files. `attachment` repeats the id sealed in the record so
+// a file's chunks can be found without unsealing the whole vault
+fn migrate_attachments(conn: &Connection, _: &str) -> Result<(), Box<dyn Error>> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS attachments (
+             id         INTEGER PRIMARY KEY AUTOINCREMENT,
+             attachment INTEGER NOT NULL DEFAULT 0,
+             record     BLOB NOT NULL
+         );
+         CREATE INDEX IF NOT EXISTS attachments_attachment ON attachments (attachment);",
+    )?;
+    Ok(())
+}
+
+// a key for each vault file, sealed under the data key. chunks sealed before
+// this are moved under such keys once the data key is known, in `open_at`
+fn migrate_attachment_keys(conn: &Connection, _: &str) -> Result<(), Box<dyn Error>> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS attachment_keys (
+             id         INTEGER PRIMARY KEY AUTOINCREMENT,
+             attachment INTEGER NOT NULL,
+             record     BLOB NOT NULL
+         );
+         CREATE INDEX IF NOT EXISTS attachment_keys_attachment ON attachment_keys (attachment);",
+    )?;
+    Ok(())
+}
+
+/// Converts a database from before whole-database encryption, where only the
+/// content column was encrypted and directly under the passphrase key. Every
+/// row is unsealed and written back in the new layout inside one transaction.
+fn migrate_column_encryption(conn: &Connection, passphrase: &str) -> Result<(), Box<dyn Error>> {
+    let (salt_vec, check_blob): (Vec<u8>, Vec<u8>) = conn.query_row(
+        "SELECT salt, check_blob FROM meta WHERE id = 1",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let salt: [u8; 16] = salt_vec
+        .try_into()
+        .map_err(|_| "corrupt salt in database")?;
+    let mut old_key = derive_key(passphrase, &salt)?;
+    decrypt(&old_key, &check_blob)?;
+
+    // the passphrase and salt stay the same, only the layout changes
+    let mut key = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut key);
+
+    let has_column = |name: &str| conn.prepare(&format!("SELECT {} FROM messages LIMIT 0", name)).is_ok();
+    let extra = if has_column("msg_id") {
+        "msg_id, reply_to, edited"
+    } else {
+        "0, 0, 0"
+    };
+    let rows: Vec<(String, Vec<u8>, i64, i64, i64, bool)> = conn
+        .prepare(&format!(
+            "SELECT direction, content, timestamp, {} FROM messages ORDER BY timestamp ASC",
+            extra
+        ))?
+        .query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?))
+        })?
         .collect::<Result<Vec<_>, _>>()?;
+    let reactions: Vec<(i64, String, Vec<u8>)> = if table_exists(conn, "reactions")? {
+        conn.prepare("SELECT msg_id, direction, emoji FROM reactions")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<Vec<_>, _>>()?
+    } else {
+        Vec::new()
+    };
 
-    let mut matches = Vec::new();
-    for (direction, encrypted, timestamp) in rows {
-        let content = decrypt(&self.key, &encrypted)?;
-        let text = String::from_utf8_lossy(&content).to_string();
-        if text.to_lowercase().contains(&term.to_lowercase()) {
-            matches.push(Message {
-                direction: MessageDirection::from_str(&direction),
-                content,
-                timestamp,
-            });
+    let tx = conn.unchecked_transaction()?;
+    tx.execute_batch(
+        "ALTER TABLE messages RENAME TO old_messages;
+         DROP TABLE IF EXISTS reactions;
+         CREATE TABLE keyring (
+             id          INTEGER PRIMARY KEY CHECK (id = 1),
+             salt        BLOB NOT NULL,
+             wrapped_key BLOB NOT NULL
+         );
+         CREATE TABLE messages (
+             id     INTEGER PRIMARY KEY AUTOINCREMENT,
+             record BLOB NOT NULL
+         );
+         CREATE TABLE reactions (
+             id     INTEGER PRIMARY KEY AUTOINCREMENT,
+             record BLOB NOT NULL
+         );",
+    )?;
+    for (direction, content, timestamp, msg_id, reply_to, edited) in rows {
+        let msg = Message {
+            conversation: String::new(),
+            direction: MessageDirection::from_str(&direction),
+            content: decrypt(&old_key, &content)?,
+            timestamp,
+            msg_id: msg_id as u64,
+            reply_to: reply_to as u64,
+            edited,
+            reactions: Vec::new(),
+            file: None,
+        };
+        tx.execute(
+            "INSERT INTO messages (record) VALUES (?1)",
+            [seal(&key, msg.to_record())?],
+        )?;
+    }
+    for (msg_id, by, emoji) in reactions {
+        let mut record = vec![MessageDirection::from_str(&by).to_byte()];
+        record.extend_from_slice(&msg_id.to_be_bytes());
+        record.extend_from_slice(&decrypt(&old_key, &emoji)?);
+        tx.execute(
+            "INSERT INTO reactions (record) VALUES (?1)",
+            [seal(&key, record)?],
+        )?;
+    }
+    for (table, column) in [("contacts", "record"), ("identity", "keypair")] {
+        if !table_exists(&tx, table)? {
+            continue;
+        }
+        let rows: Vec<(i64, Vec<u8>)> = tx
+            .prepare(&format!("SELECT id, {} FROM {}", column, table))?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (id, blob) in rows {
+            let mut plain = decrypt(&old_key, &blob)?;
+            let blob = encrypt(&key, &plain)?;
+            plain.zeroize();
+            tx.execute(
+                &format!("UPDATE {} SET {} = ?1 WHERE id = ?2", table, column),
+                rusqlite::params![blob, id],
+            )?;
         }
     }
-    Ok(matches)
-}
+    tx.execute(
+        "INSERT INTO keyring (id, salt, wrapped_key) VALUES (1, ?1, ?2)",
+        rusqlite::params![salt.as_slice(), encrypt(&old_key, &key)?],
+    )?;
+    tx.execute_batch("DROP TABLE old_messages; DROP TABLE meta;")?;
+    tx.commit()?;
+
+    old_key.zeroize();
+    key.zeroize();
+    conn.execute_batch("VACUUM;")?;
+    Ok(())
 }
 
 pub fn zero_and_delete_file(path: &std::path::Path) -> Result<(), Box<dyn Error>> {
@@ -194,11 +1504,6 @@ pub fn zero_and_delete_file(path: &std::path::Path) -> Result<(), Box<dyn Error>
     std::fs::remove_file(path)?;
     Ok(())
 }
-pub fn clear_history(storage: Option<Storage>) -> Result<(), Box<dyn Error>> {
-    let storage = storage.ok_or("no storage available")?;
-    storage.conn.execute("DELETE FROM messages", [])?;
-    Ok(())
-}
 
 pub fn db_path() -> Result<PathBuf, Box<dyn Error>> {
     let exe_dir = std::env::current_exe()?
@@ -208,10 +1513,240 @@ pub fn db_path() -> Result<PathBuf, Box<dyn Error>> {
     Ok(exe_dir.join("circuitchat.db"))
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Contact {
+    #[serde(skip)]
+    pub id: i64,
+    pub nickname: String,
+    pub onion: Option<String>,
+    pub pinned_key: Option<String>,
+    pub pinned_auth: Option<String>,
+    pub first_seen: i64,
+}
+
+pub enum PinCheck {
+    FirstUse,
+    Match,
+    Changed(String),
+}
+
+impl Contact {
+    pub fn new(nickname: &str, onion: Option<String>) -> Self {
+        Contact {
+            id: 0,
+            nickname: nickname.to_string(),
+            onion,
+            pinned_key: None,
+            pinned_auth: None,
+            first_seen: chrono::Utc::now().timestamp(),
+        }
+    }
+
+    /// Compares the session's key and auth pin against what was pinned on
+    /// first contact, pinning anything that has not been seen before.
+    pub fn check_pins(&mut self, key: Option<&[u8]>, auth: Option<&[u8]>) -> PinCheck {
+        let mut pinned_new = false;
+        let pins = [
+            ("identity key", &mut self.pinned_key, key),
+            ("password", &mut self.pinned_auth, auth),
+        ];
+        for (what, pinned, seen) in pins {
+            let Some(seen) = seen.map(hex::encode) else {
+                continue;
+            };
+            match pinned {
+                Some(p) if *p == seen => {}
+                Some(_) => return PinCheck::Changed(what.to_string()),
+                None => {
+                    *pinned = Some(seen);
+                    pinned_new = true;
+                }
+            }
+        }
+        if pinned_new {
+            PinCheck::FirstUse
+        } else {
+            PinCheck::Match
+        }
+    }
+}
+
+/// A parsed `/find` query: words and quoted phrases that must all appear,
+/// plus optional filters.
+pub struct SearchQuery {
+    /// Lowercase words and phrases.
+    pub terms: Vec<String>,
+    pub from: Option<MessageDirection>,
+    /// Unix seconds, exclusive.
+    pub before: Option<i64>,
+    pub after: Option<i64>,
+}
+
+impl SearchQuery {
+    /// Parses `hello "see you" from:peer after:2024-01-31`. Dates are local
+    /// days: `before:` excludes that day and `after:` starts the day after.
+    pub fn parse(query: &str) -> Result<Self, String> {
+        let mut parsed = SearchQuery {
+            terms: Vec::new(),
+            from: None,
+            before: None,
+            after: None,
+        };
+        let day_start = |date: &str, days_later: u64| -> Result<i64, String> {
+            chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .ok()
+                .and_then(|d| d.checked_add_days(chrono::Days::new(days_later)))
+                .and_then(|d| d.and_hms_opt(0, 0, 0)?.and_local_timezone(chrono::Local).earliest())
+                .map(|t| t.timestamp())
+                .ok_or_else(|| format!("dates look like 2024-01-31, not '{}'", date))
+        };
+
+        let mut rest = query.trim();
+        while !rest.is_empty() {
+            // quoted phrases are always search terms, even if they look like filters
+            if let Some(quoted) = rest.strip_prefix('"') {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                let phrase = quoted[..end].trim();
+                if !phrase.is_empty() {
+                    parsed.terms.push(phrase.to_lowercase());
+                }
+                rest = quoted.get(end + 1..).unwrap_or("").trim_start();
+                continue;
+            }
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let part = &rest[..end];
+            rest = rest[end..].trim_start();
+            if let Some(who) = part.strip_prefix("from:") {
+                parsed.from = Some(match who {
+                    "you" | "me" => MessageDirection::Sent,
+                    "peer" => MessageDirection::Received,
+                    _ => return Err(format!("from: takes you or peer, not '{}'", who)),
+                });
+            } else if let Some(date) = part.strip_prefix("before:") {
+                parsed.before = Some(day_start(date, 0)?);
+            } else if let Some(date) = part.strip_prefix("after:") {
+                parsed.after = Some(day_start(date, 1)?);
+            } else {
+                parsed.terms.push(part.to_lowercase());
+            }
+        }
+
+        if parsed.terms.is_empty() && parsed.from.is_none() && parsed.before.is_none() && parsed.after.is_none() {
+            return Err("empty query".to_string());
+        }
+        Ok(parsed)
+    }
+
+    fn matches(&self, msg: &Message) -> bool {
+        let text = String::from_utf8_lossy(&msg.content).to_lowercase();
+        self.from.is_none_or(|d| d == msg.direction)
+            && self.before.is_none_or(|t| msg.timestamp < t)
+            && self.after.is_none_or(|t| msg.timestamp >= t)
+            && self.terms.iter().all(|t| text.contains(t.as_str()))
+    }
+}
+
+/// A saved conversation, keyed by how its peer was recognised.
+pub struct Conversation {
+    pub key: String,
+    pub messages: usize,
+    pub last_timestamp: i64,
+}
+
 pub struct Message {
+    /// Empty for messages saved before history was kept per conversation.
+    pub conversation: String,
     pub direction: MessageDirection,
     pub content: Vec<u8>,
     pub timestamp: i64,
+    pub msg_id: u64,
+    pub reply_to: u64,
+    pub edited: bool,
+    /// Who reacted (`Sent` for us) and with what.
+    pub reactions: Vec<(MessageDirection, String)>,
+    /// Set on file transfer events, whose content is the line shown for them.
+    pub file: Option<FileRecord>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FileEvent {
+    Offered,
+    Completed,
+    Rejected,
+    Cancelled,
+    Failed,
+}
+
+impl FileEvent {
+    pub fn name(self) -> &'static str {
+        match self {
+            FileEvent::Offered => "offered",
+            FileEvent::Completed => "completed",
+            FileEvent::Rejected => "rejected",
+            FileEvent::Cancelled => "cancelled",
+            FileEvent::Failed => "failed",
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        self as u8
+    }
+
+    fn from_byte(b: u8) -> Option<Self> {
+        [
+            FileEvent::Offered,
+            FileEvent::Completed,
+            FileEvent::Rejected,
+            FileEvent::Cancelled,
+            FileEvent::Failed,
+        ]
+        .get(b as usize)
+        .copied()
+    }
+}
+
+/// Where a received file was written.
+#[derive(Clone)]
+pub enum SavedFile {
+    Downloads(PathBuf),
+    /// Sealed in the database, under this attachment id.
+    Vault(u64),
+}
+
+impl std::fmt::Display for SavedFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SavedFile::Downloads(path) => write!(f, "{}", path.display()),
+            SavedFile::Vault(_) => write!(f, "the vault"),
+        }
+    }
+}
+
+/// What a file transfer event records besides its line of text. The row's
+/// direction says who acted, `incoming` which way the file was going.
+pub struct FileRecord {
+    pub event: FileEvent,
+    pub incoming: bool,
+    pub name: String,
+    pub size: u64,
+    /// The checksum from the offer, if the sender sent one: a hash tree root
+    /// or an XXH3 of the whole file (see `files::checksum_kind`).
+    pub checksum: Option<Vec<u8>>,
+    /// Only set once a received file is complete.
+    pub saved: Option<SavedFile>,
+}
+
+impl FileRecord {
+    pub fn new(event: FileEvent, incoming: bool, name: &str, size: u64) -> Self {
+        FileRecord {
+            event,
+            incoming,
+            name: name.to_string(),
+            size,
+            checksum: None,
+            saved: None,
+        }
+    }
 }
 
 #[derive(Clone, Copy, PartialEq)]
@@ -222,14 +1757,6 @@ pub enum MessageDirection {
 }
 
 impl MessageDirection {
-    fn as_str(&self) -> &'static str {
-        match self {
-            MessageDirection::Sent => "sent",
-            MessageDirection::Received => "received",
-            MessageDirection::System => "system",
-        }
-    }
-
     fn from_str(s: &str) -> Self {
         match s {
             "sent" => MessageDirection::Sent,
@@ -237,4 +1764,20 @@ impl MessageDirection {
             _ => MessageDirection::Received,
         }
     }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            MessageDirection::Sent => 0,
+            MessageDirection::Received => 1,
+            MessageDirection::System => 2,
+        }
+    }
+
+    fn from_byte(b: u8) -> Self {
+        match b {
+            0 => MessageDirection::Sent,
+            2 => MessageDirection::System,
+            _ => MessageDirection::Received,
+        }
+    }
 }
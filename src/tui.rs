@@ -1,4 +1,5 @@
-use crate::storage::MessageDirection;
+use crate::images::{Graphics, Placement, Preview};
+use crate::storage::{MessageDirection, SavedFile};
 use chrono::{Local, TimeZone, Utc};
 use crossterm::event::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
 use ratatui::{
@@ -9,11 +10,45 @@ use ratatui::{
     widgets::{Block, Borders, Paragraph, Wrap},
 };
 use std::io::{self, Write};
+use std::sync::Arc;
 use std::time::Instant;
+
+// rows of the transfers panel before it stops growing
+const MAX_TRANSFER_ROWS: usize = 5;
+
+/// Only tracked for text that carries an id. Sent messages move forward
+/// through the states as acks arrive, received ones go to `Read` once shown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MessageState {
+    None,
+    Sent,
+    Delivered,
+    Read,
+}
+
 pub struct ChatMessage {
     pub direction: MessageDirection,
+    pub sender: Option<String>,
     pub content: Vec<(String, Option<Style>)>,
     pub timestamp: String,
+    /// Unix seconds, what expiry is measured against.
+    pub time: i64,
+    pub id: u64,
+    pub state: MessageState,
+    /// Id of the quoted message, or 0.
+    pub reply_to: u64,
+    pub edited: bool,
+    pub deleted: bool,
+    /// Who reacted (`Sent` for us) and with what, at most one per side.
+    pub reactions: Vec<(MessageDirection, String)>,
+    /// An image shown under the message, for files that were images.
+    pub preview: Option<Arc<Preview>>,
+}
+
+impl ChatMessage {
+    pub fn text(&self) -> String {
+        self.content.iter().map(|(s, _)| s.as_str()).collect()
+    }
 }
 
 pub fn plain(text: &str) -> Vec<(String, Option<Style>)> {
@@ -21,21 +56,38 @@ pub fn plain(text: &str) -> Vec<(String, Option<Style>)> {
 }
 
 pub fn highlighted(text: &str, term: &str) -> Vec<(String, Option<Style>)> {
+    highlighted_any(text, &[term])
+}
+
+/// Like `highlighted`, for every occurrence of any of `terms`.
+pub fn highlighted_any(text: &str, terms: &[&str]) -> Vec<(String, Option<Style>)> {
     let lower_text = text.to_lowercase();
-    let lower_term = term.to_lowercase();
+    // offsets into the lowercased text only carry over if lowercasing kept
+    // every length
+    if lower_text.len() != text.len() {
+        return plain(text);
+    }
+    let lower_terms: Vec<String> = terms
+        .iter()
+        .filter(|t| !t.is_empty())
+        .map(|t| t.to_lowercase())
+        .collect();
     let mut spans = Vec::new();
     let mut last = 0;
 
-    while let Some(pos) = lower_text[last..].find(&lower_term) {
-        let abs = last + pos;
+    while let Some((abs, len)) = lower_terms
+        .iter()
+        .filter_map(|t| lower_text[last..].find(t.as_str()).map(|pos| (last + pos, t.len())))
+        .min()
+    {
         if abs > last {
             spans.push((text[last..abs].to_string(), None));
         }
         spans.push((
-            text[abs..abs + term.len()].to_string(),
+            text[abs..abs + len].to_string(),
             Some(Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
         ));
-        last = abs + term.len();
+        last = abs + len;
     }
     if last < text.len() {
         spans.push((text[last..].to_string(), None));
@@ -43,150 +95,487 @@ pub fn highlighted(text: &str, term: &str) -> Vec<(String, Option<Style>)> {
     spans
 }
 
+/// One row of the transfers panel.
 pub struct TransferProgress {
+    /// The transfer id, chosen by whichever side offered the file.
+    pub id: u64,
+    pub incoming: bool,
     pub name: String,
     pub size: u64,
     pub transferred: u64,
-    pub start: Instant,
+    /// "queued", "hashing", "offered", "sending" or "receiving".
+    pub state: &'static str,
+    start: Instant,
+    // where this run started, so resumed bytes do not count towards the speed
+    offset: u64,
+    // bytes this run took on the wire, less than the file's when compressed
+    wire: u64,
 }
 
 impl TransferProgress {
-    fn pct(&self) -> u16 {
+    fn pct(&self) -> u64 {
         if self.size == 0 {
             100
         } else {
-            (self.transferred * 100 / self.size) as u16
+            self.transferred * 100 / self.size
+        }
+    }
+
+    fn speed(&self) -> f64 {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            self.transferred.saturating_sub(self.offset) as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    /// How many times smaller compression made this run, if it did.
+    fn ratio(&self) -> Option<f64> {
+        let raw = self.transferred.saturating_sub(self.offset);
+        (self.wire > 0 && raw > self.wire).then(|| raw as f64 / self.wire as f64)
+    }
+
+    fn eta(&self) -> String {
+        let speed = self.speed();
+        let remaining = self.size.saturating_sub(self.transferred) as f64;
+        if speed <= 0.0 || remaining <= 0.0 {
+            return "--:--".to_string();
+        }
+        let secs = (remaining / speed).round() as u64;
+        let (h, m, s) = (secs / 3600, (secs % 3600) / 60, secs % 60);
+        if h > 0 {
+            format!("{:02}:{:02}:{:02}", h, m, s)
+        } else {
+            format!("{:02}:{:02}", m, s)
         }
     }
 }
 
-pub struct App {
+/// Everything that belongs to one conversation. The listener keeps one view
+/// per connected peer and the tab bar switches between them.
+pub struct SessionView {
+    pub id: u64,
     pub messages: Vec<ChatMessage>,
-    pub input: String,
-    pub cursor_position: usize,
     pub status: String,
-    pub should_quit: bool,
+    pub unread: usize,
     scroll_offset: usize,
-    visible_height: usize,
-    pub show_menu: bool,
-    pub send_progress: Option<TransferProgress>,
-    pub recv_progress: Option<TransferProgress>,
-    pub pending_incoming_offer: Option<(String, u64, Option<Vec<u8>>)>,
+    follow: bool,
+    /// Transfers in both directions, numbered from 1 in the panel.
+    pub transfers: Vec<TransferProgress>,
     pub peer_typing: bool,
     pub peer_away: bool,
-    pub pending_delivery: usize,
     pub session_fingerprint: Option<String>,
-    pub message_notification_sound: bool,
-    pub mention_notification_sound: bool,
-    pub session_deadline: Option<std::time::Instant>,
+    pub peer_label: String,
+    pub identity_warning: Option<String>,
+    /// Saved history key of this tab's peer, `None` while nothing is saved.
+    pub conversation: Option<String>,
+    /// Agreed message lifetime in seconds, 0 if messages are kept.
+    pub expire_after: u64,
+    /// Voice clips received in this tab, newest last, for `/play`.
+    pub voice_clips: Vec<SavedFile>,
+    message_notification_sound: bool,
+    mention_notification_sound: bool,
 }
 
-impl App {
-    pub fn new(
+impl SessionView {
+    fn new(
+        id: u64,
         status: &str,
         message_notification_sound: bool,
         mention_notification_sound: bool,
     ) -> Self {
         Self {
+            id,
             messages: Vec::new(),
-            input: String::new(),
-            cursor_position: 0,
             status: status.to_string(),
-            should_quit: false,
-            show_menu: false,
+            unread: 0,
             scroll_offset: 0,
-            visible_height: 0,
-            send_progress: None,
-            recv_progress: None,
-            pending_incoming_offer: None,
+            follow: true,
+            transfers: Vec::new(),
             peer_typing: false,
             peer_away: false,
-            pending_delivery: 0,
             session_fingerprint: None,
+            peer_label: "peer".to_string(),
+            identity_warning: None,
+            conversation: None,
+            expire_after: 0,
+            voice_clips: Vec::new(),
             message_notification_sound,
             mention_notification_sound,
-            session_deadline: None,
         }
     }
 
     pub fn add_message(&mut self, direction: MessageDirection, content: Vec<(String, Option<Style>)>, timestamp: String) {
+        self.push_message(direction, None, content, timestamp, 0);
+    }
+
+    /// Adds a sent or received text that replies, edits, reactions and
+    /// receipts can refer to by `id`.
+    pub fn add_text(
+        &mut self,
+        direction: MessageDirection,
+        id: u64,
+        content: Vec<(String, Option<Style>)>,
+        timestamp: String,
+    ) -> &mut ChatMessage {
+        self.push_message(direction, None, content, timestamp, id);
+        self.messages.last_mut().unwrap()
+    }
+
+    /// Inserts a saved message at `index` without counting it as unread.
+    /// Receipts belonged to the session it was sent in, so it has no state.
+    pub fn insert_history(
+        &mut self,
+        index: usize,
+        direction: MessageDirection,
+        id: u64,
+        content: Vec<(String, Option<Style>)>,
+        timestamp: String,
+    ) -> &mut ChatMessage {
+        self.messages.insert(
+            index,
+            ChatMessage {
+                direction,
+                sender: None,
+                content,
+                timestamp,
+                time: Utc::now().timestamp(),
+                id,
+                state: MessageState::None,
+                reply_to: 0,
+                edited: false,
+                deleted: false,
+                reactions: Vec::new(),
+                preview: None,
+            },
+        );
+        &mut self.messages[index]
+    }
+
+    /// Drops every sent or received message older than `expire_after`.
+    /// System notices stay.
+    pub fn expire(&mut self, now: i64) {
+        if self.expire_after == 0 {
+            return;
+        }
+        let cutoff = now.saturating_sub(self.expire_after as i64);
+        self.messages
+            .retain(|m| m.direction == MessageDirection::System || m.time > cutoff);
+    }
+
+    /// Finds a text by its protocol id. Ids are only unique per sender, so
+    /// the direction has to match as well.
+    pub fn find_text(&mut self, direction: MessageDirection, id: u64) -> Option<&mut ChatMessage> {
+        self.messages
+            .iter_mut()
+            .find(|m| id != 0 && m.id == id && m.direction == direction && !m.deleted)
+    }
+
+    /// The `n`th most recent text in `direction`, counting from 1.
+    pub fn nth_last_text(&self, direction: MessageDirection, n: usize) -> Option<&ChatMessage> {
+        self.messages
+            .iter()
+            .rev()
+            .filter(|m| m.id != 0 && m.direction == direction && !m.deleted)
+            .nth(n.checked_sub(1)?)
+    }
+
+    pub fn edit_text(&mut self, direction: MessageDirection, id: u64, text: &str) -> bool {
+        let Some(msg) = self.find_text(direction, id) else {
+            return false;
+        };
+        msg.content = if text.contains("@peer") {
+            highlighted(text, "@peer")
+        } else {
+            plain(text)
+        };
+        msg.edited = true;
+        true
+    }
+
+    pub fn delete_text(&mut self, direction: MessageDirection, id: u64) -> bool {
+        let Some(msg) = self.find_text(direction, id) else {
+            return false;
+        };
+        msg.content = vec![(
+            "message deleted".to_string(),
+            Some(Style::default().fg(Color::DarkGray).add_modifier(Modifier::ITALIC)),
+        )];
+        msg.deleted = true;
+        msg.reactions.clear();
+        true
+    }
+
+    /// `by` is who reacted, an empty emoji removes the reaction.
+    pub fn set_reaction(&mut self, direction: MessageDirection, id: u64, by: MessageDirection, emoji: &str) -> bool {
+        let Some(msg) = self.find_text(direction, id) else {
+            return false;
+        };
+        msg.reactions.retain(|(who, _)| *who != by);
+        if !emoji.is_empty() {
+            msg.reactions.push((by, emoji.to_string()));
+        }
+        true
+    }
+
+    /// Adds a received message labelled with its sender instead of `peer_label`,
+    /// used when several peers share one conversation.
+    pub fn add_message_from(&mut self, sender: &str, content: Vec<(String, Option<Style>)>, timestamp: String) {
+        self.push_message(MessageDirection::Received, Some(sender.to_string()), content, timestamp, 0);
+    }
+
+    fn push_message(
+        &mut self,
+        direction: MessageDirection,
+        sender: Option<String>,
+        content: Vec<(String, Option<Style>)>,
+        timestamp: String,
+        id: u64,
+    ) {
         let full_text: String = content.iter().map(|(s, _)| s.as_str()).collect();
         let should_bell = matches!(direction, MessageDirection::Received)
             && (self.message_notification_sound
                 || (full_text.contains("@peer") && self.mention_notification_sound));
+        if should_bell {
+            let _ = io::stdout().write_all(b"\x07");
+            let _ = io::stdout().flush();
+        }
 
+        if direction == MessageDirection::Received {
+            self.unread += 1;
+        }
+        let state = match direction {
+            _ if id == 0 => MessageState::None,
+            MessageDirection::Sent => MessageState::Sent,
+            MessageDirection::Received => MessageState::Delivered,
+            MessageDirection::System => MessageState::None,
+        };
         self.messages.push(ChatMessage {
             direction,
+            sender,
             content,
             timestamp,
+            time: Utc::now().timestamp(),
+            id,
+            state,
+            reply_to: 0,
+            edited: false,
+            deleted: false,
+            reactions: Vec::new(),
+            preview: None,
         });
-
-        if should_bell {
-            let _ = io::stdout().write_all(b"\x07");
-            let _ = io::stdout().flush();
-        }
-
-        self.scroll_to_bottom();
+        self.follow = true;
     }
 
     pub fn add_plain_message(&mut self, direction: MessageDirection, content: String, timestamp: String) {
         self.add_message(direction, plain(&content), timestamp);
     }
 
-    pub fn set_send_progress(&mut self, name: String, size: u64) {
-        self.send_progress = Some(TransferProgress {
-            name,
+    /// Shows `preview` under the last message.
+    pub fn set_preview(&mut self, preview: Preview) {
+        if let Some(msg) = self.messages.last_mut() {
+            msg.preview = Some(Arc::new(preview));
+        }
+    }
+
+    pub fn add_transfer(&mut self, id: u64, incoming: bool, name: &str, size: u64, state: &'static str) {
+        self.transfers.push(TransferProgress {
+            id,
+            incoming,
+            name: name.to_string(),
             size,
             transferred: 0,
+            state,
             start: Instant::now(),
+            offset: 0,
+            wire: 0,
         });
     }
 
-    pub fn update_send_progress(&mut self, sent: u64) {
-        if let Some(ref mut p) = self.send_progress {
-            p.transferred = sent;
+    pub fn transfer(&self, id: u64, incoming: bool) -> Option<&TransferProgress> {
+        self.transfers.iter().find(|t| t.id == id && t.incoming == incoming)
+    }
+
+    /// Moves a transfer to `state`, starting its speed measurement at `offset`.
+    pub fn start_transfer(&mut self, id: u64, incoming: bool, state: &'static str, offset: u64) {
+        if let Some(t) = self.transfers.iter_mut().find(|t| t.id == id && t.incoming == incoming) {
+            t.state = state;
+            t.transferred = offset;
+            t.offset = offset;
+            t.wire = 0;
+            t.start = Instant::now();
         }
     }
 
-    pub fn clear_send_progress(&mut self) {
-        self.send_progress = None;
+    /// Records progress up to `transferred`, which took `wire` more bytes on
+    /// the wire.
+    pub fn update_transfer(&mut self, id: u64, incoming: bool, transferred: u64, wire: u64) {
+        if let Some(t) = self.transfers.iter_mut().find(|t| t.id == id && t.incoming == incoming) {
+            t.transferred = transferred;
+            t.wire += wire;
+        }
     }
 
-    pub fn set_recv_progress(&mut self, name: String, size: u64) {
-        self.recv_progress = Some(TransferProgress {
-            name,
-            size,
-            transferred: 0,
-            start: Instant::now(),
+    pub fn remove_transfer(&mut self, id: u64, incoming: bool) {
+        self.transfers.retain(|t| t.id != id || t.incoming != incoming);
+    }
+
+    /// Moves a sent message forward to `state`. An id of 0 comes from a
+    /// legacy peer and means the oldest message still waiting for it.
+    pub fn mark_sent(&mut self, id: u64, state: MessageState) {
+        let msg = self.messages.iter_mut().find(|m| {
+            m.direction == MessageDirection::Sent
+                && m.state != MessageState::None
+                && if id == 0 { m.state < state } else { m.id == id }
         });
+        if let Some(msg) = msg {
+            msg.state = msg.state.max(state);
+        }
     }
+}
+
+pub struct App {
+    pub views: Vec<SessionView>,
+    pub active: usize,
+    pub input: String,
+    pub cursor_position: usize,
+    pub should_quit: bool,
+    visible_height: usize,
+    // the messages of the active view the last draw put on screen, at least
+    // in part. wrapped lines and previews make it fewer than `visible_height`
+    shown: std::ops::Range<usize>,
+    pub show_menu: bool,
+    pub message_notification_sound: bool,
+    pub mention_notification_sound: bool,
+    pub session_deadline: Option<std::time::Instant>,
+    /// While set, the input is masked and titled with this prompt.
+    pub secret_prompt: Option<String>,
+    /// When the running `/voice` recording started. Enter and Esc then stop
+    /// it, everything else is ignored.
+    pub recording: Option<Instant>,
+    pub graphics: Graphics,
+    /// Previews the last draw left room for, which `Kitty` and `Sixel` draw
+    /// over the frame.
+    pub placements: Vec<Placement>,
+}
 
-    pub fn update_recv_progress(&mut self, received: u64) {
-        if let Some(ref mut p) = self.recv_progress {
-            p.transferred = received;
+impl App {
+    pub fn new(
+        status: &str,
+        message_notification_sound: bool,
+        mention_notification_sound: bool,
+    ) -> Self {
+        Self {
+            views: vec![SessionView::new(
+                0,
+                status,
+                message_notification_sound,
+                mention_notification_sound,
+            )],
+            active: 0,
+            input: String::new(),
+            cursor_position: 0,
+            should_quit: false,
+            show_menu: false,
+            visible_height: 0,
+            shown: 0..0,
+            message_notification_sound,
+            mention_notification_sound,
+            session_deadline: None,
+            secret_prompt: None,
+            recording: None,
+            graphics: Graphics::Off,
+            placements: Vec::new(),
         }
     }
 
-    pub fn clear_recv_progress(&mut self) {
-        self.recv_progress = None;
+    pub fn view(&self) -> &SessionView {
+        &self.views[self.active]
     }
 
-    pub fn scroll_to_bottom(&mut self) {
-        let total = self.messages.len();
-        if total > self.visible_height && self.visible_height > 0 {
-            self.scroll_offset = total - self.visible_height;
-        } else {
-            self.scroll_offset = 0;
+    pub fn view_mut(&mut self) -> &mut SessionView {
+        &mut self.views[self.active]
+    }
+
+    pub fn view_by_id(&mut self, id: u64) -> Option<&mut SessionView> {
+        self.views.iter_mut().find(|v| v.id == id)
+    }
+
+    pub fn add_view(&mut self, id: u64, status: &str) -> &mut SessionView {
+        self.views.push(SessionView::new(
+            id,
+            status,
+            self.message_notification_sound,
+            self.mention_notification_sound,
+        ));
+        self.views.last_mut().unwrap()
+    }
+
+    pub fn remove_view(&mut self, index: usize) {
+        if self.views.len() > 1 && index < self.views.len() {
+            self.views.remove(index);
+            if self.active >= self.views.len() || self.active > index {
+                self.active = self.active.saturating_sub(1);
+            }
         }
     }
 
+    pub fn switch_to(&mut self, index: usize) {
+        if index < self.views.len() {
+            self.active = index;
+            self.views[index].unread = 0;
+        }
+    }
+
+    pub fn add_message_from(&mut self, sender: &str, content: Vec<(String, Option<Style>)>, timestamp: String) {
+        self.view_mut().add_message_from(sender, content, timestamp);
+    }
+
+    pub fn add_plain_message(&mut self, direction: MessageDirection, content: String, timestamp: String) {
+        self.view_mut().add_plain_message(direction, content, timestamp);
+    }
+
+    pub fn scroll_to_bottom(&mut self) {
+        self.view_mut().follow = true;
+    }
+
+    /// Marks received texts that the last draw put on screen as read and
+    /// returns their ids, so read receipts are only sent for what was shown.
+    pub fn take_read(&mut self) -> Vec<u64> {
+        let shown = self.shown.clone();
+        let view = self.view_mut();
+        let end = view.messages.len().min(shown.end);
+        let start = shown.start.min(end);
+        view.messages[start..end]
+            .iter_mut()
+            .filter(|m| m.direction == MessageDirection::Received && m.state == MessageState::Delivered)
+            .map(|m| {
+                m.state = MessageState::Read;
+                m.id
+            })
+            .collect()
+    }
+
     fn scroll_up(&mut self, n: usize) {
-        self.scroll_offset = self.scroll_offset.saturating_sub(n);
+        let height = self.visible_height;
+        let view = self.view_mut();
+        if view.follow {
+            view.scroll_offset = view.messages.len().saturating_sub(height);
+            view.follow = false;
+        }
+        view.scroll_offset = view.scroll_offset.saturating_sub(n);
     }
 
     fn scroll_down(&mut self, n: usize) {
-        let max = self.messages.len().saturating_sub(self.visible_height);
-        self.scroll_offset = (self.scroll_offset + n).min(max);
+        let height = self.visible_height;
+        let view = self.view_mut();
+        if view.follow {
+            return;
+        }
+        let max = view.messages.len().saturating_sub(height);
+        view.scroll_offset = (view.scroll_offset + n).min(max);
+        view.follow = view.scroll_offset == max;
     }
 
     pub fn handle_key(&mut self, key: KeyEvent) -> Option<String> {
@@ -199,8 +588,34 @@ impl App {
                 return None;
             }
         }
+        if key.modifiers.contains(KeyModifiers::ALT) && self.views.len() > 1 {
+            match key.code {
+                KeyCode::Left => {
+                    let n = self.views.len();
+                    self.switch_to((self.active + n - 1) % n);
+                    return None;
+                }
+                KeyCode::Right => {
+                    self.switch_to((self.active + 1) % self.views.len());
+                    return None;
+                }
+                KeyCode::Char(c @ '1'..='9') => {
+                    self.switch_to(c as usize - '1' as usize);
+                    return None;
+                }
+                _ => {}
+            }
+        }
+
+        if self.recording.is_some() {
+            return match key.code {
+                KeyCode::Enter => Some("/voice".to_string()),
+                KeyCode::Esc => Some("/voice cancel".to_string()),
+                _ => None,
+            };
+        }
 
-        if self.show_menu && self.recv_progress.is_none() {
+        if self.show_menu {
             match key.code {
                 KeyCode::Esc => {
                     self.show_menu = false;
@@ -240,6 +655,10 @@ impl App {
                 self.should_quit = true;
                 None
             }
+            // never while a passphrase is typed, it would be taken as one
+            KeyCode::Char('v') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.secret_prompt.is_none().then(|| "/paste".to_string())
+            }
             KeyCode::Enter => {
                 if self.input.is_empty() {
                     return None;
@@ -327,78 +746,149 @@ impl App {
     }
 
     pub fn draw(&mut self, frame: &mut Frame) {
+        let tabs = if self.views.len() > 1 { 1 } else { 0 };
+        let transfers = match self.view().transfers.len() {
+            0 => 0,
+            n => n.min(MAX_TRANSFER_ROWS) as u16 + 2,
+        };
         let chunks = Layout::default()
             .direction(Direction::Vertical)
-            .constraints([Constraint::Min(1), Constraint::Length(3)])
+            .constraints([
+                Constraint::Length(tabs),
+                Constraint::Min(1),
+                Constraint::Length(transfers),
+                Constraint::Length(3),
+            ])
             .split(frame.area());
 
-        self.draw_messages(frame, chunks[0]);
-        self.draw_input(frame, chunks[1]);
-        if self.send_progress.is_some() {
-            self.draw_transfer_modal(frame, true);
-        } else if self.recv_progress.is_some() {
-            self.draw_transfer_modal(frame, false);
-        } else if self.show_menu {
+        self.views[self.active].unread = 0;
+        if tabs > 0 {
+            self.draw_tabs(frame, chunks[0]);
+        }
+        self.draw_messages(frame, chunks[1]);
+        if transfers > 0 {
+            self.draw_transfers(frame, chunks[2]);
+        }
+        self.draw_input(frame, chunks[3]);
+        if self.show_menu {
+            // graphics would be drawn over the menu
+            self.placements.clear();
             self.draw_menu(frame);
         }
     }
 
+    fn draw_tabs(&self, frame: &mut Frame, area: Rect) {
+        let mut spans = Vec::new();
+        for (i, view) in self.views.iter().enumerate() {
+            let mut label = format!(" {}:{} ", i + 1, view.peer_label);
+            if view.unread > 0 {
+                label = format!(" {}:{} ({}) ", i + 1, view.peer_label, view.unread);
+            }
+            let style = if i == self.active {
+                Style::default().fg(Color::Black).bg(Color::White)
+            } else if view.unread > 0 {
+                Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD)
+            } else {
+                Style::default().fg(Color::DarkGray)
+            };
+            spans.push(Span::styled(label, style));
+        }
+        frame.render_widget(Paragraph::new(Line::from(spans)), area);
+    }
+
     fn draw_messages(&mut self, frame: &mut Frame, area: Rect) {
         let mut inner_height = area.height.saturating_sub(2) as usize;
-        if let Some(ref fp) = self.session_fingerprint {
-            if !fp.is_empty() {
-                inner_height = inner_height.saturating_sub(1);
-            }
+        if let Some(ref fp) = self.view().session_fingerprint
+            && !fp.is_empty()
+        {
+            inner_height = inner_height.saturating_sub(1);
         }
         self.visible_height = inner_height;
+        let inner_width = area.width.saturating_sub(2);
+        let graphics = self.graphics;
+        let view = &self.views[self.active];
+        // rows a message takes once wrapped, with its preview
+        let preview_of = |msg: &ChatMessage| {
+            msg.preview
+                .clone()
+                .filter(|p| graphics != Graphics::Off && p.cols <= inner_width)
+        };
+        let rows = |msg: &ChatMessage| {
+            let text = Paragraph::new(message_line(view, msg)).wrap(Wrap { trim: false }).line_count(inner_width);
+            text + preview_of(msg).map_or(0, |p| p.rows as usize)
+        };
+        // following, as many of the newest messages as fit
+        let start = if view.follow {
+            let mut start = view.messages.len();
+            let mut used = 0;
+            while start > 0 {
+                used += rows(&view.messages[start - 1]);
+                if used > inner_height && start < view.messages.len() {
+                    break;
+                }
+                start -= 1;
+            }
+            start
+        } else {
+            view.scroll_offset.min(view.messages.len())
+        };
 
         let mut block = Block::default()
             .borders(Borders::ALL)
             .title(format!("circuitchat v{}", env!("CARGO_PKG_VERSION")))
             .border_style(Style::default().fg(Color::DarkGray));
 
-        if let Some(ref fp) = self.session_fingerprint {
-            if !fp.is_empty() {
-                block = block.title_bottom(format!("fp: {}", fp));
-            }
+        if let Some(ref fp) = view.session_fingerprint
+            && !fp.is_empty()
+        {
+            block = block.title_bottom(format!("fp: {}", fp));
         }
 
-        let end = self.messages.len().min(self.scroll_offset + inner_height);
-        let start = self.scroll_offset.min(end);
+        if let Some(ref warning) = view.identity_warning {
+            let style = Style::default().fg(Color::Red).add_modifier(Modifier::BOLD);
+            block = block
+                .border_style(style)
+                .title(Line::from(Span::styled(format!(" ! {} ! ", warning), style)).centered());
+        }
 
-        let lines: Vec<Line> = self.messages[start..end]
-            .iter()
-            .map(|msg| {
-                let (label, color) = match msg.direction {
-                    MessageDirection::Sent => ("you", Color::Green),
-                    MessageDirection::Received => ("peer", Color::Cyan),
-                    MessageDirection::System => ("system", Color::Yellow),
-                };
-                let mut spans = vec![
-                    Span::styled(
-                        format!("[{}] ", msg.timestamp),
-                        Style::default().fg(Color::DarkGray),
-                    ),
-                    Span::styled(
-                        format!("{}: ", label),
-                        Style::default().fg(color).add_modifier(Modifier::BOLD),
-                    ),
-                ];
-                for (segment, style) in &msg.content {
-                    match style {
-                        Some(s) => spans.push(Span::styled(segment.clone(), *s)),
-                        None => spans.push(Span::styled(segment.clone(), Style::default().fg(Color::White))),
-                    }
+        let mut lines: Vec<Line> = Vec::new();
+        let mut placements = Vec::new();
+        let mut used = 0;
+        let mut end = start;
+        for msg in &view.messages[start..] {
+            if used >= inner_height {
+                break;
+            }
+            end += 1;
+            let line = message_line(view, msg);
+            used += Paragraph::new(line.clone()).wrap(Wrap { trim: false }).line_count(inner_width);
+            lines.push(line);
+            if let Some(preview) = preview_of(msg) {
+                // graphics only go where the whole preview is on screen
+                if used + preview.rows as usize <= inner_height {
+                    placements.push(Placement {
+                        x: area.x + 1,
+                        y: area.y + 1 + used as u16,
+                        preview: preview.clone(),
+                    });
                 }
-                Line::from(spans)
-            })
-            .collect();
+                used += preview.rows as usize;
+                lines.extend(preview.lines(graphics));
+            }
+        }
 
         let paragraph = Paragraph::new(lines)
             .block(block)
             .wrap(Wrap { trim: false });
 
         frame.render_widget(paragraph, area);
+        let view = &mut self.views[self.active];
+        if view.follow {
+            view.scroll_offset = start;
+        }
+        self.placements = placements;
+        self.shown = start..end;
+        let view = &self.views[self.active];
 
         let label = "menu: alt+m";
         let w = (label.len() as u16).saturating_add(2);
@@ -409,16 +899,16 @@ impl App {
             frame.render_widget(p, rect);
         }
 
-        let mut status_label = self.status.clone();
+        let mut status_label = view.status.clone();
         if let Some(deadline) = self.session_deadline {
             let now = std::time::Instant::now();
             if deadline > now {
                 let rem = deadline.duration_since(now);
                 let m = rem.as_secs() / 60;
                 let s = rem.as_secs() % 60;
-                status_label = format!("{} | session expires in {}m {}s", self.status, m, s);
+                status_label = format!("{} | session expires in {}m {}s", view.status, m, s);
             } else {
-                status_label = format!("{} | session expired", self.status);
+                status_label = format!("{} | session expired", view.status);
             }
         }
 
@@ -438,7 +928,7 @@ impl App {
     fn draw_menu(&self, frame: &mut Frame) {
         let area = frame.area();
         let mw = 48u16.min(area.width.saturating_sub(4));
-        let mh = 14u16.min(area.height.saturating_sub(4));
+        let mh = 15u16.min(area.height.saturating_sub(4));
         let mx = area.x + (area.width.saturating_sub(mw)) / 2;
         let my = area.y + (area.height.saturating_sub(mh)) / 2;
         let rect = Rect::new(mx, my, mw, mh);
@@ -464,6 +954,7 @@ impl App {
             Line::from("  alt+m : toggle menu"),
             Line::from("  ctrl+c / ctrl+d : quit"),
             Line::from("  ctrl+w : panic (wipe & exit)"),
+            Line::from("  alt+left/right, alt+1-9 : switch session"),
             Line::from(""),
             Line::from(Span::styled(
                 "actions:",
@@ -485,118 +976,85 @@ impl App {
         frame.render_widget(paragraph, rect);
     }
 
-    fn draw_transfer_modal(&self, frame: &mut Frame, is_send: bool) {
-        let t = if is_send {
-            self.send_progress.as_ref().unwrap()
-        } else {
-            self.recv_progress.as_ref().unwrap()
-        };
-
-        let area = frame.area();
-        let mw = 52u16.min(area.width.saturating_sub(4));
-        let mh = 10u16.min(area.height.saturating_sub(4));
-        let mx = area.x + (area.width.saturating_sub(mw)) / 2;
-        let my = area.y + (area.height.saturating_sub(mh)) / 2;
-        let rect = Rect::new(mx, my, mw, mh);
-        let mut fill_lines: Vec<Line> = Vec::new();
-        for _ in 0..mh {
-            fill_lines.push(Line::from(" ".repeat(mw as usize)));
-        }
-        let filler = Paragraph::new(fill_lines).style(Style::default().bg(Color::Black));
-        frame.render_widget(filler, rect);
-        let pct = t.pct();
-        let pct_text = format!("{}%", pct);
-        let reserved = 12 + pct_text.len();
-        let bar_inner = (mw as usize).saturating_sub(reserved);
-        let filled = if t.size == 0 {
-            bar_inner
-        } else {
-            (bar_inner as u64 * t.transferred / t.size) as usize
-        };
-        let empty = bar_inner.saturating_sub(filled);
-        let bar = format!(
-            " [{}{}] {}%",
-            "\u{2588}".repeat(filled),
-            "\u{2591}".repeat(empty),
-            pct
-        );
-
-        let size_line = format!(
-            " {} / {}",
-            crate::files::format_size(t.transferred),
-            crate::files::format_size(t.size)
-        );
-
-        let elapsed = t.start.elapsed();
-        let speed_bps = if elapsed.as_secs_f64() > 0.0 {
-            (t.transferred as f64) / elapsed.as_secs_f64()
-        } else {
-            0.0
-        };
-        let speed_line = format!(
-            " speed: {}/s",
-            crate::files::format_size(speed_bps as u64)
-        );
-
-        let eta_line = {
-            let remaining = t.size.saturating_sub(t.transferred) as f64;
-            if speed_bps > 0.0 && remaining > 0.0 {
-                let secs = (remaining / speed_bps).round() as u64;
-                let h = secs / 3600;
-                let m = (secs % 3600) / 60;
-                let s = secs % 60;
-                if h > 0 {
-                    format!(" ETA: {:02}:{:02}:{:02}", h, m, s)
-                } else {
-                    format!(" ETA: {:02}:{:02}", m, s)
+    fn draw_transfers(&self, frame: &mut Frame, area: Rect) {
+        let view = self.view();
+        let width = area.width.saturating_sub(2) as usize;
+        let mut lines: Vec<Line> = Vec::new();
+        for (i, t) in view.transfers.iter().enumerate().take(MAX_TRANSFER_ROWS) {
+            let (arrow, color) = if t.incoming {
+                ("\u{2193}", Color::Cyan)
+            } else {
+                ("\u{2191}", Color::Yellow)
+            };
+            let detail = match t.state {
+                "sending" | "receiving" => {
+                    let bar_width = 10;
+                    let filled = (t.pct() as usize * bar_width / 100).min(bar_width);
+                    let ratio = t.ratio().map(|r| format!("  zstd {:.1}x", r)).unwrap_or_default();
+                    format!(
+                        " [{}{}] {:>3}%  {}/s  ETA {}{}",
+                        "\u{2588}".repeat(filled),
+                        "\u{2591}".repeat(bar_width - filled),
+                        t.pct(),
+                        crate::files::format_size(t.speed() as u64),
+                        t.eta(),
+                        ratio
+                    )
                 }
-            } else if t.transferred == 0 && t.size > 0 {
-                " ETA: calculating...".to_string()
+                state => format!(" {}", state),
+            };
+            let size = format!(" ({})", crate::files::format_size(t.size));
+            // the name gives way first when the panel is narrow
+            let room = width.saturating_sub(4 + size.len() + detail.chars().count());
+            let name: String = if t.name.chars().count() > room {
+                let kept: String = t.name.chars().take(room.saturating_sub(1)).collect();
+                format!("{}\u{2026}", kept)
             } else {
-                " ETA: --:--".to_string()
-            }
-        };
-
-        let (title, color, hint) = if is_send {
-            (" sending File ", Color::Yellow, " Esc to cancel")
-        } else {
-            (" receiving File ", Color::Cyan, " /cancel to abort")
-        };
+                t.name.clone()
+            };
+            lines.push(Line::from(vec![
+                Span::styled(format!("{:>2} {} ", i + 1, arrow), Style::default().fg(color)),
+                Span::styled(name, Style::default().add_modifier(Modifier::BOLD)),
+                Span::styled(size, Style::default().fg(Color::DarkGray)),
+                Span::raw(detail),
+            ]));
+        }
 
+        let mut title = " transfers - /accept, /reject, /cancel <n> ".to_string();
+        if view.transfers.len() > MAX_TRANSFER_ROWS {
+            title = format!(" transfers ({} more not shown) ", view.transfers.len() - MAX_TRANSFER_ROWS);
+        }
         let block = Block::default()
             .borders(Borders::ALL)
             .title(title)
-            .border_style(Style::default().fg(color));
-
-        let lines: Vec<Line> = vec![
-            Line::from(""),
-            Line::from(Span::styled(
-                format!(" {}", t.name),
-                Style::default().add_modifier(Modifier::BOLD),
-            )),
-            Line::from(size_line),
-            Line::from(speed_line),
-            Line::from(eta_line),
-            Line::from(bar),
-            Line::from(""),
-            Line::from(Span::styled(hint, Style::default().fg(Color::DarkGray))),
-        ];
-
-        let paragraph = Paragraph::new(lines)
-            .block(block)
-            .wrap(Wrap { trim: false });
-        frame.render_widget(paragraph, rect);
+            .border_style(Style::default().fg(Color::DarkGray));
+        frame.render_widget(Paragraph::new(lines).block(block), area);
     }
 
     fn draw_input(&self, frame: &mut Frame, area: Rect) {
+        let (title, shown) = match (self.recording, &self.secret_prompt) {
+            (Some(started), _) => {
+                let secs = started.elapsed().as_secs();
+                (
+                    format!(" recording {}:{:02} - Enter to send, Esc to discard ", secs / 60, secs % 60),
+                    self.input.clone(),
+                )
+            }
+            (None, Some(prompt)) => (
+                format!(" {} ", prompt),
+                "*".repeat(self.input.chars().count()),
+            ),
+            (None, None) => (" message ".to_string(), self.input.clone()),
+        };
+        let border = if self.recording.is_some() { Color::Red } else { Color::DarkGray };
         let block = Block::default()
             .borders(Borders::ALL)
-            .title(" message ")
-            .border_style(Style::default().fg(Color::DarkGray));
+            .title(title)
+            .border_style(Style::default().fg(border));
 
         let paragraph = Paragraph::new(Line::from(vec![
             Span::styled("> ", Style::default().fg(Color::DarkGray)),
-            Span::raw(self.input.clone()),
+            Span::raw(shown),
         ]))
         .block(block);
 
@@ -622,17 +1080,66 @@ impl App {
             frame.render_widget(p, rect);
         }
     }
-    pub fn mark_last_sent_delivered(&mut self) {
-        for msg in self.messages.iter_mut().rev() {
-            if msg.direction == MessageDirection::Sent {
-                let full: String = msg.content.iter().map(|(s, _)| s.as_str()).collect();
-                if !full.ends_with(" ✓") {
-                    msg.content.push((" ✓".to_string(), None));
-                    break;
-                }
-            }
+}
+
+fn message_line(view: &SessionView, msg: &ChatMessage) -> Line<'static> {
+    let (label, color) = match msg.direction {
+        MessageDirection::Sent => ("you", Color::Green),
+        MessageDirection::Received => (
+            msg.sender.as_deref().unwrap_or(view.peer_label.as_str()),
+            Color::Cyan,
+        ),
+        MessageDirection::System => ("system", Color::Yellow),
+    };
+    let mut spans = vec![
+        Span::styled(
+            format!("[{}] ", msg.timestamp),
+            Style::default().fg(Color::DarkGray),
+        ),
+        Span::styled(
+            format!("{}: ", label),
+            Style::default().fg(color).add_modifier(Modifier::BOLD),
+        ),
+    ];
+    if msg.reply_to != 0 {
+        // replies always quote the other side's message
+        let quoted_direction = match msg.direction {
+            MessageDirection::Sent => MessageDirection::Received,
+            _ => MessageDirection::Sent,
+        };
+        let quoted = view
+            .messages
+            .iter()
+            .find(|m| m.id == msg.reply_to && m.direction == quoted_direction)
+            .map(|m| m.text())
+            .unwrap_or_else(|| "earlier message".to_string());
+        let quoted: String = quoted.chars().take(40).collect();
+        spans.push(Span::styled(
+            format!("↪ {} | ", quoted),
+            Style::default().fg(Color::DarkGray).add_modifier(Modifier::ITALIC),
+        ));
+    }
+    for (segment, style) in &msg.content {
+        match style {
+            Some(s) => spans.push(Span::styled(segment.clone(), *s)),
+            None => spans.push(Span::styled(segment.clone(), Style::default().fg(Color::White))),
+        }
+    }
+    if msg.edited {
+        spans.push(Span::styled(" (edited)", Style::default().fg(Color::DarkGray)));
+    }
+    for (by, emoji) in &msg.reactions {
+        let color = if *by == MessageDirection::Sent { Color::Green } else { Color::Cyan };
+        spans.push(Span::styled(format!(" [{}]", emoji), Style::default().fg(color)));
+    }
+    if msg.direction == MessageDirection::Sent {
+        match msg.state {
+            MessageState::Delivered => spans.push(Span::styled(" ✓", Style::default().fg(Color::White))),
+            MessageState::Read => spans.push(Span::styled(" ✓✓", Style::default().fg(Color::Cyan))),
+            _ => {}
         }
     }
+    Line::from(spans)
 }
 
 pub fn format_timestamp(
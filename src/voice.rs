@@ -0,0 +1,385 @@
+use std::time::Duration;
+
+/// Voice clips up to this size are accepted without asking, larger ones are
+/// rejected.
+pub const MAX_CLIP_SIZE: u64 = 2 * 1024 * 1024;
+/// Clips accepted without asking in one session. Later ones wait for
+/// `/accept` like files.
+pub const MAX_AUTO_CLIPS: u32 = 20;
+/// Recording stops and sends by itself after this long.
+pub const MAX_DURATION: Duration = Duration::from_secs(5 * 60);
+
+/// `m:ss`, how clips are labelled in the chat.
+pub fn format_duration(ms: u32) -> String {
+    let secs = ms / 1000;
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+#[cfg(feature = "voice")]
+pub use enabled::*;
+
+#[cfg(not(feature = "voice"))]
+pub use disabled::*;
+
+#[cfg(feature = "voice")]
+mod enabled {
+    use audiopus::coder::{Decoder, Encoder};
+    use audiopus::{Application, Bitrate, Channels, SampleRate};
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+    use cpal::{FromSample, SampleFormat, SizedSample};
+    use ogg::{PacketReader, PacketWriteEndInfo, PacketWriter};
+    use std::error::Error;
+    use std::io::Cursor;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex, mpsc};
+    use std::thread::JoinHandle;
+    use std::time::Duration;
+
+    use super::MAX_DURATION;
+    use crate::files::QueuedFile;
+
+    // opus works at 48 kHz, everything else is resampled to it
+    const RATE: u32 = 48_000;
+    // 20 ms
+    const FRAME: usize = 960;
+    const BITRATE: i32 = 24_000;
+    // the longest frame opus can decode, 120 ms
+    const MAX_FRAME: usize = 5760;
+
+    /// Records from the default input device on a thread of its own, since
+    /// audio streams cannot move between threads everywhere. Dropping it
+    /// stops the recording and throws it away.
+    pub struct Recorder {
+        stop: mpsc::Sender<()>,
+        thread: JoinHandle<(Vec<f32>, u32)>,
+    }
+
+    impl Recorder {
+        pub fn start() -> Result<Self, Box<dyn Error>> {
+            let (stop, stopped) = mpsc::channel::<()>();
+            let (ready, started) = mpsc::sync_channel(1);
+            let thread = std::thread::spawn(move || {
+                let samples = Arc::new(Mutex::new(Vec::new()));
+                let (stream, rate) = match input_stream(samples.clone()) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        let _ = ready.send(Err(e));
+                        return (Vec::new(), RATE);
+                    }
+                };
+                let _ = ready.send(Ok(()));
+                // either a message or the recorder being dropped ends it
+                let _ = stopped.recv();
+                drop(stream);
+                let samples = std::mem::take(&mut *samples.lock().unwrap());
+                (samples, rate)
+            });
+            started.recv().map_err(|_| "the recording thread stopped")??;
+            Ok(Recorder { stop, thread })
+        }
+
+        /// Stops recording and encodes what was recorded as a voice clip.
+        pub fn finish(self) -> Result<QueuedFile, Box<dyn Error>> {
+            let _ = self.stop.send(());
+            let (samples, rate) = self.thread.join().map_err(|_| "the recording thread panicked")?;
+            let max = (MAX_DURATION.as_secs() * rate as u64) as usize;
+            encode(&samples[..samples.len().min(max)], rate)
+        }
+    }
+
+    fn input_stream(samples: Arc<Mutex<Vec<f32>>>) -> Result<(cpal::Stream, u32), String> {
+        let device = cpal::default_host()
+            .default_input_device()
+            .ok_or("no audio input device, try /voice <file.wav>")?;
+        let supported = device.default_input_config().map_err(|e| e.to_string())?;
+        let config = supported.config();
+        let stream = match supported.sample_format() {
+            SampleFormat::F32 => build_input::<f32>(&device, &config, samples),
+            SampleFormat::I16 => build_input::<i16>(&device, &config, samples),
+            SampleFormat::U16 => build_input::<u16>(&device, &config, samples),
+            SampleFormat::I32 => build_input::<i32>(&device, &config, samples),
+            SampleFormat::I8 => build_input::<i8>(&device, &config, samples),
+            SampleFormat::U8 => build_input::<u8>(&device, &config, samples),
+            format => return Err(format!("unsupported sample format {}", format)),
+        }
+        .map_err(|e| e.to_string())?;
+        stream.play().map_err(|e| e.to_string())?;
+        Ok((stream, config.sample_rate))
+    }
+
+    // mixed down to mono as it arrives
+    fn build_input<T>(
+        device: &cpal::Device,
+        config: &cpal::StreamConfig,
+        samples: Arc<Mutex<Vec<f32>>>,
+    ) -> Result<cpal::Stream, cpal::BuildStreamError>
+    where
+        T: SizedSample,
+        f32: FromSample<T>,
+    {
+        let channels = config.channels.max(1) as usize;
+        device.build_input_stream(
+            config,
+            move |data: &[T], _: &cpal::InputCallbackInfo| {
+                let mut samples = samples.lock().unwrap();
+                samples.extend(
+                    data.chunks(channels)
+                        .map(|frame| frame.iter().map(|&s| s.to_sample::<f32>()).sum::<f32>() / channels as f32),
+                );
+            },
+            |_| {},
+            None,
+        )
+    }
+
+    /// Encodes a WAV file as a voice clip, for when there is no microphone.
+    pub fn encode_wav(path: &Path) -> Result<QueuedFile, Box<dyn Error>> {
+        let mut reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+        if spec.sample_rate == 0 || spec.channels == 0 {
+            return Err("malformed WAV header".into());
+        }
+        if reader.duration() as u64 > MAX_DURATION.as_secs() * spec.sample_rate as u64 {
+            return Err(format!("longer than {} minutes", MAX_DURATION.as_secs() / 60).into());
+        }
+        let interleaved: Vec<f32> = match spec.sample_format {
+            hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+            hound::SampleFormat::Int => {
+                let scale = (1u64 << (spec.bits_per_sample.clamp(1, 32) - 1)) as f32;
+                reader
+                    .samples::<i32>()
+                    .map(|s| s.map(|s| s as f32 / scale))
+                    .collect::<Result<_, _>>()?
+            }
+        };
+        let channels = spec.channels as usize;
+        let mono: Vec<f32> = interleaved
+            .chunks(channels)
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+            .collect();
+        encode(&mono, spec.sample_rate)
+    }
+
+    /// Encodes mono samples as an Ogg Opus file in a temporary file, ready to
+    /// be offered.
+    fn encode(samples: &[f32], rate: u32) -> Result<QueuedFile, Box<dyn Error>> {
+        let mut samples = resample(samples, rate, RATE);
+        if samples.len() < RATE as usize / 2 {
+            return Err("the recording is too short".into());
+        }
+        let duration_ms = (samples.len() as u64 * 1000 / RATE as u64) as u32;
+        let mut encoder = Encoder::new(SampleRate::Hz48000, Channels::Mono, Application::Voip)?;
+        encoder.set_bitrate(Bitrate::BitsPerSecond(BITRATE))?;
+        // the encoder runs this far behind, the player skips it again
+        let pre_skip = encoder.lookahead()? as usize;
+        let total = samples.len() + pre_skip;
+        samples.resize(total.div_ceil(FRAME) * FRAME, 0.0);
+
+        let serial = rand::random::<u32>();
+        let mut writer = PacketWriter::new(Vec::new());
+        writer.write_packet(opus_head(pre_skip as u16), serial, PacketWriteEndInfo::EndPage, 0)?;
+        writer.write_packet(opus_tags(), serial, PacketWriteEndInfo::EndPage, 0)?;
+        let mut packet = [0u8; 4000];
+        let frames = samples.len() / FRAME;
+        for (i, frame) in samples.chunks_exact(FRAME).enumerate() {
+            let len = encoder.encode_float(frame, &mut packet)?;
+            let last = i + 1 == frames;
+            let end = if last {
+                PacketWriteEndInfo::EndStream
+            } else {
+                PacketWriteEndInfo::NormalPacket
+            };
+            // the position of the last one cuts off the padding
+            let granule = ((i + 1) * FRAME).min(total) as u64;
+            writer.write_packet(packet[..len].into(), serial, end, granule)?;
+        }
+        let name = format!("voice-{}.opus", chrono::Local::now().format("%Y%m%d-%H%M%S"));
+        let mut queued = QueuedFile::temporary(name, &writer.into_inner())?;
+        queued.voice_ms = Some(duration_ms);
+        Ok(queued)
+    }
+
+    // RFC 7845: version 1, mono, no gain and the simple channel mapping
+    fn opus_head(pre_skip: u16) -> Box<[u8]> {
+        let mut head = b"OpusHead".to_vec();
+        head.extend_from_slice(&[1, 1]);
+        head.extend_from_slice(&pre_skip.to_le_bytes());
+        head.extend_from_slice(&RATE.to_le_bytes());
+        head.extend_from_slice(&0i16.to_le_bytes());
+        head.push(0);
+        head.into()
+    }
+
+    fn opus_tags() -> Box<[u8]> {
+        let vendor = concat!("circuitchat ", env!("CARGO_PKG_VERSION"));
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+        tags.extend_from_slice(vendor.as_bytes());
+        tags.extend_from_slice(&0u32.to_le_bytes());
+        tags.into()
+    }
+
+    /// Decodes an Ogg Opus clip to mono samples at 48 kHz.
+    fn decode(data: &[u8]) -> Result<Vec<f32>, Box<dyn Error>> {
+        let mut reader = PacketReader::new(Cursor::new(data));
+        let head = reader.read_packet()?.ok_or("the clip is empty")?;
+        if head.data.len() < 19 || !head.data.starts_with(b"OpusHead") {
+            return Err("not an Opus clip".into());
+        }
+        let pre_skip = u16::from_le_bytes([head.data[10], head.data[11]]) as usize;
+        // the tags
+        reader.read_packet()?;
+        // stereo clips from elsewhere are mixed down by the decoder
+        let mut decoder = Decoder::new(SampleRate::Hz48000, Channels::Mono)?;
+        let max = (MAX_DURATION.as_secs() * RATE as u64) as usize + pre_skip;
+        let mut samples = Vec::new();
+        let mut frame = vec![0f32; MAX_FRAME];
+        while let Some(packet) = reader.read_packet()? {
+            let len = decoder.decode_float(Some((&packet.data).try_into()?), (&mut frame).try_into()?, false)?;
+            samples.extend_from_slice(&frame[..len]);
+            if samples.len() > max {
+                return Err("the clip is too long".into());
+            }
+        }
+        samples.drain(..pre_skip.min(samples.len()));
+        Ok(samples)
+    }
+
+    fn resample(samples: &[f32], from: u32, to: u32) -> Vec<f32> {
+        if from == to || samples.is_empty() {
+            return samples.to_vec();
+        }
+        let len = (samples.len() as u64 * to as u64 / from as u64) as usize;
+        (0..len)
+            .map(|i| {
+                let pos = i as f64 * from as f64 / to as f64;
+                let j = pos as usize;
+                let a = samples[j];
+                let b = samples.get(j + 1).copied().unwrap_or(a);
+                a + (b - a) * (pos - j as f64) as f32
+            })
+            .collect()
+    }
+
+    /// Plays a clip on the default output device from a thread of its own.
+    /// Dropping it stops playback.
+    pub struct Player {
+        _stop: mpsc::Sender<()>,
+        thread: JoinHandle<()>,
+    }
+
+    impl Player {
+        pub fn start(data: Vec<u8>) -> Result<Self, Box<dyn Error>> {
+            let samples = decode(&data)?;
+            let (stop, stopped) = mpsc::channel::<()>();
+            let (ready, started) = mpsc::sync_channel(1);
+            let thread = std::thread::spawn(move || {
+                let (_stream, duration) = match output_stream(samples) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        let _ = ready.send(Err(e));
+                        return;
+                    }
+                };
+                let _ = ready.send(Ok(()));
+                // a little longer, so the end is not cut off
+                let _ = stopped.recv_timeout(duration + Duration::from_millis(300));
+            });
+            started.recv().map_err(|_| "the playback thread stopped")??;
+            Ok(Player { _stop: stop, thread })
+        }
+
+        pub fn finished(&self) -> bool {
+            self.thread.is_finished()
+        }
+    }
+
+    fn output_stream(samples: Vec<f32>) -> Result<(cpal::Stream, Duration), String> {
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or("no audio output device")?;
+        let supported = device.default_output_config().map_err(|e| e.to_string())?;
+        let config = supported.config();
+        let samples = resample(&samples, RATE, config.sample_rate);
+        let duration = Duration::from_secs_f64(samples.len() as f64 / config.sample_rate as f64);
+        let stream = match supported.sample_format() {
+            SampleFormat::F32 => build_output::<f32>(&device, &config, samples),
+            SampleFormat::I16 => build_output::<i16>(&device, &config, samples),
+            SampleFormat::U16 => build_output::<u16>(&device, &config, samples),
+            SampleFormat::I32 => build_output::<i32>(&device, &config, samples),
+            SampleFormat::I8 => build_output::<i8>(&device, &config, samples),
+            SampleFormat::U8 => build_output::<u8>(&device, &config, samples),
+            format => return Err(format!("unsupported sample format {}", format)),
+        }
+        .map_err(|e| e.to_string())?;
+        stream.play().map_err(|e| e.to_string())?;
+        Ok((stream, duration))
+    }
+
+    // the same sample on every channel, then silence
+    fn build_output<T>(
+        device: &cpal::Device,
+        config: &cpal::StreamConfig,
+        samples: Vec<f32>,
+    ) -> Result<cpal::Stream, cpal::BuildStreamError>
+    where
+        T: SizedSample + FromSample<f32>,
+    {
+        let channels = config.channels.max(1) as usize;
+        let position = AtomicUsize::new(0);
+        device.build_output_stream(
+            config,
+            move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                for frame in data.chunks_mut(channels) {
+                    let i = position.fetch_add(1, Ordering::Relaxed);
+                    let sample = T::from_sample(samples.get(i).copied().unwrap_or(0.0));
+                    frame.fill(sample);
+                }
+            },
+            |_| {},
+            None,
+        )
+    }
+}
+
+#[cfg(not(feature = "voice"))]
+mod disabled {
+    use std::convert::Infallible;
+    use std::error::Error;
+    use std::path::Path;
+
+    use crate::files::QueuedFile;
+
+    const UNSUPPORTED: &str = "this build has no voice support, rebuild with --features voice";
+
+    /// Stands in for the recorder in builds without the `voice` feature.
+    pub struct Recorder(Infallible);
+
+    impl Recorder {
+        pub fn start() -> Result<Self, Box<dyn Error>> {
+            Err(UNSUPPORTED.into())
+        }
+
+        pub fn finish(self) -> Result<QueuedFile, Box<dyn Error>> {
+            match self.0 {}
+        }
+    }
+
+    pub fn encode_wav(_path: &Path) -> Result<QueuedFile, Box<dyn Error>> {
+        Err(UNSUPPORTED.into())
+    }
+
+    /// Stands in for the player in builds without the `voice` feature.
+    pub struct Player(Infallible);
+
+    impl Player {
+        pub fn start(_data: Vec<u8>) -> Result<Self, Box<dyn Error>> {
+            Err(UNSUPPORTED.into())
+        }
+
+        pub fn finished(&self) -> bool {
+            match self.0 {}
+        }
+    }
+}